@@ -0,0 +1,40 @@
+#ifndef BLOCKY_H
+#define BLOCKY_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define BLOCKY_OK 0
+#define BLOCKY_NOT_FOUND 1
+#define BLOCKY_INVALID_ARGUMENT 2
+#define BLOCKY_READ_ERROR 3
+#define BLOCKY_UNSUPPORTED 4
+
+typedef struct BlockyBlock BlockyBlock;
+
+typedef struct BlockyFileInfo {
+    uint64_t id;
+    uint64_t id_high;
+    uint32_t size;
+    uint32_t offset;
+    uint8_t location_hash[16];
+} BlockyFileInfo;
+
+BlockyBlock *blocky_open(const char *path);
+void blocky_close(BlockyBlock *block);
+size_t blocky_len(const BlockyBlock *block);
+int blocky_file_info_at(const BlockyBlock *block, size_t idx, BlockyFileInfo *info);
+
+/* Pointers returned via `data` stay valid until blocky_close() */
+int blocky_file_at(const BlockyBlock *block, size_t idx, const uint8_t **data, size_t *len);
+int blocky_file_by_id(const BlockyBlock *block, uint64_t id, const uint8_t **data, size_t *len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,236 @@
+//! C ABI для чтения блоков.
+//!
+//! Позволяет сервисам на C/C++ и Java (через JNI) читать блоки, не реализуя формат самостоятельно.
+//! Все функции, возвращающие содержимое файлов, отдают указатель и длину непосредственно в
+//! отображенную в память область блока. Указатели остаются валидными до вызова [`blocky_close`].
+//! Поэтому содержимое сжатых блоков, которое пришлось бы распаковывать в отдельный буфер, через
+//! C ABI недоступно.
+//!
+//! Соответствующий заголовочный файл: `include/blocky.h`.
+//!
+//! [`blocky_close`]: fn.blocky_close.html
+use blocky::block::Block;
+use blocky::errors::{Error, Result};
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+/// Операция выполнена успешно
+pub const BLOCKY_OK: c_int = 0;
+
+/// Файл с запрошенным идентификатором или индексом отсутствует в блоке
+pub const BLOCKY_NOT_FOUND: c_int = 1;
+
+/// Передан нулевой указатель
+pub const BLOCKY_INVALID_ARGUMENT: c_int = 2;
+
+/// Файл не удалось прочитать: блок поврежден или обрезан
+pub const BLOCKY_READ_ERROR: c_int = 3;
+
+/// Содержимое файла сжато и не может быть возвращено без копирования
+pub const BLOCKY_UNSUPPORTED: c_int = 4;
+
+/// Непрозрачный дескриптор открытого блока
+pub struct BlockyBlock(Block);
+
+/// Метаинформация о файле в блоке. Повторяет [`FileInfo`].
+///
+/// [`FileInfo`]: ../blocky/block/struct.FileInfo.html
+#[repr(C)]
+pub struct BlockyFileInfo {
+    pub id: u64,
+    /// Старшие 64 бита 128-битного идентификатора (см. [`FileInfo::id_high`])
+    ///
+    /// [`FileInfo::id_high`]: ../blocky/block/struct.FileInfo.html#structfield.id_high
+    pub id_high: u64,
+    pub size: u32,
+    pub offset: u32,
+    pub location_hash: [u8; 16],
+}
+
+/// Открывает блок по указанному пути. Возвращает `NULL` в случае ошибки.
+///
+/// # Safety
+/// `path` должен указывать на валидную C-строку в кодировке UTF-8.
+#[no_mangle]
+pub unsafe extern "C" fn blocky_open(path: *const c_char) -> *mut BlockyBlock {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return ptr::null_mut(),
+    };
+    match Block::open(path) {
+        Ok(block) => Box::into_raw(Box::new(BlockyBlock(block))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Закрывает блок и освобождает связанные с ним ресурсы.
+///
+/// # Safety
+/// `block` должен быть получен из [`blocky_open`] и не может использоваться после вызова.
+///
+/// [`blocky_open`]: fn.blocky_open.html
+#[no_mangle]
+pub unsafe extern "C" fn blocky_close(block: *mut BlockyBlock) {
+    if !block.is_null() {
+        drop(Box::from_raw(block));
+    }
+}
+
+/// Возвращает количество файлов в блоке
+///
+/// # Safety
+/// `block` должен быть валидным дескриптором, полученным из [`blocky_open`].
+///
+/// [`blocky_open`]: fn.blocky_open.html
+#[no_mangle]
+pub unsafe extern "C" fn blocky_len(block: *const BlockyBlock) -> usize {
+    match block.as_ref() {
+        Some(BlockyBlock(block)) => block.len(),
+        None => 0,
+    }
+}
+
+/// Записывает в `info` метаинформацию о файле с порядковым номером `idx`.
+///
+/// # Safety
+/// `block` должен быть валидным дескриптором, `info` – указывать на доступную для записи память.
+#[no_mangle]
+pub unsafe extern "C" fn blocky_file_info_at(
+    block: *const BlockyBlock,
+    idx: usize,
+    info: *mut BlockyFileInfo,
+) -> c_int {
+    let (block, info) = match (block.as_ref(), info.as_mut()) {
+        (Some(BlockyBlock(block)), Some(info)) => (block, info),
+        _ => return BLOCKY_INVALID_ARGUMENT,
+    };
+    match block.iter().nth(idx) {
+        Some(file) => {
+            *info = BlockyFileInfo {
+                id: file.id,
+                id_high: file.id_high,
+                size: file.size,
+                offset: file.offset,
+                location_hash: file.location_hash.0,
+            };
+            BLOCKY_OK
+        }
+        None => BLOCKY_NOT_FOUND,
+    }
+}
+
+/// Возвращает содержимое файла с порядковым номером `idx` через `data`/`len`.
+///
+/// # Safety
+/// `block` должен быть валидным дескриптором, `data` и `len` – указывать на доступную для
+/// записи память.
+#[no_mangle]
+pub unsafe extern "C" fn blocky_file_at(
+    block: *const BlockyBlock,
+    idx: usize,
+    data: *mut *const u8,
+    len: *mut usize,
+) -> c_int {
+    let block = match block.as_ref() {
+        Some(BlockyBlock(block)) => block,
+        None => return BLOCKY_INVALID_ARGUMENT,
+    };
+    write_content(block.file_at(idx).map(|(_, c)| c), data, len)
+}
+
+/// Возвращает содержимое файла с идентификатором `id` через `data`/`len`.
+///
+/// # Safety
+/// `block` должен быть валидным дескриптором, `data` и `len` – указывать на доступную для
+/// записи память.
+#[no_mangle]
+pub unsafe extern "C" fn blocky_file_by_id(
+    block: *const BlockyBlock,
+    id: u64,
+    data: *mut *const u8,
+    len: *mut usize,
+) -> c_int {
+    let block = match block.as_ref() {
+        Some(BlockyBlock(block)) => block,
+        None => return BLOCKY_INVALID_ARGUMENT,
+    };
+    write_content(block.file_by_id(id).map(|(_, c)| c), data, len)
+}
+
+unsafe fn write_content(
+    content: Result<Cow<[u8]>>,
+    data: *mut *const u8,
+    len: *mut usize,
+) -> c_int {
+    if data.is_null() || len.is_null() {
+        return BLOCKY_INVALID_ARGUMENT;
+    }
+    match content {
+        Ok(Cow::Owned(_)) => BLOCKY_UNSUPPORTED,
+        Ok(Cow::Borrowed(content)) => {
+            *data = content.as_ptr();
+            *len = content.len();
+            BLOCKY_OK
+        }
+        Err(Error::FileNotFound { .. }) | Err(Error::IndexOutOfRange { .. }) => BLOCKY_NOT_FOUND,
+        Err(_) => BLOCKY_READ_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use blocky::block::AddFileRequest;
+    use std::ffi::CString;
+    use std::fs;
+    use std::path::Path;
+    use std::slice;
+    use tempdir::TempDir;
+
+    #[test]
+    fn read_block_through_c_abi() {
+        let tmp = TempDir::new("blocky-ffi-test").unwrap();
+        let file_path = tmp.path().join("one.txt");
+        fs::write(&file_path, "Hello").unwrap();
+        let block_path = tmp.path().join("test.block");
+        Block::from_files(
+            &block_path,
+            &[AddFileRequest {
+                id: 42,
+                path: &file_path,
+                location: Path::new("/one.txt"),
+                content_hash: None,
+                size: None,
+            }],
+        )
+        .unwrap();
+
+        let path = CString::new(block_path.to_str().unwrap()).unwrap();
+        unsafe {
+            let block = blocky_open(path.as_ptr());
+            assert!(!block.is_null());
+            assert_eq!(blocky_len(block), 1);
+
+            let mut data = ptr::null();
+            let mut len = 0;
+            assert_eq!(blocky_file_by_id(block, 42, &mut data, &mut len), BLOCKY_OK);
+            assert_eq!(slice::from_raw_parts(data, len), b"Hello");
+            assert_eq!(
+                blocky_file_by_id(block, 1, &mut data, &mut len),
+                BLOCKY_NOT_FOUND
+            );
+            assert_eq!(
+                blocky_file_at(block, 1, &mut data, &mut len),
+                BLOCKY_NOT_FOUND
+            );
+
+            blocky_close(block);
+        }
+    }
+}
@@ -1,25 +1,260 @@
+#[cfg(feature = "zstd")]
+use crate::compression::{self, CompressedContent};
+#[cfg(feature = "encryption")]
+use crate::encryption::{self, EncryptionKey};
 use crate::errors::*;
+use crate::location::{self, Normalization};
+use crate::manifest::{self, ManifestEntry, MANIFEST_ID, MANIFEST_LOCATION};
+use crate::storage::{BlockStorage, RangeRead, RangeReader};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use md5;
-use memmap::{Mmap, MmapOptions};
+#[cfg(not(target_arch = "wasm32"))]
+use memmap::MmapOptions;
+use std::borrow::{Borrow, Cow};
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt::Debug;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{
-    self, BufReader, BufWriter, Cursor, Error, ErrorKind::NotFound, Seek, SeekFrom, Write,
+    self, BufReader, BufWriter, Cursor, ErrorKind::NotFound, Read, Seek, SeekFrom, Write,
 };
 use std::mem::size_of;
 use std::ops::DerefMut;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread;
 
 const BLOCK_PAGE_SIZE: u32 = 1024;
 
+/// Максимальный размер файла, который [`BlockOptions::stream`] читает в память целиком, чтобы
+/// прочитать его однократно
+///
+/// [`BlockOptions::stream`]: struct.BlockOptions.html#method.stream
+pub const STREAM_BUFFER_LIMIT: u64 = 1024 * 1024;
+
+/// Флаг заголовка: файлы в блоке записаны вплотную друг к другу без выравнивания
+pub const FLAG_PACKED: u32 = 0x1;
+
+/// Флаг заголовка: блок записан потоком (см. [`BlockOptions::stream`]), заголовок в начале блока
+/// не содержит записей о файлах, а блок метаинформации записан в конец блока
+///
+/// [`BlockOptions::stream`]: struct.BlockOptions.html#method.stream
+pub const FLAG_STREAMED: u32 = 0x2;
+
+/// Флаг заголовка: содержимое файлов сжато (см. [`BlockOptions::compress`])
+///
+/// [`BlockOptions::compress`]: struct.BlockOptions.html#method.compress
+pub const FLAG_COMPRESSED: u32 = 0x4;
+
+/// Флаг заголовка: содержимое файлов зашифровано (см. [`BlockOptions::encryption_key`])
+///
+/// [`BlockOptions::encryption_key`]: struct.BlockOptions.html#method.encryption_key
+pub const FLAG_ENCRYPTED: u32 = 0x8;
+
+/// Флаг заголовка: смещения и размеры файлов хранятся 64-битными
+pub const FLAG_WIDE_OFFSETS: u32 = 0x10;
+
+/// Флаг заголовка: блок содержит секцию метаданных – время создания и версию библиотеки (см.
+/// [`BlockOptions::metadata`])
+///
+/// [`BlockOptions::metadata`]: struct.BlockOptions.html#method.metadata
+pub const FLAG_METADATA: u32 = 0x20;
+
+/// Флаг заголовка: location файлов нормализованы как пути POSIX (см. [`Normalization::POSIX`])
+///
+/// [`Normalization::POSIX`]: ../location/struct.Normalization.html#associatedconstant.POSIX
+pub const FLAG_NORMALIZE_POSIX: u32 = 0x40;
+
+/// Флаг заголовка: location файлов приведены к нижнему регистру (см.
+/// [`Normalization::LOWERCASE`])
+///
+/// [`Normalization::LOWERCASE`]: ../location/struct.Normalization.html#associatedconstant.LOWERCASE
+pub const FLAG_NORMALIZE_LOWERCASE: u32 = 0x80;
+
+/// Флаг заголовка: в location файлов раскрыты последовательности `%XX` (см.
+/// [`Normalization::PERCENT_DECODE`])
+///
+/// [`Normalization::PERCENT_DECODE`]: ../location/struct.Normalization.html#associatedconstant.PERCENT_DECODE
+pub const FLAG_NORMALIZE_PERCENT: u32 = 0x100;
+
+/// Флаг заголовка: location файлов приведены из путей Windows к виду с разделителем `/` (см.
+/// [`Normalization::WINDOWS`])
+///
+/// [`Normalization::WINDOWS`]: ../location/struct.Normalization.html#associatedconstant.WINDOWS
+pub const FLAG_NORMALIZE_WINDOWS: u32 = 0x200;
+
+/// Флаг заголовка: блок содержит записи директорий (см. [`EntryKind::Directory`])
+///
+/// [`EntryKind::Directory`]: enum.EntryKind.html#variant.Directory
+pub const FLAG_DIRECTORIES: u32 = 0x400;
+
+/// Флаг заголовка: после блока метаинформации записаны копии заголовков файлов (см.
+/// [`BlockOptions::header_locations`])
+///
+/// [`BlockOptions::header_locations`]: struct.BlockOptions.html#method.header_locations
+pub const FLAG_HEADER_LOCATIONS: u32 = 0x800;
+
+/// Флаг заголовка: идентификаторы файлов 128-битные (см. [`BlockOptions::wide_ids`]), а записи
+/// блока метаинформации занимают 40 байт
+///
+/// [`BlockOptions::wide_ids`]: struct.BlockOptions.html#method.wide_ids
+pub const FLAG_WIDE_IDS: u32 = 0x1000;
+
+/// Флаг заголовка: файлы блока идентифицируются контрольной суммой содержимого (см.
+/// [`BlockOptions::content_addressed`])
+///
+/// [`BlockOptions::content_addressed`]: struct.BlockOptions.html#method.content_addressed
+pub const FLAG_CONTENT_ADDRESSED: u32 = 0x2000;
+
+/// Флаг заголовка: блок метаинформации сжат zstd (см. [`BlockOptions::compress_header`])
+///
+/// [`BlockOptions::compress_header`]: struct.BlockOptions.html#method.compress_header
+pub const FLAG_COMPRESSED_HEADER: u32 = 0x4000;
+
+/// Флаги заголовка, которые поддерживает эта версия библиотеки. Блоки с любыми другими флагами
+/// не открываются (см. [`Error::UnsupportedFeature`]), так как их содержимое было бы прочитано
+/// неверно.
+///
+/// [`Error::UnsupportedFeature`]: ../errors/enum.Error.html#variant.UnsupportedFeature
+pub const SUPPORTED_FLAGS: u32 = FLAG_PACKED
+    | FLAG_STREAMED
+    | COMPRESSION_SUPPORT
+    | HEADER_COMPRESSION_SUPPORT
+    | ENCRYPTION_SUPPORT
+    | FLAG_NORMALIZE_POSIX
+    | FLAG_NORMALIZE_LOWERCASE
+    | FLAG_NORMALIZE_PERCENT
+    | FLAG_NORMALIZE_WINDOWS
+    | FLAG_DIRECTORIES
+    | FLAG_HEADER_LOCATIONS
+    | FLAG_WIDE_IDS
+    | FLAG_CONTENT_ADDRESSED
+    | FLAG_METADATA;
+
+#[cfg(feature = "zstd")]
+const COMPRESSION_SUPPORT: u32 = FLAG_COMPRESSED;
+#[cfg(not(feature = "zstd"))]
+const COMPRESSION_SUPPORT: u32 = 0;
+
+#[cfg(feature = "zstd")]
+const HEADER_COMPRESSION_SUPPORT: u32 = FLAG_COMPRESSED_HEADER;
+#[cfg(not(feature = "zstd"))]
+const HEADER_COMPRESSION_SUPPORT: u32 = 0;
+
+#[cfg(feature = "encryption")]
+const ENCRYPTION_SUPPORT: u32 = FLAG_ENCRYPTED;
+#[cfg(not(feature = "encryption"))]
+const ENCRYPTION_SUPPORT: u32 = 0;
+
+/// Максимальная версия формата блока, которую поддерживает эта версия библиотеки
+pub const MAX_SUPPORTED_VERSION: u16 = 2;
+
+/// Декодер заголовка одной версии формата блока
+struct HeaderDecoder {
+    version: u16,
+
+    /// Читает поля заголовка, следующие за номером версии, и возвращает флаги и количество
+    /// файлов в блоке
+    preamble: fn(&mut dyn Read) -> Result<(u32, u32)>,
+
+    /// Читает одну запись блока метаинформации блока с флагами `flags`
+    file_info: fn(&mut dyn Read, u32) -> Result<FileInfo>,
+}
+
+/// Декодеры заголовка всех поддерживаемых версий формата. [`BlockHeader::decode_limited`]
+/// выбирает декодер по версии, записанной в начале блока. Изменение формата оформляется новым
+/// декодером, а декодеры существующих версий не меняются, чтобы ранее созданные блоки
+/// оставались читаемыми.
+///
+/// [`BlockHeader::decode_limited`]: struct.BlockHeader.html#method.decode_limited
+const HEADER_DECODERS: [HeaderDecoder; 2] = [
+    // v1: версия и количество файлов
+    HeaderDecoder {
+        version: 1,
+        preamble: |source| Ok((0, source.read_u32::<LE>()?)),
+        file_info: |mut source, _| FileInfo::decode(&mut source),
+    },
+    // v2: после версии следуют флаги
+    HeaderDecoder {
+        version: 2,
+        preamble: |source| Ok((source.read_u32::<LE>()?, source.read_u32::<LE>()?)),
+        file_info: |mut source, flags| FileInfo::decode_with_flags(&mut source, flags),
+    },
+];
+
+/// Названия особенностей формата, используемые в сообщениях об ошибках (см. [`feature_name`])
+///
+/// [`feature_name`]: fn.feature_name.html
+const FEATURE_NAMES: [(u32, &str); 15] = [
+    (FLAG_PACKED, "packed layout"),
+    (FLAG_STREAMED, "streamed layout"),
+    (FLAG_COMPRESSED, "compression"),
+    (FLAG_ENCRYPTED, "encryption"),
+    (FLAG_WIDE_OFFSETS, "64-bit offsets"),
+    (FLAG_METADATA, "metadata section"),
+    (FLAG_NORMALIZE_POSIX, "POSIX location normalization"),
+    (FLAG_NORMALIZE_LOWERCASE, "lowercase locations"),
+    (FLAG_NORMALIZE_PERCENT, "percent-decoded locations"),
+    (FLAG_NORMALIZE_WINDOWS, "Windows location normalization"),
+    (FLAG_DIRECTORIES, "directory entries"),
+    (FLAG_HEADER_LOCATIONS, "locations in header"),
+    (FLAG_WIDE_IDS, "128-bit ids"),
+    (FLAG_CONTENT_ADDRESSED, "content-addressed entries"),
+    (FLAG_COMPRESSED_HEADER, "compressed header"),
+];
+
+/// Название особенности формата, которой соответствует флаг заголовка `flag` (один бит маски,
+/// например [`FLAG_PACKED`])
+///
+/// [`FLAG_PACKED`]: constant.FLAG_PACKED.html
+pub fn feature_name(flag: u32) -> String {
+    FEATURE_NAMES
+        .iter()
+        .find(|(f, _)| *f == flag)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("unknown flag 0x{:x}", flag))
+}
+
+/// Проверяет, что блок с указанными флагами может быть прочитан этой версией библиотеки
+fn check_features(flags: u32) -> Result<()> {
+    let unsupported = flags & !SUPPORTED_FLAGS;
+    if unsupported != 0 {
+        let flag = unsupported & unsupported.wrapping_neg();
+        return Err(Error::UnsupportedFeature(feature_name(flag)));
+    }
+    Ok(())
+}
+
+/// Максимальный размер сжатого zstd блока метаинформации размером `len` байт (формула
+/// `ZSTD_COMPRESSBOUND`). Вычисляется здесь, а не библиотекой zstd, так как определяет
+/// раскладку блока и должна совпадать у всех версий библиотеки, в том числе собранных без zstd.
+pub(crate) fn compressed_table_bound(len: u64) -> u64 {
+    const SMALL_SRC: u64 = 128 * 1024;
+    let margin = if len < SMALL_SRC {
+        (SMALL_SRC - len) >> 11
+    } else {
+        0
+    };
+    len + (len >> 8) + margin
+}
+
+/// Максимальная степень сжатия блока метаинформации (см. [`FLAG_COMPRESSED_HEADER`]). Каждая
+/// запись содержит MD5 location, который не сжимается, поэтому записи сжимаются не больше чем
+/// в 2–3 раза. Ограничение не позволяет поврежденному заголовку, в котором небольшой сжатый
+/// блок объявляет миллиарды записей, потребовать при распаковке памяти больше, чем в это
+/// количество раз превышает размер сжатого блока.
+///
+/// [`FLAG_COMPRESSED_HEADER`]: constant.FLAG_COMPRESSED_HEADER.html
+const MAX_TABLE_COMPRESSION_RATIO: u64 = 8;
+
 /// Трейт позволяющий произвольному типу самостоятельно реализовать логику
 /// собственной сераилизации/десериализации используя библиотеку byteorder.
 ///
 /// Используется два метода: encode/decode для сериализации и десериализации
 /// соответственно
-trait SelfSerialize {
+pub(crate) trait SelfSerialize {
     fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()>;
     fn decode(source: &mut impl ReadBytesExt) -> Result<Self>
     where
@@ -35,12 +270,24 @@ trait SelfSerialize {
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct FileInfo {
-    /// Глобальный идентификатор файла в системе
+    /// Глобальный идентификатор файла в системе. В блоках со 128-битными идентификаторами
+    /// (см. [`FLAG_WIDE_IDS`]) – младшие 64 бита идентификатора (см. [`wide_id`])
+    ///
+    /// [`FLAG_WIDE_IDS`]: constant.FLAG_WIDE_IDS.html
+    /// [`wide_id`]: #method.wide_id
     pub id: u64,
 
-    /// Размер файла в байтах
+    /// Старшие 64 бита 128-битного идентификатора. В блоках без [`FLAG_WIDE_IDS`] всегда 0
+    ///
+    /// [`FLAG_WIDE_IDS`]: constant.FLAG_WIDE_IDS.html
+    pub id_high: u64,
+
+    /// Размер файла в байтах. В сжатых блоках (см. [`BlockOptions::compress`]) – размер
+    /// сжатого содержимого
+    ///
+    /// [`BlockOptions::compress`]: struct.BlockOptions.html#method.compress
     pub size: u32,
 
     /// Смещение первого байта файла относительно налача файла
@@ -50,20 +297,226 @@ pub struct FileInfo {
     pub location_hash: md5::Digest,
 }
 
+/// Файл или директория на локальной ФС, добавляемые в блок.
+///
+/// Если `path` – директория, в блок записывается запись директории (см. [`EntryKind`]): ее
+/// location дополняется завершающим `/`, а вместо содержимого сохраняются права доступа.
+///
+/// [`EntryKind`]: enum.EntryKind.html
 pub struct AddFileRequest<'a> {
     pub id: u64,
     pub path: &'a Path,
     pub location: &'a Path,
+
+    /// Контрольная сумма содержимого, если она уже известна (например, из манифеста источника).
+    /// Сверяется с содержимым файла, а при [`BlockOptions::trust_precomputed`] используется
+    /// вместо вычисления. Для директорий не используется.
+    ///
+    /// [`BlockOptions::trust_precomputed`]: struct.BlockOptions.html#method.trust_precomputed
+    pub content_hash: Option<md5::Digest>,
+
+    /// Размер содержимого, если он уже известен. Сверяется с прочитанным содержимым. Для
+    /// директорий не используется.
+    pub size: Option<u64>,
+}
+
+/// Вид записи блока.
+///
+/// Запись директории не имеет содержимого и позволяет при выгрузке воссоздать пустые
+/// директории и права доступа директорий. В блоке она хранится как файл, location которого
+/// заканчивается на `/`, а содержимое – права доступа (4 байта, little endian). Блоки с
+/// директориями отмечаются флагом [`FLAG_DIRECTORIES`], поэтому версии библиотеки, не знающие
+/// о директориях, такие блоки не открывают. Location файлов в блоке не может заканчиваться на
+/// `/`.
+///
+/// [`FLAG_DIRECTORIES`]: constant.FLAG_DIRECTORIES.html
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EntryKind {
+    File,
+    /// Директория с правами доступа `mode` (биты прав `st_mode`)
+    Directory {
+        mode: u32,
+    },
+}
+
+/// Права доступа директорий, для которых они недоступны (на платформах, отличных от Unix)
+#[cfg(not(unix))]
+const DEFAULT_DIRECTORY_MODE: u32 = 0o755;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl AddFileRequest<'_> {
+    fn is_directory(&self) -> bool {
+        self.path.is_dir()
+    }
+
+    /// Location записи в блоке: нормализованный по правилам `normalization`, а для директорий
+    /// – с завершающим `/`
+    pub(crate) fn entry_location(&self, normalization: Normalization) -> Result<Cow<'_, [u8]>> {
+        let location = normalization.apply(location::from_path(self.location)?);
+        if !self.is_directory() {
+            if location.ends_with(b"/") {
+                return Err(Error::InvalidLocation(self.location.to_path_buf()));
+            }
+            return Ok(location);
+        }
+        if location.ends_with(b"/") {
+            return Ok(location);
+        }
+        let mut location = location.into_owned();
+        location.push(b'/');
+        Ok(Cow::Owned(location))
+    }
+
+    /// Содержимое записи: содержимое файла или права доступа директории
+    pub(crate) fn open(&self) -> io::Result<Box<dyn Read>> {
+        let metadata = self.path.metadata()?;
+        if !metadata.is_dir() {
+            return Ok(Box::new(File::open(self.path)?));
+        }
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777;
+        #[cfg(not(unix))]
+        let mode = DEFAULT_DIRECTORY_MODE;
+        Ok(Box::new(Cursor::new(mode.to_le_bytes())))
+    }
+
+    /// Контрольная сумма содержимого, заданная в запросе, если ей можно доверять
+    fn trusted_hash(&self, options: &BlockOptions) -> Option<md5::Digest> {
+        self.content_hash
+            .filter(|_| options.trust_precomputed && !self.is_directory())
+    }
+
+    /// Сверяет контрольную сумму и размер прочитанного содержимого с заданными в запросе
+    fn check_precomputed(&self, hash: md5::Digest, size: u64) -> io::Result<()> {
+        if self.is_directory() {
+            return Ok(());
+        }
+        if self.content_hash.is_some_and(|expected| expected != hash) {
+            let message = format!(
+                "File: {} doesn't match its precomputed content hash",
+                self.path.display()
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        match self.size {
+            Some(expected) if expected != size => {
+                let message = format!(
+                    "File: {} has {} bytes, expected {}",
+                    self.path.display(),
+                    size,
+                    expected
+                );
+                Err(io::Error::new(io::ErrorKind::InvalidData, message))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Размер содержимого записи (см. [`open`])
+    ///
+    /// [`open`]: #method.open
+    pub(crate) fn len(&self) -> io::Result<u64> {
+        let metadata = self.path.metadata()?;
+        if metadata.is_dir() {
+            Ok(size_of::<u32>() as u64)
+        } else {
+            Ok(metadata.len())
+        }
+    }
+}
+
+/// Файл, содержимое которого читается из произвольного источника (сокета, базы данных,
+/// распаковываемого потока и т.п.), а не с локальной ФС (см. [`BlockOptions::create_from_readers`]).
+///
+/// Если размер содержимого известен заранее (`known_size`), место под блок резервируется так же,
+/// как при создании блока из файлов, а прочитанное содержимое должно иметь ровно этот размер.
+///
+/// [`BlockOptions::create_from_readers`]: struct.BlockOptions.html#method.create_from_readers
+pub struct AddReaderRequest<'a> {
+    pub id: u64,
+    /// Location файла. Произвольная последовательность байт (см. модуль [`location`])
+    ///
+    /// [`location`]: ../location/index.html
+    pub location: &'a [u8],
+    pub reader: Box<dyn Read + 'a>,
+    pub known_size: Option<u64>,
+}
+
+impl<'a> AddReaderRequest<'a> {
+    pub fn new(
+        id: u64,
+        location: &'a (impl AsRef<[u8]> + ?Sized),
+        reader: impl Read + 'a,
+        known_size: Option<u64>,
+    ) -> Self {
+        Self {
+            id,
+            location: location.as_ref(),
+            reader: Box::new(reader),
+            known_size,
+        }
+    }
+}
+
+/// Способ назначения идентификаторов файлам, добавляемым в блок (см. [`assign`]).
+///
+/// Идентификаторы, производные от хеша, не зависят от порядка файлов и от того, на какой машине
+/// создается блок, поэтому блоки с одними и теми же файлами, созданные независимо, назначают им
+/// одинаковые идентификаторы.
+///
+/// [`assign`]: #method.assign
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IdAssignment {
+    /// Файлы нумеруются последовательно с 1 в порядке перечисления
+    Sequential,
+    /// Идентификатор – первые 8 байт MD5 содержимого файла (big endian, то есть совпадает с
+    /// первыми 16 шестнадцатеричными цифрами контрольной суммы)
+    ContentHash,
+    /// Идентификатор – первые 8 байт MD5 нормализованного location файла
+    LocationHash,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl IdAssignment {
+    /// Назначает идентификаторы файлам `files`. Location нормализуется по правилам
+    /// `normalization`, с которыми будет создан блок, а для [`IdAssignment::ContentHash`]
+    /// содержимое всех файлов читается.
+    ///
+    /// Файлы с одинаковым содержимым (или location) получают одинаковые идентификаторы, и
+    /// создание блока из них завершится [`Error::DuplicateId`].
+    ///
+    /// [`IdAssignment::ContentHash`]: #variant.ContentHash
+    /// [`Error::DuplicateId`]: ../errors/enum.Error.html#variant.DuplicateId
+    pub fn assign(self, files: &mut [AddFileRequest], normalization: Normalization) -> Result<()> {
+        for (idx, file) in files.iter_mut().enumerate() {
+            file.id = match self {
+                IdAssignment::Sequential => idx as u64 + 1,
+                IdAssignment::ContentHash => hash_id(&content_hash(file)?.0),
+                IdAssignment::LocationHash => {
+                    hash_id(&md5::compute(file.entry_location(normalization)?))
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Идентификатор, производный от хеша: первые 8 байт `digest` (big endian)
+fn hash_id(digest: &md5::Digest) -> u64 {
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&digest.0[..8]);
+    u64::from_be_bytes(prefix)
 }
 
 impl FileInfo {
-    fn new_at_offset(file: &AddFileRequest, offset: u32) -> Result<Self> {
-        Ok(Self {
-            id: file.id,
-            size: file.path.metadata()?.len() as u32,
+    fn new_at_offset(id: u64, location: &[u8], offset: u32, size: u32) -> Self {
+        Self {
+            id,
+            id_high: 0,
+            size,
             offset,
-            location_hash: md5::compute(file.location.to_str().unwrap()),
-        })
+            location_hash: md5::compute(location),
+        }
     }
 }
 
@@ -85,6 +538,85 @@ impl SelfSerialize for FileInfo {
 
         Ok(Self {
             id,
+            id_high: 0,
+            size,
+            offset,
+            location_hash,
+        })
+    }
+}
+
+impl FileInfo {
+    /// Полный идентификатор файла: `id`, дополненный старшими битами `id_high`. Для UUID
+    /// совпадает с `Uuid::as_u128`
+    pub fn wide_id(&self) -> u128 {
+        u128::from(self.id_high) << 64 | u128::from(self.id)
+    }
+
+    /// Размер записи блока метаинформации в блоке с флагами `flags`
+    pub(crate) fn encoded_len(flags: u32) -> u64 {
+        if flags & FLAG_WIDE_IDS != 0 {
+            40
+        } else {
+            32
+        }
+    }
+
+    /// Место, занимаемое в заголовке блока с флагами `flags` записями о `count` файлах. Сжатый
+    /// блок метаинформации (см. [`FLAG_COMPRESSED_HEADER`]) может занять до
+    /// [`compressed_table_bound`] байт, поэтому место под него отводится с этим запасом, а
+    /// смещения файлов не зависят от того, насколько хорошо сжались записи.
+    ///
+    /// [`FLAG_COMPRESSED_HEADER`]: constant.FLAG_COMPRESSED_HEADER.html
+    /// [`compressed_table_bound`]: fn.compressed_table_bound.html
+    pub(crate) fn table_len(flags: u32, count: u64) -> u64 {
+        let len = count * Self::encoded_len(flags);
+        if flags & FLAG_COMPRESSED_HEADER == 0 || count == 0 {
+            len
+        } else {
+            4 + compressed_table_bound(len)
+        }
+    }
+
+    /// Записывает запись блока метаинформации в представлении, заданном флагами `flags`: в
+    /// блоках с [`FLAG_WIDE_IDS`] идентификатор занимает 16 байт
+    ///
+    /// [`FLAG_WIDE_IDS`]: constant.FLAG_WIDE_IDS.html
+    fn encode_with_flags(&self, target: &mut impl WriteBytesExt, flags: u32) -> Result<()> {
+        if flags & FLAG_WIDE_IDS == 0 {
+            if self.id_high != 0 {
+                return Err(Error::FormatLimitExceeded(format!(
+                    "file id {} doesn't fit in 64 bits",
+                    self.wide_id()
+                )));
+            }
+            return self.encode(target);
+        }
+        target.write_u64::<LE>(self.id)?;
+        target.write_u64::<LE>(self.id_high)?;
+        target.write_u32::<LE>(self.size)?;
+        target.write_u32::<LE>(self.offset)?;
+        target.write_all(self.location_hash.as_ref())?;
+        Ok(())
+    }
+
+    /// Читает запись блока метаинформации, записанную [`encode_with_flags`]
+    ///
+    /// [`encode_with_flags`]: #method.encode_with_flags
+    pub(crate) fn decode_with_flags(source: &mut impl ReadBytesExt, flags: u32) -> Result<Self> {
+        if flags & FLAG_WIDE_IDS == 0 {
+            return Self::decode(source);
+        }
+        let id = source.read_u64::<LE>()?;
+        let id_high = source.read_u64::<LE>()?;
+        let size = source.read_u32::<LE>()?;
+        let offset = source.read_u32::<LE>()?;
+        let mut location_hash = md5::Digest([0; 16]);
+        source.read_exact(location_hash.deref_mut())?;
+
+        Ok(Self {
+            id,
+            id_high,
             size,
             offset,
             location_hash,
@@ -101,15 +633,19 @@ impl SelfSerialize for FileInfo {
 /// ## Анатомия блока
 /// ### Заголовок
 /// ```text
-/// +-------+-------+-------+-------+-------+-------+
-/// |                     BYTES                     |
-/// +-------+-------+-------+-------+-------+-------+
-/// |   1   |   2   |   3   |   4   |   5   |   6   |
-/// +-------+-------+-------+-------+-------+-------+
-/// +    version    |              size             |
-/// +-------+-------+-------+-------+-------+-------+
+/// +-------+-------+-------+-------+-------+-------+-------+-------+-------+-------+
+/// |                                     BYTES                                     |
+/// +-------+-------+-------+-------+-------+-------+-------+-------+-------+-------+
+/// |   1   |   2   |   3   |   4   |   5   |   6   |   7   |   8   |   9   |  10   |
+/// +-------+-------+-------+-------+-------+-------+-------+-------+-------+-------+
+/// +    version    |             flags             |              size             |
+/// +-------+-------+-------+-------+-------+-------+-------+-------+-------+-------+
 /// ```
 /// * `version` – информация о версии формата блока (2 байта);
+/// * `flags` – битовая маска особенностей формата блока (например, [`FLAG_PACKED`]). Поле
+///   присутствует начиная с версии 2, в блоках версии 1 оно отсутствует и считается нулевым.
+///   Блоки с флагами, не входящими в [`SUPPORTED_FLAGS`], и блоки версий новее
+///   [`MAX_SUPPORTED_VERSION`] не открываются;
 /// * `size` – количество файлов в блоке
 ///
 /// ### Блок метаинформации
@@ -133,327 +669,6904 @@ impl SelfSerialize for FileInfo {
 /// +              hash             |
 /// +-------+-------+-------+-------+
 /// ```
-/// * `id` – глобальный идентификатор файла в системе;
+/// * `id` – глобальный идентификатор файла в системе. В блоках с флагом [`FLAG_WIDE_IDS`]
+///   идентификатор занимает 16 байт (младшие 8 байт, затем старшие), а запись – 40 байт;
 /// * `size` – размер файла в байтах;
 /// * `offset` – смещение первого байта файла относительно начала блока. Таким образом,
-/// смещение всегда больше чем длина заголовков блока.
+///   смещение всегда больше чем длина заголовков блока.
 /// * `hash` – MD5-хеш URL-файла (например, `/path/to/image.jpeg`).
 ///
+/// Если в заголовке установлен флаг [`FLAG_COMPRESSED_HEADER`] и блок содержит файлы, то вместо
+/// записей следуют их длина после сжатия (4 байта) и сами записи, сжатые zstd целиком. Место
+/// до первого файла отводится под сжатые записи с запасом на худший случай сжатия.
+///
+/// ### Копии заголовков файлов
+/// Если в заголовке установлен флаг [`FLAG_HEADER_LOCATIONS`], то за блоком метаинформации в
+/// том же порядке следуют копии [`FileHeader`] всех файлов (контрольная сумма, длина location и
+/// сам location). Это позволяет получить location файлов, не читая страницы с их содержимым.
+///
+/// ### Метаданные блока
+/// Если в заголовке установлен флаг [`FLAG_METADATA`], то за блоком метаинформации (и копиями
+/// заголовков файлов) следуют метаданные блока (см. [`BlockMetadata`]):
+/// ```text
+/// | created (8 байт) | created_by_len (2 байта) | created_by (UTF-8) |
+/// ```
+/// * `created` – время создания блока в секундах от UNIX epoch;
+/// * `created_by` – название и версия библиотеки, создавшей блок (например, `blocky 0.1.0`).
+///
+/// ### Резервная копия заголовка
+/// Опционально (см. [`BlockOptions::header_trailer`]) в конец блока записывается копия
+/// заголовка и блока метаинформации, за которой следуют ее длина (4 байта), MD5 (16 байт) и
+/// сигнатура `BTRL`.
+///
 /// [`FileInfo`]: struct.FileInfo.html
+/// [`FileHeader`]: struct.FileHeader.html
+/// [`FLAG_HEADER_LOCATIONS`]: constant.FLAG_HEADER_LOCATIONS.html
+/// [`FLAG_WIDE_IDS`]: constant.FLAG_WIDE_IDS.html
+/// [`FLAG_METADATA`]: constant.FLAG_METADATA.html
+/// [`FLAG_COMPRESSED_HEADER`]: constant.FLAG_COMPRESSED_HEADER.html
+/// [`BlockMetadata`]: struct.BlockMetadata.html
+/// [`BlockOptions::header_trailer`]: struct.BlockOptions.html#method.header_trailer
+/// [`FLAG_PACKED`]: constant.FLAG_PACKED.html
+/// [`SUPPORTED_FLAGS`]: constant.SUPPORTED_FLAGS.html
+/// [`MAX_SUPPORTED_VERSION`]: constant.MAX_SUPPORTED_VERSION.html
 #[derive(Debug, Default, Eq, PartialEq)]
 pub struct BlockHeader {
-    version: u16,
-    file_info: Vec<FileInfo>,
-}
+    pub(crate) version: u16,
+    pub(crate) flags: u32,
+    pub(crate) file_info: Vec<FileInfo>,
 
-pub struct Block {
-    header: BlockHeader,
-    mmap: Mmap,
+    /// Копии заголовков файлов (только при [`FLAG_HEADER_LOCATIONS`])
+    ///
+    /// [`FLAG_HEADER_LOCATIONS`]: constant.FLAG_HEADER_LOCATIONS.html
+    pub(crate) file_headers: Vec<FileHeader>,
+
+    /// Метаданные блока (только при [`FLAG_METADATA`])
+    ///
+    /// [`FLAG_METADATA`]: constant.FLAG_METADATA.html
+    pub(crate) metadata: Option<BlockMetadata>,
 }
 
-impl SelfSerialize for BlockHeader {
-    fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()> {
-        target.write_u16::<LE>(self.version)?;
-        let len = self.file_info.len();
-        let file_info_len = u32::try_from(len).chain_err(|| "File id can't fit in u32")?;
-        target.write_u32::<LE>(file_info_len)?;
+impl BlockHeader {
+    /// Читает заголовок блока из произвольного источника, поддерживающего чтение диапазонов.
+    ///
+    /// Содержимое файлов при этом не читается, что позволяет инспектировать метаинформацию
+    /// блока там, где отображение файла в память недоступно (например, на wasm32).
+    pub fn read_from(source: &(impl RangeRead + ?Sized)) -> Result<Self> {
+        Self::read_limited(source, &DecodeLimits::unlimited())
+    }
+
+    fn read_limited(source: &(impl RangeRead + ?Sized), limits: &DecodeLimits) -> Result<Self> {
+        let source_len = source.size()?;
+        let mut reader = BufReader::new(RangeReader::new(source)?);
+        Self::decode_limited(&mut reader, source_len, limits).map_err(header_corrupted)
+    }
+
+    /// Читает метаинформацию блока из последовательного потока (например, stdin).
+    ///
+    /// Из потока читается заголовок блока, а если `file_headers` – то и заголовки всех файлов
+    /// (в порядке записей [`file_info`]). Содержимое файлов при этом пропускается без
+    /// буферизации. Исключение – блоки, записанные потоком (см. [`BlockOptions::stream`]): их
+    /// блок метаинформации находится в конце, поэтому такие блоки читаются целиком.
+    ///
+    /// Размер потока заранее неизвестен, поэтому размер заголовка ограничивается так же, как в
+    /// [`Block::open_untrusted`].
+    ///
+    /// [`file_info`]: #method.file_info
+    /// [`BlockOptions::stream`]: struct.BlockOptions.html#method.stream
+    /// [`Block::open_untrusted`]: struct.Block.html#method.open_untrusted
+    pub fn read_from_stream(
+        reader: &mut impl Read,
+        file_headers: bool,
+    ) -> Result<(Self, Vec<FileHeader>)> {
+        let limits = DecodeLimits::untrusted();
+        // Сжатый блок метаинформации занимает меньше места, чем отведено под него в блоке,
+        // поэтому количество прочитанных байт отсчитывается по потоку
+        let mut counted = reader.take(u64::MAX);
+        let header =
+            Self::decode_limited(&mut counted, u64::MAX, &limits).map_err(header_corrupted)?;
+        let mut position = u64::MAX - counted.limit();
 
-        for file_info in self.file_info.iter() {
-            file_info.encode(target)?;
+        if header.is_streamed() {
+            let mut data = vec![];
+            header.encode(&mut data)?;
+            reader.read_to_end(&mut data)?;
+            let block = Block::from_data(Box::new(data), limits)?;
+            let file_headers = if file_headers {
+                block
+                    .entries()
+                    .map(|entry| entry.header().cloned())
+                    .collect::<Result<Vec<_>>>()?
+            } else {
+                vec![]
+            };
+            return Ok((block.header, file_headers));
+        }
+        if !file_headers {
+            return Ok((header, vec![]));
+        }
+        if let Some(file_headers) = header.file_headers() {
+            let file_headers = file_headers.to_vec();
+            return Ok((header, file_headers));
         }
 
-        Ok(())
+        // Файлы читаются в порядке их расположения в блоке, так как поток нельзя перемотать назад
+        let mut offsets = header
+            .file_info
+            .iter()
+            .map(|info| info.offset)
+            .collect::<Vec<_>>();
+        offsets.sort_unstable();
+        offsets.dedup();
+        let mut decoded = HashMap::new();
+        for offset in offsets {
+            let skip = u64::from(offset).checked_sub(position).ok_or_else(|| {
+                Error::corrupted(format!("File at offset {} overlaps previous file", offset))
+            })?;
+            io::copy(&mut reader.take(skip), &mut io::sink())?;
+            let file_header = FileHeader::decode_limited(reader, limits.max_location_len).map_err(
+                |e| match e {
+                    Error::Io(e) => {
+                        Error::corrupted(format!("Unable to read file at offset {}: {}", offset, e))
+                    }
+                    e => e,
+                },
+            )?;
+            position = u64::from(offset)
+                + u64::from(FILE_HEADER_FIXED_SIZE)
+                + file_header.location.len() as u64;
+            decoded.insert(offset, file_header);
+        }
+        let file_headers = header
+            .file_info
+            .iter()
+            .map(|info| decoded[&info.offset].clone())
+            .collect();
+        Ok((header, file_headers))
     }
 
-    fn decode(source: &mut impl ReadBytesExt) -> Result<Self> {
-        let mut header: Self = Default::default();
-        header.version = source.read_u16::<LE>()?;
-        let file_info_len = source.read_u32::<LE>()?;
-        header.file_info = vec![];
-        for _ in 0..file_info_len {
-            let file_info = FileInfo::decode(source)?;
-            header.file_info.push(file_info);
+    /// Декодирует заголовок из источника размером `source_len` байт.
+    ///
+    /// Количество файлов в заголовке проверяется до чтения блока метаинформации, поэтому даже
+    /// без ограничений `limits` записи несжатого блока метаинформации не могут занять больше
+    /// памяти, чем занимает сам источник, а сжатого – больше, чем в
+    /// `MAX_TABLE_COMPRESSION_RATIO` раз превышает его размер в источнике.
+    pub(crate) fn decode_limited(
+        source: &mut impl ReadBytesExt,
+        source_len: u64,
+        limits: &DecodeLimits,
+    ) -> Result<Self> {
+        let source: &mut dyn Read = source;
+        let (mut header, decoder, file_info_len) = Self::decode_preamble(source)?;
+        let flags = header.flags;
+
+        // Сжатый блок метаинформации занимает в источнике меньше места, чем в памяти, поэтому
+        // с размером источника сравнивается его сжатый размер
+        let records_len = u64::from(file_info_len) * FileInfo::encoded_len(flags);
+        let compressed_len = if header.has_compressed_header() && file_info_len > 0 {
+            let len = u64::from(source.read_u32::<LE>()?);
+            if len > compressed_table_bound(records_len)
+                || records_len > len * MAX_TABLE_COMPRESSION_RATIO
+            {
+                return Err(Error::corrupted(format!(
+                    "Compressed meta section of {} files takes {} bytes",
+                    file_info_len, len
+                )));
+            }
+            Some(len)
+        } else {
+            None
+        };
+        let mut stored_len =
+            header.encoded_len() + compressed_len.map_or(records_len, |len| 4 + len);
+        let mut header_len = header.encoded_len() + records_len;
+        if stored_len > source_len {
+            return Err(Error::corrupted(format!(
+                "Header of {} files ({} bytes) exceeds block size of {} bytes",
+                file_info_len, stored_len, source_len
+            )));
+        }
+        if header_len > limits.max_header_len {
+            return Err(Error::DecodeLimitExceeded(format!(
+                "header of {} bytes exceeds {} bytes",
+                header_len, limits.max_header_len
+            )));
         }
 
+        if let Some(len) = compressed_len {
+            let mut compressed = vec![];
+            source.take(len).read_to_end(&mut compressed)?;
+            if compressed.len() as u64 != len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            header.file_info = decode_table(&compressed, file_info_len, flags, decoder.file_info)?;
+        } else {
+            for _ in 0..file_info_len {
+                header.file_info.push((decoder.file_info)(source, flags)?);
+            }
+        }
+        if header.has_header_locations() {
+            for _ in 0..file_info_len {
+                let file_header =
+                    FileHeader::decode_limited(&mut &mut *source, limits.max_location_len)?;
+                let file_header_len =
+                    u64::from(FILE_HEADER_FIXED_SIZE) + file_header.location.len() as u64;
+                stored_len += file_header_len;
+                header_len += file_header_len;
+                if stored_len > source_len {
+                    return Err(Error::corrupted(format!(
+                        "Header locations of {} files exceed block size of {} bytes",
+                        file_info_len, source_len
+                    )));
+                }
+                if header_len > limits.max_header_len {
+                    return Err(Error::DecodeLimitExceeded(format!(
+                        "header of {} bytes exceeds {} bytes",
+                        header_len, limits.max_header_len
+                    )));
+                }
+                header.file_headers.push(file_header);
+            }
+        }
+        if flags & FLAG_METADATA != 0 {
+            let metadata = BlockMetadata::decode(&mut &mut *source)?;
+            stored_len += metadata.encoded_len();
+            header.metadata = Some(metadata);
+            if stored_len > source_len {
+                return Err(Error::corrupted(format!(
+                    "Block metadata exceeds block size of {} bytes",
+                    source_len
+                )));
+            }
+        }
         Ok(header)
     }
-}
 
-impl Block {
-    pub fn from_files(block_path: impl AsRef<Path>, files: &[AddFileRequest]) -> Result<Block> {
-        if files.is_empty() {
-            bail!(ErrorKind::NoFilesInBlock);
-        }
-        let file_names = files.iter().map(|f| f.path).collect::<Vec<_>>();
-        let first_missing_file = file_names.iter().find(|f| !f.is_file());
-        if let Some(file) = first_missing_file {
-            let message = format!("File: {} not found", file.display());
-            return Err(Error::new(NotFound, message).into());
-        }
+    /// Читает поля заголовка фиксированного размера: версию и флаги. Возвращает заголовок без
+    /// файлов, декодер его версии и количество файлов в блоке
+    fn decode_preamble(source: &mut dyn Read) -> Result<(Self, &'static HeaderDecoder, u32)> {
+        let version = source.read_u16::<LE>()?;
+        let decoder = HEADER_DECODERS
+            .iter()
+            .find(|decoder| decoder.version == version)
+            .ok_or(Error::UnsupportedVersion(version))?;
+        let (flags, file_info_len) = (decoder.preamble)(source)?;
+        check_features(flags)?;
+        let header = Self {
+            version,
+            flags,
+            file_info: vec![],
+            file_headers: vec![],
+            metadata: None,
+        };
+        Ok((header, decoder, file_info_len))
+    }
 
-        let block_file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&block_path)
-            .chain_err(|| {
-                ErrorKind::BlockFileAlreadyExists(block_path.as_ref().display().to_string())
-            })?;
-        let mut writer = BufWriter::new(&block_file);
+    /// Аналогичен [`decode_limited`], но читает только поля фиксированного размера (см.
+    /// [`LazyBlock`]). Возвращает заголовок без файлов и количество файлов в блоке.
+    ///
+    /// [`decode_limited`]: #method.decode_limited
+    /// [`LazyBlock`]: ../lazy/struct.LazyBlock.html
+    pub(crate) fn decode_fixed(mut source: &[u8]) -> Result<(Self, u32)> {
+        let (header, _, file_info_len) = Self::decode_preamble(&mut source)?;
+        Ok((header, file_info_len))
+    }
 
-        let header_size = (size_of::<Block>() + files.len() * size_of::<FileInfo>()) as u32;
-        let mut file_infos = vec![];
+    /// Создает заголовок минимальной версии, способной хранить указанные флаги
+    fn new(flags: u32, file_info: Vec<FileInfo>) -> Self {
+        Self {
+            version: if flags == 0 { 1 } else { 2 },
+            flags,
+            file_info,
+            file_headers: vec![],
+            metadata: None,
+        }
+    }
 
-        // Добавляем файлы в блок и попутно формируем заголовки со смещениями файлов
-        let mut next_file_offset = round_up_to(header_size, BLOCK_PAGE_SIZE);
-        for file in files {
-            block_file.set_len(next_file_offset as u64)?;
-            writer.seek(SeekFrom::End(0))?;
+    pub fn version(&self) -> u16 {
+        self.version
+    }
 
-            let mut reader = BufReader::new(File::open(file.path)?);
-            let mut memory_buffer = Cursor::new(vec![0u8]);
-            io::copy(&mut reader, &mut memory_buffer)?;
-            memory_buffer.set_position(0);
+    /// Проверяет, что заголовок, полученный не из блока (например, десериализованный), мог бы
+    /// быть записан и прочитан этой версией библиотеки
+    #[cfg(feature = "serde")]
+    pub(crate) fn check_format(&self) -> Result<()> {
+        let known_version = HEADER_DECODERS.iter().any(|d| d.version == self.version);
+        if !known_version || (self.version < 2 && self.flags != 0) {
+            return Err(Error::UnsupportedVersion(self.version));
+        }
+        check_features(self.flags)?;
+        let copies = self.file_headers.len();
+        if copies > 0 && (!self.has_header_locations() || copies != self.file_info.len()) {
+            return Err(Error::corrupted(format!(
+                "Header has {} file header copies for {} files",
+                copies,
+                self.file_info.len()
+            )));
+        }
+        if self.metadata.is_some() != (self.flags & FLAG_METADATA != 0) {
+            return Err(Error::corrupted(
+                "Block metadata doesn't match header flags".to_string(),
+            ));
+        }
+        Ok(())
+    }
 
-            let file_header = FileHeader {
-                // TODO расчет хеша
-                hash: md5::compute(memory_buffer.get_ref()),
-                location: file.location.to_str().map(String::from).unwrap(),
-            };
-            let mut bytes_written = file_header.write_to(&mut writer)?;
-            bytes_written += io::copy(&mut memory_buffer, &mut writer)
-                .chain_err(|| "Unable to copy a file to the block")?;
+    /// Размер заголовка вместе с блоком метаинформации в байтах. Для сжатого блока
+    /// метаинформации (см. [`BlockOptions::compress_header`]) – место, отведенное под него в
+    /// блоке, то есть оценка сверху.
+    ///
+    /// [`BlockOptions::compress_header`]: struct.BlockOptions.html#method.compress_header
+    pub fn encoded_len(&self) -> u64 {
+        let flags_len = if self.version >= 2 { 4 } else { 0 };
+        let file_headers_len = self
+            .file_headers
+            .iter()
+            .map(|header| FILE_HEADER_FIXED_SIZE as usize + header.location.len())
+            .sum::<usize>();
+        let file_info_len = FileInfo::table_len(self.flags, self.file_info.len() as u64);
+        let metadata_len = self.metadata.as_ref().map_or(0, BlockMetadata::encoded_len);
+        (2 + flags_len + 4 + file_headers_len) as u64 + file_info_len + metadata_len
+    }
 
-            let file_info = FileInfo::new_at_offset(file, next_file_offset)?;
-            next_file_offset =
-                round_up_to(next_file_offset + bytes_written as u32, BLOCK_PAGE_SIZE);
+    /// Проверяет, что файлы, описанные заголовком, располагаются после заголовка, не выходят за
+    /// пределы блока размером `block_len` байт и не пересекаются друг с другом.
+    ///
+    /// Совпадающие диапазоны допустимы: так хранятся дедуплицированные файлы.
+    pub(crate) fn validate(&self, block_len: u64) -> Result<()> {
+        let header_len = self.data_start();
+        let mut ranges = Vec::with_capacity(self.file_info.len());
+        for info in self.file_info.iter() {
+            let start = u64::from(info.offset);
+            let end = start + u64::from(FILE_HEADER_FIXED_SIZE) + u64::from(info.size);
+            if start < header_len {
+                return Err(Error::corrupted(format!(
+                    "File {} at offset {} overlaps with block header of {} bytes",
+                    info.id, info.offset, header_len
+                )));
+            }
+            if end > block_len {
+                return Err(Error::corrupted(format!(
+                    "File {} at offset {} of size {} is out of block bounds ({} bytes)",
+                    info.id, info.offset, info.size, block_len
+                )));
+            }
+            ranges.push((start, end, info.id));
+        }
 
-            file_infos.push(file_info);
+        ranges.sort_unstable();
+        for pair in ranges.windows(2) {
+            let ((prev_start, prev_end, prev_id), (start, end, id)) = (pair[0], pair[1]);
+            let same_range = prev_start == start && prev_end == end;
+            if !same_range && start < prev_end {
+                return Err(Error::corrupted(format!(
+                    "Files {} and {} overlap at offset {}",
+                    prev_id, id, start
+                )));
+            }
         }
+        Ok(())
+    }
 
-        // Пишем заголовки в блок
-        let header = BlockHeader {
-            version: 1,
-            file_info: file_infos,
-        };
-        writer.seek(SeekFrom::Start(0))?;
-        header
-            .encode(&mut writer)
-            .chain_err(|| "Unable to write block header")?;
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
 
-        writer.flush()?;
+    /// Записаны ли файлы блока без выравнивания (см. [`BlockOptions::packed`])
+    ///
+    /// [`BlockOptions::packed`]: struct.BlockOptions.html#method.packed
+    pub fn is_packed(&self) -> bool {
+        self.flags & FLAG_PACKED != 0
+    }
 
-        Self::open(block_path)
+    /// Записан ли блок потоком (см. [`BlockOptions::stream`])
+    ///
+    /// [`BlockOptions::stream`]: struct.BlockOptions.html#method.stream
+    pub fn is_streamed(&self) -> bool {
+        self.flags & FLAG_STREAMED != 0
     }
 
-    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let f = File::open(&path)?;
-        let mut block_file = BufReader::new(&f);
+    /// Сжато ли содержимое файлов блока (см. [`BlockOptions::compress`])
+    ///
+    /// [`BlockOptions::compress`]: struct.BlockOptions.html#method.compress
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
 
-        let header =
-            BlockHeader::decode(&mut block_file).chain_err(|| ErrorKind::BlockCorrupted)?;
-        let mmap = unsafe { MmapOptions::new().map(&f)? };
-        Ok(Block { header, mmap })
+    /// Зашифровано ли содержимое файлов блока (см. [`BlockOptions::encryption_key`])
+    ///
+    /// [`BlockOptions::encryption_key`]: struct.BlockOptions.html#method.encryption_key
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
     }
 
-    pub fn file_at(&self, idx: usize) -> Option<(FileHeader, &[u8])> {
-        let info = &self.header.file_info[idx];
-        let data = self.mmap.as_ref();
+    /// Может ли блок содержать записи директорий (см. [`EntryKind`])
+    ///
+    /// [`EntryKind`]: enum.EntryKind.html
+    pub fn has_directories(&self) -> bool {
+        self.flags & FLAG_DIRECTORIES != 0
+    }
 
-        let mut cursor = Cursor::new(&data[info.offset as usize..]);
-        let header = FileHeader::decode(&mut cursor)
-            .chain_err(|| ErrorKind::HeaderCorrupted)
-            .unwrap();
+    /// Записаны ли в заголовок копии заголовков файлов (см. [`BlockOptions::header_locations`])
+    ///
+    /// [`BlockOptions::header_locations`]: struct.BlockOptions.html#method.header_locations
+    pub fn has_header_locations(&self) -> bool {
+        self.flags & FLAG_HEADER_LOCATIONS != 0
+    }
 
-        let start = (info.offset as u64 + cursor.position()) as usize;
-        let end = start + (info.size as usize);
-        Some((header, &data[start..end]))
+    /// Идентифицируются ли файлы блока контрольной суммой содержимого (см.
+    /// [`BlockOptions::content_addressed`])
+    ///
+    /// [`BlockOptions::content_addressed`]: struct.BlockOptions.html#method.content_addressed
+    pub fn is_content_addressed(&self) -> bool {
+        self.flags & FLAG_CONTENT_ADDRESSED != 0
     }
 
-    pub fn file_by_id(&self, id: u64) -> Option<(FileHeader, &[u8])> {
-        self.header
-            .file_info
-            .iter()
-            .enumerate()
-            .find(|(_, info)| info.id == id)
-            .and_then(|(idx, _)| self.file_at(idx))
+    /// Сжат ли блок метаинформации (см. [`BlockOptions::compress_header`])
+    ///
+    /// [`BlockOptions::compress_header`]: struct.BlockOptions.html#method.compress_header
+    pub fn has_compressed_header(&self) -> bool {
+        self.flags & FLAG_COMPRESSED_HEADER != 0
     }
 
-    pub fn len(&self) -> usize {
-        self.header.file_info.len()
+    /// Используются ли 128-битные идентификаторы файлов (см. [`BlockOptions::wide_ids`])
+    ///
+    /// [`BlockOptions::wide_ids`]: struct.BlockOptions.html#method.wide_ids
+    pub fn has_wide_ids(&self) -> bool {
+        self.flags & FLAG_WIDE_IDS != 0
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &FileInfo> {
-        self.header.file_info.iter()
+    /// Смещение, начиная с которого в блоке могут располагаться файлы. У блоков, записанных
+    /// потоком, в начале блока находится только заголовок фиксированного размера.
+    fn data_start(&self) -> u64 {
+        if self.is_streamed() {
+            // Метаданные записываются и в заголовок в начале блока
+            let mut header = BlockHeader::new(self.flags, vec![]);
+            header.metadata = self.metadata.clone();
+            header.encoded_len()
+        } else {
+            self.encoded_len()
+        }
+    }
+
+    pub fn file_info(&self) -> &[FileInfo] {
+        &self.file_info
+    }
+
+    /// Копии заголовков файлов в порядке записей [`file_info`], если они записаны в заголовок
+    /// блока (см. [`BlockOptions::header_locations`]). В отличии от заголовка перед содержимым
+    /// файла, копия дедуплицированного файла содержит его собственный location.
+    ///
+    /// [`file_info`]: #method.file_info
+    /// [`BlockOptions::header_locations`]: struct.BlockOptions.html#method.header_locations
+    pub fn file_headers(&self) -> Option<&[FileHeader]> {
+        if self.has_header_locations() && self.file_headers.len() == self.file_info.len() {
+            Some(&self.file_headers)
+        } else {
+            None
+        }
+    }
+
+    /// Когда и какой версией библиотеки создан блок, если это записано в блок (см.
+    /// [`BlockOptions::metadata`])
+    ///
+    /// [`BlockOptions::metadata`]: struct.BlockOptions.html#method.metadata
+    pub fn metadata(&self) -> Option<&BlockMetadata> {
+        self.metadata.as_ref()
     }
 }
 
-/// Заголовок файла. Пишется непосредственно перед содержимым
-/// файла в блоке.
-#[derive(Debug, Eq, PartialEq, Clone)]
-pub struct FileHeader {
-    /// контрольная суммы содердимого файла
-    pub hash: md5::Digest,
+/// Название и версия библиотеки, записываемые в метаданные создаваемых блоков
+pub const CREATED_BY: &str = concat!("blocky ", env!("CARGO_PKG_VERSION"));
+
+/// Метаданные блока: когда и какой версией библиотеки он создан (см.
+/// [`BlockOptions::metadata`]). Позволяют определить, создан ли блок до или после выхода
+/// определенной версии.
+///
+/// [`BlockOptions::metadata`]: struct.BlockOptions.html#method.metadata
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockMetadata {
+    /// Время создания блока в секундах от UNIX epoch
+    pub created: u64,
 
-    /// URL файла
-    pub location: String,
+    /// Название и версия библиотеки, создавшей блок (см. [`CREATED_BY`])
+    ///
+    /// [`CREATED_BY`]: constant.CREATED_BY.html
+    pub created_by: String,
 }
 
-impl SelfSerialize for FileHeader {
-    fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()> {
-        let location_length =
-            u16::try_from(self.location.len()).chain_err(|| "Fail name too long")?;
-        target.write_all(&*self.hash)?;
-        target.write_u16::<LE>(location_length)?;
-        target.write_all(self.location.as_bytes())?;
+impl BlockMetadata {
+    /// Метаданные блока, создаваемого в текущий момент этой версией библиотеки
+    fn now() -> Self {
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self {
+            created,
+            created_by: CREATED_BY.to_string(),
+        }
+    }
+
+    fn encoded_len(&self) -> u64 {
+        8 + 2 + self.created_by.len() as u64
+    }
+}
+
+impl SelfSerialize for BlockMetadata {
+    fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()> {
+        let len = u16::try_from(self.created_by.len())
+            .map_err(|_| Error::FormatLimitExceeded("block creator name is too long".into()))?;
+        target.write_u64::<LE>(self.created)?;
+        target.write_u16::<LE>(len)?;
+        target.write_all(self.created_by.as_bytes())?;
+        Ok(())
+    }
+
+    fn decode(source: &mut impl ReadBytesExt) -> Result<Self> {
+        let created = source.read_u64::<LE>()?;
+        let len = source.read_u16::<LE>()?;
+        let mut created_by = vec![0; usize::from(len)];
+        source.read_exact(&mut created_by)?;
+        let created_by = String::from_utf8(created_by)
+            .map_err(|_| Error::corrupted("Block creator name is not valid UTF-8".to_string()))?;
+        Ok(Self {
+            created,
+            created_by,
+        })
+    }
+}
+
+/// Сжимает записи блока метаинформации (см. [`FLAG_COMPRESSED_HEADER`])
+///
+/// [`FLAG_COMPRESSED_HEADER`]: constant.FLAG_COMPRESSED_HEADER.html
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+fn compress_table(records: &[u8]) -> Result<Vec<u8>> {
+    #[cfg(feature = "zstd")]
+    return compression::compress_table(records);
+    #[cfg(not(feature = "zstd"))]
+    return Err(Error::UnsupportedFeature(feature_name(
+        FLAG_COMPRESSED_HEADER,
+    )));
+}
+
+/// Декодирует `count` записей сжатого блока метаинформации `compressed` функцией `file_info`
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+fn decode_table(
+    compressed: &[u8],
+    count: u32,
+    flags: u32,
+    file_info: fn(&mut dyn Read, u32) -> Result<FileInfo>,
+) -> Result<Vec<FileInfo>> {
+    #[cfg(feature = "zstd")]
+    {
+        let mut records = compression::table_decoder(compressed)?;
+        let mut table = vec![];
+        for _ in 0..count {
+            table.push(file_info(&mut records, flags)?);
+        }
+        if records.read(&mut [0])? != 0 {
+            return Err(Error::corrupted(format!(
+                "Compressed meta section contains more than {} files",
+                count
+            )));
+        }
+        Ok(table)
+    }
+    #[cfg(not(feature = "zstd"))]
+    Err(Error::UnsupportedFeature(feature_name(
+        FLAG_COMPRESSED_HEADER,
+    )))
+}
+
+/// Превращает ошибку декодирования заголовка блока в [`Error::BlockCorrupted`]
+///
+/// [`Error::BlockCorrupted`]: ../errors/enum.Error.html#variant.BlockCorrupted
+pub(crate) fn header_corrupted(e: Error) -> Error {
+    match e {
+        Error::BlockCorrupted { .. }
+        | Error::DecodeLimitExceeded(_)
+        | Error::UnsupportedVersion(_)
+        | Error::UnsupportedFeature(_) => e,
+        e => Error::corrupted(format!("Unable to decode block header: {}", e)),
+    }
+}
+
+/// Сигнатура, которой заканчивается резервная копия заголовка в конце блока
+const TRAILER_MAGIC: &[u8; 4] = b"BTRL";
+
+/// Размер служебных полей резервной копии заголовка: длина копии (4 байта), ее MD5 (16 байт) и
+/// сигнатура (4 байта)
+pub(crate) const TRAILER_FIXED_SIZE: usize = 4 + 16 + 4;
+
+impl BlockHeader {
+    /// Кодирует резервную копию заголовка, записываемую в конец блока.
+    ///
+    /// ```text
+    /// | header (len байт) | len (4 байта) | MD5 header (16 байт) | "BTRL" |
+    /// ```
+    fn encode_trailer(&self) -> Result<Vec<u8>> {
+        let mut trailer = vec![];
+        self.encode(&mut trailer)?;
+        let len = u32::try_from(trailer.len())
+            .map_err(|_| Error::FormatLimitExceeded("header is too large".into()))?;
+        let checksum = md5::compute(&trailer);
+        trailer.write_u32::<LE>(len)?;
+        trailer.write_all(&checksum.0)?;
+        trailer.write_all(TRAILER_MAGIC)?;
+        Ok(trailer)
+    }
+
+    /// Читает резервную копию заголовка из конца блока.
+    ///
+    /// Возвращает `None`, если копии нет или ее контрольная сумма не совпадает.
+    pub(crate) fn decode_trailer(data: &[u8], limits: &DecodeLimits) -> Option<Self> {
+        let fixed_start = data.len().checked_sub(TRAILER_FIXED_SIZE)?;
+        let mut fixed = &data[fixed_start..];
+        let len = fixed.read_u32::<LE>().ok()? as usize;
+        let mut checksum = [0u8; 16];
+        fixed.read_exact(&mut checksum).ok()?;
+        if fixed != TRAILER_MAGIC {
+            return None;
+        }
+
+        let header = data.get(fixed_start.checked_sub(len)?..fixed_start)?;
+        if md5::compute(header).0 != checksum {
+            return None;
+        }
+        Self::decode_limited(&mut Cursor::new(header), header.len() as u64, limits).ok()
+    }
+}
+
+/// Аналог [`trailer_start`] для блока в хранилище `data`, размер которого без подписи –
+/// `data_len`. Читается только фиксированная часть копии.
+///
+/// [`trailer_start`]: fn.trailer_start.html
+pub(crate) fn trailer_start_at(data: &(impl BlockStorage + ?Sized), data_len: u64) -> Option<u64> {
+    let fixed_start = data_len.checked_sub(TRAILER_FIXED_SIZE as u64)?;
+    let fixed = data.read_at(fixed_start, TRAILER_FIXED_SIZE).ok()?;
+    if !fixed.ends_with(TRAILER_MAGIC) {
+        return None;
+    }
+    let len = (&fixed[..]).read_u32::<LE>().ok()?;
+    fixed_start.checked_sub(u64::from(len))
+}
+
+/// Читает резервную копию заголовка вместе с ее фиксированной частью (см.
+/// [`BlockHeader::decode_trailer`]) из блока в хранилище `data`
+///
+/// [`BlockHeader::decode_trailer`]: struct.BlockHeader.html#method.decode_trailer
+fn read_trailer(data: &(impl BlockStorage + ?Sized), data_len: u64) -> Option<Cow<'_, [u8]>> {
+    let start = trailer_start_at(data, data_len)?;
+    data.read_at(start, usize::try_from(data_len - start).ok()?)
+        .ok()
+}
+
+/// Возвращает смещение резервной копии заголовка, если блок ее содержит.
+///
+/// В отличии от [`BlockHeader::decode_trailer`] контрольная сумма копии не проверяется, что
+/// позволяет отделить копию от содержимого файлов даже если она повреждена.
+///
+/// [`BlockHeader::decode_trailer`]: struct.BlockHeader.html#method.decode_trailer
+pub(crate) fn trailer_start(data: &[u8]) -> Option<usize> {
+    let data = split_signature(data).0;
+    let fixed_start = data.len().checked_sub(TRAILER_FIXED_SIZE)?;
+    if &data[data.len() - TRAILER_MAGIC.len()..] != TRAILER_MAGIC {
+        return None;
+    }
+    let len = (&data[fixed_start..]).read_u32::<LE>().ok()? as usize;
+    fixed_start.checked_sub(len)
+}
+
+/// Сигнатура, которой заканчивается подпись блока
+const SIGNATURE_MAGIC: &[u8; 4] = b"BSIG";
+
+/// Размер подписи Ed25519
+pub(crate) const SIGNATURE_LEN: usize = 64;
+
+/// Размер подписи вместе с ее MD5 (16 байт) и сигнатурой (4 байта)
+pub(crate) const SIGNATURE_SECTION_SIZE: usize = SIGNATURE_LEN + 16 + 4;
+
+/// Кодирует подпись, дописываемую в конец блока (см. модуль `signature`).
+///
+/// ```text
+/// | подпись (64 байта) | MD5 подписи (16 байт) | "BSIG" |
+/// ```
+#[cfg(feature = "signing")]
+pub(crate) fn encode_signature(signature: &[u8; SIGNATURE_LEN]) -> Vec<u8> {
+    let mut section = Vec::with_capacity(SIGNATURE_SECTION_SIZE);
+    section.extend_from_slice(signature);
+    section.extend_from_slice(&md5::compute(&signature[..]).0);
+    section.extend_from_slice(SIGNATURE_MAGIC);
+    section
+}
+
+/// Отделяет подпись от остального содержимого блока.
+///
+/// MD5 подписи позволяет не принять за подпись содержимое последнего файла неподписанного
+/// блока, случайно заканчивающееся сигнатурой.
+pub(crate) fn split_signature(data: &[u8]) -> (&[u8], Option<[u8; SIGNATURE_LEN]>) {
+    let section_start = match data.len().checked_sub(SIGNATURE_SECTION_SIZE) {
+        Some(start) if data.ends_with(SIGNATURE_MAGIC) => start,
+        _ => return (data, None),
+    };
+    let section = &data[section_start..];
+    let (signature, checksum) = section.split_at(SIGNATURE_LEN);
+    if md5::compute(signature).0 != checksum[..16] {
+        return (data, None);
+    }
+    let mut bytes = [0; SIGNATURE_LEN];
+    bytes.copy_from_slice(signature);
+    (&data[..section_start], Some(bytes))
+}
+
+/// Аналог [`split_signature`] для блока в хранилище `data`: читает только последние байты
+/// блока и возвращает размер блока без подписи
+///
+/// [`split_signature`]: fn.split_signature.html
+pub(crate) fn read_signature(
+    data: &(impl BlockStorage + ?Sized),
+) -> io::Result<(u64, Option<[u8; SIGNATURE_LEN]>)> {
+    let size = data.size()?;
+    let section_len = size.min(SIGNATURE_SECTION_SIZE as u64);
+    let tail = data.read_at(size - section_len, section_len as usize)?;
+    let (unsigned, signature) = split_signature(&tail);
+    Ok((size - (tail.len() - unsigned.len()) as u64, signature))
+}
+
+/// Хранилище содержимого блока: отображенный в память файл, буфер в памяти или любое другое
+/// хранилище (см. [`BlockStorage`])
+///
+/// [`BlockStorage`]: ../storage/trait.BlockStorage.html
+type BlockData = Box<dyn BlockStorage>;
+
+pub struct Block {
+    header: BlockHeader,
+    data: BlockData,
+    /// Размер блока без подписи
+    data_len: u64,
+    needs_repair: bool,
+    limits: DecodeLimits,
+    verify_on_read: bool,
+    /// Файл блока, если блок отображен в память (см. [`advise`] и [`copy_entry_to`])
+    ///
+    /// [`advise`]: #method.advise
+    /// [`copy_entry_to`]: #method.copy_entry_to
+    mapped_file: Option<File>,
+    /// Подпись блока, если он подписан (см. модуль `signature`)
+    signature: Option<[u8; SIGNATURE_LEN]>,
+    /// Номера записей заголовка, упорядоченные по идентификатору, или `None`, если записи уже
+    /// упорядочены. Строится при первом поиске по идентификатору.
+    id_index: OnceLock<Option<Vec<u32>>>,
+    /// Номера записей заголовка, упорядоченные по хешу location. Строится при первом поиске по
+    /// location.
+    location_index: OnceLock<Vec<u32>>,
+    #[cfg(feature = "encryption")]
+    decryption_key: Option<EncryptionKey>,
+}
+
+/// Декодирует содержимое файлов блока: проверяет контрольные суммы, расшифровывает и
+/// распаковывает. Используется как [`Block`], так и [`BlockReader`].
+///
+/// [`Block`]: struct.Block.html
+/// [`BlockReader`]: ../reader/struct.BlockReader.html
+pub(crate) struct ContentDecoder<'a> {
+    pub(crate) header: &'a BlockHeader,
+    pub(crate) verify_on_read: bool,
+    #[cfg(feature = "encryption")]
+    pub(crate) decryption_key: Option<&'a EncryptionKey>,
+}
+
+impl ContentDecoder<'_> {
+    /// Возвращает содержимое файла, проверяя его контрольную сумму, если включена проверка при
+    /// чтении (см. [`verify_on_read`])
+    ///
+    /// [`verify_on_read`]: struct.Block.html#method.verify_on_read
+    pub(crate) fn checked_content<'b>(
+        &self,
+        info: &FileInfo,
+        header: &FileHeader,
+        payload: Cow<'b, [u8]>,
+    ) -> Result<Cow<'b, [u8]>> {
+        // Контрольная сумма зашифрованного блока проверяется до расшифровки, так как она
+        // вычислена по записанному содержимому
+        if self.verify_on_read
+            && self.header.is_encrypted()
+            && md5::compute(&payload) != header.hash
+        {
+            return Err(checksum_mismatch(info));
+        }
+        let content = self.decode_content(header, payload)?;
+        if self.verify_on_read
+            && !self.header.is_encrypted()
+            && md5::compute(&content) != header.hash
+        {
+            return Err(checksum_mismatch(info));
+        }
+        Ok(content)
+    }
+
+    /// Возвращает содержимое файла по записанным в блоке данным: для зашифрованных блоков
+    /// расшифровывает его, для сжатых – распаковывает, для остальных – возвращает как есть
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub(crate) fn decode_content<'b>(
+        &self,
+        header: &FileHeader,
+        payload: Cow<'b, [u8]>,
+    ) -> Result<Cow<'b, [u8]>> {
+        #[cfg(feature = "encryption")]
+        if self.header.is_encrypted() {
+            let key = self.decryption_key.ok_or(Error::KeyRequired)?;
+            let decrypted = encryption::decrypt(key, &header.location, &payload)?;
+            #[cfg(feature = "zstd")]
+            if self.header.is_compressed() {
+                return decompress(&decrypted).map(Cow::Owned);
+            }
+            return Ok(Cow::Owned(decrypted));
+        }
+        #[cfg(feature = "zstd")]
+        if self.header.is_compressed() {
+            return decompress(&payload).map(Cow::Owned);
+        }
+        Ok(payload)
+    }
+}
+
+/// Читает из блока в хранилище `data` заголовок и содержимое файла, проверяя что они не выходят
+/// за границы блока (`data_len` байт без подписи)
+pub(crate) fn read_entry<'a>(
+    data: &'a (impl BlockStorage + ?Sized),
+    data_len: u64,
+    info: &FileInfo,
+    max_location_len: u16,
+) -> Result<(FileHeader, Cow<'a, [u8]>)> {
+    let (header, content_offset) = read_entry_header(data, data_len, info, max_location_len)?;
+    let content = data.read_at(content_offset, info.size as usize)?;
+    Ok((header, content))
+}
+
+/// Читает заголовок файла, не читая его содержимое. Возвращает заголовок и смещение
+/// содержимого файла (см. [`read_entry`]).
+///
+/// [`read_entry`]: fn.read_entry.html
+pub(crate) fn read_entry_header(
+    data: &(impl BlockStorage + ?Sized),
+    data_len: u64,
+    info: &FileInfo,
+    max_location_len: u16,
+) -> Result<(FileHeader, u64)> {
+    let out_of_bounds = || Error::EntryOutOfBounds {
+        id: info.wide_id(),
+        offset: info.offset,
+        size: info.size,
+    };
+    let read = |offset: u64, len: usize| match offset.checked_add(len as u64) {
+        Some(end) if end <= data_len => Ok(data.read_at(offset, len)?),
+        _ => Err(out_of_bounds()),
+    };
+
+    let offset = u64::from(info.offset);
+    let fixed = read(offset, FILE_HEADER_FIXED_SIZE as usize)?;
+    let location_len = (&fixed[16..]).read_u16::<LE>()?;
+    // Слишком длинный location отклоняется при декодировании, не читая его
+    let bytes = if location_len > max_location_len {
+        fixed
+    } else {
+        read(
+            offset,
+            FILE_HEADER_FIXED_SIZE as usize + location_len as usize,
+        )?
+    };
+    let header = FileHeader::decode_limited(&mut &bytes[..], max_location_len)?;
+
+    let content_offset = offset + bytes.len() as u64;
+    if content_offset + u64::from(info.size) > data_len {
+        return Err(out_of_bounds());
+    }
+    Ok((header, content_offset))
+}
+
+fn checksum_mismatch(info: &FileInfo) -> Error {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(id = info.id, "checksum mismatch");
+    Error::ChecksumMismatch { id: info.wide_id() }
+}
+
+/// Порядок доступа к содержимому блока, отображенного в память (см. [`Block::advise`])
+///
+/// [`Block::advise`]: struct.Block.html#method.advise
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Advice {
+    /// Поведение ядра по умолчанию
+    Normal,
+
+    /// Файлы читаются в произвольном порядке, упреждающее чтение отключается (`MADV_RANDOM`)
+    Random,
+
+    /// Блок читается последовательно, например, при проверке контрольных сумм, поэтому
+    /// упреждающее чтение выполняется агрессивнее (`MADV_SEQUENTIAL`)
+    Sequential,
+
+    /// Блок скоро будет прочитан, и ядро загружает его страницы в фоне (`MADV_WILLNEED`)
+    WillNeed,
+
+    /// Блок загружается в память целиком до возврата из [`Block::advise`] (аналог
+    /// `MAP_POPULATE`), так что первые обращения к файлам не ждут диска
+    ///
+    /// [`Block::advise`]: struct.Block.html#method.advise
+    Populate,
+}
+
+#[cfg(unix)]
+fn madvise(data: &[u8], advice: libc::c_int) -> io::Result<()> {
+    let result = unsafe { libc::madvise(data.as_ptr() as *mut libc::c_void, data.len(), advice) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn page_size() -> usize {
+    #[cfg(unix)]
+    {
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if size > 0 {
+            return size as usize;
+        }
+    }
+    4096
+}
+
+/// Размер части содержимого файла, читаемой за раз при проверке контрольной суммы (см.
+/// [`Block::verify_at`])
+///
+/// [`Block::verify_at`]: struct.Block.html#method.verify_at
+const VERIFY_CHUNK_SIZE: usize = 1 << 20;
+
+/// Открывает файл блока для чтения по мере обращения (см. [`BlockOpenOptions::pread`])
+///
+/// [`BlockOpenOptions::pread`]: struct.BlockOpenOptions.html#method.pread
+#[cfg(not(target_arch = "wasm32"))]
+fn open_pread(path: &Path) -> io::Result<BlockData> {
+    #[cfg(unix)]
+    return Ok(Box::new(File::open(path)?));
+    #[cfg(not(unix))]
+    Ok(Box::new(fs::read(path)?))
+}
+
+/// Открывает файл блока для чтения в обход page cache (см. [`BlockOpenOptions::direct_io`]).
+///
+/// Возвращает `None`, если `O_DIRECT` не поддерживается платформой или файловой системой.
+///
+/// [`BlockOpenOptions::direct_io`]: struct.BlockOpenOptions.html#method.direct_io
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn open_direct(path: &Path) -> io::Result<Option<BlockData>> {
+    #[cfg(target_os = "linux")]
+    match DirectFile::open(path) {
+        Ok(file) => return Ok(Some(Box::new(file))),
+        // Файловая система не поддерживает O_DIRECT
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+        Err(e) => return Err(e),
+    }
+    Ok(None)
+}
+
+/// Файл блока, открытый с `O_DIRECT`. Диапазоны читаются выровненными по размеру страницы
+/// чтениями через небольшие буферы, которые переиспользуются между чтениями, поэтому блок
+/// никогда не читается в память целиком.
+#[cfg(target_os = "linux")]
+struct DirectFile {
+    file: File,
+    len: u64,
+    alignment: usize,
+    /// Буферы, освободившиеся после предыдущих чтений
+    buffers: std::sync::Mutex<Vec<AlignedBuffer>>,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectFile {
+    /// Размер буфера одного чтения. Кратен размеру страницы, а значит и размеру логического
+    /// блока устройства
+    const CHUNK_SIZE: usize = 1 << 20;
+
+    fn open(path: &Path) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        let alignment = page_size();
+        let direct = Self {
+            len: file.metadata()?.len(),
+            file,
+            alignment,
+            buffers: Default::default(),
+        };
+        // Некоторые файловые системы открывают файл с O_DIRECT, но отклоняют чтения
+        let mut buffer = AlignedBuffer::new(alignment, alignment)?;
+        direct.read_aligned(&mut buffer, 0)?;
+        Ok(direct)
+    }
+
+    /// Заполняет `buffer` байтами файла начиная с выровненного смещения `offset`. Возвращает
+    /// количество прочитанных байт, которое меньше размера буфера только в конце файла.
+    fn read_aligned(&self, buffer: &mut AlignedBuffer, offset: u64) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut filled = 0;
+        while filled < buffer.capacity {
+            let read = unsafe {
+                libc::pread(
+                    self.file.as_raw_fd(),
+                    buffer.ptr.as_ptr().add(filled) as *mut libc::c_void,
+                    buffer.capacity - filled,
+                    (offset + filled as u64) as libc::off_t,
+                )
+            };
+            match read {
+                0 => break,
+                read if read > 0 => filled += read as usize,
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+            // Короткое чтение в середине файла нарушило бы выравнивание следующего чтения
+            if !filled.is_multiple_of(self.alignment) {
+                break;
+            }
+        }
+        Ok(filled)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl RangeRead for DirectFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let end = match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= self.len => end,
+            _ => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        let reused = self.buffers.lock().unwrap().pop();
+        let mut buffer = match reused {
+            Some(buffer) => buffer,
+            None => AlignedBuffer::new(Self::CHUNK_SIZE, self.alignment)?,
+        };
+        let mut position = offset;
+        let result = loop {
+            if position >= end {
+                break Ok(());
+            }
+            let aligned = position - position % self.alignment as u64;
+            let read = match self.read_aligned(&mut buffer, aligned) {
+                Ok(read) => read,
+                Err(e) => break Err(e),
+            };
+            let skip = (position - aligned) as usize;
+            if read <= skip {
+                // Файл укоротился после открытия
+                break Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let len = (read - skip).min((end - position) as usize);
+            let target = (position - offset) as usize;
+            buf[target..target + len].copy_from_slice(&buffer.as_slice()[skip..skip + len]);
+            position += len as u64;
+        };
+        self.buffers.lock().unwrap().push(buffer);
+        result
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl BlockStorage for DirectFile {}
+
+/// Буфер, выровненный по размеру страницы, как того требуют чтения с `O_DIRECT`
+#[cfg(target_os = "linux")]
+struct AlignedBuffer {
+    ptr: std::ptr::NonNull<u8>,
+    capacity: usize,
+    layout: std::alloc::Layout,
+}
+
+// Буфер владеет своей памятью, а чтения в него синхронизированы владельцем (см. DirectFile)
+#[cfg(target_os = "linux")]
+unsafe impl Send for AlignedBuffer {}
+
+#[cfg(target_os = "linux")]
+impl AlignedBuffer {
+    fn new(capacity: usize, alignment: usize) -> io::Result<Self> {
+        let layout = std::alloc::Layout::from_size_align(capacity, alignment)
+            .map_err(|_| io::ErrorKind::OutOfMemory)?;
+        let ptr = std::ptr::NonNull::new(unsafe { std::alloc::alloc(layout) })
+            .ok_or(io::ErrorKind::OutOfMemory)?;
+        Ok(Self {
+            ptr,
+            capacity,
+            layout,
+        })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.capacity) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+/// Копирует `len` байт файла `source` начиная со смещения `offset` в `target` средствами ядра.
+///
+/// Возвращает `false`, если ни `copy_file_range`, ни `sendfile` не поддерживаются для этой
+/// пары файлов и ничего не было скопировано.
+#[cfg(target_os = "linux")]
+fn copy_range(source: &File, offset: u64, len: usize, target: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let (source, target) = (source.as_raw_fd(), target.as_raw_fd());
+    let mut offset = libc::off_t::try_from(offset).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let mut remaining = len;
+    let mut use_sendfile = false;
+    while remaining > 0 {
+        let copied = unsafe {
+            if use_sendfile {
+                libc::sendfile(target, source, &mut offset, remaining)
+            } else {
+                libc::copy_file_range(
+                    source,
+                    &mut offset,
+                    target,
+                    std::ptr::null_mut(),
+                    remaining,
+                    0,
+                )
+            }
+        };
+        match copied {
+            // Файл блока оказался короче, чем при открытии
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            copied if copied > 0 => remaining -= copied as usize,
+            _ => {
+                let e = io::Error::last_os_error();
+                let unsupported = matches!(
+                    e.raw_os_error(),
+                    Some(
+                        libc::EXDEV | libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP | libc::EBADF
+                    )
+                );
+                match e.kind() {
+                    io::ErrorKind::Interrupted => {}
+                    _ if unsupported && remaining == len && !use_sendfile => use_sendfile = true,
+                    _ if unsupported && remaining == len => return Ok(false),
+                    _ => return Err(e),
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Ограничения на размеры структур блока, проверяемые при его чтении
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct DecodeLimits {
+    /// Максимальный размер заголовка вместе с блоком метаинформации в байтах
+    pub max_header_len: u64,
+
+    /// Максимальная длина location файла в байтах
+    pub max_location_len: u16,
+}
+
+impl DecodeLimits {
+    pub fn unlimited() -> Self {
+        Self {
+            max_header_len: u64::MAX,
+            max_location_len: u16::MAX,
+        }
+    }
+
+    /// Ограничения для блоков, полученных из недоверенных источников (см. [`Block::open_untrusted`])
+    ///
+    /// [`Block::open_untrusted`]: struct.Block.html#method.open_untrusted
+    pub fn untrusted() -> Self {
+        Self {
+            max_header_len: 16 * 1024 * 1024,
+            max_location_len: 4096,
+        }
+    }
+}
+
+impl SelfSerialize for BlockHeader {
+    fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()> {
+        self.encode_with(target, self.has_compressed_header())
+    }
+
+    fn decode(source: &mut impl ReadBytesExt) -> Result<Self> {
+        Self::decode_limited(source, u64::MAX, &DecodeLimits::unlimited())
+    }
+}
+
+impl BlockHeader {
+    /// Кодирует заголовок так же, как `encode`, но не сжимая блок метаинформации. Результат
+    /// сжатия зависит от версии zstd, поэтому там, где заголовок должен кодироваться одинаково
+    /// всеми версиями библиотеки (например, при подписи блока), используется этот метод.
+    #[cfg(feature = "signing")]
+    pub(crate) fn encode_uncompressed(&self, target: &mut impl WriteBytesExt) -> Result<()> {
+        self.encode_with(target, false)
+    }
+
+    fn encode_with(&self, target: &mut impl WriteBytesExt, compress: bool) -> Result<()> {
+        target.write_u16::<LE>(self.version)?;
+        if self.version >= 2 {
+            target.write_u32::<LE>(self.flags)?;
+        }
+        let len = self.file_info.len();
+        let file_info_len = u32::try_from(len)
+            .map_err(|_| Error::FormatLimitExceeded("too many files in block".into()))?;
+        target.write_u32::<LE>(file_info_len)?;
+
+        if compress && len > 0 {
+            let mut records = Vec::with_capacity(len * FileInfo::encoded_len(self.flags) as usize);
+            for file_info in self.file_info.iter() {
+                file_info.encode_with_flags(&mut records, self.flags)?;
+            }
+            let compressed = compress_table(&records)?;
+            // Такой блок метаинформации было бы невозможно прочитать (см. decode_limited)
+            if records.len() as u64 > compressed.len() as u64 * MAX_TABLE_COMPRESSION_RATIO {
+                return Err(Error::FormatLimitExceeded(format!(
+                    "meta section of {} files compresses more than {} times",
+                    len, MAX_TABLE_COMPRESSION_RATIO
+                )));
+            }
+            target.write_u32::<LE>(compressed.len() as u32)?;
+            target.write_all(&compressed)?;
+        } else {
+            for file_info in self.file_info.iter() {
+                file_info.encode_with_flags(target, self.flags)?;
+            }
+        }
+        if self.has_header_locations() {
+            for file_header in self.file_headers.iter() {
+                file_header.write_to(target)?;
+            }
+        }
+        if let Some(metadata) = &self.metadata {
+            metadata.encode(target)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Block {
+    /// Создает блок из файлов на локальной ФС с параметрами по умолчанию.
+    ///
+    /// См. [`BlockOptions::create`].
+    ///
+    /// [`BlockOptions::create`]: struct.BlockOptions.html#method.create
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_files(block_path: impl AsRef<Path>, files: &[AddFileRequest]) -> Result<Block> {
+        BlockOptions::new().create(block_path, files)
+    }
+
+    /// Создает блок, содержащий только те файлы из `files`, которых нет в блоке `base` или
+    /// содержимое которых отличается от сохраненного в `base`, с параметрами по умолчанию.
+    ///
+    /// См. [`BlockOptions::create_delta`].
+    ///
+    /// [`BlockOptions::create_delta`]: struct.BlockOptions.html#method.create_delta
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn delta_from(
+        base: &Block,
+        block_path: impl AsRef<Path>,
+        files: &[AddFileRequest],
+    ) -> Result<Block> {
+        BlockOptions::new()
+            .location_normalization(base.normalization())
+            .content_addressed(base.header.is_content_addressed())
+            .create_delta(base, block_path, files)
+    }
+
+    /// Открывает блок, отображая его файл в память.
+    ///
+    /// Если основной заголовок блока поврежден, но блок содержит резервную копию заголовка
+    /// (см. [`BlockOptions::header_trailer`]), то используется она, а блок помечается как
+    /// требующий восстановления (см. [`needs_repair`]).
+    ///
+    /// [`BlockOptions::header_trailer`]: struct.BlockOptions.html#method.header_trailer
+    /// [`needs_repair`]: #method.needs_repair
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()))
+    )]
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::options().open(path)
+    }
+
+    /// Открывает блок, полученный из недоверенного источника (например, загруженный
+    /// пользователем).
+    ///
+    /// В отличии от [`open`] ограничивает размер заголовка блока и длину location файлов, так что
+    /// специально сформированный блок не может заставить читателя выделить много памяти.
+    ///
+    /// [`open`]: #method.open
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()))
+    )]
+    pub fn open_untrusted(path: impl AsRef<Path>) -> Result<Self> {
+        Self::options().untrusted(true).open(path)
+    }
+
+    /// Параметры открытия блока (см. [`BlockOpenOptions`])
+    ///
+    /// [`BlockOpenOptions`]: struct.BlockOpenOptions.html
+    pub fn options() -> BlockOpenOptions {
+        BlockOpenOptions::new()
+    }
+
+    /// Открывает блок и проверяет его целиком: основной заголовок должен совпадать с
+    /// резервной копией (если она есть, см. [`BlockOptions::header_trailer`]), а контрольные
+    /// суммы всех файлов – с записанными в их заголовках.
+    ///
+    /// Предназначен для загрузки блоков в каталог, куда поврежденный блок попасть не должен:
+    /// вместо чтения по резервной копии заголовка возвращается [`Error::BlockCorrupted`], а при
+    /// несовпадении содержимого – [`Error::ChecksumMismatch`] первого поврежденного файла.
+    /// Файлы проверяются параллельно.
+    ///
+    /// [`BlockOptions::header_trailer`]: struct.BlockOptions.html#method.header_trailer
+    /// [`Error::BlockCorrupted`]: ../errors/enum.Error.html#variant.BlockCorrupted
+    /// [`Error::ChecksumMismatch`]: ../errors/enum.Error.html#variant.ChecksumMismatch
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()))
+    )]
+    pub fn open_verified(path: impl AsRef<Path>) -> Result<Self> {
+        Self::options().verify(true).open(path)
+    }
+
+    /// Открывает блок, целиком находящийся в памяти.
+    ///
+    /// В отличии от [`open`] не требует ни файловой системы, ни `mmap`.
+    ///
+    /// [`open`]: #method.open
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Self::from_storage(bytes)
+    }
+
+    /// Открывает блок, содержимое которого предоставляет хранилище `storage` (см.
+    /// [`BlockStorage`]), например, разделяемая память или буфер, принадлежащий другой
+    /// библиотеке.
+    ///
+    /// [`BlockStorage`]: ../storage/trait.BlockStorage.html
+    pub fn from_storage(storage: impl BlockStorage + 'static) -> Result<Self> {
+        Self::from_data(Box::new(storage), DecodeLimits::unlimited())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(bytes = data.size().unwrap_or(0)))
+    )]
+    fn from_data(data: BlockData, limits: DecodeLimits) -> Result<Self> {
+        let (data_len, signature) = read_signature(&*data)?;
+        let mut source = BufReader::new(RangeReader::new(&*data)?);
+        let primary = BlockHeader::decode_limited(&mut source, data_len, &limits)
+            .and_then(|header| header.validate(data_len).map(|_| header));
+        let trailer = read_trailer(&*data, data_len)
+            .and_then(|trailer| BlockHeader::decode_trailer(&trailer, &limits))
+            .filter(|header| header.validate(data_len).is_ok());
+        let (header, needs_repair) = match (primary, trailer) {
+            (Ok(primary), trailer) if primary.is_streamed() => match trailer {
+                Some(trailer) => (trailer, false),
+                None => {
+                    return Err(Error::corrupted(
+                        "meta section of a streamed block is missing or corrupted",
+                    ))
+                }
+            },
+            (Ok(primary), Some(trailer)) if primary != trailer => (trailer, true),
+            (Ok(primary), _) => (primary, false),
+            (Err(_), Some(trailer)) => (trailer, true),
+            (Err(e), None) => return Err(header_corrupted(e)),
+        };
+        #[cfg(feature = "tracing")]
+        if needs_repair {
+            tracing::warn!("primary block header is corrupted, using header trailer");
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            files = header.file_info.len(),
+            header_bytes = header.encoded_len(),
+            "block header decoded"
+        );
+        Ok(Block {
+            header,
+            data,
+            data_len,
+            needs_repair,
+            limits,
+            verify_on_read: false,
+            mapped_file: None,
+            signature,
+            id_index: OnceLock::new(),
+            location_index: OnceLock::new(),
+            #[cfg(feature = "encryption")]
+            decryption_key: None,
+        })
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    /// Проверяет, что основной заголовок блока не поврежден, а контрольные суммы всех файлов
+    /// совпадают с записанными в их заголовках (см. [`open_verified`])
+    ///
+    /// [`open_verified`]: #method.open_verified
+    fn verify_completely(&self) -> Result<()> {
+        if self.needs_repair {
+            return Err(Error::corrupted(
+                "primary header doesn't match its backup copy",
+            ));
+        }
+        let jobs = thread::available_parallelism().map_or(1, |jobs| jobs.get());
+        self.verify_all_parallel(jobs)
+            .into_iter()
+            .find_map(|verification| verification.result.err())
+            .map_or(Ok(()), Err)
+    }
+
+    /// Сообщает ядру, как будет читаться содержимое блока, чтобы упреждающее чтение
+    /// соответствовало порядку доступа (см. [`Advice`]).
+    ///
+    /// Действует только на блоки, отображенные в память; для блоков, открытых из буфера
+    /// ([`from_bytes`]), ничего не делает. На платформах, отличных от Unix, учитывается только
+    /// [`Advice::Populate`].
+    ///
+    /// [`Advice`]: enum.Advice.html
+    /// [`Advice::Populate`]: enum.Advice.html#variant.Populate
+    /// [`from_bytes`]: #method.from_bytes
+    pub fn advise(&self, advice: Advice) -> Result<()> {
+        let data = match (&self.mapped_file, self.data.bytes()) {
+            (Some(_), Some(data)) => data,
+            _ => return Ok(()),
+        };
+        #[cfg(unix)]
+        {
+            let flag = match advice {
+                Advice::Normal => libc::MADV_NORMAL,
+                Advice::Random => libc::MADV_RANDOM,
+                Advice::Sequential => libc::MADV_SEQUENTIAL,
+                Advice::WillNeed | Advice::Populate => libc::MADV_WILLNEED,
+            };
+            madvise(data, flag)?;
+        }
+        if advice == Advice::Populate {
+            // Аналог MAP_POPULATE: обращение к каждой странице загружает ее до возврата
+            let page_size = page_size();
+            let mut sum = 0u8;
+            for offset in (0..data.len()).step_by(page_size) {
+                sum = sum.wrapping_add(unsafe { std::ptr::read_volatile(&data[offset]) });
+            }
+            std::hint::black_box(sum);
+        }
+        Ok(())
+    }
+
+    /// Сообщает ядру, что прочитанные страницы блока больше не нужны (`MADV_DONTNEED`), и они
+    /// могут быть вытеснены из памяти. Последующее чтение загружает их с диска заново.
+    ///
+    /// Позволяет последовательно проверить блок (например, после [`Advice::Sequential`]), не
+    /// вытесняя из page cache данные, к которым обращаются другие читатели.
+    ///
+    /// [`Advice::Sequential`]: enum.Advice.html#variant.Sequential
+    pub fn release_pages(&self) -> Result<()> {
+        #[cfg(unix)]
+        if let (Some(_), Some(data)) = (&self.mapped_file, self.data.bytes()) {
+            madvise(data, libc::MADV_DONTNEED)?;
+        }
+        Ok(())
+    }
+
+    /// Вытесняет страницы файла блока из page cache (`POSIX_FADV_DONTNEED`) после того, как
+    /// они освобождены в адресном пространстве процесса (см. [`release_pages`]).
+    ///
+    /// В отличии от [`release_pages`] страницы удаляются не только из памяти процесса, но и из
+    /// кеша ядра (если их не используют другие процессы), поэтому фоновая проверка блоков не
+    /// вытесняет из page cache данные, нужные основной нагрузке. На платформах, отличных от
+    /// Linux, аналогичен [`release_pages`].
+    ///
+    /// [`release_pages`]: #method.release_pages
+    pub fn drop_page_cache(&self) -> Result<()> {
+        self.release_pages()?;
+        #[cfg(target_os = "linux")]
+        if let Some(file) = &self.mapped_file {
+            use std::os::unix::io::AsRawFd;
+            let result =
+                unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+            if result != 0 {
+                return Err(io::Error::from_raw_os_error(result).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Количество байт блока между заголовком и файлами, а также между самими файлами, не
+    /// занятых ни заголовками, ни содержимым файлов: выравнивание файлов и зарезервированное
+    /// место (см. [`BlockOptions::reserve_entries`]).
+    ///
+    /// [`BlockOptions::reserve_entries`]: struct.BlockOptions.html#method.reserve_entries
+    pub fn padding(&self) -> Result<u64> {
+        let mut ranges = self
+            .entries()
+            .map(|entry| {
+                let info = entry.info();
+                let len = u64::from(FILE_HEADER_FIXED_SIZE)
+                    + entry.header()?.location.len() as u64
+                    + u64::from(info.size);
+                Ok((u64::from(info.offset), len))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        ranges.sort_unstable();
+        ranges.dedup();
+        let mut padding = 0;
+        let mut position = self.header.data_start();
+        for (offset, len) in ranges {
+            padding += offset.saturating_sub(position);
+            position = position.max(offset + len);
+        }
+        Ok(padding)
+    }
+
+    /// Количество записей [`FileInfo`], которые поместятся в заголовок блока без сдвига
+    /// содержимого файлов: место, зарезервированное при создании блока
+    /// ([`BlockOptions::reserve_entries`]), вместе с выравниванием первого файла.
+    ///
+    /// [`FileInfo`]: struct.FileInfo.html
+    /// [`BlockOptions::reserve_entries`]: struct.BlockOptions.html#method.reserve_entries
+    pub fn reserved_entries(&self) -> u64 {
+        if self.header.is_streamed() {
+            return 0;
+        }
+        let data_end = trailer_start_at(&*self.data, self.data_len).unwrap_or(self.data_len);
+        let free_end = self
+            .header
+            .file_info
+            .iter()
+            .map(|info| u64::from(info.offset))
+            .min()
+            .unwrap_or(data_end);
+        let flags = self.header.flags;
+        let count = self.header.file_info.len() as u64;
+        let reserved =
+            free_end.saturating_sub(self.header.encoded_len()) / FileInfo::encoded_len(flags);
+        // Место под сжатые записи отводится с запасом (см. FileInfo::table_len), поэтому их
+        // помещается несколько меньше
+        let other_len = self.header.encoded_len() - FileInfo::table_len(flags, count);
+        (0..=reserved)
+            .rev()
+            .find(|&extra| other_len + FileInfo::table_len(flags, count + extra) <= free_end)
+            .unwrap_or(0)
+    }
+
+    /// Возвращает `true`, если основной заголовок блока поврежден и блок был открыт по
+    /// резервной копии заголовка. Такой блок пригоден для чтения, но его следует пересоздать.
+    pub fn needs_repair(&self) -> bool {
+        self.needs_repair
+    }
+
+    /// Подписан ли блок (см. модуль `signature`)
+    pub fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+
+    #[cfg(feature = "signing")]
+    pub(crate) fn signature(&self) -> Option<&[u8; SIGNATURE_LEN]> {
+        self.signature.as_ref()
+    }
+
+    /// Размер блока без подписи
+    #[cfg(feature = "signing")]
+    pub(crate) fn unsigned_len(&self) -> usize {
+        self.data_len as usize
+    }
+
+    /// Если `true`, то [`file_at`], [`file_by_id`] и [`Entry::content`] проверяют контрольную
+    /// сумму содержимого при каждом обращении и возвращают [`Error::ChecksumMismatch`] для
+    /// поврежденных файлов. Проверка требует прочитать файл целиком, поэтому по умолчанию
+    /// выключена.
+    ///
+    /// [`file_at`]: #method.file_at
+    /// [`file_by_id`]: #method.file_by_id
+    /// [`Entry::content`]: struct.Entry.html#method.content
+    /// [`Error::ChecksumMismatch`]: ../errors/enum.Error.html#variant.ChecksumMismatch
+    pub fn verify_on_read(&mut self, verify_on_read: bool) -> &mut Self {
+        self.verify_on_read = verify_on_read;
+        self
+    }
+
+    /// Задает ключ, которым расшифровывается содержимое файлов зашифрованного блока (см.
+    /// [`BlockOptions::encryption_key`]). Без ключа чтение содержимого такого блока возвращает
+    /// [`Error::KeyRequired`], а с неподходящим ключом – [`Error::DecryptionFailed`].
+    ///
+    /// Заголовки файлов и проверка целостности ([`verify_at`]) ключа не требуют.
+    ///
+    /// [`BlockOptions::encryption_key`]: struct.BlockOptions.html#method.encryption_key
+    /// [`Error::KeyRequired`]: ../errors/enum.Error.html#variant.KeyRequired
+    /// [`Error::DecryptionFailed`]: ../errors/enum.Error.html#variant.DecryptionFailed
+    /// [`verify_at`]: #method.verify_at
+    #[cfg(feature = "encryption")]
+    pub fn decryption_key(&mut self, key: EncryptionKey) -> &mut Self {
+        self.decryption_key = Some(key);
+        self
+    }
+
+    /// Возвращает заголовок и содержимое файла с порядковым номером `idx`.
+    ///
+    /// Возвращает [`Error::IndexOutOfRange`], если в блоке нет файла с таким номером, и
+    /// [`Error::EntryOutOfBounds`], если файл выходит за границы блока (например, блок
+    /// обрезан).
+    ///
+    /// [`Error::IndexOutOfRange`]: ../errors/enum.Error.html#variant.IndexOutOfRange
+    /// [`Error::EntryOutOfBounds`]: ../errors/enum.Error.html#variant.EntryOutOfBounds
+    pub fn file_at(&self, idx: usize) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        let info = self.file_info_at(idx)?;
+        let (header, payload) = self.read_file(info)?;
+        let content = self.checked_content(info, &header, payload)?;
+        Ok((header, content))
+    }
+
+    fn file_info_at(&self, idx: usize) -> Result<&FileInfo> {
+        let len = self.len();
+        self.header
+            .file_info
+            .get(idx)
+            .ok_or(Error::IndexOutOfRange { idx, len })
+    }
+
+    /// Читает заголовок и содержимое файла, проверяя что они не выходят за границы блока
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip_all,
+            fields(id = info.id, offset = info.offset, bytes = info.size)
+        )
+    )]
+    fn read_file(&self, info: &FileInfo) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        read_entry(
+            &*self.data,
+            self.data_len,
+            info,
+            self.limits.max_location_len,
+        )
+    }
+
+    /// Читает заголовок файла, не читая его содержимое (см. [`read_entry_header`])
+    ///
+    /// [`read_entry_header`]: fn.read_entry_header.html
+    fn read_file_header(&self, info: &FileInfo) -> Result<(FileHeader, u64)> {
+        read_entry_header(
+            &*self.data,
+            self.data_len,
+            info,
+            self.limits.max_location_len,
+        )
+    }
+
+    /// Возвращает заголовок и содержимое файла с идентификатором `id`.
+    ///
+    /// Если такого файла в блоке нет, возвращает [`Error::FileNotFound`].
+    ///
+    /// [`Error::FileNotFound`]: ../errors/enum.Error.html#variant.FileNotFound
+    pub fn file_by_id(&self, id: u64) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        self.file_by_wide_id(u128::from(id))
+    }
+
+    /// Возвращает заголовок и содержимое файла со 128-битным идентификатором `id` (см.
+    /// [`FileInfo::wide_id`]). UUID передается в виде числа (`Uuid::as_u128`). В блоках без
+    /// [`FLAG_WIDE_IDS`] аналогичен [`file_by_id`].
+    ///
+    /// [`FileInfo::wide_id`]: struct.FileInfo.html#method.wide_id
+    /// [`FLAG_WIDE_IDS`]: constant.FLAG_WIDE_IDS.html
+    /// [`file_by_id`]: #method.file_by_id
+    pub fn file_by_wide_id(&self, id: u128) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        let info = self.file_info_by_wide_id(id)?;
+        let (header, payload) = self.read_file(info)?;
+        let content = self.checked_content(info, &header, payload)?;
+        Ok((header, content))
+    }
+
+    /// Записывает содержимое файла с идентификатором `id` в `target` (с его текущей позиции) и
+    /// возвращает количество записанных байт.
+    ///
+    /// Содержимое несжатых и незашифрованных блоков, отображенных в память, копируется ядром из
+    /// файла блока (`copy_file_range`, а если он не поддерживается для этой пары файлов –
+    /// `sendfile`, что позволяет писать и в сокеты), не проходя через буферы процесса. Если
+    /// включена проверка при чтении ([`verify_on_read`]), то контрольная сумма проверяется до
+    /// копирования. В остальных случаях и на платформах, отличных от Linux, содержимое
+    /// записывается обычным образом.
+    ///
+    /// [`verify_on_read`]: #method.verify_on_read
+    pub fn copy_entry_to(&self, id: u64, target: &File) -> Result<u64> {
+        self.copy_entry_to_wide(u128::from(id), target)
+    }
+
+    /// Аналог [`copy_entry_to`] для 128-битных идентификаторов (см. [`FileInfo::wide_id`])
+    ///
+    /// [`copy_entry_to`]: #method.copy_entry_to
+    /// [`FileInfo::wide_id`]: struct.FileInfo.html#method.wide_id
+    pub fn copy_entry_to_wide(&self, id: u128, target: &File) -> Result<u64> {
+        let info = self.file_info_by_wide_id(id)?;
+        let (header, payload) = self.read_file(info)?;
+        let content = self.checked_content(info, &header, payload)?;
+        #[cfg(target_os = "linux")]
+        if let (Cow::Borrowed(content), Some(source), Some(data)) =
+            (&content, &self.mapped_file, self.data.bytes())
+        {
+            let offset = content.as_ptr() as usize - data.as_ptr() as usize;
+            if copy_range(source, offset as u64, content.len(), target)? {
+                return Ok(content.len() as u64);
+            }
+        }
+        let mut target = target;
+        target.write_all(&content)?;
+        Ok(content.len() as u64)
+    }
+
+    /// Создает блок `block_path` из файлов этого блока с идентификаторами `ids`, не извлекая их
+    /// в файловую систему. Идентификаторы, location и их хеши сохраняются, файлы записываются в
+    /// порядке этого блока.
+    ///
+    /// Новый блок создается с теми же упаковкой, сжатием и нормализацией location. Зашифрованный
+    /// блок шифруется тем же ключом (см. [`decryption_key`]), но с новыми nonce, поэтому
+    /// контрольные суммы его файлов, вычисленные по зашифрованному содержимому, меняются.
+    ///
+    /// Если какого-либо из файлов в блоке нет, возвращает [`Error::FileNotFound`].
+    ///
+    /// [`decryption_key`]: #method.decryption_key
+    /// [`Error::FileNotFound`]: ../errors/enum.Error.html#variant.FileNotFound
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn subset_to(&self, ids: &[u64], block_path: impl AsRef<Path>) -> Result<Block> {
+        let ids = ids.iter().map(|&id| u128::from(id)).collect::<Vec<_>>();
+        self.subset_to_wide(&ids, block_path)
+    }
+
+    /// Аналог [`subset_to`] для 128-битных идентификаторов (см. [`FileInfo::wide_id`]). Новый
+    /// блок создается с 128-битными идентификаторами, если они есть в этом блоке.
+    ///
+    /// [`subset_to`]: #method.subset_to
+    /// [`FileInfo::wide_id`]: struct.FileInfo.html#method.wide_id
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn subset_to_wide(&self, ids: &[u128], block_path: impl AsRef<Path>) -> Result<Block> {
+        for &id in ids {
+            self.file_info_by_wide_id(id)?;
+        }
+        let ids = ids.iter().collect::<HashSet<_>>();
+        let entries = self
+            .entries()
+            .filter(|entry| ids.contains(&entry.info().wide_id()))
+            .collect();
+
+        let mut options = BlockOptions::new();
+        options
+            .packed(self.header.is_packed())
+            .compress(self.header.is_compressed())
+            .wide_ids(self.header.has_wide_ids())
+            .manifest(self.has_manifest());
+        #[cfg(feature = "encryption")]
+        if self.header.is_encrypted() {
+            options.encryption_key(self.decryption_key.clone());
+        }
+        options.copy_entries(self, entries, block_path.as_ref())
+    }
+
+    /// Возвращает вид записи с заголовком `header` и содержимым `content` (см. [`EntryKind`]).
+    ///
+    /// [`EntryKind`]: enum.EntryKind.html
+    pub fn entry_kind(&self, header: &FileHeader, content: &[u8]) -> Result<EntryKind> {
+        if !self.is_directory(header) {
+            return Ok(EntryKind::File);
+        }
+        let mode = <[u8; 4]>::try_from(content).map_err(|_| {
+            Error::corrupted(format!(
+                "directory {} has {} bytes of metadata",
+                header.display_location(),
+                content.len()
+            ))
+        })?;
+        Ok(EntryKind::Directory {
+            mode: u32::from_le_bytes(mode),
+        })
+    }
+
+    fn is_directory(&self, header: &FileHeader) -> bool {
+        self.header.has_directories() && header.location.ends_with(b"/")
+    }
+
+    /// Возвращает JSON-манифест блока (см. [`BlockOptions::manifest`]) или `None`, если блок
+    /// записан без манифеста.
+    ///
+    /// [`BlockOptions::manifest`]: struct.BlockOptions.html#method.manifest
+    pub fn manifest(&self) -> Result<Option<Cow<'_, [u8]>>> {
+        if !self.has_manifest() {
+            return Ok(None);
+        }
+        let (_, content) = self.file_by_id(MANIFEST_ID)?;
+        Ok(Some(content))
+    }
+
+    /// Содержит ли блок манифест (см. [`BlockOptions::manifest`])
+    ///
+    /// [`BlockOptions::manifest`]: struct.BlockOptions.html#method.manifest
+    pub fn has_manifest(&self) -> bool {
+        self.file_info_by_id(MANIFEST_ID)
+            .map(|info| manifest::is_manifest(info.id, &info.location_hash))
+            .unwrap_or(false)
+    }
+
+    fn file_info_by_id(&self, id: u64) -> Result<&FileInfo> {
+        self.file_info_by_wide_id(u128::from(id))
+    }
+
+    fn file_info_by_wide_id(&self, id: u128) -> Result<&FileInfo> {
+        // Если идентификаторы повторяются, находится первая из таких записей
+        let file_info = &self.header.file_info;
+        let idx = match self.id_index() {
+            Some(index) => {
+                let pos = index.partition_point(|&idx| file_info[idx as usize].wide_id() < id);
+                index.get(pos).map(|&idx| idx as usize)
+            }
+            None => Some(file_info.partition_point(|info| info.wide_id() < id)),
+        };
+        idx.and_then(|idx| file_info.get(idx))
+            .filter(|info| info.wide_id() == id)
+            .ok_or(Error::FileNotFound { id })
+    }
+
+    /// Номера записей заголовка, упорядоченные по идентификатору, или `None`, если записи уже
+    /// упорядочены. Строится при первом обращении.
+    fn id_index(&self) -> Option<&[u32]> {
+        let index = self.id_index.get_or_init(|| {
+            let file_info = &self.header.file_info;
+            if file_info
+                .windows(2)
+                .all(|pair| pair[0].wide_id() < pair[1].wide_id())
+            {
+                return None;
+            }
+            let mut index = (0..file_info.len() as u32).collect::<Vec<_>>();
+            // Стабильная сортировка сохраняет порядок записей с одинаковыми идентификаторами
+            index.sort_by_key(|&idx| file_info[idx as usize].wide_id());
+            Some(index)
+        });
+        index.as_deref()
+    }
+
+    /// Возвращает `len` байт содержимого файла с идентификатором `id`, начиная со смещения
+    /// `offset`.
+    ///
+    /// Из несжатых блоков читается только запрошенный диапазон, и если блок находится в памяти,
+    /// то он возвращается без копирования. В сжатых блоках распаковываются только фрагменты
+    /// содержимого, покрывающие диапазон (см. [`BlockOptions::compress`]). Содержимое
+    /// зашифрованных блоков расшифровывается целиком.
+    /// Если диапазон выходит за пределы файла, возвращается [`Error::RangeOutOfBounds`].
+    ///
+    /// [`BlockOptions::compress`]: struct.BlockOptions.html#method.compress
+    /// [`Error::RangeOutOfBounds`]: ../errors/enum.Error.html#variant.RangeOutOfBounds
+    pub fn read_range(&self, id: u64, offset: u64, len: u64) -> Result<Cow<'_, [u8]>> {
+        self.read_range_wide(u128::from(id), offset, len)
+    }
+
+    /// Аналог [`read_range`] для 128-битных идентификаторов (см. [`FileInfo::wide_id`])
+    ///
+    /// [`read_range`]: #method.read_range
+    /// [`FileInfo::wide_id`]: struct.FileInfo.html#method.wide_id
+    pub fn read_range_wide(&self, id: u128, offset: u64, len: u64) -> Result<Cow<'_, [u8]>> {
+        let info = self.file_info_by_wide_id(id)?;
+        let check_bounds = |size: u64| match offset.checked_add(len) {
+            Some(end) if end <= size => Ok(()),
+            _ => Err(Error::RangeOutOfBounds {
+                id,
+                offset,
+                len,
+                size,
+            }),
+        };
+
+        if self.header.is_encrypted() {
+            let (header, payload) = self.read_file(info)?;
+            let content = self.decode_content(&header, payload)?;
+            check_bounds(content.len() as u64)?;
+            let range = offset as usize..(offset + len) as usize;
+            return Ok(Cow::Owned(content[range].to_vec()));
+        }
+        #[cfg(feature = "zstd")]
+        if self.header.is_compressed() {
+            let (_, payload) = self.read_file(info)?;
+            let content = CompressedContent::decode(&payload)?;
+            check_bounds(u64::from(content.size()))?;
+            return content.read_range(offset, len).map(Cow::Owned);
+        }
+        let (_, content_offset) = self.read_file_header(info)?;
+        check_bounds(u64::from(info.size))?;
+        Ok(self.data.read_at(content_offset + offset, len as usize)?)
+    }
+
+    /// Возвращает содержимое файла, проверяя его контрольную сумму, если включена проверка при
+    /// чтении (см. [`verify_on_read`])
+    ///
+    /// [`verify_on_read`]: #method.verify_on_read
+    fn checked_content<'b>(
+        &self,
+        info: &FileInfo,
+        header: &FileHeader,
+        payload: Cow<'b, [u8]>,
+    ) -> Result<Cow<'b, [u8]>> {
+        self.decoder().checked_content(info, header, payload)
+    }
+
+    fn checksum_mismatch(&self, info: &FileInfo) -> Error {
+        checksum_mismatch(info)
+    }
+
+    fn decode_content<'b>(
+        &self,
+        header: &FileHeader,
+        payload: Cow<'b, [u8]>,
+    ) -> Result<Cow<'b, [u8]>> {
+        self.decoder().decode_content(header, payload)
+    }
+
+    fn decoder(&self) -> ContentDecoder<'_> {
+        ContentDecoder {
+            header: &self.header,
+            verify_on_read: self.verify_on_read,
+            #[cfg(feature = "encryption")]
+            decryption_key: self.decryption_key.as_ref(),
+        }
+    }
+
+    /// Возвращает метаинформацию файла по его location (например, `/img/123.jpg`).
+    ///
+    /// Поиск выполняется по MD5-хешу location, сохраненному в заголовке блока. Перед поиском
+    /// location нормализуется по правилам, с которыми создан блок (см. [`normalization`]).
+    /// Сам location не сверяется, поэтому при коллизии хешей может быть найден другой файл
+    /// (см. [`resolve_location`]).
+    ///
+    /// [`normalization`]: #method.normalization
+    /// [`resolve_location`]: #method.resolve_location
+    pub fn find_by_location(&self, location: impl AsRef<[u8]>) -> Option<&FileInfo> {
+        let location_hash = self.normalization().hash(location.as_ref());
+        self.file_infos_by_location_hash(location_hash)
+            .next()
+            .map(|(_, info)| info)
+    }
+
+    /// Записи заголовка с хешем location `location_hash` в порядке их следования в заголовке
+    fn file_infos_by_location_hash(
+        &self,
+        location_hash: md5::Digest,
+    ) -> impl Iterator<Item = (usize, &FileInfo)> {
+        let file_info = &self.header.file_info;
+        let index = self.location_index();
+        let pos =
+            index.partition_point(|&idx| file_info[idx as usize].location_hash.0 < location_hash.0);
+        index[pos..]
+            .iter()
+            .map(move |&idx| (idx as usize, &file_info[idx as usize]))
+            .take_while(move |(_, info)| info.location_hash == location_hash)
+    }
+
+    /// Номера записей заголовка, упорядоченные по хешу location. Строится при первом обращении.
+    fn location_index(&self) -> &[u32] {
+        self.location_index.get_or_init(|| {
+            let file_info = &self.header.file_info;
+            let mut index = (0..file_info.len() as u32).collect::<Vec<_>>();
+            // Стабильная сортировка сохраняет порядок записей с одинаковыми хешами
+            index.sort_by_key(|&idx| file_info[idx as usize].location_hash.0);
+            index
+        })
+    }
+
+    /// Возвращает метаинформацию файла по его location, сверяя location целиком.
+    ///
+    /// В отличии от [`find_by_location`], совпадения MD5-хеша недостаточно: location найденного
+    /// файла сравнивается с запрошенным. Полный location берется из копии заголовка файла в
+    /// заголовке блока (см. [`BlockOptions::header_locations`]), а если ее нет – из заголовка
+    /// перед содержимым файла. Location дедуплицированного файла без копий заголовков узнать
+    /// нельзя, такой файл возвращается по совпадению хеша.
+    ///
+    /// Если хеш совпал только у файлов с другим location, возвращает
+    /// [`Error::LocationHashCollision`], а если совпадений нет – [`Error::LocationNotFound`].
+    ///
+    /// [`find_by_location`]: #method.find_by_location
+    /// [`BlockOptions::header_locations`]: struct.BlockOptions.html#method.header_locations
+    /// [`Error::LocationHashCollision`]: ../errors/enum.Error.html#variant.LocationHashCollision
+    /// [`Error::LocationNotFound`]: ../errors/enum.Error.html#variant.LocationNotFound
+    pub fn resolve_location(&self, location: impl AsRef<[u8]>) -> Result<&FileInfo> {
+        let location = self.normalization().apply(location.as_ref());
+        let location_hash = md5::compute(&location);
+        let file_headers = self.header.file_headers();
+
+        let mut unconfirmed = None;
+        let mut collision = None;
+        for (idx, info) in self.file_infos_by_location_hash(location_hash) {
+            let stored = match file_headers {
+                Some(headers) => Some(Cow::Borrowed(&headers[idx].location[..])),
+                None => {
+                    let (header, _) = self.read_file_header(info)?;
+                    // Заголовок дедуплицированного файла содержит location другого файла
+                    Some(Cow::Owned(header.location)).filter(|l| md5::compute(l) == location_hash)
+                }
+            };
+            match stored {
+                Some(stored) if stored[..] == location[..] => return Ok(info),
+                Some(_) => collision = collision.or(Some(info.id)),
+                None => unconfirmed = unconfirmed.or(Some(info)),
+            }
+        }
+
+        let display = || location::display(&location).into_owned();
+        match (unconfirmed, collision) {
+            (Some(info), _) => Ok(info),
+            (None, Some(id)) => Err(Error::LocationHashCollision {
+                id,
+                location: display(),
+            }),
+            (None, None) => Err(Error::LocationNotFound(display())),
+        }
+    }
+
+    /// Возвращает заголовок и содержимое файла с location `location`. Файл ищется так же, как
+    /// в [`resolve_location`], и для location, не совпадающего с location файла,
+    /// возвращается [`Error::LocationHashCollision`].
+    ///
+    /// [`resolve_location`]: #method.resolve_location
+    /// [`Error::LocationHashCollision`]: ../errors/enum.Error.html#variant.LocationHashCollision
+    pub fn file_by_location(
+        &self,
+        location: impl AsRef<[u8]>,
+    ) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        let info = self.resolve_location(location)?;
+        let (header, payload) = self.read_file(info)?;
+        let content = self.checked_content(info, &header, payload)?;
+        Ok((header, content))
+    }
+
+    /// Возвращает заголовок и содержимое файла, MD5 исходного содержимого которого равен
+    /// `hash`. Если таких файлов несколько, возвращается первый из них.
+    ///
+    /// В блоке, адресуемом по содержимому (см. [`BlockOptions::content_addressed`]),
+    /// идентификатор файла вычисляется по `hash`, поэтому проверяется только файл с этим
+    /// идентификатором. В остальных блоках контрольные суммы перебираются, а у зашифрованных
+    /// блоков для этого читается и расшифровывается содержимое всех файлов.
+    ///
+    /// Если такого файла нет, возвращает [`Error::ContentNotFound`].
+    ///
+    /// [`BlockOptions::content_addressed`]: struct.BlockOptions.html#method.content_addressed
+    /// [`Error::ContentNotFound`]: ../errors/enum.Error.html#variant.ContentNotFound
+    pub fn file_by_content_hash(&self, hash: &md5::Digest) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        let id = hash_id(hash);
+        let content_addressed = self.header.is_content_addressed();
+        for entry in self.entries() {
+            let info = entry.info();
+            if content_addressed && info.id != id {
+                continue;
+            }
+            if entry.content_digest()? == *hash {
+                let (header, payload) = self.read_file(info)?;
+                let content = self.checked_content(info, &header, payload)?;
+                return Ok((header, content));
+            }
+        }
+        Err(Error::ContentNotFound(format!("{:x}", hash)))
+    }
+
+    /// Содержит ли блок файл с идентификатором `id`.
+    ///
+    /// Проверяется только заголовок блока, загруженный в память при открытии, поэтому проверка
+    /// не обращается к содержимому блока и позволяет выбрать блок для запроса, не читая его.
+    pub fn contains_id(&self, id: u64) -> bool {
+        self.contains_wide_id(u128::from(id))
+    }
+
+    /// Содержит ли блок файл со 128-битным идентификатором `id` (см. [`file_by_wide_id`]).
+    ///
+    /// [`file_by_wide_id`]: #method.file_by_wide_id
+    pub fn contains_wide_id(&self, id: u128) -> bool {
+        self.file_info_by_wide_id(id).is_ok()
+    }
+
+    /// Содержит ли блок файл с location `location`. Как и [`find_by_location`], проверяет
+    /// только заголовок блока, загруженный в память.
+    ///
+    /// [`find_by_location`]: #method.find_by_location
+    pub fn contains_location(&self, location: impl AsRef<[u8]>) -> bool {
+        self.find_by_location(location).is_some()
+    }
+
+    /// Сравнивает блок с блоком `other`: какие файлы есть только в одном из блоков, а у каких
+    /// файлов, присутствующих в обоих блоках, изменились содержимое или location (см.
+    /// [`BlockDiff`]). Файлы сопоставляются по идентификатору, манифест не сравнивается.
+    ///
+    /// Location сравниваются по хешу из блока метаинформации, а содержимое – по контрольной
+    /// сумме из заголовка файла, так что содержимое файлов читается только у зашифрованных
+    /// блоков. Зашифрованным блокам должен быть задан ключ (см. [`decryption_key`]).
+    ///
+    /// [`BlockDiff`]: struct.BlockDiff.html
+    /// [`decryption_key`]: #method.decryption_key
+    pub fn diff(&self, other: &Block) -> Result<BlockDiff> {
+        fn by_id(block: &Block) -> BTreeMap<u128, Entry<'_>> {
+            block
+                .entries()
+                .filter(|e| !manifest::is_manifest(e.info().id, &e.info().location_hash))
+                .map(|entry| (entry.info().wide_id(), entry))
+                .collect()
+        }
+        let (ours, theirs) = (by_id(self), by_id(other));
+
+        let mut diff = BlockDiff::default();
+        for (id, entry) in ours.iter() {
+            let their = match theirs.get(id) {
+                Some(their) => their,
+                None => {
+                    diff.removed.push(*id);
+                    continue;
+                }
+            };
+            if entry.content_digest()? != their.content_digest()? {
+                diff.changed_content.push(*id);
+            }
+            if entry.info().location_hash != their.info().location_hash {
+                diff.changed_location.push(*id);
+            }
+        }
+        diff.added = theirs
+            .keys()
+            .filter(|id| !ours.contains_key(id))
+            .copied()
+            .collect();
+        Ok(diff)
+    }
+
+    /// Правила нормализации location, с которыми создан блок (см.
+    /// [`BlockOptions::location_normalization`])
+    ///
+    /// [`BlockOptions::location_normalization`]: struct.BlockOptions.html#method.location_normalization
+    pub fn normalization(&self) -> Normalization {
+        Normalization::from_flags(self.header.flags)
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.file_info.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.header.file_info.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &FileInfo> {
+        self.header.file_info.iter()
+    }
+
+    /// Возвращает файлы блока в порядке их следования в заголовке.
+    ///
+    /// В отличии от [`iter`] каждый элемент позволяет получить не только метаинформацию, но и
+    /// заголовок и содержимое файла (см. [`Entry`]).
+    ///
+    /// [`iter`]: #method.iter
+    /// [`Entry`]: struct.Entry.html
+    pub fn entries(&self) -> impl Iterator<Item = Entry<'_>> {
+        self.header.file_info.iter().map(move |info| Entry {
+            block: self,
+            info,
+            decoded: OnceCell::new(),
+        })
+    }
+
+    /// Проверяет, что контрольная сумма содержимого файла с порядковым номером `idx` совпадает
+    /// с записанной в его заголовке.
+    ///
+    /// Контрольная сумма файлов зашифрованного блока вычислена по зашифрованному содержимому,
+    /// поэтому такие блоки проверяются без ключа.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
+    pub fn verify_at(&self, idx: usize) -> Result<()> {
+        // Блоки, которые читаются с диска по мере обращения, проверяются без чтения содержимого
+        // файла в память целиком
+        self.verify_at_with(idx, VERIFY_CHUNK_SIZE, |_| {})
+    }
+
+    /// Аналогичен [`verify_at`], но читает содержимое файла частями не более `chunk_size` байт,
+    /// сообщая `on_chunk` размер каждой прочитанной части. Позволяет ограничивать скорость
+    /// чтения больших файлов, не дожидаясь, пока файл будет прочитан целиком.
+    ///
+    /// Содержимое сжатых блоков перед проверкой все равно распаковывается целиком.
+    ///
+    /// [`verify_at`]: #method.verify_at
+    pub(crate) fn verify_at_with(
+        &self,
+        idx: usize,
+        chunk_size: usize,
+        mut on_chunk: impl FnMut(u64),
+    ) -> Result<()> {
+        let info = self.file_info_at(idx)?;
+        let (header, content_offset) = self.read_file_header(info)?;
+        // Контрольная сумма вычислена по записанному содержимому, если оно не сжато или
+        // зашифровано, поэтому его можно хешировать по частям
+        let streaming = self.header.is_encrypted() || !self.header.is_compressed();
+        let mut context = md5::Context::new();
+        let mut payload = Vec::new();
+        let size = info.size as usize;
+        let mut position = 0;
+        while position < size {
+            let len = chunk_size.max(1).min(size - position);
+            let chunk = self.data.read_at(content_offset + position as u64, len)?;
+            if streaming {
+                context.consume(&chunk);
+            } else {
+                payload.extend_from_slice(&chunk);
+            }
+            position += len;
+            on_chunk(len as u64);
+        }
+        let hash = if streaming {
+            context.compute()
+        } else {
+            md5::compute(self.decode_content(&header, Cow::Owned(payload))?)
+        };
+        if hash != header.hash {
+            return Err(self.checksum_mismatch(info));
+        }
+        Ok(())
+    }
+
+    /// Последовательно проверяет все файлы блока
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(files = self.len()))
+    )]
+    pub fn verify_all(&self) -> Vec<EntryVerification> {
+        (0..self.len())
+            .map(|idx| EntryVerification {
+                id: self.header.file_info[idx].wide_id(),
+                result: self.verify_at(idx),
+            })
+            .collect()
+    }
+
+    /// Проверяет все файлы блока, используя не более `jobs` потоков.
+    ///
+    /// Результаты возвращаются в порядке следования файлов в блоке.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), fields(files = self.len()))
+    )]
+    pub fn verify_all_parallel(&self, jobs: usize) -> Vec<EntryVerification> {
+        let jobs = jobs.max(1).min(self.len());
+        if jobs <= 1 {
+            return self.verify_all();
+        }
+
+        let next_idx = AtomicUsize::new(0);
+        let mut results = thread::scope(|scope| {
+            let workers = (0..jobs)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = vec![];
+                        loop {
+                            let idx = next_idx.fetch_add(1, Ordering::Relaxed);
+                            if idx >= self.len() {
+                                break results;
+                            }
+                            results.push((idx, self.verify_at(idx)));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        results.sort_by_key(|(idx, _)| *idx);
+        results
+            .into_iter()
+            .map(|(idx, result)| EntryVerification {
+                id: self.header.file_info[idx].wide_id(),
+                result,
+            })
+            .collect()
+    }
+}
+
+/// Файл блока, возвращаемый [`Block::entries`].
+///
+/// Заголовок файла декодируется при первом обращении к [`header`], [`content`] или [`reader`] и
+/// кешируется.
+///
+/// [`Block::entries`]: struct.Block.html#method.entries
+/// [`header`]: #method.header
+/// [`content`]: #method.content
+/// [`reader`]: #method.reader
+pub struct Entry<'a> {
+    block: &'a Block,
+    info: &'a FileInfo,
+    /// Заголовок файла и смещение его содержимого
+    decoded: OnceCell<(FileHeader, u64)>,
+}
+
+impl<'a> Entry<'a> {
+    pub fn info(&self) -> &'a FileInfo {
+        self.info
+    }
+
+    pub fn header(&self) -> Result<&FileHeader> {
+        self.decoded().map(|(header, _)| header)
+    }
+
+    /// Возвращает содержимое файла. Содержимое читается из хранилища блока (а в сжатых блоках
+    /// распаковывается) при каждом вызове
+    pub fn content(&self) -> Result<Cow<'a, [u8]>> {
+        let (header, content_offset) = self.decoded()?;
+        let block = self.block;
+        let payload = block
+            .data
+            .read_at(*content_offset, self.info.size as usize)?;
+        block.checked_content(self.info, header, payload)
+    }
+
+    /// Возвращает вид записи (см. [`Block::entry_kind`])
+    ///
+    /// [`Block::entry_kind`]: struct.Block.html#method.entry_kind
+    pub fn kind(&self) -> Result<EntryKind> {
+        if !self.block.is_directory(self.header()?) {
+            return Ok(EntryKind::File);
+        }
+        self.block.entry_kind(self.header()?, &self.content()?)
+    }
+
+    /// Возвращает содержимое файла в виде `std::io::Read`
+    pub fn reader(&self) -> Result<impl Read + 'a> {
+        self.content().map(Cursor::new)
+    }
+
+    /// MD5 исходного содержимого файла. Контрольные суммы зашифрованного блока вычислены по
+    /// зашифрованному содержимому, поэтому у таких блоков содержимое читается и расшифровывается
+    fn content_digest(&self) -> Result<md5::Digest> {
+        if self.block.header.is_encrypted() {
+            Ok(md5::compute(self.content()?))
+        } else {
+            Ok(self.header()?.hash)
+        }
+    }
+
+    fn decoded(&self) -> Result<&(FileHeader, u64)> {
+        if let Some(decoded) = self.decoded.get() {
+            return Ok(decoded);
+        }
+        let decoded = self.block.read_file_header(self.info)?;
+        Ok(self.decoded.get_or_init(|| decoded))
+    }
+}
+
+/// Различия между двумя блоками (см. [`Block::diff`]). Идентификаторы файлов (полные,
+/// 128-битные, см. [`FileInfo::wide_id`]) перечислены по возрастанию.
+///
+/// [`Block::diff`]: struct.Block.html#method.diff
+/// [`FileInfo::wide_id`]: struct.FileInfo.html#method.wide_id
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BlockDiff {
+    /// Файлы, которые есть только во втором блоке
+    pub added: Vec<u128>,
+
+    /// Файлы, которые есть только в первом блоке
+    pub removed: Vec<u128>,
+
+    /// Файлы, содержимое которых отличается
+    pub changed_content: Vec<u128>,
+
+    /// Файлы, location которых отличается
+    pub changed_location: Vec<u128>,
+}
+
+impl BlockDiff {
+    /// Возвращает `true`, если блоки не отличаются
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed_content.is_empty()
+            && self.changed_location.is_empty()
+    }
+}
+
+/// Результат проверки целостности отдельного файла блока
+#[derive(Debug)]
+pub struct EntryVerification {
+    /// Полный (128-битный, см. [`FileInfo::wide_id`]) идентификатор проверенного файла
+    ///
+    /// [`FileInfo::wide_id`]: struct.FileInfo.html#method.wide_id
+    pub id: u128,
+
+    /// `Ok(())` если содержимое файла соответствует контрольной сумме
+    pub result: Result<()>,
+}
+
+/// Параметры открытия блока.
+///
+/// По аналогии с `std::fs::OpenOptions` параметры задаются цепочкой вызовов, после чего блок
+/// открывается методом [`open`] (или читается только его заголовок – [`open_header`]):
+///
+/// ```no_run
+/// # use blocky::block::{Advice, Block};
+/// let block = Block::options()
+///     .untrusted(true)
+///     .advice(Advice::Populate)
+///     .open("./test.block");
+/// ```
+///
+/// [`open`]: #method.open
+/// [`open_header`]: #method.open_header
+#[derive(Debug, Clone)]
+pub struct BlockOpenOptions {
+    mmap: bool,
+    untrusted: bool,
+    verify: bool,
+    verify_on_read: bool,
+    advice: Option<Advice>,
+    direct_io: bool,
+    pread: bool,
+    #[cfg(feature = "encryption")]
+    decryption_key: Option<EncryptionKey>,
+}
+
+impl Default for BlockOpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockOpenOptions {
+    pub fn new() -> Self {
+        Self {
+            mmap: true,
+            untrusted: false,
+            verify: false,
+            verify_on_read: false,
+            advice: None,
+            direct_io: false,
+            pread: false,
+            #[cfg(feature = "encryption")]
+            decryption_key: None,
+        }
+    }
+
+    /// Если `true` (по умолчанию), то блок отображается в память, иначе – читается в память
+    /// целиком. Чтение целиком не зависит от того, изменяется ли файл блока другими
+    /// процессами, пока блок открыт.
+    pub fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Ограничивает размер заголовка блока и длину location файлов так же, как
+    /// [`Block::open_untrusted`].
+    ///
+    /// [`Block::open_untrusted`]: struct.Block.html#method.open_untrusted
+    pub fn untrusted(&mut self, untrusted: bool) -> &mut Self {
+        self.untrusted = untrusted;
+        self
+    }
+
+    /// Проверяет блок целиком при открытии так же, как [`Block::open_verified`].
+    ///
+    /// [`Block::open_verified`]: struct.Block.html#method.open_verified
+    pub fn verify(&mut self, verify: bool) -> &mut Self {
+        self.verify = verify;
+        self
+    }
+
+    /// См. [`Block::verify_on_read`].
+    ///
+    /// [`Block::verify_on_read`]: struct.Block.html#method.verify_on_read
+    pub fn verify_on_read(&mut self, verify_on_read: bool) -> &mut Self {
+        self.verify_on_read = verify_on_read;
+        self
+    }
+
+    /// Порядок доступа к содержимому блока, о котором сообщается ядру после открытия (см.
+    /// [`Block::advise`]).
+    ///
+    /// [`Block::advise`]: struct.Block.html#method.advise
+    pub fn advice(&mut self, advice: Advice) -> &mut Self {
+        self.advice = Some(advice);
+        self
+    }
+
+    /// Если `true`, то блок читается в обход page cache (`O_DIRECT`): как и с [`pread`],
+    /// заголовки и содержимое файлов читаются с диска при обращении к ним, но выровненными по
+    /// размеру страницы чтениями через небольшой буфер, поэтому блок не читается в память
+    /// целиком. Предназначено для фоновой проверки большого количества блоков, которая иначе
+    /// вытесняла бы из page cache данные, нужные при раздаче файлов. Имеет приоритет над
+    /// [`pread`] и [`mmap`].
+    ///
+    /// Поддерживается только в Linux. На других платформах, а также на файловых системах без
+    /// поддержки `O_DIRECT` (например, tmpfs), блок открывается обычным образом.
+    ///
+    /// [`pread`]: #method.pread
+    /// [`mmap`]: #method.mmap
+    pub fn direct_io(&mut self, direct_io: bool) -> &mut Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Если `true`, то блок не отображается в память и не читается целиком: при открытии
+    /// читается только заголовок блока, а заголовки и содержимое файлов читаются с диска при
+    /// каждом обращении к ним (`pread`). В отличии от отображенного в память блока, ошибка
+    /// чтения (например, файл блока обрезан) возвращается как ошибка, а не сигналом `SIGBUS`.
+    /// Имеет приоритет над [`mmap`], но не над [`direct_io`].
+    ///
+    /// Поддерживается только в Unix. На других платформах блок читается в память целиком.
+    ///
+    /// [`mmap`]: #method.mmap
+    /// [`direct_io`]: #method.direct_io
+    pub fn pread(&mut self, pread: bool) -> &mut Self {
+        self.pread = pread;
+        self
+    }
+
+    /// См. [`Block::decryption_key`].
+    ///
+    /// [`Block::decryption_key`]: struct.Block.html#method.decryption_key
+    #[cfg(feature = "encryption")]
+    pub fn decryption_key(&mut self, key: EncryptionKey) -> &mut Self {
+        self.decryption_key = Some(key);
+        self
+    }
+
+    fn limits(&self) -> DecodeLimits {
+        if self.untrusted {
+            DecodeLimits::untrusted()
+        } else {
+            DecodeLimits::unlimited()
+        }
+    }
+
+    /// Открывает блок
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<Block> {
+        let path = path.as_ref();
+        let direct = match self.direct_io {
+            true => open_direct(path)?,
+            false => None,
+        };
+        let mut block = if let Some(data) = direct {
+            Block::from_data(data, self.limits())
+        } else if self.pread {
+            Block::from_data(open_pread(path)?, self.limits())
+        } else if self.mmap {
+            let f = File::open(path)?;
+            let mmap = unsafe { MmapOptions::new().map(&f)? };
+            let mut block = Block::from_data(Box::new(mmap), self.limits());
+            if let Ok(block) = block.as_mut() {
+                block.mapped_file = Some(f);
+            }
+            block
+        } else {
+            Block::from_data(Box::new(fs::read(path)?), self.limits())
+        }
+        .map_err(|e| e.with_path(path))?;
+
+        block.verify_on_read = self.verify_on_read;
+        #[cfg(feature = "encryption")]
+        {
+            block.decryption_key = self.decryption_key.clone();
+        }
+        if let Some(advice) = self.advice {
+            block.advise(advice)?;
+        }
+        if self.verify {
+            block.verify_completely().map_err(|e| e.with_path(path))?;
+        }
+        Ok(block)
+    }
+
+    /// Читает только заголовок блока, не отображая в память и не читая содержимое файлов.
+    ///
+    /// Параметры, относящиеся к содержимому блока, при этом не учитываются.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open_header(&self, path: impl AsRef<Path>) -> Result<BlockHeader> {
+        let path = path.as_ref();
+        #[cfg(unix)]
+        let source = File::open(path)?;
+        // Чтение диапазонов файла (RangeRead) реализовано только для Unix
+        #[cfg(not(unix))]
+        let source = fs::read(path)?;
+        let header =
+            BlockHeader::read_limited(&source, &self.limits()).map_err(|e| e.with_path(path))?;
+        if header.is_streamed() {
+            // Блок метаинформации блока, записанного потоком, находится в его конце
+            return Ok(self.open(path)?.header);
+        }
+        Ok(header)
+    }
+
+    /// Открывает блок для пакетного чтения файлов через io_uring (см. модуль [`uring`]).
+    /// Читается только заголовок блока, содержимое файлов читается по запросу.
+    ///
+    /// `queue_depth` – максимальное количество чтений, отправляемых в ядро одним системным
+    /// вызовом. Учитываются только параметры [`untrusted`] и [`verify_on_read`].
+    ///
+    /// [`uring`]: ../uring/index.html
+    /// [`untrusted`]: #method.untrusted
+    /// [`verify_on_read`]: #method.verify_on_read
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn open_uring(
+        &self,
+        path: impl AsRef<Path>,
+        queue_depth: u32,
+    ) -> Result<crate::uring::UringBlockReader> {
+        let path = path.as_ref();
+        let header = self.open_header(path)?;
+        crate::uring::UringBlockReader::new(
+            path,
+            header,
+            self.verify_on_read,
+            self.limits().max_location_len,
+            queue_depth,
+        )
+        .map_err(|e| e.with_path(path))
+    }
+}
+
+/// Расположение файлов в будущем блоке (см. [`BlockOptions::plan`])
+///
+/// [`BlockOptions::plan`]: struct.BlockOptions.html#method.plan
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockLayout {
+    /// Размер заголовка блока вместе с блоком метаинформации
+    pub header_size: u64,
+
+    /// Место, зарезервированное под дополнительные записи заголовка (см.
+    /// [`BlockOptions::reserve_entries`])
+    ///
+    /// [`BlockOptions::reserve_entries`]: struct.BlockOptions.html#method.reserve_entries
+    pub reserved_size: u64,
+
+    /// Файлы в порядке их записи в блок
+    pub entries: Vec<PlannedEntry>,
+
+    /// Суммарный размер отступов между заголовком и файлами и между файлами, включая
+    /// зарезервированное место
+    pub padding: u64,
+
+    /// Размер резервной копии заголовка (см. [`BlockOptions::header_trailer`])
+    ///
+    /// [`BlockOptions::header_trailer`]: struct.BlockOptions.html#method.header_trailer
+    pub trailer_size: u64,
+
+    /// Итоговый размер блока
+    pub block_size: u64,
+
+    /// `false`, если фактические размеры станут известны только при записи блока (сжатие,
+    /// дедупликация), и размеры в плане – верхняя оценка
+    pub exact: bool,
+}
+
+/// Файл в будущем блоке (см. [`BlockLayout`])
+///
+/// [`BlockLayout`]: struct.BlockLayout.html
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PlannedEntry {
+    /// Полный (128-битный, см. [`FileInfo::wide_id`]) идентификатор файла
+    ///
+    /// [`FileInfo::wide_id`]: struct.FileInfo.html#method.wide_id
+    pub id: u128,
+
+    /// Смещение заголовка файла от начала блока
+    pub offset: u64,
+
+    /// Размер содержимого файла в блоке
+    pub size: u64,
+
+    /// Нормализованный location файла
+    pub location: Vec<u8>,
+}
+
+/// Параметры создания блока.
+///
+/// По аналогии с `std::fs::OpenOptions` параметры задаются цепочкой вызовов, после чего блок
+/// создается методом [`create`]:
+///
+/// ```no_run
+/// # use blocky::block::BlockOptions;
+/// let block = BlockOptions::new().sync(true).create("./test.block", &[]);
+/// ```
+///
+/// [`create`]: #method.create
+#[derive(Debug, Clone, Default)]
+pub struct BlockOptions {
+    sync: bool,
+    packed: bool,
+    dedup: bool,
+    header_trailer: bool,
+    sparse: bool,
+    compress: bool,
+    compress_header: bool,
+    reserve_entries: u32,
+    manifest: bool,
+    header_locations: bool,
+    wide_ids: bool,
+    content_addressed: bool,
+    metadata: bool,
+    trust_precomputed: bool,
+    #[cfg(feature = "encryption")]
+    encryption_key: Option<EncryptionKey>,
+    normalization: Normalization,
+}
+
+impl BlockOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Если `true`, то перед возвратом из [`create`] содержимое блока и запись о нем в
+    /// родительской директории сбрасываются на диск (`fsync`). Только в этом случае созданный
+    /// блок гарантированно переживет отключение питания.
+    ///
+    /// [`create`]: #method.create
+    pub fn sync(&mut self, sync: bool) -> &mut Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Если `true`, то файлы записываются вплотную друг к другу без выравнивания по границе
+    /// 1 КиБ. Для блоков из большого количества маленьких файлов это заметно экономит место.
+    /// Использованная раскладка отмечается в заголовке блока флагом [`FLAG_PACKED`].
+    ///
+    /// [`FLAG_PACKED`]: constant.FLAG_PACKED.html
+    pub fn packed(&mut self, packed: bool) -> &mut Self {
+        self.packed = packed;
+        self
+    }
+
+    /// Если `true`, то файлы с одинаковым содержимым сохраняются в блоке однократно, а их
+    /// [`FileInfo`] ссылаются на одно и то же смещение.
+    ///
+    /// Заголовок [`FileHeader`] в этом случае также общий и содержит location первого из
+    /// файлов с таким содержимым. Пустые файлы и директории не дедуплицируются, чтобы location
+    /// каждого из них сохранился в блоке: иначе при выгрузке по location (например, маркеров
+    /// `.keep`) они были бы потеряны.
+    ///
+    /// [`FileInfo`]: struct.FileInfo.html
+    /// [`FileHeader`]: struct.FileHeader.html
+    pub fn dedup(&mut self, dedup: bool) -> &mut Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Если `true`, то в конец блока записывается резервная копия заголовка вместе с ее длиной и
+    /// контрольной суммой. При повреждении основного заголовка [`Block::open`] использует копию.
+    ///
+    /// [`Block::open`]: struct.Block.html#method.open
+    pub fn header_trailer(&mut self, header_trailer: bool) -> &mut Self {
+        self.header_trailer = header_trailer;
+        self
+    }
+
+    /// Если `true`, то отступы между файлами не занимают места на диске: место под блок заранее
+    /// не резервируется, а отступы после записи блока превращаются в «дыры» (`FALLOC_FL_PUNCH_HOLE`
+    /// на Linux). Если файловая система не поддерживает дыры, отступы заполняются нулями.
+    pub fn sparse(&mut self, sparse: bool) -> &mut Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Резервирует после заголовка место под `entries` дополнительных записей [`FileInfo`],
+    /// чтобы в будущем файлы можно было дописывать в блок, не сдвигая содержимое уже записанных
+    /// файлов. Свободное место в заголовке готового блока возвращает
+    /// [`Block::reserved_entries`].
+    ///
+    /// Не действует на блоки, записанные потоком ([`stream`]), так как их заголовок находится в
+    /// конце блока.
+    ///
+    /// [`FileInfo`]: struct.FileInfo.html
+    /// [`Block::reserved_entries`]: struct.Block.html#method.reserved_entries
+    /// [`stream`]: #method.stream
+    pub fn reserve_entries(&mut self, entries: u32) -> &mut Self {
+        self.reserve_entries = entries;
+        self
+    }
+
+    /// Если `true`, то после файлов в блок записывается JSON-манифест с идентификаторами,
+    /// location, размерами и контрольными суммами всех файлов блока (см. модуль [`manifest`]).
+    /// Манифест – обычный файл блока с идентификатором [`MANIFEST_ID`] и location
+    /// [`MANIFEST_LOCATION`], поэтому эти идентификатор и location нельзя использовать для
+    /// других файлов. Прочитать манифест позволяет [`Block::manifest`].
+    ///
+    /// Манифест сжимается и шифруется так же, как остальные файлы блока. Не поддерживается для
+    /// блоков, записанных потоком ([`stream`]).
+    ///
+    /// [`manifest`]: ../manifest/index.html
+    /// [`MANIFEST_ID`]: ../manifest/constant.MANIFEST_ID.html
+    /// [`MANIFEST_LOCATION`]: ../manifest/constant.MANIFEST_LOCATION.html
+    /// [`Block::manifest`]: struct.Block.html#method.manifest
+    /// [`stream`]: #method.stream
+    pub fn manifest(&mut self, manifest: bool) -> &mut Self {
+        self.manifest = manifest;
+        self
+    }
+
+    /// Если `true`, то после блока метаинформации в заголовок записываются копии заголовков
+    /// всех файлов (см. [`BlockHeader::file_headers`]), а блок отмечается флагом
+    /// [`FLAG_HEADER_LOCATIONS`]. Тогда location и контрольные суммы файлов читаются вместе с
+    /// заголовком блока, не затрагивая страницы с содержимым файлов, ценой увеличения заголовка
+    /// на длину всех location.
+    ///
+    /// [`BlockHeader::file_headers`]: struct.BlockHeader.html#method.file_headers
+    /// [`FLAG_HEADER_LOCATIONS`]: constant.FLAG_HEADER_LOCATIONS.html
+    pub fn header_locations(&mut self, header_locations: bool) -> &mut Self {
+        self.header_locations = header_locations;
+        self
+    }
+
+    /// Если `true`, то идентификаторы файлов записываются 128-битными (например, UUID, см.
+    /// [`create_wide`]), а блок отмечается флагом [`FLAG_WIDE_IDS`]. Записи блока
+    /// метаинформации при этом занимают 40 байт вместо 32. Версии библиотеки, не знающие об
+    /// этом флаге, такие блоки не открывают, а блоки без флага по-прежнему читаются как раньше.
+    ///
+    /// [`create_wide`]: #method.create_wide
+    /// [`FLAG_WIDE_IDS`]: constant.FLAG_WIDE_IDS.html
+    pub fn wide_ids(&mut self, wide_ids: bool) -> &mut Self {
+        self.wide_ids = wide_ids;
+        self
+    }
+
+    /// Если `true`, то блок становится хранилищем, адресуемым по содержимому: идентичность файла
+    /// определяется контрольной суммой его содержимого, а блок отмечается флагом
+    /// [`FLAG_CONTENT_ADDRESSED`].
+    ///
+    /// Файл, содержимое которого уже записано в блок, пропускается (в отличии от [`dedup`],
+    /// запись о нем не добавляется), а идентификатор каждого файла вычисляется по содержимому
+    /// (см. [`IdAssignment::ContentHash`]), так что `id` запросов не используется. Файлы
+    /// ищутся по контрольной сумме методом [`Block::file_by_content_hash`], а
+    /// [`create_delta`] с таким `base` пропускает все файлы, содержимое которых в нем уже есть.
+    ///
+    /// Контрольные суммы зашифрованного блока вычисляются по зашифрованному содержимому,
+    /// поэтому такой блок не может быть зашифрован.
+    ///
+    /// [`FLAG_CONTENT_ADDRESSED`]: constant.FLAG_CONTENT_ADDRESSED.html
+    /// [`dedup`]: #method.dedup
+    /// [`IdAssignment::ContentHash`]: enum.IdAssignment.html#variant.ContentHash
+    /// [`Block::file_by_content_hash`]: struct.Block.html#method.file_by_content_hash
+    /// [`create_delta`]: #method.create_delta
+    pub fn content_addressed(&mut self, content_addressed: bool) -> &mut Self {
+        self.content_addressed = content_addressed;
+        self
+    }
+
+    /// Если `true`, то в заголовок блока записываются время его создания и версия библиотеки
+    /// (см. [`BlockMetadata`]), а блок отмечается флагом [`FLAG_METADATA`]. Версии библиотеки,
+    /// не знающие об этом флаге, такие блоки не открывают.
+    ///
+    /// [`BlockMetadata`]: struct.BlockMetadata.html
+    /// [`FLAG_METADATA`]: constant.FLAG_METADATA.html
+    pub fn metadata(&mut self, metadata: bool) -> &mut Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Если `true`, то контрольные суммы содержимого, заданные в запросах (см.
+    /// [`AddFileRequest::content_hash`]), не сверяются с содержимым, а используются вместо его
+    /// хеширования. Это вдвое сокращает затраты CPU на создание больших блоков, но если
+    /// контрольная сумма неверна, блок будет содержать файл, не проходящий проверку.
+    ///
+    /// Сжатое и зашифрованное содержимое хешируется в любом случае, а размер содержимого
+    /// сверяется всегда.
+    ///
+    /// [`AddFileRequest::content_hash`]: struct.AddFileRequest.html#structfield.content_hash
+    pub fn trust_precomputed(&mut self, trust_precomputed: bool) -> &mut Self {
+        self.trust_precomputed = trust_precomputed;
+        self
+    }
+
+    /// Если `true`, то содержимое файлов сжимается zstd независимыми фрагментами по 64 КБ, так что
+    /// [`Block::read_range`] распаковывает только фрагменты, покрывающие запрошенный диапазон.
+    /// Использованное сжатие отмечается в заголовке блока флагом [`FLAG_COMPRESSED`].
+    ///
+    /// Файлы сжатого блока не могут быть найдены при восстановлении блока сканированием (см.
+    /// [`repair`]), так как их контрольная сумма вычисляется по исходному содержимому.
+    ///
+    /// [`Block::read_range`]: struct.Block.html#method.read_range
+    /// [`FLAG_COMPRESSED`]: constant.FLAG_COMPRESSED.html
+    ///
+    /// Если библиотека собрана без поддержки сжатия (feature `zstd`), создание блока завершится
+    /// ошибкой [`Error::UnsupportedFeature`].
+    ///
+    /// [`repair`]: ../repair/index.html
+    /// [`Error::UnsupportedFeature`]: ../errors/enum.Error.html#variant.UnsupportedFeature
+    pub fn compress(&mut self, compress: bool) -> &mut Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Если `true`, то блок метаинформации сжимается zstd, а блок отмечается флагом
+    /// [`FLAG_COMPRESSED_HEADER`]. Записи о файлах хорошо сжимаются (идентификаторы и смещения
+    /// соседних файлов близки), так что заголовок занимает меньше места на диске и быстрее
+    /// передается по сети, а при открытии блока записи распаковываются.
+    ///
+    /// Размер сжатых записей заранее неизвестен, поэтому в блоке, записанном не потоком
+    /// ([`stream`]), под них отводится место с запасом на худший случай и выигрыш достигается
+    /// только при чтении заголовка и в резервной копии заголовка ([`header_trailer`]). Такие
+    /// блоки не открываются [`LazyBlock`], так как их записи нельзя декодировать по одной.
+    ///
+    /// Если библиотека собрана без поддержки сжатия (feature `zstd`), создание блока завершится
+    /// ошибкой [`Error::UnsupportedFeature`].
+    ///
+    /// [`FLAG_COMPRESSED_HEADER`]: constant.FLAG_COMPRESSED_HEADER.html
+    /// [`stream`]: #method.stream
+    /// [`header_trailer`]: #method.header_trailer
+    /// [`LazyBlock`]: ../lazy/struct.LazyBlock.html
+    /// [`Error::UnsupportedFeature`]: ../errors/enum.Error.html#variant.UnsupportedFeature
+    pub fn compress_header(&mut self, compress_header: bool) -> &mut Self {
+        self.compress_header = compress_header;
+        self
+    }
+
+    /// Если задан ключ, то содержимое файлов шифруется им (см. модуль [`encryption`]), а блок
+    /// отмечается флагом [`FLAG_ENCRYPTED`]. Для чтения содержимого такого блока ключ нужно
+    /// передать в [`Block::decryption_key`].
+    ///
+    /// Заголовки файлов не шифруются, а их контрольные суммы вычисляются по зашифрованному
+    /// содержимому, так что целостность блока проверяется без ключа. Шифрование не
+    /// поддерживается при записи блока потоком ([`stream`]).
+    ///
+    /// [`encryption`]: ../encryption/index.html
+    /// [`FLAG_ENCRYPTED`]: constant.FLAG_ENCRYPTED.html
+    /// [`Block::decryption_key`]: struct.Block.html#method.decryption_key
+    /// [`stream`]: #method.stream
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(&mut self, key: Option<EncryptionKey>) -> &mut Self {
+        self.encryption_key = key;
+        self
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn key(&self) -> Option<&EncryptionKey> {
+        self.encryption_key.as_ref()
+    }
+
+    /// Правила нормализации location файлов (см. [`Normalization`]). Location нормализуется
+    /// перед записью в блок, а правила записываются в заголовок блока флагами
+    /// `FLAG_NORMALIZE_*`, так что [`Block::find_by_location`] применяет их к искомому location.
+    ///
+    /// [`Normalization`]: ../location/struct.Normalization.html
+    /// [`Block::find_by_location`]: struct.Block.html#method.find_by_location
+    pub fn location_normalization(&mut self, normalization: Normalization) -> &mut Self {
+        self.normalization = normalization;
+        self
+    }
+
+    pub(crate) fn normalization(&self) -> Normalization {
+        self.normalization
+    }
+
+    /// Флаги заголовка создаваемого блока
+    fn flags(&self) -> u32 {
+        let mut flags = self.normalization.flags();
+        if self.packed {
+            flags |= FLAG_PACKED;
+        }
+        if self.compress {
+            flags |= FLAG_COMPRESSED;
+        }
+        if self.compress_header {
+            flags |= FLAG_COMPRESSED_HEADER;
+        }
+        if self.is_encrypted() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        if self.header_locations {
+            flags |= FLAG_HEADER_LOCATIONS;
+        }
+        if self.wide_ids {
+            flags |= FLAG_WIDE_IDS;
+        }
+        if self.content_addressed {
+            flags |= FLAG_CONTENT_ADDRESSED;
+        }
+        if self.metadata {
+            flags |= FLAG_METADATA;
+        }
+        flags
+    }
+
+    /// Создает заголовок блока с флагами `flags` и метаданными, если они включены
+    fn new_header(&self, flags: u32, file_info: Vec<FileInfo>) -> BlockHeader {
+        let mut header = BlockHeader::new(flags, file_info);
+        header.metadata = Some(BlockMetadata::now()).filter(|_| self.metadata);
+        header
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.encryption_key.is_some();
+        #[cfg(not(feature = "encryption"))]
+        return false;
+    }
+
+    /// Выравнивание смещений файлов в создаваемом блоке
+    pub(crate) fn alignment(&self) -> u32 {
+        if self.packed {
+            1
+        } else {
+            BLOCK_PAGE_SIZE
+        }
+    }
+
+    /// Место в заголовке, занимаемое копиями заголовков файлов с location `locations` (см.
+    /// [`header_locations`])
+    ///
+    /// [`header_locations`]: #method.header_locations
+    pub(crate) fn header_locations_len<'l>(
+        &self,
+        locations: impl Iterator<Item = &'l [u8]>,
+    ) -> u64 {
+        if !self.header_locations {
+            return 0;
+        }
+        locations
+            .map(|location| u64::from(FILE_HEADER_FIXED_SIZE) + location.len() as u64)
+            .sum()
+    }
+
+    /// Оценка места, занимаемого в блоке из `files_count` файлов заголовком (вместе с
+    /// выравниванием первого файла и резервной копией заголовка)
+    pub(crate) fn estimated_overhead(&self, files_count: usize) -> u64 {
+        let files_count = files_count + self.manifest as usize;
+        let header = self.new_header(self.flags(), vec![]).encoded_len()
+            + FileInfo::table_len(self.flags(), files_count as u64);
+        let trailer = if self.header_trailer {
+            header + TRAILER_FIXED_SIZE as u64
+        } else {
+            0
+        };
+        let reserved = FileInfo::table_len(
+            self.flags(),
+            files_count as u64 + u64::from(self.reserve_entries),
+        ) - FileInfo::table_len(self.flags(), files_count as u64);
+        // Записи о файлах учитываются в estimated_entry_size, здесь – остальная часть манифеста
+        let manifest = if self.manifest {
+            self.estimated_entry_size(MANIFEST_LOCATION.len(), manifest::max_envelope_len())
+                + u64::from(self.alignment())
+        } else {
+            0
+        };
+        // Копии заголовков файлов учитываются в estimated_entry_size без выравнивания, поэтому
+        // заголовок может занять еще одну страницу
+        let header_locations = if self.header_locations {
+            u64::from(self.alignment())
+        } else {
+            0
+        };
+        round_up_to_u64(header + reserved, self.alignment()) + trailer + manifest + header_locations
+    }
+
+    /// Оценка места, занимаемого в блоке файлом размером `size` с location длиной
+    /// `location_len` байт вместе с выравниванием. Сжатие и дедупликация не учитываются, так
+    /// что фактический размер не превышает оценку.
+    pub(crate) fn estimated_entry_size(&self, location_len: usize, size: u64) -> u64 {
+        #[allow(unused_mut)]
+        let mut stored = size;
+        #[cfg(feature = "encryption")]
+        if self.is_encrypted() {
+            stored += encryption::OVERHEAD as u64;
+        }
+        let entry = u64::from(FILE_HEADER_FIXED_SIZE) + location_len as u64 + stored;
+        let manifest = if self.manifest {
+            manifest::max_entry_len(location_len)
+        } else {
+            0
+        };
+        let header_copy = if self.header_locations {
+            u64::from(FILE_HEADER_FIXED_SIZE) + location_len as u64
+        } else {
+            0
+        };
+        round_up_to_u64(entry, self.alignment()) + manifest + header_copy
+    }
+
+    /// Вычисляет расположение файлов `files` в блоке, не создавая его: размер заголовка,
+    /// смещение каждого файла, объем выравнивания и итоговый размер блока (см. [`BlockLayout`]).
+    ///
+    /// Размеры файлов берутся из метаданных ФС, а файлы проверяются так же, как в [`create`].
+    /// Сжатие и дедупликация не учитываются, поэтому для таких блоков результат – верхняя
+    /// оценка ([`BlockLayout::exact`] равен `false`).
+    ///
+    /// [`BlockLayout`]: struct.BlockLayout.html
+    /// [`BlockLayout::exact`]: struct.BlockLayout.html#structfield.exact
+    /// [`create`]: #method.create
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn plan(&self, files: &[AddFileRequest]) -> Result<BlockLayout> {
+        let ids = files
+            .iter()
+            .map(|file| u128::from(file.id))
+            .collect::<Vec<_>>();
+        self.plan_with_ids(files, &ids)
+    }
+
+    /// Аналог [`plan`] для блока с 128-битными идентификаторами (см. [`create_wide`])
+    ///
+    /// [`plan`]: #method.plan
+    /// [`create_wide`]: #method.create_wide
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn plan_wide(&self, files: &[(u128, AddFileRequest)]) -> Result<BlockLayout> {
+        let ids = files.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .map(|(_, file)| AddFileRequest {
+                id: file.id,
+                path: file.path,
+                location: file.location,
+                content_hash: file.content_hash,
+                size: file.size,
+            })
+            .collect::<Vec<_>>();
+        let mut options = self.clone();
+        options.wide_ids(true);
+        options.plan_with_ids(&files, &ids)
+    }
+
+    /// Вычисляет расположение файлов `files` с идентификаторами `ids` (в том же порядке)
+    #[cfg(not(target_arch = "wasm32"))]
+    fn plan_with_ids(&self, files: &[AddFileRequest], ids: &[u128]) -> Result<BlockLayout> {
+        if files.is_empty() {
+            return Err(Error::NoFilesInBlock);
+        }
+        validate_unique_ids(files.iter().zip(ids.iter().copied()), self)?;
+
+        let mut sources = Vec::with_capacity(files.len() + 1);
+        for (file, &id) in files.iter().zip(ids) {
+            let size = file.len().map_err(|_| {
+                let message = format!("File: {} not found", file.path.display());
+                io::Error::new(NotFound, message)
+            })?;
+            let location = file.entry_location(self.normalization)?;
+            sources.push((id, location.into_owned(), size));
+        }
+        if self.manifest {
+            // Контрольные суммы в манифесте имеют фиксированную длину, поэтому размер манифеста
+            // известен без чтения файлов
+            let records = sources
+                .iter()
+                .map(|(id, location, size)| ManifestEntry {
+                    id: *id,
+                    location: Some(location.clone()),
+                    size: *size,
+                    hash: md5::Digest([0; 16]),
+                })
+                .collect::<Vec<_>>();
+            let size = manifest::encode(&records).len() as u64;
+            sources.push((MANIFEST_ID.into(), MANIFEST_LOCATION.to_vec(), size));
+        }
+
+        let files_count = sources.len() as u64;
+        let header_size = self.new_header(self.flags(), vec![]).encoded_len()
+            + FileInfo::table_len(self.flags(), files_count)
+            + self.header_locations_len(sources.iter().map(|(_, location, _)| &location[..]));
+        let reserved =
+            FileInfo::table_len(self.flags(), files_count + u64::from(self.reserve_entries))
+                - FileInfo::table_len(self.flags(), files_count);
+        let alignment = self.alignment();
+        let mut offset = round_up_to_u64(header_size + reserved, alignment);
+        let mut end = header_size;
+        let mut padding = 0;
+        let mut entries = Vec::with_capacity(sources.len());
+        for (id, location, size) in sources {
+            #[allow(unused_mut)]
+            let mut size = size;
+            #[cfg(feature = "encryption")]
+            if self.is_encrypted() {
+                size += encryption::OVERHEAD as u64;
+            }
+            padding += offset - end;
+            end = offset + u64::from(FILE_HEADER_FIXED_SIZE) + location.len() as u64 + size;
+            if end > u64::from(u32::MAX) {
+                return Err(Error::FormatLimitExceeded(format!(
+                    "block with file {} exceeds 4 GiB",
+                    location::display(&location)
+                )));
+            }
+            entries.push(PlannedEntry {
+                id,
+                offset,
+                size,
+                location,
+            });
+            offset = round_up_to_u64(end, alignment);
+        }
+        let trailer_size = if self.header_trailer {
+            header_size + TRAILER_FIXED_SIZE as u64
+        } else {
+            0
+        };
+        Ok(BlockLayout {
+            header_size,
+            reserved_size: reserved,
+            entries,
+            padding,
+            trailer_size,
+            block_size: end + trailer_size,
+            exact: !self.compress && !self.dedup,
+        })
+    }
+
+    /// Создает блок из файлов на локальной ФС и открывает его.
+    ///
+    /// Блок пишется во временный файл рядом с `block_path` и переименовывается только после
+    /// успешной записи, поэтому по целевому пути никогда не бывает недописанного блока.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn create(&self, block_path: impl AsRef<Path>, files: &[AddFileRequest]) -> Result<Block> {
+        let ids = files
+            .iter()
+            .map(|file| u128::from(file.id))
+            .collect::<Vec<_>>();
+        self.create_with_ids(block_path.as_ref(), files, &ids)
+    }
+
+    /// Создает блок из файлов, содержимое которых читается из `files`, и открывает его.
+    ///
+    /// Позволяет создать блок из данных, которых нет на локальной ФС, без записи во временные
+    /// файлы. Каждый источник читается однократно в порядке следования. Location нормализуются,
+    /// а идентификаторы и location проверяются так же, как в [`create`]. Если прочитанное
+    /// содержимое не совпадает по размеру с `known_size`, блок не создается.
+    ///
+    /// [`create`]: #method.create
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn create_from_readers(
+        &self,
+        block_path: impl AsRef<Path>,
+        files: Vec<AddReaderRequest>,
+    ) -> Result<Block> {
+        let block_path = block_path.as_ref();
+        if files.is_empty() {
+            return Err(Error::NoFilesInBlock);
+        }
+        let locations = files
+            .iter()
+            .map(|file| self.normalization.apply(file.location))
+            .collect::<Vec<_>>();
+        validate_unique_entries(
+            files
+                .iter()
+                .zip(locations.iter())
+                .map(|(file, location)| Ok((u128::from(file.id), Cow::Borrowed(&location[..])))),
+            self,
+        )?;
+        if block_path.exists() {
+            return Err(Error::BlockFileAlreadyExists(block_path.to_path_buf()));
+        }
+
+        self.write_atomically(block_path, |tmp_file| {
+            let locations_iter = locations.iter().map(|location| location.as_ref());
+            let mut writer = BlockWriter::with_target(self, tmp_file, locations_iter)?;
+            writer.preallocate(
+                locations
+                    .iter()
+                    .zip(files.iter())
+                    .map(|(location, file)| (location.len(), file.known_size)),
+            )?;
+            for (file, location) in files.into_iter().zip(locations.iter()) {
+                let (_, size) = writer.add(file.id, location, file.reader)?;
+                match file.known_size {
+                    Some(known_size) if known_size != size => {
+                        let message = format!(
+                            "File: {} has {} bytes, expected {}",
+                            location::display(location),
+                            size,
+                            known_size
+                        );
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+                    }
+                    _ => {}
+                }
+            }
+            writer.finish().map(drop)
+        })?;
+        Block::open(block_path)
+    }
+
+    /// Создает блок из файлов, перебираемых итератором `files`, и открывает его.
+    ///
+    /// В отличии от [`create`] файлы не нужно заранее собирать в памяти: заголовок блока
+    /// строится по мере записи файлов, поэтому блок можно создать, например, из потока строк
+    /// манифеста с миллионами файлов. Блок записывается в формате [`stream`], так что его
+    /// метаинформация располагается в конце блока. Как и в [`create`], блок пишется во временный
+    /// файл и переименовывается только после успешной записи.
+    ///
+    /// [`create`]: #method.create
+    /// [`stream`]: #method.stream
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn create_from_iter<'a>(
+        &self,
+        block_path: impl AsRef<Path>,
+        files: impl IntoIterator<Item = AddFileRequest<'a>>,
+    ) -> Result<Block> {
+        let block_path = block_path.as_ref();
+        if block_path.exists() {
+            return Err(Error::BlockFileAlreadyExists(block_path.to_path_buf()));
+        }
+        self.write_atomically(block_path, |tmp_file| {
+            let mut target = BufWriter::new(tmp_file);
+            self.stream(&mut target, files)?;
+            let file = target.into_inner().map_err(|e| e.into_error())?;
+            if self.sync {
+                file.sync_all()?;
+            }
+            Ok(())
+        })?;
+        Block::open(block_path)
+    }
+
+    /// Создает блок со 128-битными идентификаторами файлов (см. [`wide_ids`]) и открывает его.
+    ///
+    /// Идентификатор каждого файла (например, UUID в виде `Uuid::as_u128`) задается первым
+    /// элементом пары, поле `id` запроса не используется. В остальном аналогичен [`create`].
+    ///
+    /// [`wide_ids`]: #method.wide_ids
+    /// [`create`]: #method.create
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn create_wide(
+        &self,
+        block_path: impl AsRef<Path>,
+        files: &[(u128, AddFileRequest)],
+    ) -> Result<Block> {
+        let ids = files.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .map(|(_, file)| AddFileRequest {
+                id: file.id,
+                path: file.path,
+                location: file.location,
+                content_hash: file.content_hash,
+                size: file.size,
+            })
+            .collect::<Vec<_>>();
+        let mut options = self.clone();
+        options.wide_ids(true);
+        options.create_with_ids(block_path.as_ref(), &files, &ids)
+    }
+
+    /// Создает блок из файлов `files` с идентификаторами `ids` (в том же порядке)
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "info",
+            skip_all,
+            fields(path = %block_path.display(), files = files.len(), bytes)
+        )
+    )]
+    fn create_with_ids(
+        &self,
+        block_path: &Path,
+        files: &[AddFileRequest],
+        ids: &[u128],
+    ) -> Result<Block> {
+        if files.is_empty() {
+            return Err(Error::NoFilesInBlock);
+        }
+        let file_names = files.iter().map(|f| f.path).collect::<Vec<_>>();
+        let first_missing_file = file_names.iter().find(|f| !f.is_file() && !f.is_dir());
+        if let Some(file) = first_missing_file {
+            let message = format!("File: {} not found", file.display());
+            return Err(io::Error::new(NotFound, message).into());
+        }
+
+        validate_unique_ids(files.iter().zip(ids.iter().copied()), self)?;
+
+        if block_path.exists() {
+            return Err(Error::BlockFileAlreadyExists(block_path.to_path_buf()));
+        }
+
+        let locations = files
+            .iter()
+            .map(|file| file.entry_location(self.normalization))
+            .collect::<Result<Vec<_>>>()?;
+        self.write_atomically(block_path, |tmp_file| {
+            let locations_iter = locations.iter().map(|location| location.as_ref());
+            let mut writer = BlockWriter::with_target(self, tmp_file, locations_iter)?;
+            writer.preallocate(
+                files
+                    .iter()
+                    .map(|file| (file.location.as_os_str().len(), file.len().ok())),
+            )?;
+            for ((file, location), id) in files.iter().zip(locations.iter()).zip(ids) {
+                if file.is_directory() {
+                    writer.add_flags(FLAG_DIRECTORIES);
+                }
+                let location_hash = md5::compute(location);
+                let trusted_hash = file.trusted_hash(self);
+                let (hash, size) =
+                    writer.add_entry(*id, location, location_hash, file.open()?, trusted_hash)?;
+                file.check_precomputed(hash, size)?;
+            }
+            writer.finish().map(drop)
+        })?;
+
+        let block = Block::open(block_path)?;
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("bytes", block.data_len);
+        Ok(block)
+    }
+
+    /// Создает блок из тех файлов `files`, которые отсутствуют в блоке `base` или изменились
+    /// относительно него. Файлы сопоставляются по location, изменение определяется по
+    /// контрольной сумме содержимого.
+    ///
+    /// Location файлов нормализуется по правилам `base` (см. [`Block::normalization`]).
+    ///
+    /// Позволяет распространять большие наборы файлов инкрементально: получатель, у которого уже
+    /// есть `base`, загружает только разностный блок. Если изменившихся файлов нет, возвращается
+    /// [`Error::NoFilesInBlock`]. Зашифрованному `base` должен быть задан ключ (см.
+    /// [`Block::decryption_key`]).
+    ///
+    /// Если `base` адресуется по содержимому (см. [`content_addressed`]), то location не
+    /// учитывается: пропускаются все файлы, содержимое которых в `base` уже есть.
+    ///
+    /// [`Error::NoFilesInBlock`]: ../errors/enum.Error.html#variant.NoFilesInBlock
+    /// [`Block::decryption_key`]: struct.Block.html#method.decryption_key
+    /// [`Block::normalization`]: struct.Block.html#method.normalization
+    /// [`content_addressed`]: #method.content_addressed
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn create_delta(
+        &self,
+        base: &Block,
+        block_path: impl AsRef<Path>,
+        files: &[AddFileRequest],
+    ) -> Result<Block> {
+        let by_content = base.header.is_content_addressed();
+        let key = |location_hash| Some(location_hash).filter(|_| !by_content);
+        let base_hashes = base
+            .entries()
+            .map(|entry| Ok((key(entry.info().location_hash), entry.content_digest()?)))
+            .collect::<Result<HashSet<_>>>()?;
+
+        let mut changed = vec![];
+        for file in files {
+            let location = file.entry_location(base.normalization())?;
+            let location_hash = md5::compute(location);
+            let hash = match file.trusted_hash(self) {
+                Some(hash) => hash,
+                None => content_hash(file)?.0,
+            };
+            if !base_hashes.contains(&(key(location_hash), hash)) {
+                changed.push(AddFileRequest {
+                    id: file.id,
+                    path: file.path,
+                    location: file.location,
+                    content_hash: file.content_hash,
+                    size: file.size,
+                });
+            }
+        }
+        self.create(block_path, &changed)
+    }
+
+    /// Создает блок `block_path` из всех файлов блока `source` с параметрами `self`, сохраняя
+    /// идентификаторы и location файлов.
+    ///
+    /// Позволяет изменить представление уже созданного блока, не пересоздавая его из исходных
+    /// файлов: например, зашифровать его (см. [`encryption_key`]) или, наоборот, расшифровать.
+    /// Для чтения зашифрованного `source` ему должен быть задан ключ (см.
+    /// [`Block::decryption_key`]). Location файлов уже нормализованы, поэтому правила
+    /// нормализации берутся из `source`, а не из `self`.
+    ///
+    /// [`encryption_key`]: #method.encryption_key
+    /// [`Block::decryption_key`]: struct.Block.html#method.decryption_key
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn rewrite(&self, source: &Block, block_path: impl AsRef<Path>) -> Result<Block> {
+        self.copy_entries(source, source.entries().collect(), block_path.as_ref())
+    }
+
+    /// Создает блок `block_path` из файлов `entries` блока `source` (см. [`rewrite`]).
+    ///
+    /// Манифест `source` описывает его файлы, поэтому не копируется, а при
+    /// [`manifest`] записывается заново.
+    ///
+    /// [`rewrite`]: #method.rewrite
+    /// [`manifest`]: #method.manifest
+    #[cfg(not(target_arch = "wasm32"))]
+    fn copy_entries(
+        &self,
+        source: &Block,
+        mut entries: Vec<Entry>,
+        block_path: &Path,
+    ) -> Result<Block> {
+        let mut options = self.clone();
+        options.location_normalization(source.normalization());
+        let options = &options;
+        entries
+            .retain(|entry| !manifest::is_manifest(entry.info().id, &entry.info().location_hash));
+        if entries.is_empty() {
+            return Err(Error::NoFilesInBlock);
+        }
+        if options.manifest
+            && entries
+                .iter()
+                .any(|e| e.info().wide_id() == MANIFEST_ID.into())
+        {
+            return Err(Error::DuplicateId(MANIFEST_ID.into()));
+        }
+        if block_path.exists() {
+            return Err(Error::BlockFileAlreadyExists(block_path.to_path_buf()));
+        }
+
+        let locations = entries
+            .iter()
+            .map(|entry| entry.header().map(|header| &header.location[..]))
+            .collect::<Result<Vec<_>>>()?;
+        options.write_atomically(block_path, |tmp_file| {
+            let mut writer =
+                BlockWriter::with_target(options, tmp_file, locations.iter().copied())?;
+            writer.add_flags(source.header().flags() & FLAG_DIRECTORIES);
+            for (entry, location) in entries.iter().zip(locations.iter()) {
+                // У файлов с общим содержимым (см. `dedup`) заголовок содержит location первого
+                // из них, поэтому хеш location берется из метаинформации
+                let info = entry.info();
+                let content = entry.content()?;
+                let id = info.wide_id();
+                writer.add_entry(id, location, info.location_hash, &content[..], None)?;
+            }
+            writer.finish().map(drop)
+        })?;
+        Block::open(block_path)
+    }
+
+    /// Записывает блок в поток, не поддерживающий позиционирование (например, stdout или сокет),
+    /// и возвращает количество записанных байт.
+    ///
+    /// Смещения файлов становятся известны только по мере записи, поэтому в начало блока пишется
+    /// лишь заголовок фиксированного размера с флагом [`FLAG_STREAMED`], а блок метаинформации
+    /// записывается в конец блока в формате резервной копии заголовка (см. [`header_trailer`]).
+    /// Длина блока метаинформации хранится в последних байтах блока, так что [`Block::open`]
+    /// находит его без сканирования.
+    ///
+    /// Контрольная сумма файла пишется перед его содержимым, а вернуться к уже записанному
+    /// заголовку в потоке нельзя. Поэтому файлы размером до [`STREAM_BUFFER_LIMIT`] байт
+    /// читаются в память однократно, а файлы большего размера – дважды: для вычисления
+    /// контрольной суммы и для записи. Если файл изменился между чтениями, возвращается ошибка.
+    ///
+    /// Файлы `files` перебираются однократно, а метаинформация блока накапливается по мере
+    /// записи, так что их не нужно заранее собирать в памяти. Поэтому и уникальность
+    /// идентификаторов и location проверяется по мере записи: при ошибке в `target` остается
+    /// недописанный блок.
+    ///
+    /// [`STREAM_BUFFER_LIMIT`]: constant.STREAM_BUFFER_LIMIT.html
+    ///
+    /// [`FLAG_STREAMED`]: constant.FLAG_STREAMED.html
+    /// [`header_trailer`]: #method.header_trailer
+    /// [`Block::open`]: struct.Block.html#method.open
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn stream<'a, F: Borrow<AddFileRequest<'a>>>(
+        &self,
+        mut target: impl Write,
+        files: impl IntoIterator<Item = F>,
+    ) -> Result<u64> {
+        let mut files = files.into_iter().peekable();
+        if files.peek().is_none() {
+            return Err(Error::NoFilesInBlock);
+        }
+        if self.is_encrypted() {
+            return Err(Error::UnsupportedFeature(
+                "encryption of a streamed block".into(),
+            ));
+        }
+        if self.manifest {
+            return Err(Error::UnsupportedFeature(
+                "manifest of a streamed block".into(),
+            ));
+        }
+        if self.content_addressed {
+            return Err(Error::UnsupportedFeature(
+                "content-addressed streamed block".into(),
+            ));
+        }
+
+        // Флаги основного заголовка нужны только для того, чтобы найти блок метаинформации, так
+        // что флаги, зависящие от файлов, записываются только в него
+        let mut flags = FLAG_STREAMED | self.flags();
+        let alignment = self.alignment();
+        let prefix = self.new_header(flags, vec![]);
+        let mut position = prefix.write_to(&mut target)?;
+        let mut unique = UniqueEntries::new(self);
+        let mut file_infos = Vec::with_capacity(files.size_hint().0);
+        let mut file_headers = vec![];
+        let mut stored_content = HashMap::new();
+        for file in files {
+            let file = file.borrow();
+            let location = file.entry_location(self.normalization)?;
+            let location = location.as_ref();
+            unique.check(u128::from(file.id), location)?;
+            if file.is_directory() {
+                flags |= FLAG_DIRECTORIES;
+            }
+            // Если контрольной сумме из запроса можно доверять, файл читается однократно
+            let trusted_hash = file.trusted_hash(self);
+            let buffered = if trusted_hash.is_none() && file.len()? <= STREAM_BUFFER_LIMIT {
+                let mut content = vec![];
+                file.open()?.read_to_end(&mut content)?;
+                Some(content)
+            } else {
+                None
+            };
+            let (hash, file_length) = match (&buffered, trusted_hash) {
+                (Some(content), _) => (md5::compute(content), content.len() as u64),
+                (None, Some(hash)) => (hash, file.size.map_or_else(|| file.len(), Ok)?),
+                (None, None) => content_hash(file)?,
+            };
+            file.check_precomputed(hash, file_length)?;
+
+            if self.dedup && file_length > 0 && !file.is_directory() {
+                if let Some(&(offset, size)) = stored_content.get(&(hash, file_length)) {
+                    file_infos.push(FileInfo::new_at_offset(file.id, location, offset, size));
+                    if self.header_locations {
+                        file_headers.push(FileHeader {
+                            hash,
+                            location: location.to_vec(),
+                        });
+                    }
+                    continue;
+                }
+            }
+
+            let offset = u32::try_from(position)
+                .ok()
+                .and_then(|position| position.checked_next_multiple_of(alignment))
+                .ok_or_else(|| Error::FormatLimitExceeded("block is larger than 4 GiB".into()))?;
+            io::copy(
+                &mut io::repeat(0).take(u64::from(offset) - position),
+                &mut target,
+            )?;
+            let file_header = FileHeader {
+                hash,
+                location: location.to_vec(),
+            };
+            let header_length = file_header.write_to(&mut target)?;
+
+            let written = match &buffered {
+                Some(content) => {
+                    write_content(&mut &content[..], &mut target, self, location, Some(hash))?
+                }
+                None => {
+                    write_content(&mut file.open()?, &mut target, self, location, trusted_hash)?
+                }
+            };
+            let stored = written.stored;
+            if written.size != file_length || written.content_hash != hash {
+                let message = format!("File: {} changed while streaming", file.path.display());
+                return Err(io::Error::other(message).into());
+            }
+            let size = u32::try_from(stored).map_err(|_| {
+                Error::FormatLimitExceeded(format!(
+                    "file {} is larger than 4 GiB",
+                    location::display(location)
+                ))
+            })?;
+
+            stored_content.insert((hash, file_length), (offset, size));
+            file_infos.push(FileInfo::new_at_offset(file.id, location, offset, size));
+            if self.header_locations {
+                file_headers.push(file_header);
+            }
+            position = u64::from(offset) + header_length + stored;
+        }
+
+        let mut header = BlockHeader::new(flags, file_infos);
+        header.file_headers = file_headers;
+        header.metadata = prefix.metadata;
+        let trailer = header.encode_trailer()?;
+        target.write_all(&trailer)?;
+        target.flush()?;
+        Ok(position + trailer.len() as u64)
+    }
+
+    /// Пишет блок во временный файл при помощи `write` и публикует его под именем
+    /// `block_path`. В случае ошибки временный файл удаляется.
+    ///
+    /// Временный файл создается с уникальным именем, поэтому одновременно создаваемые блоки не
+    /// пишут в один и тот же файл. Блок публикуется жесткой ссылкой, которая в отличии от
+    /// переименования не заменяет существующий файл: если блок `block_path` появился во время
+    /// записи, возвращается [`Error::BlockFileAlreadyExists`].
+    ///
+    /// [`Error::BlockFileAlreadyExists`]: ../errors/enum.Error.html#variant.BlockFileAlreadyExists
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn write_atomically(
+        &self,
+        block_path: &Path,
+        write: impl FnOnce(File) -> Result<()>,
+    ) -> Result<()> {
+        let (tmp_path, tmp_file) = create_tmp_file(block_path)?;
+        let result = write(tmp_file).and_then(|_| match fs::hard_link(&tmp_path, block_path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(Error::BlockFileAlreadyExists(block_path.to_path_buf()))
+            }
+            Err(e) => Err(e.into()),
+        });
+        let _ = fs::remove_file(&tmp_path);
+        result?;
+        // Блок уже опубликован, так что ошибка сброса директории не означает, что блока нет
+        if let Err(_e) = self.sync_parent_dir(block_path) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %_e, "unable to sync block directory");
+        }
+        Ok(())
+    }
+
+    /// Сбрасывает на диск запись о созданном блоке в родительской директории
+    #[cfg(not(target_arch = "wasm32"))]
+    fn sync_parent_dir(&self, block_path: &Path) -> Result<()> {
+        if self.sync {
+            let parent = match block_path.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            };
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Назначение, в которое [`BlockWriter`] записывает блок: файл или буфер в памяти
+///
+/// [`BlockWriter`]: struct.BlockWriter.html
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) trait BlockTarget: Write + Seek {
+    /// Устанавливает размер блока
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Резервирует место под блок размером `len` байт (см. `preallocate`)
+    fn preallocate(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Освобождает место, занимаемое диапазоном блока (см. `punch_hole`)
+    fn punch_hole(&mut self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Сбрасывает записанный блок на диск
+    fn sync_all(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl BlockTarget for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        preallocate(self, len)
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        punch_hole(self, offset, len)
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+/// Буфер в памяти. Промежутки между файлами при записи заполняются нулями, поэтому
+/// освобождать их не нужно.
+#[cfg(not(target_arch = "wasm32"))]
+impl BlockTarget for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        self.get_mut().resize(len, 0);
+        Ok(())
+    }
+}
+
+/// Последовательно записывает файлы в новый блок.
+///
+/// Количество файлов должно быть известно заранее, так как от него зависит размер заголовка,
+/// после которого располагается содержимое файлов.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) struct BlockWriter<'a, T: BlockTarget = File> {
+    options: &'a BlockOptions,
+    block_file: T,
+    file_infos: Vec<FileInfo>,
+    alignment: u32,
+    next_file_offset: u32,
+    block_end: u32,
+    /// Смещение, размер и контрольная сумма из заголовка записанного содержимого по его
+    /// контрольной сумме и исходному размеру
+    stored_content: HashMap<(md5::Digest, u64), (u32, u32, md5::Digest)>,
+    /// Отступы между файлами (начало, конец)
+    gaps: Vec<(u32, u32)>,
+    /// Описания записанных файлов для манифеста (см. `BlockOptions::manifest`)
+    manifest: Vec<ManifestEntry>,
+    /// Флаги заголовка, зависящие от записанных файлов (например, `FLAG_DIRECTORIES`)
+    extra_flags: u32,
+    /// Копии заголовков файлов (см. `BlockOptions::header_locations`)
+    file_headers: Vec<FileHeader>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<'a, T: BlockTarget> BlockWriter<'a, T> {
+    /// Создает в `block_file` блок для файлов с location `locations`. Количество файлов и их
+    /// location должны быть известны заранее, так как от них зависит размер заголовка.
+    pub(crate) fn with_target<'l>(
+        options: &'a BlockOptions,
+        block_file: T,
+        locations: impl ExactSizeIterator<Item = &'l [u8]>,
+    ) -> Result<Self> {
+        if options.content_addressed && options.is_encrypted() {
+            return Err(Error::UnsupportedFeature(
+                "encryption of a content-addressed block".into(),
+            ));
+        }
+        let files_count = locations.len();
+        let manifest_location = Some(MANIFEST_LOCATION).filter(|_| options.manifest);
+        let entries_count =
+            files_count + options.reserve_entries as usize + options.manifest as usize;
+        let header_size = options.new_header(options.flags(), vec![]).encoded_len()
+            + FileInfo::table_len(options.flags(), entries_count as u64)
+            + options.header_locations_len(locations.chain(manifest_location));
+        let header_size = u32::try_from(header_size)
+            .map_err(|_| Error::FormatLimitExceeded("too many files in block".into()))?;
+        let alignment = options.alignment();
+        let first_file_offset = header_size
+            .checked_next_multiple_of(alignment)
+            .ok_or_else(|| Error::FormatLimitExceeded("too many files in block".into()))?;
+        Ok(Self {
+            options,
+            block_file,
+            file_infos: vec![],
+            alignment,
+            next_file_offset: first_file_offset,
+            block_end: first_file_offset,
+            stored_content: HashMap::new(),
+            gaps: vec![],
+            manifest: vec![],
+            extra_flags: 0,
+            file_headers: vec![],
+        })
+    }
+
+    /// Резервирует место под блок, если размеры всех файлов известны заранее. Файлы задаются
+    /// длиной location и размером содержимого.
+    fn preallocate(&mut self, files: impl IntoIterator<Item = (usize, Option<u64>)>) -> Result<()> {
+        // Размер сжатого или зашифрованного блока заранее неизвестен
+        if self.options.sparse || self.options.compress || self.options.is_encrypted() {
+            return Ok(());
+        }
+        if let Some(size) = expected_block_size(self.next_file_offset, self.alignment, files) {
+            self.block_file.preallocate(u64::from(size))?;
+        }
+        Ok(())
+    }
+
+    /// Добавляет флаги `flags` в заголовок блока. Используется, когда особенность формата
+    /// зависит от записываемых файлов, например для записей директорий ([`FLAG_DIRECTORIES`])
+    ///
+    /// [`FLAG_DIRECTORIES`]: constant.FLAG_DIRECTORIES.html
+    pub(crate) fn add_flags(&mut self, flags: u32) {
+        self.extra_flags |= flags;
+    }
+
+    /// Добавляет в блок файл, содержимое которого читается из `reader`. Возвращает контрольную
+    /// сумму и размер прочитанного содержимого.
+    pub(crate) fn add(
+        &mut self,
+        id: u64,
+        location: &[u8],
+        reader: impl Read,
+    ) -> Result<(md5::Digest, u64)> {
+        self.add_entry(
+            u128::from(id),
+            location,
+            md5::compute(location),
+            reader,
+            None,
+        )
+    }
+
+    /// Добавляет в блок файл с заданным хешем location. Используется при переносе файлов из
+    /// другого блока, где хеш location может не совпадать с location в заголовке файла.
+    ///
+    /// Если задана `trusted_hash`, то содержимое не хешируется (см. `write_content`).
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip(self, location_hash, reader, trusted_hash),
+            fields(offset, bytes)
+        )
+    )]
+    pub(crate) fn add_entry(
+        &mut self,
+        wide_id: u128,
+        location: &[u8],
+        location_hash: md5::Digest,
+        mut reader: impl Read,
+        trusted_hash: Option<md5::Digest>,
+    ) -> Result<(md5::Digest, u64)> {
+        let (id, id_high) = (wide_id as u64, (wide_id >> 64) as u64);
+        if id_high != 0 && !self.options.wide_ids {
+            return Err(Error::FormatLimitExceeded(format!(
+                "file id {} doesn't fit in 64 bits",
+                wide_id
+            )));
+        }
+        let mut writer = BufWriter::new(&mut self.block_file);
+        let offset = self.next_file_offset;
+
+        // Контрольная сумма известна только после копирования файла, поэтому сначала пишем
+        // заголовок-заглушку, а после копирования перезаписываем его
+        let mut file_header = FileHeader {
+            hash: md5::Digest([0; 16]),
+            location: location.to_vec(),
+        };
+        writer.seek(SeekFrom::Start(u64::from(offset)))?;
+        let header_length = file_header.write_to(&mut writer)?;
+
+        let written = write_content(
+            &mut reader,
+            &mut writer,
+            self.options,
+            location,
+            trusted_hash,
+        )?;
+        file_header.hash = written.header_hash;
+        let size = u32::try_from(written.stored).map_err(|_| {
+            Error::FormatLimitExceeded(format!(
+                "file {} is larger than 4 GiB",
+                location::display(location)
+            ))
+        })?;
+        // Смещения файлов 32-битные, поэтому и конец файла, и смещение следующего за ним файла
+        // должны помещаться в 4 ГиБ
+        let alignment = self.alignment;
+        let next_offset = offset
+            .checked_add(header_length as u32)
+            .and_then(|end| end.checked_add(size))
+            .and_then(|end| end.checked_next_multiple_of(alignment))
+            .ok_or_else(|| {
+                Error::FormatLimitExceeded(format!(
+                    "block with file {} exceeds 4 GiB",
+                    location::display(location)
+                ))
+            })?;
+
+        #[cfg(feature = "tracing")]
+        tracing::Span::current()
+            .record("offset", offset)
+            .record("bytes", size);
+
+        let directory = self.extra_flags & FLAG_DIRECTORIES != 0 && location.ends_with(b"/");
+        let key = (written.content_hash, written.size);
+        // В блоке, адресуемом по содержимому, файл с уже записанным содержимым – тот же файл,
+        // поэтому он пропускается, а только что записанную копию перезапишет следующий файл
+        let content_addressed = self.options.content_addressed
+            && !directory
+            && !manifest::is_manifest(id, &location_hash);
+        let (id, id_high) = if content_addressed {
+            if self.stored_content.contains_key(&key) {
+                #[cfg(feature = "tracing")]
+                tracing::trace!("content already stored, skipping");
+                return Ok((written.content_hash, written.size));
+            }
+            (hash_id(&written.content_hash), 0)
+        } else {
+            (id, id_high)
+        };
+
+        if self.options.manifest {
+            self.manifest.push(ManifestEntry {
+                id: u128::from(id_high) << 64 | u128::from(id),
+                location: Some(location.to_vec()).filter(|l| md5::compute(l) == location_hash),
+                size: written.size,
+                hash: written.content_hash,
+            });
+        }
+
+        // Если такое содержимое уже записано в блок, то ссылаемся на него, а только что
+        // записанную копию перезапишет следующий файл. Пустые файлы и директории не
+        // дедуплицируются: их заголовок – единственное место, где хранится их location
+        let dedup = self.options.dedup && written.size > 0 && !directory;
+        if dedup {
+            if let Some(&(offset, size, hash)) = self.stored_content.get(&key) {
+                #[cfg(feature = "tracing")]
+                tracing::trace!(duplicate_offset = offset, "content already stored");
+                self.file_infos.push(FileInfo {
+                    id,
+                    id_high,
+                    size,
+                    offset,
+                    location_hash,
+                });
+                if self.options.header_locations {
+                    self.file_headers.push(FileHeader {
+                        hash,
+                        location: file_header.location,
+                    });
+                }
+                return Ok((written.content_hash, written.size));
+            }
+        }
+        if dedup || content_addressed {
+            self.stored_content
+                .insert(key, (offset, size, file_header.hash));
+        }
+
+        writer.seek(SeekFrom::Start(u64::from(offset)))?;
+        file_header.write_to(&mut writer)?;
+        writer.flush()?;
+
+        self.file_infos.push(FileInfo {
+            id,
+            id_high,
+            size,
+            offset,
+            location_hash,
+        });
+        if self.options.header_locations {
+            self.file_headers.push(file_header);
+        }
+        if offset > self.block_end {
+            self.gaps.push((self.block_end, offset));
+        }
+        self.block_end = offset + header_length as u32 + size;
+        self.next_file_offset = next_offset;
+        Ok((written.content_hash, written.size))
+    }
+
+    /// Записывает манифест (если он включен) и заголовок блока. Возвращает назначение, в
+    /// которое записан блок.
+    pub(crate) fn finish(mut self) -> Result<T> {
+        if self.options.manifest {
+            let content = manifest::encode(&std::mem::take(&mut self.manifest));
+            let location_hash = md5::compute(MANIFEST_LOCATION);
+            let id = u128::from(MANIFEST_ID);
+            self.add_entry(id, MANIFEST_LOCATION, location_hash, &content[..], None)?;
+        }
+        let flags = self.options.flags() | self.extra_flags;
+        let data_start = self.file_infos.iter().map(|info| info.offset).min();
+        let mut header = self.options.new_header(flags, self.file_infos);
+        header.file_headers = self.file_headers;
+        if let Some(data_start) = data_start {
+            // Место под заголовок резервируется по location, переданным при создании
+            if header.encoded_len() > u64::from(data_start) {
+                return Err(Error::FormatLimitExceeded(format!(
+                    "header of {} bytes overlaps file at offset {}",
+                    header.encoded_len(),
+                    data_start
+                )));
+            }
+            self.gaps.push((header.encoded_len() as u32, data_start));
+        }
+        let mut writer = BufWriter::new(&mut self.block_file);
+        writer.seek(SeekFrom::Start(0))?;
+        header.encode(&mut writer)?;
+        if self.options.header_trailer {
+            let trailer = header.encode_trailer()?;
+            writer.seek(SeekFrom::Start(u64::from(self.block_end)))?;
+            writer.write_all(&trailer)?;
+            let block_end = self.block_end;
+            self.block_end = u32::try_from(trailer.len())
+                .ok()
+                .and_then(|len| block_end.checked_add(len))
+                .ok_or_else(|| {
+                    Error::FormatLimitExceeded("block with header trailer exceeds 4 GiB".into())
+                })?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        // Файлы могли измениться после того, как был вычислен размер для предварительного
+        // выделения места, поэтому обрезаем блок по фактическому концу последнего файла
+        self.block_file.set_len(u64::from(self.block_end))?;
+        if self.options.sparse {
+            for &(start, end) in self.gaps.iter().filter(|(start, end)| start < end) {
+                self.block_file
+                    .punch_hole(u64::from(start), u64::from(end - start))?;
+            }
+        }
+        if self.options.sync {
+            self.block_file.sync_all()?;
+        }
+        Ok(self.block_file)
+    }
+}
+
+/// Размер полей [`FileHeader`] фиксированной длины: хеш содержимого (16 байт) и длина location
+/// (2 байта)
+///
+/// [`FileHeader`]: struct.FileHeader.html
+pub(crate) const FILE_HEADER_FIXED_SIZE: u32 = 16 + 2;
+
+/// Заголовок файла. Пишется непосредственно перед содержимым
+/// файла в блоке.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct FileHeader {
+    /// контрольная суммы содердимого файла
+    pub hash: md5::Digest,
+
+    /// URL файла. Произвольная последовательность байт, не обязательно UTF-8 (см. модуль
+    /// [`location`])
+    ///
+    /// [`location`]: ../location/index.html
+    pub location: Vec<u8>,
+}
+
+impl FileHeader {
+    /// Location файла для вывода пользователю (см. [`location::display`])
+    ///
+    /// [`location::display`]: ../location/fn.display.html
+    pub fn display_location(&self) -> Cow<'_, str> {
+        location::display(&self.location)
+    }
+
+    /// Location файла в виде имени файла локальной ФС (см. [`location::to_os_str`])
+    ///
+    /// [`location::to_os_str`]: ../location/fn.to_os_str.html
+    pub fn location_os_str(&self) -> Option<&std::ffi::OsStr> {
+        location::to_os_str(&self.location)
+    }
+}
+
+impl SelfSerialize for FileHeader {
+    fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()> {
+        let location_length = u16::try_from(self.location.len()).map_err(|_| {
+            Error::FormatLimitExceeded(format!("location {} is too long", self.display_location()))
+        })?;
+        target.write_all(&*self.hash)?;
+        target.write_u16::<LE>(location_length)?;
+        target.write_all(&self.location)?;
+        Ok(())
+    }
+    fn decode(source: &mut impl ReadBytesExt) -> Result<Self> {
+        Self::decode_limited(source, u16::MAX)
+    }
+}
+
+impl FileHeader {
+    /// Декодирует заголовок файла, отклоняя location длиннее `max_location_len` байт
+    pub(crate) fn decode_limited(
+        source: &mut impl ReadBytesExt,
+        max_location_len: u16,
+    ) -> Result<Self> {
+        let mut hash = [0u8; 16];
+        source.read_exact(&mut hash)?;
+        let location_length = source.read_u16::<LE>()?;
+        if location_length > max_location_len {
+            return Err(Error::DecodeLimitExceeded(format!(
+                "location of {} bytes exceeds {} bytes",
+                location_length, max_location_len
+            )));
+        }
+        let mut location = vec![0u8; location_length as usize];
+        source.read_exact(&mut location)?;
+
+        Ok(Self {
+            hash: md5::Digest(hash),
+            location,
+        })
+    }
+}
+
+/// Проверяет, что идентификаторы и нормализованные location файлов не повторяются. В противном
+/// случае [`Block::file_by_id`] и поиск по location были бы неоднозначны. Если в блок
+/// записывается манифест (см. [`BlockOptions::manifest`]), его идентификатор и location также
+/// считаются занятыми.
+///
+/// [`Block::file_by_id`]: struct.Block.html#method.file_by_id
+/// [`BlockOptions::manifest`]: struct.BlockOptions.html#method.manifest
+pub(crate) fn validate_unique(files: &[AddFileRequest], options: &BlockOptions) -> Result<()> {
+    validate_unique_ids(
+        files.iter().map(|file| (file, u128::from(file.id))),
+        options,
+    )
+}
+
+/// Аналогичен [`validate_unique`] для файлов с идентификаторами, заданными отдельно от запросов
+///
+/// [`validate_unique`]: fn.validate_unique.html
+fn validate_unique_ids<'a, 'f: 'a>(
+    files: impl Iterator<Item = (&'a AddFileRequest<'f>, u128)>,
+    options: &BlockOptions,
+) -> Result<()> {
+    let normalization = options.normalization;
+    let entries = files.map(|(file, id)| Ok((id, file.entry_location(normalization)?)));
+    validate_unique_entries(entries, options)
+}
+
+/// Аналогичен [`validate_unique`] для записей, заданных идентификатором и уже нормализованным
+/// location. Ошибка получения location записи возвращается в порядке следования записей.
+///
+/// [`validate_unique`]: fn.validate_unique.html
+pub(crate) fn validate_unique_entries<'l>(
+    entries: impl Iterator<Item = Result<(u128, Cow<'l, [u8]>)>>,
+    options: &BlockOptions,
+) -> Result<()> {
+    let mut unique = UniqueEntries::new(options);
+    for entry in entries {
+        let (id, location) = entry?;
+        unique.check(id, &location)?;
+    }
+    Ok(())
+}
+
+/// Идентификаторы и хеши location уже добавленных в блок записей. Позволяет проверять
+/// уникальность записей по мере их поступления (см. [`validate_unique`]).
+///
+/// [`validate_unique`]: fn.validate_unique.html
+struct UniqueEntries {
+    ids: HashSet<u128>,
+    location_hashes: HashSet<md5::Digest>,
+}
+
+impl UniqueEntries {
+    fn new(options: &BlockOptions) -> Self {
+        let mut ids = HashSet::new();
+        let mut location_hashes = HashSet::new();
+        if options.manifest {
+            ids.insert(u128::from(MANIFEST_ID));
+            location_hashes.insert(md5::compute(MANIFEST_LOCATION));
+        }
+        Self {
+            ids,
+            location_hashes,
+        }
+    }
+
+    /// Запоминает запись с идентификатором `id` и нормализованным location `location` или
+    /// возвращает ошибку, если такой идентификатор или location уже встречались
+    fn check(&mut self, id: u128, location: &[u8]) -> Result<()> {
+        if !self.ids.insert(id) {
+            return Err(Error::DuplicateId(id));
+        }
+        if !self.location_hashes.insert(md5::compute(location)) {
+            return Err(Error::DuplicateLocation(
+                location::display(location).into_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Вычисляет итоговый размер блока исходя из длин location и текущих размеров входных файлов.
+///
+/// Возвращает `None`, если размер какого-либо файла недоступен или блок не умещается в
+/// 32-битные смещения.
+fn expected_block_size(
+    first_file_offset: u32,
+    alignment: u32,
+    files: impl IntoIterator<Item = (usize, Option<u64>)>,
+) -> Option<u32> {
+    let mut offset = first_file_offset;
+    let mut end = first_file_offset;
+    for (location_length, size) in files {
+        let size = u32::try_from(size?).ok()?;
+        let location_length = u32::try_from(location_length).ok()?;
+        end = offset
+            .checked_add(FILE_HEADER_FIXED_SIZE + location_length)?
+            .checked_add(size)?;
+        if end > u32::MAX - alignment {
+            return None;
+        }
+        offset = round_up_to(end, alignment);
+    }
+    Some(end)
+}
+
+/// Резервирует на диске место под блок размером `len` байт.
+///
+/// На Linux используется `fallocate`, что позволяет файловой системе выделить под блок
+/// непрерывный экстент. Если файловая система не поддерживает `fallocate`, место не
+/// резервируется.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if result != 0 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(not(target_os = "linux"), not(target_arch = "wasm32")))]
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    file.set_len(len)
+}
+
+/// Освобождает место, занимаемое диапазоном файла длиной `len` байт, так что он читается как
+/// нули. Если файловая система не поддерживает дыры, диапазон заполняется нулями.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let result = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if result != 0 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(error);
+        }
+        return fill_with_zeros(file, offset, len);
+    }
+    Ok(())
+}
+
+#[cfg(all(not(target_os = "linux"), not(target_arch = "wasm32")))]
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    fill_with_zeros(file, offset, len)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn fill_with_zeros(mut file: &File, offset: u64, len: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    io::copy(&mut io::repeat(0).take(len), &mut file)?;
+    Ok(())
+}
+
+/// Вычисляет контрольную сумму и размер содержимого файла и сверяет их с заданными в запросе
+#[cfg(not(target_arch = "wasm32"))]
+fn content_hash(file: &AddFileRequest) -> io::Result<(md5::Digest, u64)> {
+    let mut hashing_writer = HashingWriter::new(io::sink());
+    let len = io::copy(&mut file.open()?, &mut hashing_writer)?;
+    let hash = hashing_writer.finish();
+    file.check_precomputed(hash, len)?;
+    Ok((hash, len))
+}
+
+/// Возвращает путь временного файла, в который пишется блок до его переименования в `path`
+fn tmp_path_for(path: &Path, attempt: usize) -> PathBuf {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}-{}.tmp", std::process::id(), attempt));
+    PathBuf::from(tmp_path)
+}
+
+/// Создает рядом с блоком `block_path` временный файл с именем, не занятым другими файлами
+#[cfg(not(target_arch = "wasm32"))]
+fn create_tmp_file(block_path: &Path) -> Result<(PathBuf, File)> {
+    static ATTEMPT: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let tmp_path = tmp_path_for(block_path, ATTEMPT.fetch_add(1, Ordering::Relaxed));
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+        {
+            Ok(file) => return Ok((tmp_path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Содержимое файла, записанное в блок [`write_content`]
+///
+/// [`write_content`]: fn.write_content.html
+#[cfg(not(target_arch = "wasm32"))]
+struct WrittenContent {
+    /// Контрольная сумма исходного содержимого
+    content_hash: md5::Digest,
+
+    /// Размер исходного содержимого
+    size: u64,
+
+    /// Контрольная сумма для заголовка файла: для зашифрованных блоков вычисляется по
+    /// записанному содержимому, для остальных совпадает с `content_hash`
+    header_hash: md5::Digest,
+
+    /// Количество записанных байт
+    stored: u64,
+}
+
+/// Записывает содержимое файла с location `location` из `reader` в `target` в соответствии с
+/// `options`: сжатым (см. [`BlockOptions::compress`]) и/или зашифрованным (см.
+/// [`BlockOptions::encryption_key`]).
+///
+/// Если задана `trusted_hash`, то несжатое содержимое не хешируется, а его контрольной суммой
+/// считается `trusted_hash` (см. [`BlockOptions::trust_precomputed`]).
+///
+/// Зашифровать содержимое можно только целиком, поэтому для зашифрованных блоков оно
+/// буферизуется в памяти.
+///
+/// [`BlockOptions::compress`]: struct.BlockOptions.html#method.compress
+/// [`BlockOptions::encryption_key`]: struct.BlockOptions.html#method.encryption_key
+/// [`BlockOptions::trust_precomputed`]: struct.BlockOptions.html#method.trust_precomputed
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+fn write_content(
+    reader: &mut impl Read,
+    target: &mut impl Write,
+    options: &BlockOptions,
+    location: &[u8],
+    trusted_hash: Option<md5::Digest>,
+) -> Result<WrittenContent> {
+    #[cfg(feature = "encryption")]
+    if let Some(key) = &options.encryption_key {
+        let mut buffer = vec![];
+        let (content_hash, size, _) =
+            encode_content(reader, &mut buffer, options.compress, trusted_hash)?;
+        let encrypted = encryption::encrypt(key, location, &buffer)?;
+        target.write_all(&encrypted)?;
+        return Ok(WrittenContent {
+            content_hash,
+            size,
+            header_hash: md5::compute(&encrypted),
+            stored: encrypted.len() as u64,
+        });
+    }
+    let (content_hash, size, stored) =
+        encode_content(reader, target, options.compress, trusted_hash)?;
+    Ok(WrittenContent {
+        content_hash,
+        size,
+        header_hash: content_hash,
+        stored,
+    })
+}
+
+/// Записывает содержимое из `reader` в `target`, при `compress` – сжатым.
+///
+/// Возвращает контрольную сумму и размер исходного содержимого, а также количество записанных
+/// байт. Несжатое содержимое не хешируется, если его контрольная сумма `trusted_hash` уже
+/// известна.
+#[cfg(not(target_arch = "wasm32"))]
+fn encode_content(
+    reader: &mut impl Read,
+    target: &mut impl Write,
+    compress: bool,
+    trusted_hash: Option<md5::Digest>,
+) -> Result<(md5::Digest, u64, u64)> {
+    if compress {
+        #[cfg(feature = "zstd")]
+        return compression::compress(reader, target);
+        #[cfg(not(feature = "zstd"))]
+        return Err(Error::UnsupportedFeature("compression".into()));
+    }
+    if let Some(hash) = trusted_hash {
+        let size = io::copy(reader, target)?;
+        return Ok((hash, size, size));
+    }
+    let mut hashing_writer = HashingWriter::new(target);
+    let size = io::copy(reader, &mut hashing_writer)?;
+    Ok((hashing_writer.finish(), size, size))
+}
+
+/// Распаковывает сжатое содержимое файла целиком
+#[cfg(feature = "zstd")]
+pub(crate) fn decompress(payload: &[u8]) -> Result<Vec<u8>> {
+    let content = CompressedContent::decode(payload)?;
+    content.read_range(0, u64::from(content.size()))
+}
+
+/// Обертка над `Write`, вычисляющая MD5 всех записанных через нее байт
+struct HashingWriter<W> {
+    inner: W,
+    context: md5::Context,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            context: md5::Context::new(),
+        }
+    }
+
+    fn finish(self) -> md5::Digest {
+        self.context.compute()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.context.consume(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Округляет целое беззнаковое целое `value` до следующего кратного `base`.
+///
+/// Например:
+/// ```rust
+/// use blocky::block::round_up_to;
+/// assert_eq!(round_up_to(10, 12), 12);
+/// assert_eq!(round_up_to(12, 12), 12);
+/// assert_eq!(round_up_to(13, 12), 24);
+///
+/// assert_eq!(round_up_to(1, 2048), 2048);
+/// assert_eq!(round_up_to(2048, 2048), 2048);
+/// assert_eq!(round_up_to(2049, 2048), 2 * 2048);
+/// assert_eq!(round_up_to(2 * 2048 + 1, 2048), 3 * 2048);
+/// ```
+pub fn round_up_to(value: u32, base: u32) -> u32 {
+    let reminder = value % base;
+    if reminder == 0 {
+        value
+    } else {
+        value + base - reminder
+    }
+}
+
+fn round_up_to_u64(value: u64, base: u32) -> u64 {
+    let base = u64::from(base);
+    value.div_ceil(base) * base
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs::File;
+    use std::io::{Cursor, Write};
+    use tempdir;
+
+    fn fixture(files: &[(impl AsRef<Path>, impl AsRef<[u8]>)]) -> Result<Block> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let tmp = tmp.path();
+
+        let mut absolute_file_names = vec![];
+        let mut locations = vec![];
+        let root = Path::new("/");
+
+        for (file_name, content) in files {
+            let path = tmp.join(file_name);
+            let mut file = File::create(&path)?;
+            file.write_all(content.as_ref())?;
+
+            locations.push(root.join(file_name));
+            absolute_file_names.push(path);
+        }
+
+        let add_requests = absolute_file_names
+            .iter()
+            .zip(locations.iter())
+            .enumerate()
+            .map(|(id, (path, location))| AddFileRequest {
+                id: (id + 1) as u64,
+                path,
+                location,
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+
+        let block_path = tmp.join("test.block");
+        Block::from_files(&block_path, &add_requests)?;
+        Block::open(&block_path)
+    }
+
+    #[test]
+    #[should_panic]
+    fn block_could_not_be_created_twice() {
+        let block_path_name = "./target/test.block";
+        Block::from_files(
+            block_path_name,
+            &[AddFileRequest {
+                id: 1,
+                path: Path::new("./foo"),
+                location: Path::new("./foo"),
+                content_hash: None,
+                size: None,
+            }],
+        )
+        .unwrap();
+
+        Block::from_files(
+            block_path_name,
+            &[AddFileRequest {
+                id: 1,
+                path: Path::new("./foo"),
+                location: Path::new("./foo"),
+                content_hash: None,
+                size: None,
+            }],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn should_be_able_to_create_block_and_return_file_info() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
+        assert_eq!(block.len(), 2);
+
+        let info = block.iter().collect::<Vec<_>>();
+        assert!(info.iter().all(|i| i.id > 0));
+
+        assert_eq!(info[0].size, 5);
+        assert_eq!(
+            format!("{:x}", info[0].location_hash),
+            "d0e14e5f5e76ec1a00e5fb02e4b47d9a"
+        );
+
+        assert_eq!(info[1].size, 5);
+        assert_eq!(
+            format!("{:x}", info[1].location_hash),
+            "475e9b6e16f464efea93b8312b90ec02"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_be_able_to_return_block_content() -> Result<()> {
+        let content = "text-content";
+        let block = fixture(&[("one.txt", content)])?;
+        let (header, bytes) = block.file_at(0).unwrap();
+
+        let expected_hash = md5::compute(content);
+        assert_eq!(expected_hash, md5::compute(bytes));
+        assert_eq!(expected_hash, header.hash);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_iterate_over_entries() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
+        let entries = block.entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+
+        let second = &entries[1];
+        assert_eq!(second.info().id, 2);
+        assert_eq!(second.header()?.location, b"/2.bin");
+        assert_eq!(second.content()?, &b"World"[..]);
+
+        let mut content = String::new();
+        entries[0].reader()?.read_to_string(&mut content)?;
+        assert_eq!(content, "Hello");
+        Ok(())
+    }
+
+    #[test]
+    fn should_be_able_to_return_file_by_id() -> Result<()> {
+        let content = "text-content";
+        let block = fixture(&[("one.txt", content)])?;
+        // Файлы нумеруются последовательно, поэтому у первого файла id = 1
+        let (_, bytes) = block.file_by_id(1).unwrap();
+
+        assert_eq!(content, String::from_utf8_lossy(&bytes));
+        Ok(())
+    }
+
+    #[test]
+    fn should_find_file_by_location() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
+        assert_eq!(
+            block.find_by_location("/2.bin").map(|info| info.id),
+            Some(2)
+        );
+        assert!(block.find_by_location("/3.bin").is_none());
+
+        assert!(block.contains_location("/1.bin"));
+        assert!(!block.contains_location("/3.bin"));
+        assert!(block.contains_id(2));
+        assert!(!block.contains_id(3));
+        Ok(())
+    }
+
+    #[test]
+    fn should_find_files_by_unordered_ids() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let content = tmp.path().join("content");
+        fs::write(&content, "content")?;
+        let block_path = tmp.path().join("test.block");
+        let ids = [30, 10, 20];
+        let locations = ids
+            .iter()
+            .map(|id| PathBuf::from(format!("/{}", id)))
+            .collect::<Vec<_>>();
+        let requests = ids
+            .iter()
+            .zip(locations.iter())
+            .map(|(&id, location)| AddFileRequest {
+                id,
+                path: &content,
+                location,
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+        BlockOptions::new().create(&block_path, &requests)?;
+
+        let block = Block::open(&block_path)?;
+        for id in ids.iter() {
+            assert!(block.contains_id(*id));
+            assert_eq!(block.file_info_by_id(*id)?.id, *id);
+        }
+        assert!(!block.contains_id(15));
+        assert!(!block.contains_id(40));
+        assert_eq!(block.id_index(), Some(&[1, 2, 0][..]));
+        for (id, location) in ids.iter().zip(locations.iter()) {
+            let location = location.to_str().unwrap();
+            assert_eq!(block.find_by_location(location).map(|i| i.id), Some(*id));
+            assert_eq!(block.resolve_location(location)?.id, *id);
+        }
+        assert!(!block.contains_location("/15"));
+        assert_eq!(block.location_index().len(), ids.len());
+
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
+        assert!(block.contains_id(2));
+        assert_eq!(block.id_index(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn should_be_able_to_store_files_larger_than_copy_buffer() -> Result<()> {
+        let content = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let block = fixture(&[("large.bin", &content), ("small.bin", &vec![1, 2, 3])])?;
+
+        let (header, bytes) = block.file_by_id(1).unwrap();
+        assert_eq!(bytes, &content[..]);
+        assert_eq!(header.hash, md5::compute(&content));
+        assert!(block.verify_all().iter().all(|r| r.result.is_ok()));
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_leave_partial_block_on_failure() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "content")?;
+        let block_path = tmp.path().join("test.block");
+
+        // Location не помещается в заголовок файла, что приводит к ошибке посреди записи блока
+        let location = "a".repeat(usize::from(u16::MAX) + 1);
+        let result = Block::from_files(
+            &block_path,
+            &[AddFileRequest {
+                id: 1,
+                path: &file_path,
+                location: Path::new(&location),
+                content_hash: None,
+                size: None,
+            }],
+        );
+
+        assert!(result.is_err());
+        assert!(!block_path.exists());
+        // Временный файл удален
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_replace_block_created_concurrently() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let block_path = tmp.path().join("test.block");
+        let options = BlockOptions::new();
+        let result = options.write_atomically(&block_path, |mut tmp_file| {
+            // Другой процесс создает блок, пока этот блок записывается
+            std::fs::write(&block_path, "other block")?;
+            tmp_file.write_all(b"this block")?;
+            Ok(())
+        });
+        match result {
+            Err(Error::BlockFileAlreadyExists(path)) => assert_eq!(path, block_path),
+            r => panic!("BlockFileAlreadyExists expected, got: {:?}", r),
+        }
+        assert_eq!(std::fs::read(&block_path)?, b"other block");
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_be_able_to_create_block_with_fsync() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "durable")?;
+
+        let block = BlockOptions::new().sync(true).create(
+            tmp.path().join("test.block"),
+            &[AddFileRequest {
+                id: 1,
+                path: &file_path,
+                location: Path::new("/one.txt"),
+                content_hash: None,
+                size: None,
+            }],
+        )?;
+        assert_eq!(block.file_by_id(1).unwrap().1, &b"durable"[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn preallocated_size_should_match_block_size() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let first = tmp.path().join("first.txt");
+        let second = tmp.path().join("second.txt");
+        std::fs::write(&first, "a".repeat(3000))?;
+        std::fs::write(&second, "b")?;
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &first,
+                location: Path::new("/first.txt"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &second,
+                location: Path::new("/second.txt"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+
+        let block_path = tmp.path().join("test.block");
+        Block::from_files(&block_path, &files)?;
+        let expected = expected_block_size(
+            BLOCK_PAGE_SIZE,
+            BLOCK_PAGE_SIZE,
+            files
+                .iter()
+                .map(|file| (file.location.as_os_str().len(), file.len().ok())),
+        )
+        .unwrap();
+        assert_eq!(u64::from(expected), block_path.metadata()?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn packed_block_should_not_have_padding() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let first = tmp.path().join("first.txt");
+        let second = tmp.path().join("second.txt");
+        std::fs::write(&first, "Hello")?;
+        std::fs::write(&second, "World")?;
+
+        let block = BlockOptions::new().packed(true).create(
+            tmp.path().join("test.block"),
+            &[
+                AddFileRequest {
+                    id: 1,
+                    path: &first,
+                    location: Path::new("/first.txt"),
+                    content_hash: None,
+                    size: None,
+                },
+                AddFileRequest {
+                    id: 2,
+                    path: &second,
+                    location: Path::new("/second.txt"),
+                    content_hash: None,
+                    size: None,
+                },
+            ],
+        )?;
+
+        assert!(block.header().is_packed());
+        assert_eq!(block.header().version(), 2);
+        let info = block.iter().collect::<Vec<_>>();
+        let first_file_length = FILE_HEADER_FIXED_SIZE + "/first.txt".len() as u32 + 5;
+        assert_eq!(info[1].offset, info[0].offset + first_file_length);
+        assert_eq!(block.file_by_id(2).unwrap().1, &b"World"[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_content_should_be_stored_once() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let names = ["a.txt", "b.txt", "c.txt"];
+        let contents = ["same", "other", "same"];
+        let paths = names.iter().map(|n| tmp.path().join(n)).collect::<Vec<_>>();
+        let locations = names
+            .iter()
+            .map(|n| Path::new("/").join(n))
+            .collect::<Vec<_>>();
+        for (path, content) in paths.iter().zip(contents.iter()) {
+            std::fs::write(path, content)?;
+        }
+        let requests = (0..names.len())
+            .map(|i| AddFileRequest {
+                id: i as u64 + 1,
+                path: &paths[i],
+                location: &locations[i],
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+
+        let block = BlockOptions::new()
+            .dedup(true)
+            .create(tmp.path().join("test.block"), &requests)?;
+
+        let info = block.iter().collect::<Vec<_>>();
+        assert_eq!(info[0].offset, info[2].offset);
+        assert_ne!(info[0].location_hash, info[2].location_hash);
+        assert_eq!(info[1].offset, 2 * BLOCK_PAGE_SIZE);
+        for (id, content) in [(1, "same"), (2, "other"), (3, "same")].iter() {
+            assert_eq!(block.file_by_id(*id).unwrap().1, content.as_bytes());
+        }
+        assert!(block.verify_all().iter().all(|r| r.result.is_ok()));
+        Ok(())
+    }
+
+    #[test]
+    fn should_fall_back_to_header_trailer() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "recoverable")?;
+        let block_path = tmp.path().join("test.block");
+        let block = BlockOptions::new().header_trailer(true).create(
+            &block_path,
+            &[AddFileRequest {
+                id: 1,
+                path: &file_path,
+                location: Path::new("/one.txt"),
+                content_hash: None,
+                size: None,
+            }],
+        )?;
+        assert!(!block.needs_repair());
+
+        let mut bytes = std::fs::read(&block_path)?;
+        // Портим размер файла и количество файлов в основном заголовке
+        bytes[2..6].copy_from_slice(&[0xFF; 4]);
+        bytes[14..18].copy_from_slice(&[0xFF; 4]);
+
+        let block = Block::from_bytes(bytes)?;
+        assert!(block.needs_repair());
+        assert_eq!(block.file_by_id(1).unwrap().1, &b"recoverable"[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn open_verified_should_reject_damaged_blocks() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "content")?;
+        let block_path = tmp.path().join("test.block");
+        let block = BlockOptions::new().header_trailer(true).create(
+            &block_path,
+            &[AddFileRequest {
+                id: 1,
+                path: &file_path,
+                location: Path::new("/one.txt"),
+                content_hash: None,
+                size: None,
+            }],
+        )?;
+        let offset = block.iter().next().unwrap().offset as usize;
+        assert!(Block::open_verified(&block_path).is_ok());
+
+        let bytes = std::fs::read(&block_path)?;
+        let damaged_path = tmp.path().join("damaged.block");
+        let mut damaged = bytes.clone();
+        damaged[2..6].copy_from_slice(&[0xFF; 4]);
+        std::fs::write(&damaged_path, &damaged)?;
+        assert!(Block::open(&damaged_path).is_ok());
+        match Block::open_verified(&damaged_path) {
+            Err(Error::BlockCorrupted { path: Some(_), .. }) => {}
+            r => panic!("BlockCorrupted expected, got: {:?}", r.err()),
+        }
+
+        let mut damaged = bytes;
+        damaged[offset + FILE_HEADER_FIXED_SIZE as usize + "/one.txt".len()] ^= 0xFF;
+        std::fs::write(&damaged_path, &damaged)?;
+        match Block::open_verified(&damaged_path) {
+            Err(Error::ChecksumMismatch { id: 1 }) => {}
+            r => panic!("ChecksumMismatch expected, got: {:?}", r.err()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_open_blocks_with_options() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "content")?;
+        let files = [AddFileRequest {
+            id: 1,
+            path: &file_path,
+            location: Path::new("/one.txt"),
+            content_hash: None,
+            size: None,
+        }];
+        let block_path = tmp.path().join("test.block");
+        let created = Block::from_files(&block_path, &files)?;
+
+        let block = Block::options()
+            .mmap(false)
+            .verify(true)
+            .verify_on_read(true)
+            .advice(Advice::Populate)
+            .open(&block_path)?;
+        assert_eq!(block.header(), created.header());
+        assert_eq!(block.file_by_id(1)?.1, &b"content"[..]);
+        assert_eq!(
+            &Block::options().untrusted(true).open_header(&block_path)?,
+            created.header()
+        );
+
+        let streamed_path = tmp.path().join("streamed.block");
+        BlockOptions::new().stream(File::create(&streamed_path)?, &files)?;
+        let header = Block::options().open_header(&streamed_path)?;
+        assert_eq!(header.file_info().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_store_directory_entries() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let (dir, empty, other, file) = (
+            tmp.path().join("dir"),
+            tmp.path().join("dir/empty"),
+            tmp.path().join("dir/other"),
+            tmp.path().join("dir/a.txt"),
+        );
+        std::fs::create_dir_all(&empty)?;
+        std::fs::create_dir_all(&other)?;
+        std::fs::write(&file, "content")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&empty, std::fs::Permissions::from_mode(0o700))?;
+        }
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &dir,
+                location: Path::new("/dir"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &empty,
+                location: Path::new("/dir/empty/"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 3,
+                path: &file,
+                location: Path::new("/dir/a.txt"),
+                content_hash: None,
+                size: None,
+            },
+            // Те же права, что и у /dir: содержимое совпадает, но записи не дедуплицируются
+            AddFileRequest {
+                id: 4,
+                path: &other,
+                location: Path::new("/dir/other"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+
+        let options = {
+            let mut options = BlockOptions::new();
+            options.dedup(true);
+            options
+        };
+        let block = options.create(tmp.path().join("test.block"), &files)?;
+        let streamed = {
+            let mut bytes = vec![];
+            options.stream(&mut bytes, &files)?;
+            Block::from_bytes(bytes)?
+        };
+        for block in [&block, &streamed] {
+            assert!(block.header().has_directories());
+            let kinds = block
+                .entries()
+                .map(|entry| Ok((entry.header()?.location.clone(), entry.kind()?)))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(kinds[0].0, b"/dir/");
+            assert_eq!(kinds[1].0, b"/dir/empty/");
+            assert!(matches!(kinds[0].1, EntryKind::Directory { .. }));
+            #[cfg(unix)]
+            assert_eq!(kinds[1].1, EntryKind::Directory { mode: 0o700 });
+            assert_eq!(kinds[2], (b"/dir/a.txt".to_vec(), EntryKind::File));
+            assert_eq!(kinds[3].0, b"/dir/other/");
+            assert_eq!(kinds[3].1, kinds[0].1);
+            assert_eq!(block.find_by_location("/dir/").map(|i| i.id), Some(1));
+        }
+
+        // Блоки без директорий не отмечаются флагом, а location файлов не может заканчиваться на /
+        let plain = BlockOptions::new().create(tmp.path().join("plain.block"), &files[2..3])?;
+        assert!(!plain.header().has_directories());
+        let trailing_slash = [AddFileRequest {
+            id: 1,
+            path: &file,
+            location: Path::new("/a.txt/"),
+            content_hash: None,
+            size: None,
+        }];
+        assert!(matches!(
+            BlockOptions::new().create(tmp.path().join("slash.block"), &trailing_slash),
+            Err(Error::InvalidLocation(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_store_zero_length_files() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let (a, b, c) = (
+            tmp.path().join("a.keep"),
+            tmp.path().join("b.keep"),
+            tmp.path().join("c.txt"),
+        );
+        std::fs::write(&a, "")?;
+        std::fs::write(&b, "")?;
+        std::fs::write(&c, "content")?;
+        // Пустой файл последним, так что его содержимое заканчивается вместе с блоком
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &a,
+                location: Path::new("/a.keep"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &c,
+                location: Path::new("/c.txt"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 3,
+                path: &b,
+                location: Path::new("/b.keep"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+
+        let variants: [fn(&mut BlockOptions) -> &mut BlockOptions; 6] = [
+            |o| o,
+            |o| o.packed(true),
+            |o| o.dedup(true),
+            |o| o.sparse(true),
+            |o| o.header_trailer(true).packed(true),
+            |o| o.compress(cfg!(feature = "zstd")),
+        ];
+        for (idx, configure) in variants.iter().enumerate() {
+            let mut options = BlockOptions::new();
+            configure(&mut options);
+            let block_path = tmp.path().join(format!("{}.block", idx));
+            let block = options.create(&block_path, &files)?;
+            let streamed = {
+                let mut bytes = vec![];
+                options.stream(&mut bytes, &files)?;
+                Block::from_bytes(bytes)?
+            };
+            for block in [&block, &streamed] {
+                assert!(block.verify_all().iter().all(|v| v.result.is_ok()));
+                for (id, location) in [(1, "/a.keep"), (3, "/b.keep")] {
+                    let (header, content) = block.file_by_id(id)?;
+                    assert!(content.is_empty());
+                    // Пустые файлы не дедуплицируются и сохраняют свой location
+                    assert_eq!(header.location, location.as_bytes());
+                    assert!(block.read_range(id, 0, 0)?.is_empty());
+                    assert!(matches!(
+                        block.read_range(id, 0, 1),
+                        Err(Error::RangeOutOfBounds { size: 0, .. })
+                    ));
+                }
+            }
+            let target_path = tmp.path().join(format!("{}.out", idx));
+            let target = File::create(&target_path)?;
+            assert_eq!(block.copy_entry_to(3, &target)?, 0);
+            assert_eq!(std::fs::metadata(&target_path)?.len(), 0);
+        }
+
+        #[cfg(feature = "encryption")]
+        {
+            let key = EncryptionKey::generate();
+            let mut block = BlockOptions::new()
+                .encryption_key(Some(key.clone()))
+                .create(tmp.path().join("encrypted.block"), &files)?;
+            block.decryption_key(key).verify_on_read(true);
+            assert!(block.file_by_id(3)?.1.is_empty());
+            assert!(block.read_range(1, 0, 0)?.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_copy_entries_to_files() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let (a, b) = (tmp.path().join("a.txt"), tmp.path().join("b.txt"));
+        std::fs::write(&a, "first")?;
+        std::fs::write(&b, "second")?;
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &a,
+                location: Path::new("/a.txt"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &b,
+                location: Path::new("/b.txt"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+        let block_path = tmp.path().join("test.block");
+        Block::from_files(&block_path, &files)?;
+
+        let mapped = Block::options().verify_on_read(true).open(&block_path)?;
+        let in_memory = Block::from_bytes(std::fs::read(&block_path)?)?;
+        for (idx, block) in [mapped, in_memory].iter().enumerate() {
+            let target_path = tmp.path().join(format!("out-{}", idx));
+            let target = File::create(&target_path)?;
+            assert_eq!(block.copy_entry_to(2, &target)?, 6);
+            assert_eq!(block.copy_entry_to(1, &target)?, 5);
+            assert_eq!(std::fs::read(&target_path)?, b"secondfirst");
+            match block.copy_entry_to(3, &target) {
+                Err(Error::FileNotFound { id: 3 }) => {}
+                r => panic!("FileNotFound expected, got: {:?}", r),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_block_from_custom_storage() -> Result<()> {
+        struct SharedStorage(std::sync::Arc<Vec<u8>>);
+
+        impl RangeRead for SharedStorage {
+            fn size(&self) -> io::Result<u64> {
+                self.0.size()
+            }
+
+            fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+                self.0.read_range(offset, buf)
+            }
+        }
+
+        impl BlockStorage for SharedStorage {
+            fn bytes(&self) -> Option<&[u8]> {
+                Some(&self.0)
+            }
+        }
+
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let a = tmp.path().join("a.txt");
+        std::fs::write(&a, "first")?;
+        let files = [AddFileRequest {
+            id: 1,
+            path: &a,
+            location: Path::new("/a.txt"),
+            content_hash: None,
+            size: None,
+        }];
+        let block_path = tmp.path().join("test.block");
+        Block::from_files(&block_path, &files)?;
+
+        let bytes = std::sync::Arc::new(std::fs::read(&block_path)?);
+        let block = Block::from_storage(SharedStorage(bytes.clone()))?;
+        let (_, content) = block.file_by_location("/a.txt")?;
+        assert!(matches!(content, Cow::Borrowed(_)));
+        assert_eq!(&content[..], b"first");
+        assert_eq!(std::sync::Arc::strong_count(&bytes), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn should_copy_subset_of_entries_to_new_block() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let paths = (1..=3)
+            .map(|id| {
+                let path = tmp.path().join(format!("{}.txt", id));
+                std::fs::write(&path, format!("content {}", id)).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let locations = ["/A.txt", "/b.txt", "/c.txt"];
+        let files = paths
+            .iter()
+            .zip(locations.iter())
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 * 10,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+        let source = BlockOptions::new()
+            .packed(true)
+            .location_normalization(Normalization::LOWERCASE)
+            .create(tmp.path().join("source.block"), &files)?;
+
+        let subset_path = tmp.path().join("subset.block");
+        let subset = source.subset_to(&[20, 0], &subset_path)?;
+        assert_eq!(subset.iter().map(|i| i.id).collect::<Vec<_>>(), [0, 20]);
+        assert!(subset.header().is_packed());
+        assert_eq!(subset.normalization(), Normalization::LOWERCASE);
+        for id in [0, 20] {
+            assert_eq!(subset.file_by_id(id)?, source.file_by_id(id)?);
+            let info = subset.iter().find(|i| i.id == id).unwrap();
+            assert_eq!(
+                info.location_hash,
+                source.file_info_by_id(id)?.location_hash
+            );
+        }
+        assert_eq!(subset.find_by_location("/a.txt").map(|i| i.id), Some(0));
+
+        let missing = source.subset_to(&[10, 42], tmp.path().join("missing.block"));
+        match missing {
+            Err(Error::FileNotFound { id: 42 }) => {}
+            r => panic!("FileNotFound expected, got: {:?}", r.map(|_| ())),
+        }
+        match source.subset_to(&[10], &subset_path) {
+            Err(Error::BlockFileAlreadyExists(_)) => {}
+            r => panic!("BlockFileAlreadyExists expected, got: {:?}", r.map(|_| ())),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_plan_block_layout() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let (a, b) = (tmp.path().join("a.txt"), tmp.path().join("b.txt"));
+        std::fs::write(&a, vec![1; 3000])?;
+        std::fs::write(&b, "second")?;
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &a,
+                location: Path::new("/a.txt"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &b,
+                location: Path::new("/b.txt"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+
+        for (trailer, header_locations) in [(false, false), (true, false), (true, true)] {
+            let options = {
+                let mut options = BlockOptions::new();
+                options
+                    .header_trailer(trailer)
+                    .header_locations(header_locations)
+                    .reserve_entries(2);
+                options
+            };
+            let plan = options.plan(&files)?;
+            let block_path = tmp
+                .path()
+                .join(format!("{}-{}.block", trailer, header_locations));
+            let block = options.create(&block_path, &files)?;
+
+            assert!(plan.exact);
+            assert_eq!(plan.header_size, block.header().encoded_len());
+            assert_eq!(plan.block_size, std::fs::metadata(&block_path)?.len());
+            assert_eq!(plan.padding, block.padding()?);
+            let offsets = plan.entries.iter().map(|e| (e.id, e.offset as u32));
+            let expected = block.iter().map(|info| (info.wide_id(), info.offset));
+            assert!(offsets.eq(expected));
+        }
+
+        let missing = [AddFileRequest {
+            id: 1,
+            path: &tmp.path().join("missing.txt"),
+            location: Path::new("/missing.txt"),
+            content_hash: None,
+            size: None,
+        }];
+        assert!(matches!(
+            BlockOptions::new().plan(&missing),
+            Err(Error::Io(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_manifest_entry() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let (a, b) = (tmp.path().join("a.txt"), tmp.path().join("b.txt"));
+        std::fs::write(&a, "first")?;
+        std::fs::write(&b, "second")?;
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &a,
+                location: Path::new("/a.txt"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &b,
+                location: Path::new("/b.txt"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+        let options = {
+            let mut options = BlockOptions::new();
+            options.manifest(true);
+            options
+        };
+        let plan = options.plan(&files)?;
+        let block_path = tmp.path().join("test.block");
+        let block = options.create(&block_path, &files)?;
+        assert_eq!(plan.block_size, std::fs::metadata(&block_path)?.len());
+
+        let expected = format!(
+            "{{\"version\":1,\"files\":[\n\
+             {{\"id\":1,\"location\":\"/a.txt\",\"size\":5,\"md5\":\"{:x}\"}},\n\
+             {{\"id\":2,\"location\":\"/b.txt\",\"size\":6,\"md5\":\"{:x}\"}}\n\
+             ]}}\n",
+            md5::compute("first"),
+            md5::compute("second")
+        );
+        assert_eq!(block.manifest()?.as_deref(), Some(expected.as_bytes()));
+        assert_eq!(
+            block.find_by_location(MANIFEST_LOCATION).map(|i| i.id),
+            Some(MANIFEST_ID)
+        );
+
+        // Манифест копии блока описывает только ее файлы
+        let subset = block.subset_to(&[2], tmp.path().join("subset.block"))?;
+        let manifest = subset.manifest()?.unwrap();
+        assert!(!manifest.windows(6).any(|w| w == b"/a.txt"));
+        assert_eq!(subset.len(), 2);
+        let plain = BlockOptions::new().create(tmp.path().join("plain.block"), &files)?;
+        assert!(plain.manifest()?.is_none());
+
+        let reserved = [AddFileRequest {
+            id: MANIFEST_ID,
+            path: &a,
+            location: Path::new("/c.txt"),
+            content_hash: None,
+            size: None,
+        }];
+        assert!(matches!(
+            options.create(tmp.path().join("reserved.block"), &reserved),
+            Err(Error::DuplicateId(id)) if id == u128::from(MANIFEST_ID)
+        ));
+        assert!(matches!(
+            options.stream(std::io::sink(), &files),
+            Err(Error::UnsupportedFeature(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_reserve_header_entries() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "content")?;
+        let files = [AddFileRequest {
+            id: 1,
+            path: &file_path,
+            location: Path::new("/one.txt"),
+            content_hash: None,
+            size: None,
+        }];
+        let file_info_size = FileInfo::encoded_len(0);
+
+        let block_path = tmp.path().join("plain.block");
+        let plain = BlockOptions::new()
+            .packed(true)
+            .create(&block_path, &files)?;
+        assert_eq!(plain.reserved_entries(), 0);
+        assert_eq!(plain.padding()?, 0);
+
+        let block_path = tmp.path().join("reserved.block");
+        let options = {
+            let mut options = BlockOptions::new();
+            options
+                .packed(true)
+                .header_trailer(true)
+                .reserve_entries(10);
+            options
+        };
+        let block = options.create(&block_path, &files)?;
+        assert_eq!(block.reserved_entries(), 10);
+        assert_eq!(block.padding()?, 10 * file_info_size);
+        assert_eq!(
+            block.header().file_info()[0].offset,
+            plain.header().file_info()[0].offset + 10 * file_info_size as u32
+        );
+        assert_eq!(block.file_by_id(1)?.1, &b"content"[..]);
+        assert!(!block.needs_repair());
+
+        // Выравнивание первого файла также оставляет место под записи
+        let block_path = tmp.path().join("aligned.block");
+        let aligned = BlockOptions::new()
+            .reserve_entries(3)
+            .create(&block_path, &files)?;
+        let header_len = aligned.header().encoded_len();
+        assert_eq!(
+            aligned.reserved_entries(),
+            (u64::from(BLOCK_PAGE_SIZE) - header_len) / file_info_size
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_blocks_bypassing_page_cache() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("large.bin");
+        let content = (0..10_001).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&file_path, &content)?;
+        let files = [AddFileRequest {
+            id: 1,
+            path: &file_path,
+            location: Path::new("/large.bin"),
+            content_hash: None,
+            size: None,
+        }];
+        let block_path = tmp.path().join("test.block");
+        let created = Block::from_files(&block_path, &files)?;
+
+        let block = Block::options()
+            .direct_io(true)
+            .verify(true)
+            .open(&block_path)?;
+        assert_eq!(block.header(), created.header());
+        assert_eq!(block.file_by_id(1)?.1, &content[..]);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn direct_file_should_read_unaligned_ranges_in_chunks() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let path = tmp.path().join("data.bin");
+        let content = (0..DirectFile::CHUNK_SIZE + 5000)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&path, &content)?;
+        let file = match DirectFile::open(&path) {
+            Ok(file) => file,
+            // Файловая система не поддерживает O_DIRECT
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        assert_eq!(file.size()?, content.len() as u64);
+
+        for (offset, len) in [(0, 10), (4095, 2), (1000, DirectFile::CHUNK_SIZE + 3000)] {
+            let range = file.read_at(offset as u64, len)?;
+            assert_eq!(&range[..], &content[offset..offset + len]);
+        }
+        let end = content.len() as u64;
+        assert!(file.read_at(end - 1, 2).is_err());
+        // Буфер переиспользуется последующими чтениями
+        assert_eq!(file.buffers.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn block_should_be_read_from_disk_on_demand() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let (a, b) = (tmp.path().join("a.txt"), tmp.path().join("b.txt"));
+        std::fs::write(&a, "first")?;
+        std::fs::write(&b, "second")?;
+        let request = |id, path, location| AddFileRequest {
+            id,
+            path,
+            location: Path::new(location),
+            content_hash: None,
+            size: None,
+        };
+        let files = [request(1, &a, "/a.txt"), request(2, &b, "/b.txt")];
+        let block_path = tmp.path().join("test.block");
+        let created = BlockOptions::new()
+            .header_trailer(true)
+            .create(&block_path, &files)?;
+
+        let block = Block::options().pread(true).open(&block_path)?;
+        assert!(block.data.bytes().is_none());
+        assert_eq!(block.header(), created.header());
+        let (header, content) = block.file_by_id(2)?;
+        assert_eq!(header.location, b"/b.txt");
+        assert!(matches!(content, Cow::Owned(_)));
+        assert_eq!(&content[..], b"second");
+        assert_eq!(&block.read_range(1, 1, 3)?[..], b"irs");
+        assert_eq!(block.resolve_location("/a.txt")?.id, 1);
+        assert!(block.verify_all().iter().all(|v| v.result.is_ok()));
+        assert_eq!(block.reserved_entries(), created.reserved_entries());
+
+        // Чтение файла, обрезанного после открытия блока, возвращает ошибку
+        let offset = block.header().file_info()[1].offset;
+        let file = OpenOptions::new().write(true).open(&block_path)?;
+        file.set_len(u64::from(offset) + 1)?;
+        assert!(matches!(
+            block.file_by_id(2),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        assert_eq!(&block.file_by_id(1)?.1[..], b"first");
+        Ok(())
+    }
+
+    #[test]
+    fn header_without_flags_should_be_written_as_version_1() -> Result<()> {
+        let header = BlockHeader::new(0, vec![]);
+        let mut bytes = vec![];
+        header.encode(&mut bytes)?;
+        assert_eq!(bytes, [1, 0, 0, 0, 0, 0]);
+        Ok(())
+    }
+
+    /// Блоки каждой версии формата, созданные из файлов `a.txt` (`hello`) и `b.txt` (`world!`)
+    const FIXTURES: [(&str, &[u8], u16, u32); 2] = [
+        ("v1", include_bytes!("../fixtures/v1.block"), 1, 0),
+        (
+            "v2-packed",
+            include_bytes!("../fixtures/v2-packed.block"),
+            2,
+            FLAG_PACKED,
+        ),
+    ];
+
+    #[test]
+    fn should_decode_fixtures_of_every_version() -> Result<()> {
+        let decoded_versions = FIXTURES.iter().map(|f| f.2).collect::<HashSet<_>>();
+        let registered_versions = HEADER_DECODERS
+            .iter()
+            .map(|d| d.version)
+            .collect::<HashSet<_>>();
+        assert_eq!(decoded_versions, registered_versions);
+        assert_eq!(
+            registered_versions.iter().max(),
+            Some(&MAX_SUPPORTED_VERSION)
+        );
+
+        for (name, bytes, version, flags) in FIXTURES.iter() {
+            let block = Block::from_bytes(bytes.to_vec())?;
+            assert_eq!(block.header().version(), *version, "{}", name);
+            assert_eq!(block.header().flags(), *flags, "{}", name);
+            let ids = block.iter().map(|f| f.id).collect::<Vec<_>>();
+            assert_eq!(ids, [1, 2], "{}", name);
+
+            let (header, content) = block.file_by_id(1)?;
+            assert_eq!(header.location, b"a.txt", "{}", name);
+            assert_eq!(content, &b"hello"[..], "{}", name);
+            let (header, content) = block.file_by_id(2)?;
+            assert_eq!(header.location, b"b.txt", "{}", name);
+            assert_eq!(content, &b"world!"[..], "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_blocks_identical_to_fixtures() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let a = tmp.path().join("a.txt");
+        let b = tmp.path().join("b.txt");
+        std::fs::write(&a, "hello")?;
+        std::fs::write(&b, "world!")?;
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &a,
+                location: Path::new("a.txt"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &b,
+                location: Path::new("b.txt"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+
+        for (name, bytes, _, flags) in FIXTURES.iter() {
+            let block_path = tmp.path().join(name);
+            BlockOptions::new()
+                .packed(flags & FLAG_PACKED != 0)
+                .create(&block_path, &files)?;
+            assert_eq!(&std::fs::read(&block_path)?, bytes, "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_unsupported_versions_and_features() {
+        let decode = |bytes: &[u8]| Block::from_bytes(bytes.to_vec()).err();
+
+        match decode(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 0]) {
+            Some(Error::UnsupportedVersion(3)) => {}
+            e => panic!("UnsupportedVersion expected, got: {:?}", e),
+        }
+        match decode(&[2, 0, 0x11, 0, 0, 0, 0, 0, 0, 0]) {
+            Some(Error::UnsupportedFeature(feature)) => assert_eq!(feature, "64-bit offsets"),
+            e => panic!("UnsupportedFeature expected, got: {:?}", e),
+        }
+        match decode(&[2, 0, 0, 0, 0, 0x80, 0, 0, 0, 0]) {
+            Some(Error::UnsupportedFeature(feature)) => {
+                assert_eq!(feature, "unknown flag 0x80000000")
+            }
+            e => panic!("UnsupportedFeature expected, got: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn should_reject_duplicate_ids_and_locations() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "content")?;
+        let request = |id, location| AddFileRequest {
+            id,
+            path: &file_path,
+            location: Path::new(location),
+            content_hash: None,
+            size: None,
+        };
+
+        let result = Block::from_files(
+            tmp.path().join("ids.block"),
+            &[request(1, "/a"), request(2, "/b"), request(1, "/c")],
+        );
+        match result {
+            Err(Error::DuplicateId(1)) => {}
+            r => panic!("DuplicateId expected, got: {:?}", r.err()),
+        }
+
+        let result = Block::from_files(
+            tmp.path().join("locations.block"),
+            &[request(1, "/a"), request(2, "/a")],
+        );
+        match result {
+            Err(Error::DuplicateLocation(location)) => assert_eq!(location, "/a"),
+            r => panic!("DuplicateLocation expected, got: {:?}", r.err()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_normalize_locations_at_create_and_lookup_time() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "content")?;
+        let request = |id, location| AddFileRequest {
+            id,
+            path: &file_path,
+            location: Path::new(location),
+            content_hash: None,
+            size: None,
+        };
+        let mut options = BlockOptions::new();
+        options.location_normalization(Normalization::POSIX | Normalization::LOWERCASE);
+
+        let block = options.create(
+            tmp.path().join("normalized.block"),
+            &[request(1, "/Img//./a.jpg"), request(2, "/img/b/../B.jpg")],
+        )?;
+        assert_eq!(
+            block.header().flags(),
+            FLAG_NORMALIZE_POSIX | FLAG_NORMALIZE_LOWERCASE
+        );
+        assert_eq!(block.file_by_id(1)?.0.location, b"/img/a.jpg");
+        for (location, id) in [("/img/a.jpg", 1), ("/IMG/a.JPG", 1), ("/img/./b.jpg", 2)].iter() {
+            assert_eq!(block.find_by_location(location).map(|i| i.id), Some(*id));
+        }
+
+        let result = options.create(
+            tmp.path().join("duplicates.block"),
+            &[request(1, "/a"), request(2, "//A")],
+        );
+        match result {
+            Err(Error::DuplicateLocation(location)) => assert_eq!(location, "/a"),
+            r => panic!("DuplicateLocation expected, got: {:?}", r.err()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_convert_windows_paths_to_portable_locations() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "content")?;
+        let request = |id, location| AddFileRequest {
+            id,
+            path: &file_path,
+            location: Path::new(location),
+            content_hash: None,
+            size: None,
+        };
+        let mut options = BlockOptions::new();
+        options.location_normalization(Normalization::WINDOWS | Normalization::POSIX);
+
+        let block_path = tmp.path().join("windows.block");
+        options.create(
+            &block_path,
+            &[
+                request(1, "C:\\img\\a.jpg"),
+                request(2, "\\\\server\\share\\img\\.\\b.jpg"),
+            ],
+        )?;
+
+        // Блок читается так же, как если бы он был создан из путей POSIX
+        let block = Block::from_bytes(std::fs::read(&block_path)?)?;
+        assert_eq!(
+            block.normalization(),
+            Normalization::WINDOWS | Normalization::POSIX
+        );
+        assert_eq!(block.file_by_id(1)?.0.location, b"/img/a.jpg");
+        assert_eq!(block.file_by_id(2)?.0.location, b"/img/b.jpg");
+        let lookups = [("/img/a.jpg", 1), ("D:\\img\\a.jpg", 1), ("/img/b.jpg", 2)];
+        for (location, id) in lookups.iter() {
+            assert_eq!(block.find_by_location(location).map(|i| i.id), Some(*id));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_blocks_opened_with_advice() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "content")?;
+        let block_path = tmp.path().join("advised.block");
+        Block::from_files(
+            &block_path,
+            &[AddFileRequest {
+                id: 1,
+                path: &file_path,
+                location: Path::new("/one.txt"),
+                content_hash: None,
+                size: None,
+            }],
+        )?;
+
+        let advices = [
+            Advice::Normal,
+            Advice::Random,
+            Advice::Sequential,
+            Advice::WillNeed,
+            Advice::Populate,
+        ];
+        for advice in advices.iter() {
+            let block = Block::options().advice(*advice).open(&block_path)?;
+            assert_eq!(block.file_by_id(1)?.1, &b"content"[..]);
+            block.release_pages()?;
+            assert_eq!(block.file_by_id(1)?.1, &b"content"[..]);
+        }
+
+        // Для блоков в памяти рекомендации игнорируются
+        let block = Block::from_bytes(std::fs::read(&block_path)?)?;
+        block.advise(Advice::Sequential)?;
+        block.release_pages()?;
+        assert_eq!(block.file_by_id(1)?.1, &b"content"[..]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn should_store_non_utf8_locations() -> Result<()> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let name = OsStr::from_bytes(b"caf\xE9.txt");
+        let file_path = tmp.path().join(name);
+        std::fs::write(&file_path, "content")?;
+        let location = Path::new("/").join(name);
+        let block = Block::from_files(
+            tmp.path().join("test.block"),
+            &[AddFileRequest {
+                id: 1,
+                path: &file_path,
+                location: &location,
+                content_hash: None,
+                size: None,
+            }],
+        )?;
+
+        let (header, _) = block.file_by_id(1)?;
+        assert_eq!(header.location, b"/caf\xE9.txt");
+        assert_eq!(header.display_location(), "/caf\u{FFFD}.txt");
+        assert_eq!(header.location_os_str(), Some(location.as_os_str()));
+        assert_eq!(
+            block.find_by_location(b"/caf\xE9.txt").map(|i| i.id),
+            Some(1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_headers_violating_invariants() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
+        let bytes = block.data.bytes().unwrap().to_vec();
+        // Заголовок версии 1: версия (2 байта), количество файлов (4 байта), затем записи FileInfo
+        let offset_field = |idx: usize| {
+            let start = 6 + idx * FileInfo::encoded_len(0) as usize + 8 + 4;
+            start..start + 4
+        };
+
+        let mut out_of_bounds = bytes.clone();
+        out_of_bounds[offset_field(1)].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Block::from_bytes(out_of_bounds).is_err());
+
+        let mut inside_header = bytes.clone();
+        inside_header[offset_field(0)].copy_from_slice(&4u32.to_le_bytes());
+        assert!(Block::from_bytes(inside_header).is_err());
+
+        let mut overlapping = bytes;
+        let first_offset = block.iter().next().unwrap().offset;
+        overlapping[offset_field(1)].copy_from_slice(&(first_offset + 1).to_le_bytes());
+        match Block::from_bytes(overlapping) {
+            Err(Error::BlockCorrupted { details, .. }) => assert!(details.contains("overlap")),
+            Err(e) => panic!("BlockCorrupted expected, got: {:?}", e),
+            Ok(_) => panic!("BlockCorrupted expected"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_check_precomputed_hashes_and_sizes() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let path = tmp.path().join("a.txt");
+        std::fs::write(&path, "Hello")?;
+        let request = |content_hash, size| AddFileRequest {
+            id: 1,
+            path: &path,
+            location: Path::new("/a.txt"),
+            content_hash: Some(content_hash),
+            size,
+        };
+        let valid = md5::compute("Hello");
+        let invalid = md5::compute("World");
+
+        let block = Block::from_files(tmp.path().join("1.block"), &[request(valid, Some(5))])?;
+        assert_eq!(block.file_by_id(1)?.1, &b"Hello"[..]);
+
+        let block_path = tmp.path().join("2.block");
+        assert!(Block::from_files(&block_path, &[request(invalid, None)]).is_err());
+        assert!(Block::from_files(&block_path, &[request(valid, Some(4))]).is_err());
+        assert!(BlockOptions::new()
+            .stream(&mut vec![], &[request(invalid, None)])
+            .is_err());
+
+        // Доверенная контрольная сумма не сверяется с содержимым, а размер сверяется
+        let mut options = BlockOptions::new();
+        options.trust_precomputed(true);
+        assert!(options
+            .create(&block_path, &[request(valid, Some(4))])
+            .is_err());
+        let block = options.create(&block_path, &[request(invalid, None)])?;
+        assert!(block.verify_all().iter().all(|r| r.result.is_err()));
+
+        let mut bytes = vec![];
+        options.stream(&mut bytes, &[request(valid, Some(5))])?;
+        let block = Block::from_bytes(bytes)?;
+        assert!(block.verify_all().iter().all(|r| r.result.is_ok()));
+        Ok(())
+    }
+
+    #[test]
+    fn should_create_block_from_readers() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let block_path = tmp.path().join("test.block");
+        let mut options = BlockOptions::new();
+        options.location_normalization(Normalization::LOWERCASE);
+        let files = vec![
+            AddReaderRequest::new(1, "/A.txt", &b"Hello"[..], Some(5)),
+            AddReaderRequest::new(2, "/b.txt", io::repeat(b'x').take(3000), None),
+        ];
+        let block = options.create_from_readers(&block_path, files)?;
+        assert_eq!(block.file_by_location("/a.txt")?.1, &b"Hello"[..]);
+        assert_eq!(block.file_by_id(2)?.1, &vec![b'x'; 3000][..]);
+
+        let block_path = tmp.path().join("invalid.block");
+        let files = vec![AddReaderRequest::new(1, "/a.txt", &b"Hello"[..], Some(4))];
+        assert!(options.create_from_readers(&block_path, files).is_err());
+        assert!(!block_path.exists());
+
+        let files = vec![
+            AddReaderRequest::new(1, "/a.txt", &b""[..], None),
+            AddReaderRequest::new(2, "/A.txt", &b""[..], None),
+        ];
+        assert!(matches!(
+            options.create_from_readers(&block_path, files),
+            Err(Error::DuplicateLocation(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_create_block_from_iterator() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let paths = (0..3)
+            .map(|idx| {
+                let path = tmp.path().join(format!("{}.txt", idx));
+                std::fs::write(&path, format!("content {}", idx))?;
+                Ok((path, PathBuf::from(format!("/{}.txt", idx))))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let dir = (tmp.path().join("dir"), PathBuf::from("/dir"));
+        std::fs::create_dir(&dir.0)?;
+        let requests = paths
+            .iter()
+            .chain(Some(&dir))
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location,
+                content_hash: None,
+                size: None,
+            });
+
+        let block_path = tmp.path().join("test.block");
+        let block = BlockOptions::new().create_from_iter(&block_path, requests)?;
+        assert_eq!(block.len(), 4);
+        assert!(block.header().has_directories());
+        assert_eq!(block.file_by_location("/2.txt")?.1, &b"content 2"[..]);
+        assert!(matches!(
+            block.entry_kind(&block.file_by_id(4)?.0, &block.file_by_id(4)?.1)?,
+            EntryKind::Directory { .. }
+        ));
+
+        let duplicates = paths.iter().map(|(path, location)| AddFileRequest {
+            id: 1,
+            path,
+            location,
+            content_hash: None,
+            size: None,
+        });
+        let block_path = tmp.path().join("duplicates.block");
+        assert!(matches!(
+            BlockOptions::new().create_from_iter(&block_path, duplicates),
+            Err(Error::DuplicateId(1))
+        ));
+        assert!(!block_path.exists());
+        assert!(matches!(
+            BlockOptions::new().create_from_iter(&block_path, vec![]),
+            Err(Error::NoFilesInBlock)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_streamed_block() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let mut paths = vec![];
+        // Файл больше STREAM_BUFFER_LIMIT читается дважды, остальные – однократно
+        let large = vec![7; STREAM_BUFFER_LIMIT as usize + 1];
+        let contents = [&b"Hello"[..], b"World", b"Hello", &large];
+        for (name, content) in ["a", "b", "c", "d"].iter().zip(contents.iter()) {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, content)?;
+            paths.push(path);
+        }
+        let locations = ["/a", "/b", "/c", "/d"];
+        let requests = paths
+            .iter()
+            .zip(locations.iter())
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+
+        for packed in &[false, true] {
+            let mut bytes = vec![];
+            let written = BlockOptions::new()
+                .packed(*packed)
+                .dedup(true)
+                .stream(&mut bytes, &requests)?;
+            assert_eq!(written, bytes.len() as u64);
+
+            let block = Block::from_bytes(bytes)?;
+            assert!(block.header().is_streamed());
+            assert_eq!(block.header().is_packed(), *packed);
+            assert!(!block.needs_repair());
+            assert_eq!(block.file_by_id(2)?.1, &b"World"[..]);
+            assert_eq!(block.file_by_id(3)?.1, &b"Hello"[..]);
+            assert_eq!(block.file_by_id(4)?.1, &large[..]);
+            assert_eq!(
+                block.iter().next().unwrap().offset,
+                block.iter().nth(2).unwrap().offset
+            );
+            assert!(block.verify_all().iter().all(|r| r.result.is_ok()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_block_metadata_from_stream() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
+        let bytes = block.data.bytes().unwrap().to_vec();
+
+        let (header, file_headers) = BlockHeader::read_from_stream(&mut &bytes[..], false)?;
+        assert_eq!(&header, block.header());
+        assert!(file_headers.is_empty());
+
+        let (_, file_headers) = BlockHeader::read_from_stream(&mut &bytes[..], true)?;
+        let locations = file_headers
+            .iter()
+            .map(|h| &h.location[..])
+            .collect::<Vec<_>>();
+        assert_eq!(locations, [&b"/1.bin"[..], b"/2.bin"]);
+
+        // Поток, оборвавшийся посреди содержимого файлов
+        let truncated = &bytes[..block.iter().nth(1).unwrap().offset as usize];
+        assert!(BlockHeader::read_from_stream(&mut &truncated[..], true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_store_locations_in_header() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let contents = ["Hello", "World", "Hello"];
+        let locations = ["/a", "/b", "/copy-of-a"];
+        let paths = contents
+            .iter()
+            .enumerate()
+            .map(|(idx, content)| {
+                let path = tmp.path().join(format!("{}.txt", idx));
+                std::fs::write(&path, content).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let requests = paths
+            .iter()
+            .zip(locations.iter())
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+        let mut options = BlockOptions::new();
+        options.dedup(true).manifest(true).header_locations(true);
+
+        let block = options.create(tmp.path().join("test.block"), &requests)?;
+        assert!(block.header().has_header_locations());
+        let file_headers = block.header().file_headers().unwrap();
+        let stored = file_headers
+            .iter()
+            .map(|h| location::display(&h.location).into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(stored, ["/a", "/b", "/copy-of-a", "/.blocky/manifest.json"]);
+        // Копия дедуплицированного файла хранит свой location, но общую контрольную сумму
+        for (entry, copy) in block.entries().zip(file_headers) {
+            assert_eq!(entry.header()?.hash, copy.hash);
+        }
+        assert!(block.verify_all().iter().all(|r| r.result.is_ok()));
+
+        // Для получения location достаточно заголовка блока
+        let bytes = block.data.bytes().unwrap();
+        let header_only = &bytes[..block.header().encoded_len() as usize];
+        let (_, from_stream) = BlockHeader::read_from_stream(&mut &header_only[..], true)?;
+        assert_eq!(&from_stream[..], file_headers);
+
+        let mut streamed = vec![];
+        options.manifest(false).stream(&mut streamed, &requests)?;
+        let streamed = Block::from_bytes(streamed)?;
+        assert_eq!(streamed.header().file_headers(), Some(&file_headers[..3]));
+
+        let plain = fixture(&[("1.bin", "Hello")])?;
+        assert_eq!(plain.header().file_headers(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn sparse_block_should_have_zero_padding() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        // Копия второго файла отбрасывается при дедупликации, и третий файл пишется поверх нее
+        let contents = ["Hello, long content", "Hello, long content", "Hi", "Bye"];
+        let locations = ["/0", "/1", "/2", "/3"];
+        let paths = contents
+            .iter()
+            .enumerate()
+            .map(|(idx, content)| {
+                let path = tmp.path().join(format!("{}.txt", idx));
+                std::fs::write(&path, content).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let requests = paths
+            .iter()
+            .zip(locations.iter())
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+
+        let block_path = tmp.path().join("test.block");
+        let block = BlockOptions::new()
+            .dedup(true)
+            .sparse(true)
+            .create(&block_path, &requests)?;
+        assert_eq!(block.file_by_id(3)?.1, &b"Hi"[..]);
+        assert_eq!(block.file_by_id(4)?.1, &b"Bye"[..]);
+
+        let bytes = block.data.bytes().unwrap();
+        let mut ranges = block
+            .iter()
+            .map(|info| {
+                let start = info.offset as usize;
+                (
+                    start,
+                    start + FILE_HEADER_FIXED_SIZE as usize + 2 + info.size as usize,
+                )
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_unstable();
+        ranges.dedup();
+        let mut gap_start = block.header().encoded_len() as usize;
+        for (start, end) in ranges {
+            assert!(bytes[gap_start..start].iter().all(|b| *b == 0));
+            gap_start = end;
+        }
+        assert_eq!(gap_start, bytes.len());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn compressed_block_should_support_range_reads() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let large = (0..200_000u32)
+            .map(|i| (i % 7) as u8 + b'a')
+            .collect::<Vec<_>>();
+        let (large_path, copy_path) = (tmp.path().join("large"), tmp.path().join("copy"));
+        std::fs::write(&large_path, &large)?;
+        std::fs::write(&copy_path, &large)?;
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &large_path,
+                location: Path::new("/large"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &copy_path,
+                location: Path::new("/copy"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+
+        let mut options = BlockOptions::new();
+        options.compress(true).dedup(true);
+        let block = options.create(tmp.path().join("compressed.block"), &files)?;
+        let mut streamed = vec![];
+        options.stream(&mut streamed, &files)?;
+        let streamed = Block::from_bytes(streamed)?;
+
+        for block in [block, streamed].iter() {
+            assert!(block.header().is_compressed());
+            let file_info = block.header().file_info();
+            // Дедупликация выполняется по исходному содержимому
+            assert_eq!(file_info[0].offset, file_info[1].offset);
+            assert!((file_info[0].size as usize) < large.len());
+            assert!(block.verify_all().iter().all(|v| v.result.is_ok()));
+
+            assert_eq!(block.file_by_id(2)?.1, &large[..]);
+            assert_eq!(
+                block.read_range(1, 70_000, 100_000)?,
+                &large[70_000..170_000]
+            );
+            match block.read_range(1, 150_000, 50_001) {
+                Err(Error::RangeOutOfBounds { size: 200_000, .. }) => {}
+                r => panic!("RangeOutOfBounds expected, got: {:?}", r.err()),
+            }
+        }
         Ok(())
     }
-    fn decode(source: &mut impl ReadBytesExt) -> Result<Self> {
-        let mut hash = [0u8; 16];
-        source.read_exact(&mut hash)?;
-        let location_length = source.read_u16::<LE>()?;
-        let mut utf8 = vec![0u8; location_length as usize];
-        source.read_exact(&mut utf8)?;
 
-        Ok(Self {
-            hash: md5::Digest(hash),
-            location: String::from_utf8(utf8).chain_err(|| "Unable to decode file location")?,
-        })
-    }
-}
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn should_reject_compressed_table_expanding_too_much() -> Result<()> {
+        // Небольшой сжатый блок метаинформации распаковывается в миллион одинаковых записей
+        let count = 1_000_000u32;
+        let records = vec![0u8; count as usize * 32];
+        let compressed = zstd::bulk::compress(&records, 19)?;
+        let mut bytes = vec![];
+        bytes.write_u16::<LE>(2)?;
+        bytes.write_u32::<LE>(FLAG_COMPRESSED_HEADER)?;
+        bytes.write_u32::<LE>(count)?;
+        bytes.write_u32::<LE>(compressed.len() as u32)?;
+        bytes.extend_from_slice(&compressed);
+        bytes.resize(bytes.len() + 1024, 0);
 
-/// Округляет целое беззнаковое целое `value` до следующего кратного `base`.
-///
-/// Например:
-/// ```rust
-/// use blocky::block::round_up_to;
-/// assert_eq!(round_up_to(10, 12), 12);
-/// assert_eq!(round_up_to(12, 12), 12);
-/// assert_eq!(round_up_to(13, 12), 24);
-///
-/// assert_eq!(round_up_to(1, 2048), 2048);
-/// assert_eq!(round_up_to(2048, 2048), 2048);
-/// assert_eq!(round_up_to(2049, 2048), 2 * 2048);
-/// assert_eq!(round_up_to(2 * 2048 + 1, 2048), 3 * 2048);
-/// ```
-pub fn round_up_to(value: u32, base: u32) -> u32 {
-    let reminder = value % base;
-    if reminder == 0 {
-        value
-    } else {
-        value + base - reminder
+        let source_len = bytes.len() as u64;
+        let limits = DecodeLimits::unlimited();
+        match BlockHeader::decode_limited(&mut &bytes[..], source_len, &limits) {
+            Err(Error::BlockCorrupted { .. }) => {}
+            r => panic!(
+                "BlockCorrupted expected, got: {:?}",
+                r.map(|h| h.file_info.len())
+            ),
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn should_compress_file_info_table() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let paths = (0..200)
+            .map(|idx| {
+                let path = tmp.path().join(format!("{}.txt", idx));
+                std::fs::write(&path, format!("content {}", idx))?;
+                Ok((path, PathBuf::from(format!("/{}.txt", idx))))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let files = paths
+            .iter()
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location,
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
 
-    use super::*;
-    use std::fs::File;
-    use std::io::{Cursor, Write};
-    use tempdir;
+        let mut options = BlockOptions::new();
+        options.packed(true).header_trailer(true).reserve_entries(5);
+        let mut plain = vec![];
+        options.stream(&mut plain, &files)?;
+        options.compress_header(true);
+        let planned = options.plan(&files)?;
+        let block = options.create(tmp.path().join("test.block"), &files)?;
+        let mut streamed = vec![];
+        options.stream(&mut streamed, &files)?;
+        // Хеши location не сжимаются, поэтому записи сжимаются не больше чем вдвое
+        assert!(streamed.len() < plain.len() - 200 * 32 / 4);
+        let streamed = Block::from_bytes(streamed)?;
 
-    fn fixture(files: &[(impl AsRef<Path>, impl AsRef<[u8]>)]) -> Result<Block> {
+        assert_eq!(planned.header_size, block.header().encoded_len());
+        assert!(block.reserved_entries() >= 5);
+        for block in [&block, &streamed] {
+            assert!(block.header().has_compressed_header());
+            assert!(!block.needs_repair());
+            assert_eq!(block.len(), 200);
+            assert_eq!(block.file_by_id(150)?.1, &b"content 149"[..]);
+            assert_eq!(block.file_by_location("/7.txt")?.1, &b"content 7"[..]);
+        }
+
+        let bytes = std::fs::read(tmp.path().join("test.block"))?;
+        let (header, file_headers) = BlockHeader::read_from_stream(&mut &bytes[..], true)?;
+        assert_eq!(&header, block.header());
+        assert_eq!(file_headers[10].location, b"/10.txt");
+        // Копия заголовка в конце блока занимает меньше места, чем несжатые записи
+        let trailer_len = bytes.len() - trailer_start(&bytes).unwrap() - TRAILER_FIXED_SIZE;
+        assert!(trailer_len < 200 * 32 * 3 / 4);
+        assert!(matches!(
+            crate::lazy::LazyBlock::from_storage(bytes),
+            Err(Error::UnsupportedFeature(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn should_encrypt_and_decrypt_existing_blocks() -> Result<()> {
         let tmp = tempdir::TempDir::new("rust-block-test")?;
-        let tmp = tmp.path();
+        let (a, b, c) = (
+            tmp.path().join("a"),
+            tmp.path().join("b"),
+            tmp.path().join("c"),
+        );
+        std::fs::write(&a, "secret")?;
+        std::fs::write(&b, "secret")?;
+        std::fs::write(&c, "another secret")?;
+        let files = [(1, &a, "/a"), (2, &b, "/b"), (3, &c, "/c")]
+            .iter()
+            .map(|&(id, path, location)| AddFileRequest {
+                id,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+        let plain = BlockOptions::new()
+            .dedup(true)
+            .create(tmp.path().join("plain.block"), &files)?;
 
-        let mut absolute_file_names = vec![];
-        let mut locations = vec![];
-        let root = Path::new("/");
+        let key = EncryptionKey::generate();
+        let mut encrypted = BlockOptions::new()
+            .dedup(true)
+            .encryption_key(Some(key.clone()))
+            .rewrite(&plain, tmp.path().join("encrypted.block"))?;
+        assert!(encrypted.header().is_encrypted());
+        assert!(!encrypted
+            .data
+            .bytes()
+            .unwrap()
+            .windows(6)
+            .any(|w| w == b"secret"));
+        // Целостность проверяется без ключа
+        assert!(encrypted.verify_all().iter().all(|v| v.result.is_ok()));
+        match encrypted.file_by_id(1) {
+            Err(Error::KeyRequired) => {}
+            r => panic!("KeyRequired expected, got: {:?}", r.err()),
+        }
 
-        for (file_name, content) in files {
-            let path = tmp.join(file_name);
-            let mut file = File::create(&path)?;
-            file.write_all(content.as_ref())?;
+        encrypted.decryption_key(EncryptionKey::generate());
+        match encrypted.file_by_id(1) {
+            Err(Error::DecryptionFailed) => {}
+            r => panic!("DecryptionFailed expected, got: {:?}", r.err()),
+        }
 
-            locations.push(root.join(file_name));
-            absolute_file_names.push(path);
+        encrypted.decryption_key(key).verify_on_read(true);
+        assert_eq!(encrypted.read_range(3, 8, 6)?, &b"secret"[..]);
+        let decrypted = BlockOptions::new()
+            .dedup(true)
+            .rewrite(&encrypted, tmp.path().join("decrypted.block"))?;
+        assert!(!decrypted.header().is_encrypted());
+        assert_eq!(decrypted.header().file_info(), plain.header().file_info());
+        for id in 1..=3 {
+            assert_eq!(decrypted.file_by_id(id)?, plain.file_by_id(id)?);
+            assert_eq!(encrypted.file_by_id(id)?.1, plain.file_by_id(id)?.1);
         }
+        assert!(decrypted.find_by_location("/b").is_some());
+        Ok(())
+    }
 
-        let add_requests = absolute_file_names
+    #[test]
+    fn should_resolve_location() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let files = [("/a", "Hello"), ("/b", "World"), ("/copy-of-a", "Hello")];
+        let paths = files
             .iter()
-            .zip(locations.iter())
             .enumerate()
-            .map(|(id, (path, location))| AddFileRequest {
-                id: (id + 1) as u64,
+            .map(|(idx, (_, content))| {
+                let path = tmp.path().join(format!("{}.txt", idx));
+                std::fs::write(&path, content).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let requests = files
+            .iter()
+            .zip(paths.iter())
+            .enumerate()
+            .map(|(idx, ((location, _), path))| AddFileRequest {
+                id: idx as u64 + 1,
                 path,
-                location,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
             })
             .collect::<Vec<_>>();
 
-        let block_path = tmp.join("test.block");
-        Block::from_files(&block_path, &add_requests)?;
-        Ok(Block::open(&block_path)?)
+        for header_locations in [false, true] {
+            let path = tmp.path().join(format!("{}.block", header_locations));
+            let mut block = BlockOptions::new()
+                .dedup(true)
+                .header_locations(header_locations)
+                .create(path, &requests)?;
+
+            let (header, content) = block.file_by_location("/b")?;
+            assert_eq!(header.location, b"/b");
+            assert_eq!(&content[..], b"World");
+            // Заголовок перед содержимым дедуплицированного файла хранит location "/a"
+            assert_eq!(block.resolve_location("/copy-of-a")?.id, 3);
+            match block.resolve_location("/missing") {
+                Err(Error::LocationNotFound(location)) => assert_eq!(location, "/missing"),
+                r => panic!("LocationNotFound expected, got: {:?}", r),
+            }
+
+            // Коллизию можно обнаружить, только если location записан в заголовке блока
+            if header_locations {
+                block.header.file_info[1].location_hash = md5::compute("/colliding");
+                assert!(block.find_by_location("/colliding").is_some());
+                match block.file_by_location("/colliding") {
+                    Err(Error::LocationHashCollision { id: 2, location }) => {
+                        assert_eq!(location, "/colliding")
+                    }
+                    r => panic!("LocationHashCollision expected, got: {:?}", r.map(|_| ())),
+                }
+            }
+        }
+        Ok(())
     }
 
     #[test]
-    #[should_panic]
-    fn block_could_not_be_created_twice() {
-        let block_path_name = "./target/test.block";
-        Block::from_files(
-            block_path_name,
-            &[AddFileRequest {
-                id: 1,
-                path: Path::new("./foo"),
-                location: Path::new("./foo"),
-            }],
-        )
-        .unwrap();
+    fn should_assign_ids() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let a = tmp.path().join("a.txt");
+        let b = tmp.path().join("b.txt");
+        std::fs::write(&a, "Hello")?;
+        std::fs::write(&b, "World")?;
 
-        Block::from_files(
-            block_path_name,
-            &[AddFileRequest {
-                id: 1,
-                path: Path::new("./foo"),
-                location: Path::new("./foo"),
-            }],
-        )
-        .unwrap();
+        let ids = |order: &[(&Path, &str)], assignment: IdAssignment| -> Result<Vec<u64>> {
+            let mut files = order
+                .iter()
+                .map(|(path, location)| AddFileRequest {
+                    id: 0,
+                    path,
+                    location: Path::new(location),
+                    content_hash: None,
+                    size: None,
+                })
+                .collect::<Vec<_>>();
+            assignment.assign(&mut files, Normalization::LOWERCASE)?;
+            Ok(files.iter().map(|file| file.id).collect())
+        };
+        let forward = [(a.as_path(), "/A"), (b.as_path(), "/B")];
+        let backward = [(b.as_path(), "/B"), (a.as_path(), "/A")];
+
+        assert_eq!(ids(&forward, IdAssignment::Sequential)?, [1, 2]);
+        assert_eq!(ids(&backward, IdAssignment::Sequential)?, [1, 2]);
+
+        // "Hello" – 8b1a9953c4611296a827abf8c47804d7
+        let by_content = ids(&forward, IdAssignment::ContentHash)?;
+        assert_eq!(by_content[0], 0x8b1a_9953_c461_1296);
+        let mut reversed = ids(&backward, IdAssignment::ContentHash)?;
+        reversed.reverse();
+        assert_eq!(by_content, reversed);
+
+        // Хеш вычисляется по нормализованному location
+        let by_location = ids(&forward, IdAssignment::LocationHash)?;
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&md5::compute("/a").0[..8]);
+        assert_eq!(by_location[0], u64::from_be_bytes(prefix));
+        let mut reversed = ids(&backward, IdAssignment::LocationHash)?;
+        reversed.reverse();
+        assert_eq!(by_location, reversed);
+        Ok(())
     }
 
     #[test]
-    fn should_be_able_to_create_block_and_return_file_info() -> Result<()> {
-        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
-        assert_eq!(block.len(), 2);
+    fn should_record_block_metadata() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file = tmp.path().join("file.txt");
+        std::fs::write(&file, "content")?;
+        let files = [AddFileRequest {
+            id: 1,
+            path: &file,
+            location: Path::new("/file"),
+            content_hash: None,
+            size: None,
+        }];
+        let started = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
 
-        let info = block.iter().collect::<Vec<_>>();
-        assert!(info.iter().all(|i| i.id > 0));
+        let mut options = BlockOptions::new();
+        options.metadata(true).header_trailer(true);
+        let block = options.create(tmp.path().join("a.block"), &files)?;
+        let metadata = block.header().metadata().unwrap();
+        assert_eq!(metadata.created_by, CREATED_BY);
+        assert!(metadata.created >= started);
+        assert_eq!(block.file_by_id(1)?.1, &b"content"[..]);
 
-        assert_eq!(info[0].size, 5);
-        assert_eq!(
-            format!("{:x}", info[0].location_hash),
-            "d0e14e5f5e76ec1a00e5fb02e4b47d9a"
+        let mut streamed = vec![];
+        options.stream(&mut streamed, &files)?;
+        let block = Block::from_bytes(streamed)?;
+        assert_eq!(block.header().metadata().unwrap().created_by, CREATED_BY);
+        assert_eq!(block.file_by_id(1)?.1, &b"content"[..]);
+
+        let block = BlockOptions::new().create(tmp.path().join("b.block"), &files)?;
+        assert!(block.header().metadata().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn should_create_content_addressed_block() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let write = |name: &str, content: &str| -> std::io::Result<PathBuf> {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, content).map(|_| path)
+        };
+        let (a, b, c) = (
+            write("a", "Hello")?,
+            write("b", "World")?,
+            write("c", "Hello")?,
         );
+        let files = [(&a, "/a"), (&b, "/b"), (&c, "/c")];
+        let requests = files
+            .iter()
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
 
-        assert_eq!(info[1].size, 5);
+        let mut options = BlockOptions::new();
+        options.content_addressed(true);
+        let block = options.create(tmp.path().join("cas.block"), &requests)?;
+        assert!(block.header().is_content_addressed());
+        // Файл "/c" с тем же содержимым, что и "/a", пропущен
+        let ids = block.iter().map(|info| info.id).collect::<Vec<_>>();
+        let hello = md5::compute("Hello");
+        assert_eq!(ids, [hash_id(&hello), hash_id(&md5::compute("World"))]);
+
+        let (header, content) = block.file_by_content_hash(&md5::compute("World"))?;
+        assert_eq!(header.location, b"/b");
+        assert_eq!(&content[..], b"World");
+        match block.file_by_content_hash(&md5::compute("missing")) {
+            Err(Error::ContentNotFound(hash)) => {
+                assert_eq!(hash, format!("{:x}", md5::compute("missing")))
+            }
+            r => panic!("ContentNotFound expected, got: {:?}", r.map(|_| ())),
+        }
+        // В остальных блоках контрольные суммы перебираются
+        let plain = BlockOptions::new().create(tmp.path().join("plain.block"), &requests)?;
+        assert_eq!(plain.file_by_content_hash(&hello)?.0.location, b"/a");
+
+        // Разностный блок содержит только новое содержимое, независимо от location
+        let (d, e) = (write("d", "Hello")?, write("e", "New")?);
+        let delta_files = [(&d, "/d"), (&e, "/e")];
+        let delta_requests = delta_files
+            .iter()
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 10,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+        let delta = Block::delta_from(&block, tmp.path().join("delta.block"), &delta_requests)?;
+        assert!(delta.header().is_content_addressed());
+        assert_eq!(delta.len(), 1);
         assert_eq!(
-            format!("{:x}", info[1].location_hash),
-            "475e9b6e16f464efea93b8312b90ec02"
+            delta.file_by_content_hash(&md5::compute("New"))?.0.location,
+            b"/e"
         );
-
         Ok(())
     }
 
     #[test]
-    fn should_be_able_to_return_block_content() -> Result<()> {
-        let content = "text-content";
-        let block = fixture(&[("one.txt", content)])?;
-        let (header, bytes) = block.file_at(0).unwrap();
+    fn should_create_block_with_wide_ids() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let files = [("/a", "Hello"), ("/b", "World")];
+        let paths = files
+            .iter()
+            .enumerate()
+            .map(|(idx, (_, content))| {
+                let path = tmp.path().join(format!("{}.txt", idx));
+                std::fs::write(&path, content).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        // UUIDv7 и идентификатор, помещающийся в 64 бита
+        let ids = [0x0189_9b4a_8f10_7c3a_9d2e_4b5f_6a7c_8d9e_u128, 7];
+        let requests = files
+            .iter()
+            .zip(paths.iter())
+            .zip(ids.iter())
+            .map(|(((location, _), path), id)| {
+                let request = AddFileRequest {
+                    id: 0,
+                    path,
+                    location: Path::new(location),
+                    content_hash: None,
+                    size: None,
+                };
+                (*id, request)
+            })
+            .collect::<Vec<_>>();
 
-        let expected_hash = md5::compute(content);
-        assert_eq!(expected_hash, md5::compute(bytes));
-        assert_eq!(expected_hash, header.hash);
+        let block_path = tmp.path().join("wide.block");
+        BlockOptions::new()
+            .manifest(true)
+            .create_wide(&block_path, &requests)?;
+        let block = Block::open(&block_path)?;
+        assert!(block.header().has_wide_ids());
+        let wide_ids = block
+            .header()
+            .file_info()
+            .iter()
+            .map(FileInfo::wide_id)
+            .collect::<Vec<_>>();
+        assert_eq!(wide_ids, [ids[0], ids[1], u128::from(MANIFEST_ID)]);
+
+        let (header, content) = block.file_by_wide_id(ids[0])?;
+        assert_eq!(header.location, b"/a");
+        assert_eq!(&content[..], b"Hello");
+        assert_eq!(&block.file_by_id(7)?.1[..], b"World");
+        assert!(block.contains_wide_id(ids[0]));
+        // Младшие 64 бита не идентифицируют файл
+        assert!(!block.contains_id(ids[0] as u64));
+        match block.file_by_wide_id(ids[0] + 1) {
+            Err(Error::FileNotFound { id }) => assert_eq!(id, ids[0] + 1),
+            r => panic!("FileNotFound expected, got: {:?}", r.map(|_| ())),
+        }
+        let manifest = String::from_utf8(block.file_by_id(MANIFEST_ID)?.1.into_owned()).unwrap();
+        assert!(manifest.contains(&format!("{{\"id\":{},", ids[0])));
+
+        assert_eq!(&block.read_range_wide(ids[0], 1, 3)?[..], b"ell");
+        let copy_path = tmp.path().join("copy.txt");
+        block.copy_entry_to_wide(ids[0], &File::create(&copy_path)?)?;
+        assert_eq!(std::fs::read(&copy_path)?, b"Hello");
+        let subset = block.subset_to_wide(&[ids[0]], tmp.path().join("subset.block"))?;
+        assert!(subset.header().has_wide_ids());
+        assert_eq!(&subset.file_by_wide_id(ids[0])?.1[..], b"Hello");
+        let diff = block.diff(&subset)?;
+        assert_eq!(diff.removed, [ids[1]]);
+        assert!(diff.added.is_empty());
+        let plan = BlockOptions::new().plan_wide(&requests)?;
+        let planned = plan.entries.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(planned, ids);
 
+        let duplicate = requests
+            .iter()
+            .map(|(_, request)| {
+                let request = AddFileRequest {
+                    id: request.id,
+                    path: request.path,
+                    location: request.location,
+                    content_hash: request.content_hash,
+                    size: request.size,
+                };
+                (ids[0], request)
+            })
+            .collect::<Vec<_>>();
+        match BlockOptions::new().create_wide(tmp.path().join("duplicate.block"), &duplicate) {
+            Err(Error::DuplicateId(id)) => assert_eq!(id, ids[0]),
+            r => panic!("DuplicateId expected, got: {:?}", r.map(|_| ())),
+        }
         Ok(())
     }
 
     #[test]
-    fn should_be_able_to_return_file_by_id() -> Result<()> {
-        let content = "text-content";
-        let block = fixture(&[("one.txt", content)])?;
-        // Файлы нумеруются последовательно, поэтому у первого файла id = 1
-        let (_, bytes) = block.file_by_id(1).unwrap();
+    fn should_diff_blocks() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let create = |name: &str, files: &[(u64, &str, &str)]| -> Result<Block> {
+            let paths = files
+                .iter()
+                .map(|(id, _, content)| {
+                    let path = tmp.path().join(format!("{}-{}.txt", name, id));
+                    std::fs::write(&path, content).map(|_| path)
+                })
+                .collect::<std::io::Result<Vec<_>>>()?;
+            let requests = files
+                .iter()
+                .zip(paths.iter())
+                .map(|((id, location, _), path)| AddFileRequest {
+                    id: *id,
+                    path,
+                    location: Path::new(location),
+                    content_hash: None,
+                    size: None,
+                })
+                .collect::<Vec<_>>();
+            BlockOptions::new()
+                .manifest(true)
+                .create(tmp.path().join(format!("{}.block", name)), &requests)
+        };
+        let old = create("old", &[(1, "/a", "a"), (2, "/b", "b"), (3, "/c", "c")])?;
+        let new = create("new", &[(2, "/b", "B"), (3, "/moved", "c"), (4, "/d", "d")])?;
+
+        let diff = old.diff(&new)?;
+        assert_eq!(
+            diff,
+            BlockDiff {
+                added: vec![4],
+                removed: vec![1],
+                changed_content: vec![2],
+                changed_location: vec![3],
+            }
+        );
+        assert!(old.diff(&old)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn delta_should_contain_only_new_and_changed_files() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let write = |name: &str, content: &str| -> Result<PathBuf> {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, content)?;
+            Ok(path)
+        };
+        let (a, b) = (write("a", "same")?, write("b", "old")?);
+        let base = Block::from_files(
+            tmp.path().join("base.block"),
+            &[
+                AddFileRequest {
+                    id: 1,
+                    path: &a,
+                    location: Path::new("/a"),
+                    content_hash: None,
+                    size: None,
+                },
+                AddFileRequest {
+                    id: 2,
+                    path: &b,
+                    location: Path::new("/b"),
+                    content_hash: None,
+                    size: None,
+                },
+            ],
+        )?;
+
+        let (b, c) = (write("b", "new")?, write("c", "added")?);
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &a,
+                location: Path::new("/a"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &b,
+                location: Path::new("/b"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 3,
+                path: &c,
+                location: Path::new("/c"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+        let delta = Block::delta_from(&base, tmp.path().join("delta.block"), &files)?;
+        assert_eq!(delta.iter().map(|f| f.id).collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(delta.file_by_id(2)?.1, &b"new"[..]);
 
-        assert_eq!(content, String::from_utf8_lossy(bytes));
+        let unchanged = Block::delta_from(&base, tmp.path().join("empty.block"), &files[..1]);
+        assert!(matches!(unchanged, Err(Error::NoFilesInBlock)));
         Ok(())
     }
 
@@ -464,24 +7577,231 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_be_able_to_read_block_from_memory() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "in-memory")?;
+        let block_path = tmp.path().join("test.block");
+        let requests = [AddFileRequest {
+            id: 7,
+            path: &file_path,
+            location: Path::new("/one.txt"),
+            content_hash: None,
+            size: None,
+        }];
+        Block::from_files(&block_path, &requests)?;
+        let bytes = std::fs::read(&block_path)?;
+
+        let header = BlockHeader::read_from(&bytes)?;
+        assert_eq!(header.file_info().len(), 1);
+        assert_eq!(header.file_info()[0].id, 7);
+
+        let block = Block::from_bytes(bytes)?;
+        let (header, content) = block.file_by_id(7).unwrap();
+        assert_eq!(header.location, b"/one.txt");
+        assert_eq!(content, &b"in-memory"[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_be_able_to_verify_block_content() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World"), ("3.bin", "!")])?;
+        let results = block.verify_all_parallel(2);
+
+        assert_eq!(results.iter().map(|r| r.id).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(results.iter().all(|r| r.result.is_ok()));
+        Ok(())
+    }
+
+    #[test]
+    fn should_detect_corrupted_content() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
+        let offset = block.iter().nth(1).unwrap().offset as usize;
+        let mut bytes = block.data.bytes().unwrap().to_vec();
+        let last_content_byte = offset + FILE_HEADER_FIXED_SIZE as usize + "/2.bin".len() + 4;
+        bytes[last_content_byte] ^= 0xFF;
+
+        let block = Block::from_bytes(bytes)?;
+        let results = block.verify_all_parallel(4);
+        assert!(results[0].result.is_ok());
+        match &results[1].result {
+            Err(Error::ChecksumMismatch { id: 2 }) => {}
+            r => panic!("Checksum mismatch expected, got: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_verify_content_in_chunks() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello, World!"), ("2.bin", "")])?;
+        let mut chunks = vec![];
+        block.verify_at_with(0, 5, |bytes| chunks.push(bytes))?;
+        assert_eq!(chunks, [5, 5, 3]);
+
+        chunks.clear();
+        block.verify_at_with(1, 5, |bytes| chunks.push(bytes))?;
+        assert!(chunks.is_empty());
+
+        let offset = block.iter().next().unwrap().offset as usize;
+        let mut bytes = block.data.bytes().unwrap().to_vec();
+        bytes[offset + FILE_HEADER_FIXED_SIZE as usize + "/1.bin".len()] ^= 0xFF;
+        let block = Block::from_bytes(bytes)?;
+        match block.verify_at_with(0, 5, |_| {}) {
+            Err(Error::ChecksumMismatch { id: 1 }) => {}
+            r => panic!("Checksum mismatch expected, got: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_verify_content_on_read_when_requested() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
+        let offset = block.iter().nth(1).unwrap().offset as usize;
+        let mut bytes = block.data.bytes().unwrap().to_vec();
+        bytes[offset + FILE_HEADER_FIXED_SIZE as usize + "/2.bin".len()] ^= 0xFF;
+
+        let mut block = Block::from_bytes(bytes)?;
+        assert!(block.file_by_id(2).is_ok());
+
+        block.verify_on_read(true);
+        assert_eq!(block.file_by_id(1)?.1, &b"Hello"[..]);
+        match block.file_by_id(2) {
+            Err(Error::ChecksumMismatch { id: 2 }) => {}
+            r => panic!("Checksum mismatch expected, got: {:?}", r.err()),
+        }
+        match block.entries().nth(1).unwrap().content() {
+            Err(Error::ChecksumMismatch { id: 2 }) => {}
+            r => panic!("Checksum mismatch expected, got: {:?}", r.err()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_missing_and_out_of_bounds_files() -> Result<()> {
+        let block = fixture(&[("1.bin", "Hello"), ("2.bin", "World")])?;
+        match block.file_at(2) {
+            Err(Error::IndexOutOfRange { idx: 2, len: 2 }) => {}
+            r => panic!("IndexOutOfRange expected, got: {:?}", r),
+        }
+        match block.file_by_id(3) {
+            Err(Error::FileNotFound { id: 3 }) => {}
+            r => panic!("FileNotFound expected, got: {:?}", r),
+        }
+
+        // Длина location в заголовке второго файла выходит за пределы блока
+        let offset = block.iter().nth(1).unwrap().offset;
+        let mut bytes = block.data.bytes().unwrap().to_vec();
+        let location_len = offset as usize + 16;
+        bytes[location_len..location_len + 2].copy_from_slice(&[0xFF, 0xFF]);
+
+        let block = Block::from_bytes(bytes)?;
+        assert_eq!(block.file_at(0)?.1, &b"Hello"[..]);
+        match block.file_by_id(2) {
+            Err(Error::EntryOutOfBounds {
+                id: 2,
+                offset: o,
+                size: 5,
+            }) if o == offset => {}
+            r => panic!("EntryOutOfBounds expected, got: {:?}", r),
+        }
+        assert!(block.verify_at(1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_enforce_decode_limits() -> Result<()> {
+        // Заголовок объявляет u32::MAX файлов, хотя блок содержит только 10 байт
+        let bytes = b"\x01\x00\xFF\xFF\xFF\xFF\x00\x00\x00\x00".to_vec();
+        assert!(Block::from_bytes(bytes).is_err());
+
+        let tmp = tempdir::TempDir::new("rust-block-test")?;
+        let file_path = tmp.path().join("one.txt");
+        std::fs::write(&file_path, "untrusted")?;
+        let location = format!("/{}", "a".repeat(5000));
+        let block_path = tmp.path().join("test.block");
+        let requests = [AddFileRequest {
+            id: 1,
+            path: &file_path,
+            location: Path::new(&location),
+            content_hash: None,
+            size: None,
+        }];
+        Block::from_files(&block_path, &requests)?;
+
+        assert!(Block::open(&block_path)?.file_at(0).is_ok());
+        match Block::open_untrusted(&block_path)?.file_at(0) {
+            Err(Error::DecodeLimitExceeded(_)) => {}
+            r => panic!("DecodeLimitExceeded expected, got: {:?}", r),
+        }
+
+        let limits = DecodeLimits {
+            max_header_len: 16,
+            ..DecodeLimits::untrusted()
+        };
+        let data = Box::new(std::fs::read(&block_path)?);
+        match Block::from_data(data, limits) {
+            Err(Error::DecodeLimitExceeded(_)) => {}
+            Err(e) => panic!("DecodeLimitExceeded expected, got: {:?}", e),
+            Ok(_) => panic!("DecodeLimitExceeded expected"),
+        }
+        Ok(())
+    }
+
     #[test]
     fn read_write_header() -> Result<()> {
         test_read_write_cycle(&BlockHeader {
-            version: 3,
+            version: 2,
+            flags: FLAG_PACKED,
             file_info: vec![FileInfo {
                 id: 1,
+                id_high: 0,
                 size: 15,
                 offset: 0,
                 location_hash: md5::Digest([0u8; 16]),
             }],
-        })
+            file_headers: vec![],
+            metadata: None,
+        })?;
+        test_read_write_cycle(&BlockHeader {
+            version: 2,
+            flags: FLAG_HEADER_LOCATIONS | FLAG_METADATA,
+            file_info: vec![FileInfo::new_at_offset(1, b"/file", 64, 15)],
+            file_headers: vec![FileHeader {
+                hash: md5::compute("content"),
+                location: b"/file".to_vec(),
+            }],
+            metadata: Some(BlockMetadata {
+                created: 1_700_000_000,
+                created_by: "blocky 0.1.0".to_string(),
+            }),
+        })?;
+        let wide_header = BlockHeader {
+            version: 2,
+            flags: FLAG_WIDE_IDS,
+            file_info: vec![FileInfo {
+                id_high: 42,
+                ..FileInfo::new_at_offset(1, b"/file", 64, 15)
+            }],
+            file_headers: vec![],
+            metadata: None,
+        };
+        test_read_write_cycle(&wide_header)?;
+        assert_eq!(wide_header.encoded_len(), 10 + 40);
+
+        // Без флага старшие биты идентификатора записать некуда
+        let narrow_header = BlockHeader::new(0, wide_header.file_info);
+        match narrow_header.encode(&mut vec![]) {
+            Err(Error::FormatLimitExceeded(_)) => Ok(()),
+            r => panic!("FormatLimitExceeded expected, got: {:?}", r),
+        }
     }
 
     #[test]
     fn read_write_file_block() -> Result<()> {
         test_read_write_cycle(&FileHeader {
             hash: md5::compute("string"),
-            location: String::from("/foo/bar"),
+            location: b"/foo/bar".to_vec(),
         })
     }
 
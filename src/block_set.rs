@@ -0,0 +1,662 @@
+//! Наборы блоков, размер каждого из которых ограничен.
+//!
+//! [`BlockSetBuilder`] распределяет файлы по нескольким блокам так, чтобы размер каждого блока
+//! не превышал заданного. Блоки именуются по шаблону (см. [`PathPattern`]), например
+//! `out-%03d.block` дает `out-001.block`, `out-002.block` и т.д.
+//!
+//! [`BlockSetWriter`] принимает файлы по одному в течение неограниченного времени (например, в
+//! сервисе загрузки файлов) и закрывает очередной блок, когда он достигает заданного размера или
+//! возраста.
+//!
+//! [`BlockSetBuilder`]: struct.BlockSetBuilder.html
+//! [`BlockSetWriter`]: struct.BlockSetWriter.html
+//! [`PathPattern`]: struct.PathPattern.html
+use crate::block::{validate_unique, AddFileRequest, BlockOptions, BlockWriter};
+use crate::errors::*;
+use crate::location;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Шаблон имен блоков набора, содержащий номер блока в формате `printf`: `%d` или `%0Nd`
+/// (номер дополняется нулями до `N` цифр). Последовательность `%%` обозначает символ `%`.
+///
+/// ```rust
+/// use blocky::block_set::PathPattern;
+/// use std::path::Path;
+/// let pattern = PathPattern::parse("out-%03d.block").unwrap();
+/// assert_eq!(pattern.path(7), Path::new("out-007.block"));
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PathPattern {
+    prefix: String,
+    width: usize,
+    suffix: String,
+}
+
+impl PathPattern {
+    /// Разбирает шаблон. Шаблон должен содержать ровно один номер, иначе возвращается
+    /// [`Error::InvalidPathPattern`].
+    ///
+    /// [`Error::InvalidPathPattern`]: ../errors/enum.Error.html#variant.InvalidPathPattern
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let invalid = || Error::InvalidPathPattern(pattern.to_string());
+        let mut parts = (String::new(), String::new());
+        let mut width = None;
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            let part = if width.is_none() {
+                &mut parts.0
+            } else {
+                &mut parts.1
+            };
+            if c != '%' {
+                part.push(c);
+                continue;
+            }
+            if chars.peek() == Some(&'%') {
+                chars.next();
+                part.push('%');
+                continue;
+            }
+            if width.is_some() {
+                return Err(invalid());
+            }
+            let mut digits = String::new();
+            while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                digits.push(digit);
+            }
+            if chars.next() != Some('d') || (!digits.is_empty() && !digits.starts_with('0')) {
+                return Err(invalid());
+            }
+            width = Some(digits.parse().unwrap_or(0));
+        }
+        Ok(Self {
+            prefix: parts.0,
+            width: width.ok_or_else(invalid)?,
+            suffix: parts.1,
+        })
+    }
+
+    /// Путь к блоку с номером `index`
+    pub fn path(&self, index: usize) -> PathBuf {
+        PathBuf::from(format!(
+            "{}{:0width$}{}",
+            self.prefix,
+            index,
+            self.suffix,
+            width = self.width
+        ))
+    }
+}
+
+/// Блоки, созданные [`BlockSetBuilder`]
+///
+/// [`BlockSetBuilder`]: struct.BlockSetBuilder.html
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct BlockSet {
+    blocks: Vec<PathBuf>,
+    block_by_id: HashMap<u64, usize>,
+}
+
+impl BlockSet {
+    /// Пути к блокам набора в порядке создания
+    pub fn blocks(&self) -> &[PathBuf] {
+        &self.blocks
+    }
+
+    /// Путь к блоку, в который записан файл с идентификатором `id`
+    pub fn block_of(&self, id: u64) -> Option<&Path> {
+        self.block_by_id
+            .get(&id)
+            .map(|&idx| self.blocks[idx].as_path())
+    }
+
+    /// Идентификаторы файлов и номера блоков (в [`blocks`]), в которые они записаны
+    ///
+    /// [`blocks`]: #method.blocks
+    pub fn ids(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.block_by_id.iter().map(|(&id, &idx)| (id, idx))
+    }
+}
+
+/// Создает набор блоков, размер каждого из которых не превышает `max_block_size` байт.
+///
+/// Файлы записываются в блоки в порядке следования: когда очередной файл не умещается в
+/// текущий блок, начинается следующий. Размер блока оценивается по размерам файлов без учета
+/// сжатия и дедупликации, поэтому фактический размер блока может быть меньше ограничения.
+///
+/// ```no_run
+/// # use blocky::block::{AddFileRequest, BlockOptions};
+/// # use blocky::block_set::BlockSetBuilder;
+/// # let files: Vec<AddFileRequest> = vec![];
+/// let set = BlockSetBuilder::new(&BlockOptions::new(), 1 << 30).create("out-%03d.block", &files);
+/// ```
+pub struct BlockSetBuilder<'a> {
+    options: &'a BlockOptions,
+    max_block_size: u64,
+}
+
+impl<'a> BlockSetBuilder<'a> {
+    /// Блоки набора создаются с параметрами `options`. Смещения в блоке 32-битные, поэтому
+    /// `max_block_size` в любом случае не превышает 4 ГБ.
+    pub fn new(options: &'a BlockOptions, max_block_size: u64) -> Self {
+        Self {
+            options,
+            max_block_size: max_block_size.min(u64::from(u32::MAX)),
+        }
+    }
+
+    /// Создает блоки с именами по шаблону `path_pattern` (см. [`PathPattern`]) и возвращает
+    /// распределение файлов по блокам. Номера блоков начинаются с 1.
+    ///
+    /// Если какой-либо из блоков уже существует, ни один блок не создается. Если файл не
+    /// умещается в блок даже один, возвращается [`Error::FormatLimitExceeded`]. При ошибке
+    /// записи уже созданные блоки набора удаляются.
+    ///
+    /// [`PathPattern`]: struct.PathPattern.html
+    /// [`Error::FormatLimitExceeded`]: ../errors/enum.Error.html#variant.FormatLimitExceeded
+    pub fn create(&self, path_pattern: &str, files: &[AddFileRequest]) -> Result<BlockSet> {
+        let pattern = PathPattern::parse(path_pattern)?;
+        if files.is_empty() {
+            return Err(Error::NoFilesInBlock);
+        }
+        validate_unique(files, self.options)?;
+
+        let groups = self.split(files)?;
+        let blocks = (1..=groups.len())
+            .map(|idx| pattern.path(idx))
+            .collect::<Vec<_>>();
+        if let Some(existing) = blocks.iter().find(|path| path.exists()) {
+            return Err(Error::BlockFileAlreadyExists(existing.clone()));
+        }
+
+        let mut block_by_id = HashMap::new();
+        for (idx, (group, path)) in groups.iter().zip(blocks.iter()).enumerate() {
+            if let Err(e) = self.options.create(path, group) {
+                for created in blocks[..idx].iter() {
+                    let _ = fs::remove_file(created);
+                }
+                return Err(e);
+            }
+            block_by_id.extend(group.iter().map(|file| (file.id, idx)));
+        }
+        Ok(BlockSet {
+            blocks,
+            block_by_id,
+        })
+    }
+
+    /// Разбивает файлы на группы, каждая из которых умещается в один блок
+    fn split<'f>(&self, files: &'f [AddFileRequest<'f>]) -> Result<Vec<Vec<AddFileRequest<'f>>>> {
+        let mut groups = vec![];
+        let mut group: Vec<AddFileRequest> = vec![];
+        let mut entries_size = 0;
+        for file in files {
+            let location_len = file.entry_location(self.options.normalization())?.len();
+            let entry_size = self.options.estimated_entry_size(location_len, file.len()?);
+            let block_size = |count, size| self.options.estimated_overhead(count) + size;
+            if !group.is_empty()
+                && block_size(group.len() + 1, entries_size + entry_size) > self.max_block_size
+            {
+                groups.push(std::mem::take(&mut group));
+                entries_size = 0;
+            }
+            if block_size(1, entry_size) > self.max_block_size {
+                return Err(Error::FormatLimitExceeded(format!(
+                    "file {} doesn't fit into a block of {} bytes",
+                    file.path.display(),
+                    self.max_block_size
+                )));
+            }
+            group.push(AddFileRequest { ..*file });
+            entries_size += entry_size;
+        }
+        groups.push(group);
+        Ok(groups)
+    }
+}
+
+/// Пишет файлы в последовательность блоков, закрывая очередной блок, когда он достигает
+/// размера `max_block_size` байт (см. [`BlockSetBuilder`]) или возраста [`max_age`].
+///
+/// Количество файлов блока должно быть известно до начала его записи, поэтому добавляемые
+/// файлы сначала накапливаются во временном файле `<блок>.staging` рядом с блоком, а блок
+/// записывается целиком при закрытии. После закрытия каждого блока вызывается `on_sealed` с
+/// путем к нему.
+///
+/// Блоки именуются по шаблону (см. [`PathPattern`]) с номерами, начиная с 1; номера уже
+/// существующих блоков пропускаются, так что после перезапуска запись продолжается в новые
+/// блоки. Файлы, не попавшие в закрытый блок, при уничтожении писателя без вызова [`finish`]
+/// теряются.
+///
+/// ```no_run
+/// # use blocky::block::BlockOptions;
+/// # use blocky::block_set::BlockSetWriter;
+/// # fn main() -> blocky::errors::Result<()> {
+/// let mut writer = BlockSetWriter::new(BlockOptions::new(), "ingest-%06d.block", 1 << 30, |path| {
+///     println!("sealed: {}", path.display());
+/// })?;
+/// writer.add(1, b"/a.jpg", &b"content"[..])?;
+/// writer.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`BlockSetBuilder`]: struct.BlockSetBuilder.html
+/// [`PathPattern`]: struct.PathPattern.html
+/// [`max_age`]: #method.max_age
+/// [`finish`]: #method.finish
+pub struct BlockSetWriter<F: FnMut(&Path)> {
+    options: BlockOptions,
+    pattern: PathPattern,
+    max_block_size: u64,
+    max_age: Option<Duration>,
+    max_files: Option<usize>,
+    on_sealed: F,
+    next_index: usize,
+    staging: Option<Staging>,
+}
+
+/// Файлы очередного блока, накопленные во временном файле
+struct Staging {
+    block_path: PathBuf,
+    path: PathBuf,
+    file: File,
+    len: u64,
+    entries: Vec<StagedEntry>,
+    ids: HashSet<u64>,
+    location_hashes: HashSet<md5::Digest>,
+    entries_size: u64,
+    started: Instant,
+}
+
+struct StagedEntry {
+    id: u64,
+    location: Vec<u8>,
+    offset: u64,
+    len: u64,
+}
+
+impl<F: FnMut(&Path)> BlockSetWriter<F> {
+    pub fn new(
+        options: BlockOptions,
+        path_pattern: &str,
+        max_block_size: u64,
+        on_sealed: F,
+    ) -> Result<Self> {
+        Ok(Self {
+            options,
+            pattern: PathPattern::parse(path_pattern)?,
+            max_block_size: max_block_size.min(u64::from(u32::MAX)),
+            max_age: None,
+            max_files: None,
+            on_sealed,
+            next_index: 1,
+            staging: None,
+        })
+    }
+
+    /// Максимальное время между добавлением первого файла блока и закрытием блока. Возраст
+    /// проверяется при добавлении файлов и при вызове [`seal_expired`].
+    ///
+    /// [`seal_expired`]: #method.seal_expired
+    pub fn max_age(&mut self, max_age: Duration) -> &mut Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Максимальное количество файлов в блоке: блок закрывается, как только в него добавлено
+    /// `max_files` файлов.
+    pub fn max_files(&mut self, max_files: usize) -> &mut Self {
+        self.max_files = Some(max_files.max(1));
+        self
+    }
+
+    /// Добавляет файл, содержимое которого читается из `reader`. Location нормализуется по
+    /// правилам, заданным в параметрах блоков (см. [`BlockOptions::location_normalization`]).
+    ///
+    /// Идентификаторы и location должны быть уникальны в пределах блока. Файл, который не
+    /// умещается в блок даже один, не добавляется, и возвращается
+    /// [`Error::FormatLimitExceeded`].
+    ///
+    /// [`BlockOptions::location_normalization`]: ../block/struct.BlockOptions.html#method.location_normalization
+    /// [`Error::FormatLimitExceeded`]: ../errors/enum.Error.html#variant.FormatLimitExceeded
+    pub fn add(&mut self, id: u64, location: &[u8], mut reader: impl Read) -> Result<()> {
+        let location = self.options.normalization().apply(location).into_owned();
+        let location_hash = md5::compute(&location);
+        if let Some(staging) = &self.staging {
+            if staging.ids.contains(&id) {
+                return Err(Error::DuplicateId(id.into()));
+            }
+            if staging.location_hashes.contains(&location_hash) {
+                return Err(Error::DuplicateLocation(
+                    location::display(&location).into_owned(),
+                ));
+            }
+        }
+
+        let staging = match self.staging.as_mut() {
+            Some(staging) => staging,
+            None => {
+                let staging = self.start_staging()?;
+                self.staging.get_or_insert(staging)
+            }
+        };
+        let offset = staging.len;
+        let mut writer = BufWriter::new(&staging.file);
+        writer.seek(SeekFrom::Start(offset))?;
+        let len = io::copy(&mut reader, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+
+        let options = &self.options;
+        let entry_size = options.estimated_entry_size(location.len(), len);
+        let count = staging.entries.len();
+        let block_size = |count, size| options.estimated_overhead(count) + size;
+        if block_size(1, entry_size) > self.max_block_size {
+            staging.file.set_len(offset)?;
+            return Err(Error::FormatLimitExceeded(format!(
+                "file {} doesn't fit into a block of {} bytes",
+                location::display(&location),
+                self.max_block_size
+            )));
+        }
+        staging.len += len;
+        staging.ids.insert(id);
+        staging.location_hashes.insert(location_hash);
+        staging.entries.push(StagedEntry {
+            id,
+            location,
+            offset,
+            len,
+        });
+        staging.entries_size += entry_size;
+
+        if block_size(count + 1, staging.entries_size) > self.max_block_size {
+            // Блок переполнен только что добавленным файлом: он переносится в следующий блок
+            self.seal_first(count)?;
+        }
+        let count = self.staging.as_ref().map_or(0, |s| s.entries.len());
+        if self.max_files.is_some_and(|max_files| count >= max_files) {
+            self.seal()?;
+        }
+        self.seal_expired()?;
+        Ok(())
+    }
+
+    /// Закрывает текущий блок, если он старше [`max_age`]. Позволяет закрывать блоки по
+    /// времени, когда новые файлы не поступают.
+    ///
+    /// [`max_age`]: #method.max_age
+    pub fn seal_expired(&mut self) -> Result<()> {
+        let expired = match (&self.staging, self.max_age) {
+            (Some(staging), Some(max_age)) => staging.started.elapsed() >= max_age,
+            _ => false,
+        };
+        if expired {
+            self.seal()?;
+        }
+        Ok(())
+    }
+
+    /// Закрывает текущий блок, если в нем есть файлы
+    pub fn seal(&mut self) -> Result<()> {
+        let count = self.staging.as_ref().map_or(0, |s| s.entries.len());
+        self.seal_first(count)
+    }
+
+    /// Закрывает текущий блок и завершает запись
+    pub fn finish(mut self) -> Result<()> {
+        self.seal()
+    }
+
+    /// Записывает в блок первые `count` накопленных файлов. Остальные файлы переносятся в
+    /// следующий блок.
+    fn seal_first(&mut self, count: usize) -> Result<()> {
+        let mut staging = match self.staging.take() {
+            Some(staging) => staging,
+            None => return Ok(()),
+        };
+        if count > 0 {
+            let options = &self.options;
+            let block_path = staging.block_path.clone();
+            let entries = &staging.entries[..count];
+            let result = options.write_atomically(&block_path, |tmp_file| {
+                let locations = entries.iter().map(|entry| &entry.location[..]);
+                let mut writer = BlockWriter::with_target(options, tmp_file, locations)?;
+                for entry in entries {
+                    writer.add(entry.id, &entry.location, staging.reader(entry)?)?;
+                }
+                writer.finish().map(drop)
+            });
+            if let Err(e) = result {
+                self.staging = Some(staging);
+                return Err(e);
+            }
+            (self.on_sealed)(&block_path);
+        }
+
+        let remaining = staging.entries.split_off(count);
+        if !remaining.is_empty() {
+            let mut next = self.start_staging()?;
+            for entry in remaining {
+                let mut writer = BufWriter::new(&next.file);
+                writer.seek(SeekFrom::Start(next.len))?;
+                io::copy(&mut staging.reader(&entry)?, &mut writer)?;
+                writer.flush()?;
+                drop(writer);
+                next.entries_size += self
+                    .options
+                    .estimated_entry_size(entry.location.len(), entry.len);
+                next.ids.insert(entry.id);
+                next.location_hashes.insert(md5::compute(&entry.location));
+                next.entries.push(StagedEntry {
+                    offset: next.len,
+                    ..entry
+                });
+                next.len += entry.len;
+            }
+            self.staging = Some(next);
+        }
+        drop(staging);
+        Ok(())
+    }
+
+    /// Начинает накопление файлов следующего блока
+    fn start_staging(&mut self) -> Result<Staging> {
+        while self.pattern.path(self.next_index).exists() {
+            self.next_index += 1;
+        }
+        let block_path = self.pattern.path(self.next_index);
+        self.next_index += 1;
+
+        let mut path = OsString::from(block_path.as_os_str());
+        path.push(".staging");
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Staging {
+            block_path,
+            path,
+            file,
+            len: 0,
+            entries: vec![],
+            ids: HashSet::new(),
+            location_hashes: HashSet::new(),
+            entries_size: 0,
+            started: Instant::now(),
+        })
+    }
+}
+
+impl Staging {
+    fn reader(&self, entry: &StagedEntry) -> io::Result<impl Read + '_> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        Ok(file.take(entry.len))
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::Block;
+
+    #[test]
+    fn should_parse_path_patterns() -> Result<()> {
+        assert_eq!(PathPattern::parse("b%d")?.path(12), Path::new("b12"));
+        assert_eq!(
+            PathPattern::parse("%%%04d%%")?.path(12),
+            Path::new("%0012%")
+        );
+        for pattern in ["out.block", "%d-%d", "%3d", "%x", "100%"].iter() {
+            match PathPattern::parse(pattern) {
+                Err(Error::InvalidPathPattern(_)) => {}
+                r => panic!("InvalidPathPattern expected for {}, got: {:?}", pattern, r),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_split_files_into_blocks_of_limited_size() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-set-test")?;
+        let paths = (1..=5)
+            .map(|id| {
+                let path = tmp.path().join(format!("{}.bin", id));
+                fs::write(&path, vec![id as u8; 3000]).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let locations = (1..=5).map(|id| format!("/{}", id)).collect::<Vec<_>>();
+        let files = paths
+            .iter()
+            .zip(locations.iter())
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+
+        // Заголовок занимает страницу, а каждый файл – 3 страницы, так что в блок умещается 2
+        // файла
+        let max_block_size = 7 * 1024;
+        let pattern = tmp.path().join("set-%02d.block");
+        let pattern = pattern.to_str().unwrap();
+        let options = BlockOptions::new();
+        let set = BlockSetBuilder::new(&options, max_block_size).create(pattern, &files)?;
+
+        let names = set
+            .blocks()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["set-01.block", "set-02.block", "set-03.block"]);
+        for path in set.blocks() {
+            assert!(fs::metadata(path)?.len() <= max_block_size);
+        }
+        for file in files.iter() {
+            let block = Block::open(set.block_of(file.id).unwrap())?;
+            assert_eq!(block.file_by_id(file.id)?.1.len(), 3000);
+        }
+        assert_eq!(set.block_of(5), Some(set.blocks()[2].as_path()));
+
+        match BlockSetBuilder::new(&options, 1024).create(pattern, &files) {
+            Err(Error::FormatLimitExceeded(_)) => {}
+            r => panic!("FormatLimitExceeded expected, got: {:?}", r),
+        }
+        match BlockSetBuilder::new(&options, max_block_size).create(pattern, &files) {
+            Err(Error::BlockFileAlreadyExists(_)) => {}
+            r => panic!("BlockFileAlreadyExists expected, got: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_roll_over_blocks_while_writing() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-block-set-test")?;
+        let pattern = tmp.path().join("ingest-%03d.block");
+        let pattern = pattern.to_str().unwrap();
+        let content = |id: u64| vec![id as u8; 3000];
+
+        let mut sealed = vec![];
+        let mut writer = BlockSetWriter::new(BlockOptions::new(), pattern, 7 * 1024, |path| {
+            sealed.push(path.to_path_buf())
+        })?;
+        for id in 1..=5 {
+            writer.add(id, format!("/{}", id).as_bytes(), &content(id)[..])?;
+        }
+        match writer.add(5, b"/other", &b""[..]) {
+            Err(Error::DuplicateId(5)) => {}
+            r => panic!("DuplicateId expected, got: {:?}", r),
+        }
+        match writer.add(6, b"/big", &vec![0; 8 * 1024][..]) {
+            Err(Error::FormatLimitExceeded(_)) => {}
+            r => panic!("FormatLimitExceeded expected, got: {:?}", r),
+        }
+        writer.finish()?;
+
+        let names = sealed
+            .iter()
+            .map(|p| p.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["ingest-001.block", "ingest-002.block", "ingest-003.block"]
+        );
+        let ids = sealed
+            .iter()
+            .map(|path| Ok(Block::open(path)?.iter().map(|i| i.id).collect()))
+            .collect::<Result<Vec<Vec<_>>>>()?;
+        assert_eq!(ids, vec![vec![1, 2], vec![3, 4], vec![5]]);
+        let block = Block::open(&sealed[2])?;
+        assert_eq!(block.file_by_id(5)?.1, &content(5)[..]);
+        // Временные файлы удаляются
+        assert_eq!(fs::read_dir(tmp.path())?.count(), 3);
+
+        // Блоки с возрастом 0 закрываются сразу, а номера существующих блоков пропускаются
+        let mut sealed = vec![];
+        let mut writer = BlockSetWriter::new(BlockOptions::new(), pattern, 7 * 1024, |path| {
+            sealed.push(path.to_path_buf())
+        })?;
+        writer.max_age(Duration::from_secs(0));
+        writer.add(1, b"/1", &b"one"[..])?;
+        writer.add(1, b"/1", &b"one"[..])?;
+        drop(writer);
+        assert_eq!(sealed.len(), 2);
+        assert!(sealed[0].ends_with("ingest-004.block"));
+        assert!(sealed[1].ends_with("ingest-005.block"));
+
+        // Блоки закрываются по количеству файлов
+        let mut sealed = vec![];
+        let mut writer = BlockSetWriter::new(BlockOptions::new(), pattern, 7 * 1024, |path| {
+            sealed.push(path.to_path_buf())
+        })?;
+        writer.max_files(2);
+        for id in 1..=3 {
+            writer.add(id, format!("/{}", id).as_bytes(), &b"small"[..])?;
+        }
+        drop(writer);
+        assert_eq!(sealed.len(), 1);
+        assert_eq!(Block::open(&sealed[0])?.len(), 2);
+        Ok(())
+    }
+}
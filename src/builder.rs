@@ -0,0 +1,175 @@
+//! Сборка блока целиком в памяти.
+//!
+//! [`BlockBuilder`] собирает блок из содержимого, уже находящегося в памяти, без временных
+//! файлов: например, в тестах или в сервисах, которые формируют небольшие блоки и сразу
+//! загружают их в объектное хранилище.
+//!
+//! ```
+//! # use blocky::builder::BlockBuilder;
+//! # use blocky::block::Block;
+//! let bytes = BlockBuilder::new()
+//!     .add(1, "/a.txt", "first")
+//!     .add(2, "/b.txt", "second")
+//!     .finish()?;
+//! let block = Block::from_bytes(bytes)?;
+//! assert_eq!(&block.file_by_location("/b.txt")?.1[..], b"second");
+//! # Ok::<(), blocky::errors::Error>(())
+//! ```
+//!
+//! [`BlockBuilder`]: struct.BlockBuilder.html
+use crate::block::{validate_unique_entries, Block, BlockOptions, BlockWriter};
+use crate::errors::*;
+use std::borrow::Cow;
+use std::io::Cursor;
+
+/// Блок, собираемый в памяти. Блок кодируется так же, как [`BlockOptions::create`], и с теми же
+/// параметрами.
+///
+/// [`BlockOptions::create`]: ../block/struct.BlockOptions.html#method.create
+#[derive(Default)]
+pub struct BlockBuilder {
+    options: BlockOptions,
+    /// Идентификатор, location и содержимое файлов в порядке добавления
+    files: Vec<(u64, Vec<u8>, Vec<u8>)>,
+}
+
+impl BlockBuilder {
+    /// Создает пустой блок с параметрами по умолчанию
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Создает пустой блок с параметрами `options`
+    pub fn with_options(options: &BlockOptions) -> Self {
+        Self {
+            options: options.clone(),
+            files: vec![],
+        }
+    }
+
+    /// Добавляет в блок файл с идентификатором `id`, location `location` и содержимым `content`
+    pub fn add(
+        &mut self,
+        id: u64,
+        location: impl AsRef<[u8]>,
+        content: impl Into<Vec<u8>>,
+    ) -> &mut Self {
+        self.files
+            .push((id, location.as_ref().to_vec(), content.into()));
+        self
+    }
+
+    /// Количество добавленных файлов
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Кодирует блок и возвращает его байты.
+    ///
+    /// Как и при создании блока из файлов, идентификаторы и нормализованные location файлов не
+    /// должны повторяться, а блок должен содержать хотя бы один файл.
+    pub fn finish(&self) -> Result<Vec<u8>> {
+        if self.files.is_empty() {
+            return Err(Error::NoFilesInBlock);
+        }
+        let normalization = self.options.normalization();
+        let locations = self
+            .files
+            .iter()
+            .map(|(_, location, _)| normalization.apply(location))
+            .collect::<Vec<_>>();
+        let entries = self.files.iter().zip(locations.iter());
+        validate_unique_entries(
+            entries
+                .map(|((id, _, _), location)| Ok((u128::from(*id), Cow::Borrowed(&location[..])))),
+            &self.options,
+        )?;
+
+        let target = Cursor::new(vec![]);
+        let locations_iter = locations.iter().map(|location| location.as_ref());
+        let mut writer = BlockWriter::with_target(&self.options, target, locations_iter)?;
+        for ((id, _, content), location) in self.files.iter().zip(locations.iter()) {
+            writer.add(*id, location, &content[..])?;
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+
+    /// Кодирует блок и открывает его (см. [`Block::from_bytes`])
+    ///
+    /// [`Block::from_bytes`]: ../block/struct.Block.html#method.from_bytes
+    pub fn build(&self) -> Result<Block> {
+        Block::from_bytes(self.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::AddFileRequest;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn should_build_same_block_as_from_files() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-builder-test")?;
+        let (a, b) = (tmp.path().join("a.txt"), tmp.path().join("b.txt"));
+        fs::write(&a, "first")?;
+        fs::write(&b, "second")?;
+        let files = [
+            AddFileRequest {
+                id: 1,
+                path: &a,
+                location: Path::new("/a.txt"),
+                content_hash: None,
+                size: None,
+            },
+            AddFileRequest {
+                id: 2,
+                path: &b,
+                location: Path::new("/b.txt"),
+                content_hash: None,
+                size: None,
+            },
+        ];
+        let mut options = BlockOptions::new();
+        options.header_trailer(true).dedup(true);
+        let block_path = tmp.path().join("test.block");
+        options.create(&block_path, &files)?;
+
+        let bytes = BlockBuilder::with_options(&options)
+            .add(1, "/a.txt", "first")
+            .add(2, "/b.txt", "second")
+            .finish()?;
+        assert_eq!(bytes, fs::read(&block_path)?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_duplicate_and_missing_files() {
+        assert!(matches!(
+            BlockBuilder::new().finish(),
+            Err(Error::NoFilesInBlock)
+        ));
+        assert!(matches!(
+            BlockBuilder::new()
+                .add(1, "/a", "")
+                .add(1, "/b", "")
+                .finish(),
+            Err(Error::DuplicateId(1))
+        ));
+        let mut options = BlockOptions::new();
+        options.location_normalization(crate::location::Normalization::LOWERCASE);
+        assert!(matches!(
+            BlockBuilder::with_options(&options)
+                .add(1, "/a", "")
+                .add(2, "/A", "")
+                .build(),
+            Err(Error::DuplicateLocation(_))
+        ));
+    }
+}
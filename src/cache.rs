@@ -0,0 +1,288 @@
+//! Локальный кеш диапазонов удаленных блоков.
+//!
+//! [`RangeCache`] сохраняет на диск диапазоны байт, прочитанные из источника с дорогим доступом
+//! (см. [`RangeRead`]), чтобы часто запрашиваемые файлы не загружались повторно при каждом
+//! запросе. Диапазон идентифицируется именем блока, смещением и длиной. Суммарный размер кеша
+//! ограничен, при превышении ограничения удаляются диапазоны, которые дольше всего не
+//! запрашивались (LRU). Содержимое кеша переживает перезапуск процесса: при открытии порядок
+//! использования восстанавливается по времени изменения файлов.
+//!
+//! Ошибки записи в кеш не прерывают чтение – диапазон в этом случае просто не кешируется.
+//!
+//! [`RangeCache`]: struct.RangeCache.html
+//! [`RangeRead`]: ../storage/trait.RangeRead.html
+use crate::errors::*;
+use crate::storage::RangeRead;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Кеш диапазонов байт блоков в директории с ограничением по размеру
+pub struct RangeCache {
+    dir: PathBuf,
+    max_size: u64,
+    state: Mutex<CacheState>,
+    tmp_counter: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    by_use: BTreeMap<u64, String>,
+    size: u64,
+    clock: u64,
+}
+
+struct CacheEntry {
+    len: u64,
+    last_used: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.by_use.remove(&entry.last_used);
+            entry.last_used = self.clock;
+            self.by_use.insert(self.clock, key.to_string());
+        }
+    }
+
+    fn insert(&mut self, key: String, len: u64) {
+        self.remove(&key);
+        self.clock += 1;
+        self.size += len;
+        self.by_use.insert(self.clock, key.clone());
+        let last_used = self.clock;
+        self.entries.insert(key, CacheEntry { len, last_used });
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.by_use.remove(&entry.last_used);
+                self.size -= entry.len;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Удаляет из кеша давно не использованные диапазоны, пока размер кеша превышает `max_size`,
+    /// и возвращает их ключи
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = vec![];
+        while self.size > max_size {
+            let key = match self.by_use.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            self.remove(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+impl RangeCache {
+    /// Открывает кеш в директории `dir`, создавая ее при необходимости. Суммарный размер
+    /// закешированных диапазонов не превышает `max_size` байт.
+    pub fn open(dir: impl AsRef<Path>, max_size: u64) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut files = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() || name.contains(".tmp") {
+                continue;
+            }
+            files.push((metadata.modified()?, name, metadata.len()));
+        }
+        files.sort();
+
+        let mut state = CacheState::default();
+        for (_, name, len) in files {
+            state.insert(name, len);
+        }
+        let cache = Self {
+            dir,
+            max_size,
+            state: Mutex::new(state),
+            tmp_counter: AtomicU64::new(0),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    /// Суммарный размер закешированных диапазонов в байтах
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    /// Оборачивает источник `source` блока `block` (например, URL или ключ объекта) в источник,
+    /// читающий диапазоны через кеш
+    pub fn source<'a, R: RangeRead>(&'a self, block: &str, source: R) -> CachedSource<'a, R> {
+        CachedSource {
+            cache: self,
+            prefix: format!("{:x}", md5::compute(block)),
+            source,
+        }
+    }
+
+    fn get(&self, key: &str, buf: &mut [u8]) -> bool {
+        let cached = match self.state.lock().unwrap().entries.get(key) {
+            Some(entry) => entry.len == buf.len() as u64,
+            None => false,
+        };
+        if !cached {
+            return false;
+        }
+        let read = File::open(self.dir.join(key)).and_then(|mut file| file.read_exact(buf));
+        let mut state = self.state.lock().unwrap();
+        match read {
+            Ok(()) => state.touch(key),
+            Err(_) => {
+                state.remove(key);
+            }
+        }
+        read.is_ok()
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let len = data.len() as u64;
+        if len > self.max_size {
+            return Ok(());
+        }
+        let tmp = self.dir.join(format!(
+            "{}.tmp{}",
+            key,
+            self.tmp_counter.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, self.dir.join(key))?;
+        self.state.lock().unwrap().insert(key.to_string(), len);
+        self.evict();
+        Ok(())
+    }
+
+    fn evict(&self) {
+        let evicted = self.state.lock().unwrap().evict(self.max_size);
+        for key in evicted {
+            let _ = fs::remove_file(self.dir.join(key));
+        }
+    }
+}
+
+/// Источник, читающий диапазоны через [`RangeCache`]. Создается методом [`RangeCache::source`].
+///
+/// [`RangeCache`]: struct.RangeCache.html
+/// [`RangeCache::source`]: struct.RangeCache.html#method.source
+pub struct CachedSource<'a, R> {
+    cache: &'a RangeCache,
+    prefix: String,
+    source: R,
+}
+
+impl<'a, R: RangeRead> RangeRead for CachedSource<'a, R> {
+    fn size(&self) -> io::Result<u64> {
+        self.source.size()
+    }
+
+    fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let key = format!("{}-{}-{}", self.prefix, offset, buf.len());
+        if self.cache.get(&key, buf) {
+            return Ok(());
+        }
+        self.source.read_range(offset, buf)?;
+        let _ = self.cache.put(&key, buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::cell::Cell;
+
+    struct CountingSource {
+        bytes: Vec<u8>,
+        reads: Cell<usize>,
+    }
+
+    impl RangeRead for CountingSource {
+        fn size(&self) -> io::Result<u64> {
+            self.bytes.size()
+        }
+
+        fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            self.reads.set(self.reads.get() + 1);
+            self.bytes.read_range(offset, buf)
+        }
+    }
+
+    fn read(source: &impl RangeRead, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        source.read_range(offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn should_read_ranges_through_cache() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-cache-test")?;
+        let cache = RangeCache::open(tmp.path(), 1024)?;
+        let remote = CountingSource {
+            bytes: (0..100).collect(),
+            reads: Cell::new(0),
+        };
+        let source = cache.source("https://example.com/1.block", &remote);
+
+        assert_eq!(read(&source, 10, 5)?, vec![10, 11, 12, 13, 14]);
+        assert_eq!(read(&source, 10, 5)?, vec![10, 11, 12, 13, 14]);
+        assert_eq!(remote.reads.get(), 1);
+        assert_eq!(cache.size(), 5);
+
+        read(&source, 10, 6)?;
+        assert_eq!(remote.reads.get(), 2);
+
+        let reopened = RangeCache::open(tmp.path(), 1024)?;
+        assert_eq!(reopened.size(), 11);
+        let source = reopened.source("https://example.com/1.block", &remote);
+        read(&source, 10, 5)?;
+        assert_eq!(remote.reads.get(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn should_evict_least_recently_used_ranges() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-cache-test")?;
+        let cache = RangeCache::open(tmp.path(), 20)?;
+        let remote = CountingSource {
+            bytes: (0..100).collect(),
+            reads: Cell::new(0),
+        };
+        let source = cache.source("1.block", &remote);
+
+        read(&source, 0, 10)?;
+        read(&source, 10, 10)?;
+        read(&source, 0, 10)?;
+        read(&source, 20, 10)?;
+        assert_eq!(cache.size(), 20);
+        assert_eq!(remote.reads.get(), 3);
+
+        read(&source, 0, 10)?;
+        assert_eq!(remote.reads.get(), 3);
+        read(&source, 10, 10)?;
+        assert_eq!(remote.reads.get(), 4);
+
+        read(&source, 30, 21)?;
+        assert_eq!(cache.size(), 20);
+        Ok(())
+    }
+}
@@ -0,0 +1,512 @@
+//! Каталог блоков директории.
+//!
+//! Каталог сопоставляет хеш location каждого файла с блоком, в котором он записан, и
+//! идентификатором файла в этом блоке, что позволяет найти файл среди множества блоков без
+//! открытия каждого из них. Каталог сохраняется в файл [`CATALOG_FILE_NAME`] в корне директории
+//! и обновляется инкрементально (см. [`Catalog::refresh`]): заново читаются только блоки,
+//! размер или время изменения которых отличаются от записанных в каталоге.
+//!
+//! Если один и тот же location записан в нескольких блоках, каталог возвращает блок, путь
+//! которого относительно корня директории больше (например, разностный блок `0002.block`
+//! перекрывает базовый `0001.block`).
+//!
+//! ## Формат
+//! ```text
+//! magic (4 байта, "BCAT") | version (2 байта) | blocks (4 байта)
+//! blocks × [
+//!   path_len (2 байта) | path | size (8 байт) | modified (8 байт, нс. от UNIX epoch)
+//!   | flags (4 байта) | entries (4 байта) | entries × [location_hash (16 байт) | id (8 байт)]
+//! ]
+//! ```
+//!
+//! [`CATALOG_FILE_NAME`]: constant.CATALOG_FILE_NAME.html
+//! [`Catalog::refresh`]: struct.Catalog.html#method.refresh
+use crate::block::{Block, SelfSerialize};
+use crate::errors::*;
+use crate::location::{self, Normalization};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind::NotFound, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const CATALOG_MAGIC: &[u8; 4] = b"BCAT";
+const CATALOG_VERSION: u16 = 1;
+
+/// Имя файла каталога в корне директории с блоками
+pub const CATALOG_FILE_NAME: &str = "blocky.catalog";
+
+/// Расширение файлов блоков, включаемых в каталог
+const BLOCK_EXTENSION: &str = "block";
+
+/// Файл, найденный в каталоге
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CatalogEntry {
+    /// Путь к блоку, содержащему файл
+    pub block_path: PathBuf,
+
+    /// Идентификатор файла в блоке
+    pub id: u64,
+}
+
+/// Результат обновления каталога (см. [`Catalog::refresh`])
+///
+/// [`Catalog::refresh`]: struct.Catalog.html#method.refresh
+#[derive(Debug, Default)]
+pub struct RefreshStats {
+    /// Количество блоков, добавленных в каталог
+    pub added: usize,
+
+    /// Количество блоков, изменившихся с момента предыдущего обновления
+    pub updated: usize,
+
+    /// Количество блоков, удаленных из директории или переставших читаться
+    pub removed: usize,
+
+    /// Блоки, которые не удалось прочитать. Такие блоки не включаются в каталог и читаются
+    /// повторно при каждом обновлении
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
+impl RefreshStats {
+    /// Изменился ли каталог при обновлении
+    pub fn is_changed(&self) -> bool {
+        self.added + self.updated + self.removed > 0
+    }
+}
+
+/// Блок, включенный в каталог
+#[derive(Debug, Eq, PartialEq)]
+struct CatalogBlock {
+    /// Путь к блоку относительно корня директории
+    path: PathBuf,
+    size: u64,
+    modified: u64,
+    normalization: Normalization,
+    entries: Vec<(md5::Digest, u64)>,
+}
+
+#[derive(Debug)]
+pub struct Catalog {
+    dir: PathBuf,
+
+    /// Блоки, отсортированные по пути
+    blocks: Vec<CatalogBlock>,
+
+    /// `(правила нормализации, location_hash) → (номер блока в blocks, id)`
+    by_location: HashMap<(Normalization, [u8; 16]), (usize, u64)>,
+}
+
+impl Catalog {
+    /// Открывает каталог директории `dir`.
+    ///
+    /// Если в директории есть сохраненный каталог, он читается и обновляется, иначе каталог
+    /// строится заново. Сохраненный каталог, который не удается прочитать, игнорируется.
+    /// Обновленный каталог не сохраняется автоматически (см. [`save`]).
+    ///
+    /// [`save`]: #method.save
+    pub fn open(dir: impl AsRef<Path>) -> Result<(Self, RefreshStats)> {
+        let dir = dir.as_ref();
+        let mut catalog = Self::load(dir).or_else(|e| match e {
+            Error::Io(ref io) if io.kind() == NotFound => Ok(Self::empty(dir)),
+            Error::CatalogCorrupted(_) => Ok(Self::empty(dir)),
+            e => Err(e),
+        })?;
+        let stats = catalog.refresh()?;
+        Ok((catalog, stats))
+    }
+
+    /// Читает каталог, сохраненный в директории `dir`, не проверяя, изменились ли блоки
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut reader = BufReader::new(File::open(dir.join(CATALOG_FILE_NAME))?);
+        let blocks = decode_blocks(&mut reader).map_err(|e| match e {
+            Error::Io(e) => Error::CatalogCorrupted(e.to_string()),
+            e => e,
+        })?;
+        let mut catalog = Self::empty(dir);
+        catalog.blocks = blocks;
+        catalog.reindex();
+        Ok(catalog)
+    }
+
+    fn empty(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            blocks: vec![],
+            by_location: HashMap::new(),
+        }
+    }
+
+    /// Сохраняет каталог в файл [`CATALOG_FILE_NAME`] в корне директории
+    ///
+    /// [`CATALOG_FILE_NAME`]: constant.CATALOG_FILE_NAME.html
+    pub fn save(&self) -> Result<()> {
+        let path = self.dir.join(CATALOG_FILE_NAME);
+        let tmp_path = self.dir.join(format!(".{}.tmp", CATALOG_FILE_NAME));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        encode_blocks(&self.blocks, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Приводит каталог в соответствие с блоками директории (включая поддиректории).
+    ///
+    /// Блоки, размер и время изменения которых совпадают с записанными в каталоге, повторно не
+    /// читаются.
+    pub fn refresh(&mut self) -> Result<RefreshStats> {
+        let mut paths = vec![];
+        find_blocks(&self.dir, Path::new(""), &mut paths)?;
+        paths.sort();
+
+        let mut stats = RefreshStats::default();
+        let mut known = self
+            .blocks
+            .drain(..)
+            .map(|block| (block.path.clone(), block))
+            .collect::<HashMap<_, _>>();
+        let mut blocks = Vec::with_capacity(paths.len());
+        for path in paths {
+            let full_path = self.dir.join(&path);
+            let metadata = fs::metadata(&full_path)?;
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_nanos() as u64)
+                .unwrap_or(0);
+            let previous = known.remove(&path);
+            if let Some(block) = previous.as_ref() {
+                if block.size == metadata.len() && block.modified == modified {
+                    blocks.extend(previous);
+                    continue;
+                }
+            }
+            match Block::open(&full_path) {
+                Ok(block) => {
+                    match previous {
+                        Some(_) => stats.updated += 1,
+                        None => stats.added += 1,
+                    }
+                    blocks.push(CatalogBlock {
+                        path,
+                        size: metadata.len(),
+                        modified,
+                        normalization: block.normalization(),
+                        entries: block.iter().map(|i| (i.location_hash, i.id)).collect(),
+                    });
+                }
+                Err(e) => {
+                    if previous.is_some() {
+                        stats.removed += 1;
+                    }
+                    stats.failed.push((full_path, e));
+                }
+            }
+        }
+        stats.removed += known.len();
+
+        self.blocks = blocks;
+        self.reindex();
+        Ok(stats)
+    }
+
+    fn reindex(&mut self) {
+        self.by_location = self
+            .blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, block)| {
+                block
+                    .entries
+                    .iter()
+                    .map(move |(hash, id)| ((block.normalization, hash.0), (idx, *id)))
+            })
+            .collect();
+    }
+
+    /// Находит блок и идентификатор файла с location `location`. Location нормализуется по
+    /// правилам каждого из блоков (см. [`Block::find_by_location`]).
+    ///
+    /// [`Block::find_by_location`]: ../block/struct.Block.html#method.find_by_location
+    pub fn lookup(&self, location: impl AsRef<[u8]>) -> Option<CatalogEntry> {
+        let location = location.as_ref();
+        let mut normalizations = self
+            .blocks
+            .iter()
+            .map(|block| block.normalization)
+            .collect::<Vec<_>>();
+        normalizations.sort_by_key(|n| n.flags());
+        normalizations.dedup();
+
+        normalizations
+            .into_iter()
+            .filter_map(|normalization| {
+                let hash = normalization.hash(location);
+                self.by_location.get(&(normalization, hash.0))
+            })
+            .max()
+            .map(|&(idx, id)| CatalogEntry {
+                block_path: self.dir.join(&self.blocks[idx].path),
+                id,
+            })
+    }
+
+    /// Находит все блоки, содержащие файл с location `location`, в порядке путей блоков.
+    /// Последний из них совпадает с найденным [`lookup`].
+    ///
+    /// В отличии от [`lookup`] просматривает все записи каталога.
+    ///
+    /// [`lookup`]: #method.lookup
+    pub fn lookup_all(&self, location: impl AsRef<[u8]>) -> Vec<CatalogEntry> {
+        let location = location.as_ref();
+        let mut found = vec![];
+        for block in self.blocks.iter() {
+            let hash = block.normalization.hash(location);
+            let ids = block.entries.iter().filter(|(h, _)| *h == hash);
+            found.extend(ids.map(|(_, id)| CatalogEntry {
+                block_path: self.dir.join(&block.path),
+                id: *id,
+            }));
+        }
+        found
+    }
+
+    /// Находит файл с location `location` и открывает блок, в котором он записан
+    pub fn get(&self, location: impl AsRef<[u8]>) -> Result<Option<(Block, CatalogEntry)>> {
+        match self.lookup(location) {
+            Some(entry) => Ok(Some((Block::open(&entry.block_path)?, entry))),
+            None => Ok(None),
+        }
+    }
+
+    /// Пути ко всем блокам каталога
+    pub fn block_paths(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.blocks
+            .iter()
+            .map(move |block| self.dir.join(&block.path))
+    }
+
+    /// Количество файлов во всех блоках каталога
+    pub fn len(&self) -> usize {
+        self.blocks.iter().map(|block| block.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Наибольший идентификатор файла среди всех блоков каталога, `None` – если каталог пуст
+    pub fn max_id(&self) -> Option<u64> {
+        self.blocks
+            .iter()
+            .flat_map(|block| block.entries.iter().map(|(_, id)| *id))
+            .max()
+    }
+}
+
+/// Находит файлы блоков в директории `root.join(relative)` и ее поддиректориях, добавляя в
+/// `paths` их пути относительно `root`
+pub(crate) fn find_blocks(
+    root: &Path,
+    relative: &Path,
+    paths: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(root.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_blocks(root, &path, paths)?;
+        } else if path.extension().is_some_and(|ext| ext == BLOCK_EXTENSION) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn encode_blocks(blocks: &[CatalogBlock], target: &mut impl WriteBytesExt) -> Result<()> {
+    target.write_all(CATALOG_MAGIC)?;
+    target.write_u16::<LE>(CATALOG_VERSION)?;
+    target.write_u32::<LE>(blocks.len() as u32)?;
+    for block in blocks {
+        block.encode(target)?;
+    }
+    Ok(())
+}
+
+fn decode_blocks(source: &mut impl ReadBytesExt) -> Result<Vec<CatalogBlock>> {
+    let mut magic = [0u8; 4];
+    source.read_exact(&mut magic)?;
+    let version = source.read_u16::<LE>()?;
+    if &magic != CATALOG_MAGIC || version != CATALOG_VERSION {
+        return Err(Error::CatalogCorrupted("unknown magic or version".into()));
+    }
+    let len = source.read_u32::<LE>()?;
+    (0..len).map(|_| CatalogBlock::decode(source)).collect()
+}
+
+impl SelfSerialize for CatalogBlock {
+    fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()> {
+        let path = location::from_path(&self.path)?;
+        target.write_u16::<LE>(path.len() as u16)?;
+        target.write_all(path)?;
+        target.write_u64::<LE>(self.size)?;
+        target.write_u64::<LE>(self.modified)?;
+        target.write_u32::<LE>(self.normalization.flags())?;
+        target.write_u32::<LE>(self.entries.len() as u32)?;
+        for (hash, id) in self.entries.iter() {
+            target.write_all(&hash.0)?;
+            target.write_u64::<LE>(*id)?;
+        }
+        Ok(())
+    }
+
+    fn decode(source: &mut impl ReadBytesExt) -> Result<Self> {
+        let mut path = vec![0; source.read_u16::<LE>()? as usize];
+        source.read_exact(&mut path)?;
+        let path = location::to_os_str(&path)
+            .map(PathBuf::from)
+            .ok_or_else(|| Error::CatalogCorrupted("invalid block path".into()))?;
+        let size = source.read_u64::<LE>()?;
+        let modified = source.read_u64::<LE>()?;
+        let normalization = Normalization::from_flags(source.read_u32::<LE>()?);
+        let len = source.read_u32::<LE>()?;
+        let mut entries = vec![];
+        for _ in 0..len {
+            let mut hash = [0u8; 16];
+            source.read_exact(&mut hash)?;
+            entries.push((md5::Digest(hash), source.read_u64::<LE>()?));
+        }
+        Ok(Self {
+            path,
+            size,
+            modified,
+            normalization,
+            entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::{AddFileRequest, BlockOptions};
+
+    fn create_block(
+        path: &Path,
+        files: &[(u64, &str)],
+        normalization: Normalization,
+    ) -> Result<()> {
+        let content = path.with_extension("txt");
+        fs::write(&content, "content")?;
+        let requests = files
+            .iter()
+            .map(|(id, location)| AddFileRequest {
+                id: *id,
+                path: &content,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+        BlockOptions::new()
+            .location_normalization(normalization)
+            .create(path, &requests)?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_find_files_across_blocks() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-catalog-test")?;
+        fs::create_dir(tmp.path().join("nested"))?;
+        create_block(
+            &tmp.path().join("0001.block"),
+            &[(1, "/a.jpg"), (2, "/b.jpg")],
+            Normalization::NONE,
+        )?;
+        create_block(
+            &tmp.path().join("nested/0002.block"),
+            &[(3, "/b.jpg"), (4, "/Img/C.jpg")],
+            Normalization::LOWERCASE,
+        )?;
+
+        let (catalog, stats) = Catalog::open(tmp.path())?;
+        assert_eq!((stats.added, stats.updated, stats.removed), (2, 0, 0));
+        assert_eq!(catalog.len(), 4);
+        assert_eq!(catalog.max_id(), Some(4));
+        let lookup = |location| catalog.lookup(location).map(|e| e.id);
+        assert_eq!(lookup("/a.jpg"), Some(1));
+        // Блок с большим путем перекрывает остальные
+        assert_eq!(lookup("/b.jpg"), Some(3));
+        assert_eq!(lookup("/IMG/c.JPG"), Some(4));
+        assert_eq!(lookup("/missing.jpg"), None);
+        let all = catalog.lookup_all("/b.jpg");
+        assert_eq!(all.iter().map(|e| e.id).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(all.last(), catalog.lookup("/b.jpg").as_ref());
+
+        let (block, entry) = catalog.get("/img/c.jpg")?.unwrap();
+        assert_eq!(entry.block_path, tmp.path().join("nested/0002.block"));
+        assert_eq!(block.file_by_id(entry.id)?.0.location, b"/img/c.jpg");
+        Ok(())
+    }
+
+    #[test]
+    fn should_refresh_saved_catalog_incrementally() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-catalog-test")?;
+        let first = tmp.path().join("0001.block");
+        let second = tmp.path().join("0002.block");
+        create_block(&first, &[(1, "/a.jpg")], Normalization::NONE)?;
+        create_block(&second, &[(2, "/b.jpg")], Normalization::NONE)?;
+
+        let (catalog, _) = Catalog::open(tmp.path())?;
+        catalog.save()?;
+        let loaded = Catalog::load(tmp.path())?;
+        assert_eq!(loaded.blocks, catalog.blocks);
+
+        fs::remove_file(&first)?;
+        fs::remove_file(&second)?;
+        create_block(
+            &second,
+            &[(3, "/c.jpg"), (4, "/d.jpg")],
+            Normalization::NONE,
+        )?;
+        create_block(
+            &tmp.path().join("0003.block"),
+            &[(5, "/e.jpg")],
+            Normalization::NONE,
+        )?;
+        fs::write(tmp.path().join("broken.block"), "not a block")?;
+
+        let (catalog, stats) = Catalog::open(tmp.path())?;
+        assert_eq!((stats.added, stats.updated, stats.removed), (1, 1, 1));
+        assert_eq!(stats.failed.len(), 1);
+        assert_eq!(catalog.lookup("/a.jpg"), None);
+        assert_eq!(catalog.lookup("/d.jpg").map(|e| e.id), Some(4));
+        assert_eq!(catalog.lookup("/e.jpg").map(|e| e.id), Some(5));
+        catalog.save()?;
+
+        let (_, stats) = Catalog::open(tmp.path())?;
+        assert!(!stats.is_changed());
+        Ok(())
+    }
+
+    #[test]
+    fn should_ignore_corrupted_catalog_file() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-catalog-test")?;
+        create_block(
+            &tmp.path().join("0001.block"),
+            &[(1, "/a")],
+            Normalization::NONE,
+        )?;
+        fs::write(tmp.path().join(CATALOG_FILE_NAME), "garbage")?;
+        assert!(Catalog::load(tmp.path()).is_err());
+        let (catalog, _) = Catalog::open(tmp.path())?;
+        assert_eq!(catalog.lookup("/a").map(|e| e.id), Some(1));
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+//! Контрольные суммы фрагментов файлов блока, сохраняемые в отдельный файл (`.chunks`).
+//!
+//! Контрольная сумма в заголовке файла ([`FileHeader`]) покрывает содержимое целиком, поэтому
+//! проверить по ней фрагмент многогигабайтного файла (например, при чтении диапазона или
+//! докачке) можно, только прочитав весь файл. Файл `.chunks` содержит MD5 каждого фрагмента
+//! фиксированного размера ([`DEFAULT_CHUNK_SIZE`] по умолчанию), что позволяет проверять
+//! только фрагменты, покрывающие прочитанный диапазон (см. [`Block::read_range_verified`]).
+//!
+//! Контрольные суммы вычисляются по исходному содержимому файлов (до сжатия и шифрования), так
+//! что получатель может проверить фрагменты без доступа к блоку. Файл `.chunks` строится по
+//! уже записанному блоку и должен соответствовать ему: после пересоздания блока его нужно
+//! построить заново.
+//!
+//! ## Формат
+//! ```text
+//! magic (4 байта, "BCHK") | version (2 байта) | chunk_size (4 байта) | size (4 байта)
+//! size × [id (8 байт) | file_size (8 байт) | ⌈file_size / chunk_size⌉ × md5 (16 байт)]
+//! ```
+//! Записи отсортированы по `id`.
+//!
+//! [`FileHeader`]: ../block/struct.FileHeader.html
+//! [`DEFAULT_CHUNK_SIZE`]: constant.DEFAULT_CHUNK_SIZE.html
+//! [`Block::read_range_verified`]: ../block/struct.Block.html#method.read_range_verified
+use crate::block::{Block, SelfSerialize};
+use crate::errors::*;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const CHUNKS_MAGIC: &[u8; 4] = b"BCHK";
+const CHUNKS_VERSION: u16 = 1;
+
+/// Размер фрагмента по умолчанию
+pub const DEFAULT_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Контрольные суммы фрагментов одного файла
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileChunks {
+    pub id: u64,
+
+    /// Размер исходного содержимого файла
+    pub size: u64,
+
+    /// MD5 каждого фрагмента по порядку. Последний фрагмент может быть короче остальных
+    pub hashes: Vec<md5::Digest>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ChunkHashes {
+    chunk_size: u32,
+    by_id: Vec<FileChunks>,
+}
+
+impl ChunkHashes {
+    /// Вычисляет контрольные суммы фрагментов размером `chunk_size` всех файлов блока.
+    ///
+    /// Перед этим проверяется контрольная сумма каждого файла целиком, чтобы не сохранить
+    /// контрольные суммы поврежденного содержимого.
+    pub fn from_block(block: &Block, chunk_size: u32) -> Result<Self> {
+        if chunk_size == 0 {
+            return Err(Error::FormatLimitExceeded(
+                "chunk size should be positive".into(),
+            ));
+        }
+        let mut by_id = vec![];
+        for (idx, info) in block.iter().enumerate() {
+            block.verify_at(idx)?;
+            let (_, content) = block.file_at(idx)?;
+            by_id.push(FileChunks {
+                id: info.id,
+                size: content.len() as u64,
+                hashes: content
+                    .chunks(chunk_size as usize)
+                    .map(md5::compute)
+                    .collect(),
+            });
+        }
+        by_id.sort_by_key(|file| file.id);
+        Ok(Self { chunk_size, by_id })
+    }
+
+    /// Читает контрольные суммы из файла
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::decode(&mut reader).map_err(|e| match e {
+            Error::Io(e) => Error::ChunkHashesCorrupted(e.to_string()),
+            e => e,
+        })
+    }
+
+    /// Записывает контрольные суммы в файл
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.encode(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Размер фрагмента в байтах
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    /// Возвращает контрольные суммы фрагментов файла с идентификатором `id`
+    pub fn get(&self, id: u64) -> Option<&FileChunks> {
+        self.by_id
+            .binary_search_by_key(&id, |file| file.id)
+            .ok()
+            .map(|idx| &self.by_id[idx])
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+}
+
+impl SelfSerialize for ChunkHashes {
+    fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()> {
+        target.write_all(CHUNKS_MAGIC)?;
+        target.write_u16::<LE>(CHUNKS_VERSION)?;
+        target.write_u32::<LE>(self.chunk_size)?;
+        target.write_u32::<LE>(self.by_id.len() as u32)?;
+        for file in self.by_id.iter() {
+            target.write_u64::<LE>(file.id)?;
+            target.write_u64::<LE>(file.size)?;
+            for hash in file.hashes.iter() {
+                target.write_all(&hash.0)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(source: &mut impl ReadBytesExt) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        source.read_exact(&mut magic)?;
+        let version = source.read_u16::<LE>()?;
+        if &magic != CHUNKS_MAGIC || version != CHUNKS_VERSION {
+            return Err(Error::ChunkHashesCorrupted(
+                "unknown magic or version".into(),
+            ));
+        }
+        let chunk_size = source.read_u32::<LE>()?;
+        if chunk_size == 0 {
+            return Err(Error::ChunkHashesCorrupted("zero chunk size".into()));
+        }
+
+        let len = source.read_u32::<LE>()?;
+        let mut by_id: Vec<FileChunks> = vec![];
+        for _ in 0..len {
+            let id = source.read_u64::<LE>()?;
+            if by_id.last().is_some_and(|previous| previous.id >= id) {
+                return Err(Error::ChunkHashesCorrupted(
+                    "files are not sorted by id".into(),
+                ));
+            }
+            let size = source.read_u64::<LE>()?;
+            // Количество фрагментов не выделяется заранее: при повреждении размера чтение
+            // завершится ошибкой на конце файла, а не попыткой выделить память под него
+            let mut hashes = vec![];
+            for _ in 0..size.div_ceil(u64::from(chunk_size)) {
+                let mut hash = [0u8; 16];
+                source.read_exact(&mut hash)?;
+                hashes.push(md5::Digest(hash));
+            }
+            by_id.push(FileChunks { id, size, hashes });
+        }
+        Ok(Self { chunk_size, by_id })
+    }
+}
+
+impl Block {
+    /// Записывает контрольные суммы фрагментов размером `chunk_size` всех файлов блока в
+    /// отдельный файл (см. [`ChunkHashes`])
+    ///
+    /// [`ChunkHashes`]: ../chunks/struct.ChunkHashes.html
+    pub fn write_chunk_hashes(&self, path: impl AsRef<Path>, chunk_size: u32) -> Result<()> {
+        ChunkHashes::from_block(self, chunk_size)?.write(path)
+    }
+
+    /// Аналогичен [`read_range`], но проверяет контрольные суммы фрагментов, покрывающих
+    /// диапазон (см. [`ChunkHashes`]). Для этого читаются фрагменты целиком, но не весь файл.
+    ///
+    /// Если контрольная сумма какого-либо из фрагментов не совпадает, возвращается
+    /// [`Error::ChecksumMismatch`]. Если файла нет в `chunks`, возвращается
+    /// [`Error::FileNotFound`].
+    ///
+    /// [`read_range`]: #method.read_range
+    /// [`ChunkHashes`]: ../chunks/struct.ChunkHashes.html
+    /// [`Error::ChecksumMismatch`]: ../errors/enum.Error.html#variant.ChecksumMismatch
+    /// [`Error::FileNotFound`]: ../errors/enum.Error.html#variant.FileNotFound
+    pub fn read_range_verified(
+        &self,
+        id: u64,
+        offset: u64,
+        len: u64,
+        chunks: &ChunkHashes,
+    ) -> Result<Cow<'_, [u8]>> {
+        let file = chunks
+            .get(id)
+            .ok_or(Error::FileNotFound { id: id.into() })?;
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= file.size)
+            .ok_or(Error::RangeOutOfBounds {
+                id: id.into(),
+                offset,
+                len,
+                size: file.size,
+            })?;
+        let chunk_size = u64::from(chunks.chunk_size);
+        let first_chunk = offset / chunk_size;
+        let start = first_chunk * chunk_size;
+        let stop = end
+            .div_ceil(chunk_size)
+            .saturating_mul(chunk_size)
+            .min(file.size)
+            .max(start);
+
+        let content = self.read_range(id, start, stop - start)?;
+        let hashes = file.hashes.iter().skip(first_chunk as usize);
+        for (chunk, hash) in content.chunks(chunk_size as usize).zip(hashes) {
+            if md5::compute(chunk) != *hash {
+                return Err(Error::ChecksumMismatch { id: id.into() });
+            }
+        }
+        let range = (offset - start) as usize..(end - start) as usize;
+        Ok(match content {
+            Cow::Borrowed(content) => Cow::Borrowed(&content[range]),
+            Cow::Owned(content) => Cow::Owned(content[range].to_vec()),
+        })
+    }
+}
+
+/// Возвращает путь к файлу контрольных сумм фрагментов по умолчанию для блока: `<block>.chunks`
+pub fn chunks_path_for(block_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = OsString::from(block_path.as_ref().as_os_str());
+    path.push(".chunks");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::{AddFileRequest, FILE_HEADER_FIXED_SIZE};
+    use std::fs;
+
+    #[test]
+    fn should_verify_ranges_by_chunk_hashes() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-chunks-test")?;
+        let file = tmp.path().join("file.bin");
+        let empty = tmp.path().join("empty.bin");
+        let content = (0..10u8).collect::<Vec<_>>();
+        fs::write(&file, &content)?;
+        fs::write(&empty, b"")?;
+        let block_path = tmp.path().join("test.block");
+        let block = Block::from_files(
+            &block_path,
+            &[
+                AddFileRequest {
+                    id: 2,
+                    path: &file,
+                    location: Path::new("/file.bin"),
+                    content_hash: None,
+                    size: None,
+                },
+                AddFileRequest {
+                    id: 1,
+                    path: &empty,
+                    location: Path::new("/empty.bin"),
+                    content_hash: None,
+                    size: None,
+                },
+            ],
+        )?;
+
+        let chunks_path = chunks_path_for(&block_path);
+        block.write_chunk_hashes(&chunks_path, 4)?;
+        let chunks = ChunkHashes::open(&chunks_path)?;
+        assert_eq!(chunks, ChunkHashes::from_block(&block, 4)?);
+        assert_eq!(chunks.chunk_size(), 4);
+        assert_eq!(chunks.len(), 2);
+        let file_chunks = chunks.get(2).unwrap();
+        assert_eq!(file_chunks.size, 10);
+        assert_eq!(
+            file_chunks.hashes,
+            vec![
+                md5::compute(&content[0..4]),
+                md5::compute(&content[4..8]),
+                md5::compute(&content[8..10]),
+            ]
+        );
+        assert!(chunks.get(1).unwrap().hashes.is_empty());
+
+        for (offset, len) in [(0, 10), (3, 6), (4, 4), (9, 1), (10, 0)] {
+            let range = offset as usize..(offset + len) as usize;
+            assert_eq!(
+                &*block.read_range_verified(2, offset, len, &chunks)?,
+                &content[range]
+            );
+        }
+        assert_eq!(&*block.read_range_verified(1, 0, 0, &chunks)?, b"");
+        assert!(matches!(
+            block.read_range_verified(2, 8, 3, &chunks),
+            Err(Error::RangeOutOfBounds { .. })
+        ));
+        assert!(matches!(
+            block.read_range_verified(3, 0, 0, &chunks),
+            Err(Error::FileNotFound { id: 3 })
+        ));
+
+        // Повреждение второго фрагмента не мешает читать остальные
+        let info = block.iter().find(|info| info.id == 2).unwrap();
+        let payload_offset =
+            info.offset as usize + FILE_HEADER_FIXED_SIZE as usize + "/file.bin".len();
+        drop(block);
+        let mut bytes = fs::read(&block_path)?;
+        bytes[payload_offset + 5] ^= 0xFF;
+        fs::write(&block_path, bytes)?;
+        let block = Block::open(&block_path)?;
+        assert!(matches!(
+            block.read_range_verified(2, 3, 2, &chunks),
+            Err(Error::ChecksumMismatch { id: 2 })
+        ));
+        assert_eq!(
+            &*block.read_range_verified(2, 8, 2, &chunks)?,
+            &content[8..10]
+        );
+        assert_eq!(
+            &*block.read_range_verified(2, 0, 4, &chunks)?,
+            &content[0..4]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_non_chunk_hashes_files() {
+        let mut bytes = &b"BIDX\x01\x00\x00\x00\x00\x00"[..];
+        assert!(ChunkHashes::decode(&mut bytes).is_err());
+    }
+}
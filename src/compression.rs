@@ -0,0 +1,244 @@
+//! Сжатие содержимого файлов блока (см. [`BlockOptions::compress`]).
+//!
+//! Содержимое файла сжимается zstd не целиком, а независимыми фрагментами по [`CHUNK_SIZE`]
+//! байт. Размеры сжатых фрагментов записаны в таблице после них, поэтому для чтения диапазона
+//! содержимого распаковываются только покрывающие его фрагменты:
+//!
+//! ```text
+//! | фрагмент 0 | ... | фрагмент n-1 | длина 0 | ... | длина n-1 | size | chunk_size |
+//! ```
+//! * `длина` – размер сжатого фрагмента (4 байта);
+//! * `size` – размер исходного содержимого (4 байта);
+//! * `chunk_size` – размер фрагмента до сжатия (4 байта). Последний фрагмент может быть короче.
+//!
+//! Таблица пишется после фрагментов, так что файл сжимается потоком, без буферизации целиком.
+//!
+//! [`BlockOptions::compress`]: ../block/struct.BlockOptions.html#method.compress
+//! [`CHUNK_SIZE`]: constant.CHUNK_SIZE.html
+use crate::errors::*;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+/// Размер фрагмента содержимого до сжатия
+pub(crate) const CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Уровень сжатия zstd
+const LEVEL: i32 = 3;
+
+/// Размер полей `size` и `chunk_size`, завершающих сжатое содержимое
+const FOOTER_SIZE: usize = 4 + 4;
+
+/// Сжимает содержимое `reader` в `target`.
+///
+/// Возвращает контрольную сумму и размер исходного содержимого, а также количество записанных
+/// байт.
+pub(crate) fn compress(
+    reader: &mut impl Read,
+    target: &mut impl Write,
+) -> Result<(md5::Digest, u64, u64)> {
+    let mut compressor = zstd::bulk::Compressor::new(LEVEL)?;
+    let mut context = md5::Context::new();
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+    let mut lengths = vec![];
+    let mut size = 0;
+    let mut stored = 0;
+    loop {
+        chunk.clear();
+        (&mut *reader)
+            .take(u64::from(CHUNK_SIZE))
+            .read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            break;
+        }
+        context.consume(&chunk);
+        let compressed = compressor.compress(&chunk)?;
+        target.write_all(&compressed)?;
+        lengths.push(compressed.len() as u32);
+        size += chunk.len() as u64;
+        stored += compressed.len() as u64;
+    }
+
+    for len in lengths.iter() {
+        target.write_u32::<LE>(*len)?;
+    }
+    let size_field = u32::try_from(size)
+        .map_err(|_| Error::FormatLimitExceeded("content is larger than 4 GiB".into()))?;
+    target.write_u32::<LE>(size_field)?;
+    target.write_u32::<LE>(CHUNK_SIZE)?;
+    stored += (lengths.len() * 4 + FOOTER_SIZE) as u64;
+    Ok((context.compute(), size, stored))
+}
+
+/// Сжатое содержимое файла
+pub(crate) struct CompressedContent<'a> {
+    payload: &'a [u8],
+    /// Границы сжатых фрагментов в `payload` (начало, конец)
+    chunks: Vec<(usize, usize)>,
+    size: u32,
+    chunk_size: u32,
+}
+
+impl<'a> CompressedContent<'a> {
+    /// Читает таблицу фрагментов сжатого содержимого
+    pub fn decode(payload: &'a [u8]) -> Result<Self> {
+        let corrupted = |details: &str| Error::corrupted(format!("compressed entry: {}", details));
+        let footer_start = payload
+            .len()
+            .checked_sub(FOOTER_SIZE)
+            .ok_or_else(|| corrupted("footer is missing"))?;
+        let mut footer = &payload[footer_start..];
+        let size = footer.read_u32::<LE>()?;
+        let chunk_size = footer.read_u32::<LE>()?;
+        if chunk_size == 0 {
+            return Err(corrupted("zero chunk size"));
+        }
+
+        let chunks_count = size.div_ceil(chunk_size) as usize;
+        let table_start = footer_start
+            .checked_sub(chunks_count * 4)
+            .ok_or_else(|| corrupted("chunk table is out of bounds"))?;
+        let mut table = &payload[table_start..footer_start];
+        let mut chunks = Vec::with_capacity(chunks_count);
+        let mut start = 0;
+        for _ in 0..chunks_count {
+            let end = start + table.read_u32::<LE>()? as usize;
+            chunks.push((start, end));
+            start = end;
+        }
+        if start != table_start {
+            return Err(corrupted("chunk table doesn't match entry size"));
+        }
+
+        Ok(Self {
+            payload,
+            chunks,
+            size,
+            chunk_size,
+        })
+    }
+
+    /// Размер исходного содержимого
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Распаковывает `len` байт исходного содержимого, начиная со смещения `offset`.
+    ///
+    /// Диапазон должен находиться в пределах содержимого.
+    pub fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        debug_assert!(offset + len <= u64::from(self.size));
+        let mut content = Vec::with_capacity(len as usize);
+        if len == 0 {
+            return Ok(content);
+        }
+
+        let chunk_size = u64::from(self.chunk_size);
+        let end = offset + len;
+        let mut decompressor = zstd::bulk::Decompressor::new()?;
+        for idx in (offset / chunk_size)..=((end - 1) / chunk_size) {
+            let chunk_start = idx * chunk_size;
+            let expected_len = chunk_size.min(u64::from(self.size) - chunk_start) as usize;
+            let (start, end_in_payload) = self.chunks[idx as usize];
+            let chunk = decompressor
+                .decompress(&self.payload[start..end_in_payload], expected_len)
+                .map_err(|e| Error::corrupted(format!("unable to decompress chunk: {}", e)))?;
+            if chunk.len() != expected_len {
+                return Err(Error::corrupted(format!(
+                    "chunk {} is {} bytes instead of {}",
+                    idx,
+                    chunk.len(),
+                    expected_len
+                )));
+            }
+            let from = offset.saturating_sub(chunk_start) as usize;
+            let to = (end - chunk_start).min(chunk_size) as usize;
+            content.extend_from_slice(&chunk[from..to]);
+        }
+        Ok(content)
+    }
+}
+
+/// Сжимает блок метаинформации заголовка (см. [`BlockOptions::compress_header`])
+///
+/// [`BlockOptions::compress_header`]: ../block/struct.BlockOptions.html#method.compress_header
+pub(crate) fn compress_table(records: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::compress(records, LEVEL)?)
+}
+
+/// Поток распакованных записей сжатого блока метаинформации. Записи читаются из потока по
+/// одной, так что память под них выделяется по мере распаковки, а не по количеству файлов,
+/// указанному в (возможно, поврежденном) заголовке.
+pub(crate) fn table_decoder(compressed: &[u8]) -> Result<impl Read + '_> {
+    Ok(zstd::stream::read::Decoder::with_buffer(compressed)?)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn compressed(content: &[u8]) -> Result<Vec<u8>> {
+        let mut payload = vec![];
+        let (hash, size, stored) = compress(&mut &content[..], &mut payload)?;
+        assert_eq!(hash, md5::compute(content));
+        assert_eq!(size, content.len() as u64);
+        assert_eq!(stored, payload.len() as u64);
+        Ok(payload)
+    }
+
+    #[test]
+    fn should_read_ranges_across_chunk_boundaries() -> Result<()> {
+        let content = (0..3 * CHUNK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let payload = compressed(&content)?;
+        let compressed = CompressedContent::decode(&payload)?;
+        assert_eq!(compressed.size() as usize, content.len());
+
+        let chunk = u64::from(CHUNK_SIZE);
+        let ranges = [
+            (0, 0),
+            (0, 10),
+            (chunk - 5, 10),
+            (chunk, chunk),
+            (10, 2 * chunk + 50),
+            (3 * chunk, 100),
+            (0, content.len() as u64),
+        ];
+        for (offset, len) in ranges.iter() {
+            let expected = &content[*offset as usize..(offset + len) as usize];
+            assert_eq!(compressed.read_range(*offset, *len)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_compress_empty_content() -> Result<()> {
+        let payload = compressed(b"")?;
+        let compressed = CompressedContent::decode(&payload)?;
+        assert_eq!(compressed.size(), 0);
+        assert!(compressed.read_range(0, 0)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn should_detect_corrupted_chunk_table() -> Result<()> {
+        let mut payload = compressed(&[42; 1000])?;
+        let table_start = payload.len() - FOOTER_SIZE - 4;
+        payload[table_start] ^= 0xFF;
+        match CompressedContent::decode(&payload) {
+            Err(Error::BlockCorrupted { .. }) => {}
+            r => panic!("BlockCorrupted expected, got: {:?}", r.err()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn table_bound_should_match_zstd() {
+        for len in [0, 1, 32, 4000, 128 * 1024 - 1, 128 * 1024, 10_000_000] {
+            let bound = zstd::zstd_safe::compress_bound(len as usize) as u64;
+            assert_eq!(crate::block::compressed_table_bound(len), bound);
+        }
+    }
+}
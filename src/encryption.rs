@@ -0,0 +1,162 @@
+//! Шифрование содержимого файлов блока (см. [`BlockOptions::encryption_key`]).
+//!
+//! Содержимое каждого файла (после сжатия, если оно включено) шифруется ChaCha20-Poly1305 со
+//! случайным nonce и записывается в блок в виде
+//!
+//! ```text
+//! | nonce (12 байт) | шифротекст | тег аутентификации (16 байт) |
+//! ```
+//!
+//! Location файла используется как связанные данные (AAD), поэтому зашифрованное содержимое
+//! нельзя незаметно переставить между файлами блока.
+//!
+//! Шифруется только содержимое файлов: заголовок блока и заголовки файлов (идентификаторы,
+//! location, размеры) остаются открытыми, так что блок можно инспектировать без ключа.
+//! Контрольная сумма в заголовке файла зашифрованного блока вычисляется по зашифрованному
+//! содержимому: она позволяет проверить целостность блока без ключа и не раскрывает исходное
+//! содержимое.
+//!
+//! [`BlockOptions::encryption_key`]: ../block/struct.BlockOptions.html#method.encryption_key
+use crate::errors::*;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Размер nonce ChaCha20-Poly1305
+const NONCE_SIZE: usize = 12;
+
+/// На сколько байт зашифрованное содержимое больше исходного: nonce и тег аутентификации
+pub(crate) const OVERHEAD: usize = NONCE_SIZE + 16;
+
+/// Имя переменной окружения, из которой CLI читает ключ шифрования в шестнадцатеричном виде
+pub const KEY_ENV_VAR: &str = "BLOCKY_KEY";
+
+/// Ключ шифрования блока (256 бит)
+#[derive(Clone, Eq, PartialEq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Создает новый случайный ключ
+    pub fn generate() -> Self {
+        Self(ChaCha20Poly1305::generate_key(&mut rand_core::OsRng).into())
+    }
+
+    /// Читает ключ из файла, содержащего 32 байта ключа
+    pub fn read_from(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        <[u8; 32]>::try_from(bytes.as_slice())
+            .map(Self)
+            .map_err(|_| {
+                Error::InvalidKey(format!(
+                    "{}: expected 32 bytes, got {}",
+                    path.display(),
+                    bytes.len()
+                ))
+            })
+    }
+
+    /// Разбирает ключ, записанный 64 шестнадцатеричными символами (например, в переменной
+    /// окружения [`KEY_ENV_VAR`])
+    ///
+    /// [`KEY_ENV_VAR`]: constant.KEY_ENV_VAR.html
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        let invalid = || Error::InvalidKey("expected 64 hexadecimal characters".into());
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0; 32];
+        for (idx, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// Шифрует содержимое файла с location `location`
+pub(crate) fn encrypt(key: &EncryptionKey, location: &[u8], content: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(&key.0.into());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut rand_core::OsRng);
+    let payload = Payload {
+        msg: content,
+        aad: location,
+    };
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| Error::FormatLimitExceeded("content is too large to encrypt".into()))?;
+
+    let mut encrypted = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    Ok(encrypted)
+}
+
+/// Расшифровывает содержимое файла с location `location`.
+///
+/// Возвращает [`Error::DecryptionFailed`], если ключ не подходит или содержимое изменено.
+///
+/// [`Error::DecryptionFailed`]: ../errors/enum.Error.html#variant.DecryptionFailed
+pub(crate) fn decrypt(key: &EncryptionKey, location: &[u8], encrypted: &[u8]) -> Result<Vec<u8>> {
+    if encrypted.len() < NONCE_SIZE {
+        return Err(Error::DecryptionFailed);
+    }
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+    let cipher = ChaCha20Poly1305::new(&key.0.into());
+    let payload = Payload {
+        msg: ciphertext,
+        aad: location,
+    };
+    cipher
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| Error::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn should_encrypt_and_decrypt_content() -> Result<()> {
+        let key = EncryptionKey::generate();
+        let encrypted = encrypt(&key, b"/a.txt", b"secret")?;
+        assert!(!encrypted.windows(6).any(|w| w == b"secret"));
+        assert_eq!(decrypt(&key, b"/a.txt", &encrypted)?, b"secret");
+
+        for (key, location) in [(&EncryptionKey::generate(), b"/a.txt"), (&key, b"/b.txt")].iter() {
+            match decrypt(key, &location[..], &encrypted) {
+                Err(Error::DecryptionFailed) => {}
+                r => panic!("DecryptionFailed expected, got: {:?}", r),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_hex_keys() -> Result<()> {
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let key = EncryptionKey::from_hex(hex)?;
+        assert_eq!(key.as_bytes()[31], 0x1f);
+        assert!(EncryptionKey::from_hex(&hex[2..]).is_err());
+        assert!(EncryptionKey::from_hex(&hex.replace("0a", "zz")).is_err());
+        Ok(())
+    }
+}
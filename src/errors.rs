@@ -0,0 +1,277 @@
+//! Ошибки библиотеки.
+//!
+//! Каждая ошибка представлена отдельным вариантом [`Error`] с контекстом (идентификатор файла,
+//! смещение, путь к блоку), поэтому потребители библиотеки могут отличить, например, отсутствие
+//! файла в блоке от повреждения блока, не разбирая текст сообщения.
+//!
+//! [`Error`]: enum.Error.html
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Ошибка ввода-вывода
+    Io(io::Error),
+
+    /// Попытка создать блок без файлов
+    NoFilesInBlock,
+
+    /// Файл блока уже существует
+    BlockFileAlreadyExists(PathBuf),
+
+    /// Идентификатор повторяется среди файлов, добавляемых в блок
+    DuplicateId(u128),
+
+    /// Location повторяется среди файлов, добавляемых в блок
+    DuplicateLocation(String),
+
+    /// Значение не может быть представлено в формате блока (например, файл больше 4 ГБ)
+    FormatLimitExceeded(String),
+
+    /// Структура блока нарушена
+    BlockCorrupted {
+        /// Путь к блоку, если блок был открыт из файла
+        path: Option<PathBuf>,
+        details: String,
+    },
+
+    /// Структура индекса блока нарушена
+    IndexCorrupted(String),
+
+    /// Структура каталога блоков нарушена
+    CatalogCorrupted(String),
+
+    /// Структура файла контрольных сумм фрагментов нарушена
+    ChunkHashesCorrupted(String),
+
+    /// Структура файла данных четности нарушена
+    ParityCorrupted(String),
+
+    /// Версия формата блока новее, чем поддерживает библиотека
+    UnsupportedVersion(u16),
+
+    /// Блок использует особенность формата (см. [`SUPPORTED_FLAGS`]), которую не поддерживает
+    /// библиотека
+    ///
+    /// [`SUPPORTED_FLAGS`]: ../block/constant.SUPPORTED_FLAGS.html
+    UnsupportedFeature(String),
+
+    /// Размер структуры блока превышает ограничения, заданные при его открытии
+    DecodeLimitExceeded(String),
+
+    /// Контрольная сумма содержимого файла не совпадает с записанной в его заголовке
+    ChecksumMismatch { id: u128 },
+
+    /// Файл с указанным идентификатором отсутствует в блоке. Идентификатор 128-битный, чтобы
+    /// вместить идентификаторы блоков с [`FLAG_WIDE_IDS`]
+    ///
+    /// [`FLAG_WIDE_IDS`]: ../block/constant.FLAG_WIDE_IDS.html
+    FileNotFound { id: u128 },
+
+    /// Файл с указанным location отсутствует в блоке
+    LocationNotFound(String),
+
+    /// Файл с указанной контрольной суммой содержимого отсутствует в блоке
+    ContentNotFound(String),
+
+    /// MD5-хеш location совпадает с хешем location файла `id`, но сами location различаются
+    LocationHashCollision { id: u64, location: String },
+
+    /// Порядковый номер файла выходит за пределы блока
+    IndexOutOfRange { idx: usize, len: usize },
+
+    /// Файл, описанный в заголовке, выходит за границы блока
+    EntryOutOfBounds { id: u128, offset: u32, size: u32 },
+
+    /// Блок не подписан
+    SignatureMissing,
+
+    /// Подпись блока не соответствует ключу, или блок изменился после подписания
+    SignatureInvalid,
+
+    /// Файл ключа имеет неверный формат
+    InvalidKey(String),
+
+    /// Сертификат или закрытый ключ TLS имеет неверный формат, или они не подходят друг другу
+    InvalidTlsConfig(String),
+
+    /// Запрошенный диапазон выходит за пределы содержимого файла размером `size` байт
+    RangeOutOfBounds {
+        id: u128,
+        offset: u64,
+        len: u64,
+        size: u64,
+    },
+
+    /// Содержимое файлов блока зашифровано, а ключ для расшифровки не задан
+    KeyRequired,
+
+    /// Содержимое файла не удалось расшифровать: ключ не подходит, или содержимое изменено
+    DecryptionFailed,
+
+    /// Location файла указывает за пределы директории, в которую выгружаются файлы (см.
+    /// [`location::extraction_path`])
+    ///
+    /// [`location::extraction_path`]: ../location/fn.extraction_path.html
+    UnsafeLocation(String),
+
+    /// Путь на локальной ФС не может быть представлен в виде location на текущей платформе
+    /// (см. [`location::from_path`])
+    ///
+    /// [`location::from_path`]: ../location/fn.from_path.html
+    InvalidLocation(PathBuf),
+
+    /// Шаблон имен блоков не содержит номера блока или содержит его несколько раз (см.
+    /// [`PathPattern`])
+    ///
+    /// [`PathPattern`]: ../block_set/struct.PathPattern.html
+    InvalidPathPattern(String),
+}
+
+impl Error {
+    /// Ошибка [`Error::BlockCorrupted`] с описанием нарушения структуры блока
+    ///
+    /// [`Error::BlockCorrupted`]: enum.Error.html#variant.BlockCorrupted
+    pub(crate) fn corrupted(details: impl Into<String>) -> Self {
+        Error::BlockCorrupted {
+            path: None,
+            details: details.into(),
+        }
+    }
+
+    /// Добавляет путь к блоку в ошибки, описывающие его повреждение
+    pub(crate) fn with_path(self, block_path: &Path) -> Self {
+        match self {
+            Error::BlockCorrupted {
+                path: None,
+                details,
+            } => Error::BlockCorrupted {
+                path: Some(block_path.to_path_buf()),
+                details,
+            },
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::NoFilesInBlock => write!(f, "No files given for a block"),
+            Error::BlockFileAlreadyExists(path) => {
+                write!(f, "Block file already exists: {}", path.display())
+            }
+            Error::DuplicateId(id) => write!(f, "Duplicate file id: {}", id),
+            Error::DuplicateLocation(location) => {
+                write!(f, "Duplicate file location: {}", location)
+            }
+            Error::FormatLimitExceeded(details) => {
+                write!(f, "Block format limit exceeded: {}", details)
+            }
+            Error::BlockCorrupted {
+                path: Some(path),
+                details,
+            } => write!(
+                f,
+                "Illegal block structure ({}): {}",
+                path.display(),
+                details
+            ),
+            Error::BlockCorrupted {
+                path: None,
+                details,
+            } => write!(f, "Illegal block structure: {}", details),
+            Error::IndexCorrupted(details) => write!(f, "Illegal index structure: {}", details),
+            Error::CatalogCorrupted(details) => {
+                write!(f, "Illegal catalog structure: {}", details)
+            }
+            Error::ChunkHashesCorrupted(details) => {
+                write!(f, "Illegal chunk hashes structure: {}", details)
+            }
+            Error::ParityCorrupted(details) => write!(f, "Illegal parity structure: {}", details),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Unsupported block format version: {}", version)
+            }
+            Error::UnsupportedFeature(feature) => {
+                write!(f, "Unsupported block format feature: {}", feature)
+            }
+            Error::DecodeLimitExceeded(details) => {
+                write!(f, "Decode limit exceeded: {}", details)
+            }
+            Error::ChecksumMismatch { id } => write!(f, "Checksum mismatch for file: {}", id),
+            Error::FileNotFound { id } => write!(f, "File not found in block: {}", id),
+            Error::LocationNotFound(location) => {
+                write!(f, "File not found in block: {}", location)
+            }
+            Error::ContentNotFound(hash) => {
+                write!(f, "File with content hash {} not found in block", hash)
+            }
+            Error::LocationHashCollision { id, location } => write!(
+                f,
+                "Location {} has the same MD5 hash as location of file {}",
+                location, id
+            ),
+            Error::IndexOutOfRange { idx, len } => write!(
+                f,
+                "File index {} is out of range, block contains {} files",
+                idx, len
+            ),
+            Error::EntryOutOfBounds { id, offset, size } => write!(
+                f,
+                "File {} (offset: {}, size: {}) is out of block bounds",
+                id, offset, size
+            ),
+            Error::SignatureMissing => write!(f, "Block is not signed"),
+            Error::SignatureInvalid => write!(f, "Block signature is invalid"),
+            Error::InvalidKey(details) => write!(f, "Invalid key: {}", details),
+            Error::InvalidTlsConfig(details) => write!(f, "Invalid TLS configuration: {}", details),
+            Error::RangeOutOfBounds {
+                id,
+                offset,
+                len,
+                size,
+            } => write!(
+                f,
+                "Range {}+{} is out of bounds of file {} ({} bytes)",
+                offset, len, id, size
+            ),
+            Error::KeyRequired => write!(f, "Block is encrypted, decryption key is required"),
+            Error::DecryptionFailed => write!(f, "Unable to decrypt file content"),
+            Error::UnsafeLocation(location) => {
+                write!(
+                    f,
+                    "Location points outside of output directory: {}",
+                    location
+                )
+            }
+            Error::InvalidLocation(path) => {
+                write!(f, "Path can't be used as a location: {}", path.display())
+            }
+            Error::InvalidPathPattern(pattern) => write!(
+                f,
+                "Block path pattern must contain exactly one %d or %0Nd: {}",
+                pattern
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,563 @@
+//! gRPC сервер содержимого блоков.
+//!
+//! [`GrpcServer`] реализует сервис `blocky.Blocky` (описание протокола – `proto/blocky.proto`
+//! в репозитории) для набора блоков, упорядоченных по приоритету так же, как в [`MultiBlock`]:
+//!
+//! * `GetById` и `GetByLocation` возвращают поток [`FileChunk`]: метаинформация файла в первом
+//!   сообщении и содержимое частями не больше 64 КиБ;
+//! * `StatBlock` возвращает метаинформацию блоков сервера.
+//!
+//! Содержимое читается из блоков в отдельных потоках, поэтому медленный диск не блокирует
+//! обработку остальных запросов. Доступ к сервису можно ограничить токеном (см.
+//! [`GrpcServer::token`]).
+//!
+//! [`GrpcServer`]: struct.GrpcServer.html
+//! [`MultiBlock`]: ../multi_block/struct.MultiBlock.html
+//! [`FileChunk`]: struct.FileChunk.html
+//! [`GrpcServer::token`]: struct.GrpcServer.html#method.token
+// Ошибки методов сервиса – tonic::Status, размер которого от нас не зависит
+#![allow(clippy::result_large_err)]
+use crate::block::Block;
+use crate::errors::*;
+use crate::multi_block::MultiBlock;
+use crate::server::bearer_token_matches;
+use std::convert::Infallible;
+use std::future::{self, Ready};
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService};
+use tonic::Status;
+
+/// Максимальный размер содержимого в одном сообщении [`FileChunk`]
+///
+/// [`FileChunk`]: struct.FileChunk.html
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Количество сообщений, которые читаются из блока, пока клиент не принял предыдущие
+const CHUNK_BUFFER: usize = 4;
+
+/// Запрос файла по идентификатору
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetByIdRequest {
+    /// Младшие 64 бита идентификатора
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+
+    /// Старшие 64 бита 128-битного идентификатора (см. [`FileInfo::wide_id`])
+    ///
+    /// [`FileInfo::wide_id`]: ../block/struct.FileInfo.html#method.wide_id
+    #[prost(uint64, tag = "2")]
+    pub id_high: u64,
+}
+
+/// Запрос файла по location
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetByLocationRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub location: Vec<u8>,
+}
+
+/// Часть содержимого файла. Сообщения потока следуют в порядке смещения в файле
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FileChunk {
+    /// Метаинформация файла (только в первом сообщении)
+    #[prost(message, optional, tag = "1")]
+    pub file: Option<FileStat>,
+
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+}
+
+/// Метаинформация файла
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FileStat {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+
+    #[prost(uint64, tag = "2")]
+    pub id_high: u64,
+
+    #[prost(bytes = "vec", tag = "3")]
+    pub location: Vec<u8>,
+
+    /// Размер содержимого в байтах
+    #[prost(uint64, tag = "4")]
+    pub size: u64,
+
+    /// MD5 контрольная сумма содержимого
+    #[prost(bytes = "vec", tag = "5")]
+    pub hash: Vec<u8>,
+
+    /// Путь блока, из которого возвращается файл
+    #[prost(string, tag = "6")]
+    pub block: String,
+}
+
+/// Запрос метаинформации блоков
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatBlockRequest {
+    /// Путь блока, как он передан серверу. Пустой путь – все блоки сервера
+    #[prost(string, tag = "1")]
+    pub path: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatBlockResponse {
+    /// Блоки в порядке приоритета
+    #[prost(message, repeated, tag = "1")]
+    pub blocks: Vec<BlockStat>,
+}
+
+/// Метаинформация блока
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BlockStat {
+    #[prost(string, tag = "1")]
+    pub path: String,
+
+    #[prost(uint32, tag = "2")]
+    pub version: u32,
+
+    /// Флаги заголовка блока (см. [`BlockHeader::flags`])
+    ///
+    /// [`BlockHeader::flags`]: ../block/struct.BlockHeader.html#method.flags
+    #[prost(uint32, tag = "3")]
+    pub flags: u32,
+
+    /// Количество файлов в блоке
+    #[prost(uint64, tag = "4")]
+    pub files: u64,
+}
+
+/// Отдает файлы блоков по gRPC (см. [`serve`])
+///
+/// [`serve`]: fn.serve.html
+pub struct GrpcServer {
+    blocks: MultiBlock,
+    /// Пути блоков в порядке приоритета
+    paths: Vec<PathBuf>,
+    token: Option<String>,
+}
+
+type FileStream = ReceiverStream<std::result::Result<FileChunk, Status>>;
+
+impl GrpcServer {
+    /// Создает сервер для блоков `blocks` с путями, по которым они были открыты. Блоки, стоящие
+    /// в списке раньше, перекрывают последующие (см. [`MultiBlock`]).
+    ///
+    /// [`MultiBlock`]: ../multi_block/struct.MultiBlock.html
+    pub fn new(blocks: Vec<(PathBuf, Block)>) -> Self {
+        let (paths, blocks) = blocks.into_iter().unzip();
+        Self {
+            blocks: MultiBlock::new(blocks),
+            paths,
+            token: None,
+        }
+    }
+
+    /// Отвечает статусом `UNAUTHENTICATED` на вызовы без метаданных
+    /// `authorization: Bearer <token>`
+    pub fn token(&mut self, token: impl Into<String>) -> &mut Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Пути блоков в порядке приоритета
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    fn get_by_id(
+        self: &Arc<Self>,
+        request: GetByIdRequest,
+    ) -> std::result::Result<FileStream, Status> {
+        let id = u128::from(request.id_high) << 64 | u128::from(request.id);
+        let block = self
+            .blocks
+            .block_of_wide_id(id)
+            .ok_or_else(|| status(Error::FileNotFound { id }))?;
+        let idx = self
+            .blocks
+            .blocks()
+            .iter()
+            .position(|b| ptr::eq(b, block))
+            .unwrap();
+        Ok(self.stream(idx, id))
+    }
+
+    fn get_by_location(
+        self: &Arc<Self>,
+        request: GetByLocationRequest,
+    ) -> std::result::Result<FileStream, Status> {
+        let (idx, info) = self
+            .blocks
+            .resolve_location(&request.location)
+            .map_err(status)?;
+        Ok(self.stream(idx, info.wide_id()))
+    }
+
+    fn stat_block(
+        &self,
+        request: StatBlockRequest,
+    ) -> std::result::Result<StatBlockResponse, Status> {
+        let blocks = self
+            .paths
+            .iter()
+            .zip(self.blocks.blocks())
+            .map(|(path, block)| (path.to_string_lossy(), block))
+            .filter(|(path, _)| request.path.is_empty() || *path == request.path)
+            .map(|(path, block)| BlockStat {
+                path: path.into_owned(),
+                version: u32::from(block.header().version()),
+                flags: block.header().flags(),
+                files: block.len() as u64,
+            })
+            .collect::<Vec<_>>();
+        if blocks.is_empty() {
+            return Err(Status::not_found(format!(
+                "Block not found: {}",
+                request.path
+            )));
+        }
+        Ok(StatBlockResponse { blocks })
+    }
+
+    /// Передает содержимое файла `id` из блока `idx`. Содержимое читается в отдельном потоке и
+    /// не больше, чем на [`CHUNK_BUFFER`] сообщений вперед.
+    ///
+    /// [`CHUNK_BUFFER`]: constant.CHUNK_BUFFER.html
+    fn stream(self: &Arc<Self>, idx: usize, id: u128) -> FileStream {
+        let (tx, rx) = mpsc::channel(CHUNK_BUFFER);
+        let server = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            let (header, content) = match server.blocks.blocks()[idx].file_by_wide_id(id) {
+                Ok(file) => file,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(status(e)));
+                    return;
+                }
+            };
+            let mut file = Some(FileStat {
+                id: id as u64,
+                id_high: (id >> 64) as u64,
+                location: header.location,
+                size: content.len() as u64,
+                hash: header.hash.to_vec(),
+                block: server.paths[idx].to_string_lossy().into_owned(),
+            });
+            // Пустой файл передается одним сообщением с метаинформацией
+            let mut offset = 0;
+            loop {
+                let end = content.len().min(offset + CHUNK_SIZE);
+                let chunk = FileChunk {
+                    file: file.take(),
+                    data: content[offset..end].to_vec(),
+                };
+                // Ошибка означает, что клиент закрыл поток
+                if tx.blocking_send(Ok(chunk)).is_err() || end == content.len() {
+                    return;
+                }
+                offset = end;
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+}
+
+fn status(e: Error) -> Status {
+    match e {
+        Error::FileNotFound { .. }
+        | Error::LocationNotFound(_)
+        | Error::LocationHashCollision { .. } => Status::not_found(e.to_string()),
+        Error::ChecksumMismatch { .. } | Error::BlockCorrupted { .. } => {
+            Status::data_loss(e.to_string())
+        }
+        e => Status::internal(e.to_string()),
+    }
+}
+
+/// Запускает gRPC сервер `server` на адресе `addr` в отдельном потоке. Сервер работает до
+/// завершения процесса или ошибки.
+pub fn serve(
+    server: Arc<GrpcServer>,
+    addr: impl ToSocketAddrs,
+) -> io::Result<JoinHandle<io::Result<()>>> {
+    serve_on(server, TcpListener::bind(addr)?)
+}
+
+fn serve_on(
+    server: Arc<GrpcServer>,
+    listener: TcpListener,
+) -> io::Result<JoinHandle<io::Result<()>>> {
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    Ok(thread::spawn(move || {
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            tonic::transport::Server::builder()
+                .add_service(Routes(server))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .map_err(io::Error::other)
+        })
+    }))
+}
+
+/// Диспетчер методов сервиса `blocky.Blocky`
+#[derive(Clone)]
+struct Routes(Arc<GrpcServer>);
+
+impl NamedService for Routes {
+    const NAME: &'static str = "blocky.Blocky";
+}
+
+impl<B> Service<http::Request<B>> for Routes
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<std::result::Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let server = Arc::clone(&self.0);
+        if let Some(token) = &server.token {
+            let authorization = request.headers().get(http::header::AUTHORIZATION);
+            if !bearer_token_matches(authorization.and_then(|a| a.to_str().ok()), token) {
+                let status = Status::unauthenticated("Invalid or missing bearer token");
+                return Box::pin(async { Ok(status.into_http()) });
+            }
+        }
+        match request.uri().path() {
+            "/blocky.Blocky/GetById" => Box::pin(async move {
+                let method = Method(move |request| server.get_by_id(request));
+                let mut grpc = Grpc::new(ProstCodec::default());
+                Ok(grpc.server_streaming(method, request).await)
+            }),
+            "/blocky.Blocky/GetByLocation" => Box::pin(async move {
+                let method = Method(move |request| server.get_by_location(request));
+                let mut grpc = Grpc::new(ProstCodec::default());
+                Ok(grpc.server_streaming(method, request).await)
+            }),
+            "/blocky.Blocky/StatBlock" => Box::pin(async move {
+                let method = Method(move |request| server.stat_block(request));
+                let mut grpc = Grpc::new(ProstCodec::default());
+                Ok(grpc.unary(method, request).await)
+            }),
+            _ => Box::pin(async { Ok(Status::unimplemented("Unknown method").into_http()) }),
+        }
+    }
+}
+
+/// Метод сервиса, синхронно возвращающий ответ на сообщение запроса
+struct Method<F>(F);
+
+impl<F, R, T> Service<tonic::Request<R>> for Method<F>
+where
+    F: FnMut(R) -> std::result::Result<T, Status>,
+{
+    type Response = tonic::Response<T>;
+    type Error = Status;
+    type Future = Ready<std::result::Result<Self::Response, Status>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<std::result::Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: tonic::Request<R>) -> Self::Future {
+        future::ready((self.0)(request.into_inner()).map(tonic::Response::new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::builder::BlockBuilder;
+    use tonic::transport::{Channel, Endpoint};
+
+    fn connect(runtime: &tokio::runtime::Runtime, server: GrpcServer) -> Result<Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        serve_on(Arc::new(server), listener)?;
+        let channel = runtime.block_on(Endpoint::from_shared(endpoint).unwrap().connect());
+        Ok(channel.unwrap())
+    }
+
+    async fn get<R: prost::Message + 'static>(
+        channel: Channel,
+        method: &'static str,
+        request: R,
+    ) -> std::result::Result<Vec<FileChunk>, Status> {
+        let mut client = tonic::client::Grpc::new(channel);
+        client.ready().await.unwrap();
+        let path = http::uri::PathAndQuery::from_static(method);
+        let request = tonic::Request::new(request);
+        let codec = ProstCodec::default();
+        let mut stream = client
+            .server_streaming(request, path, codec)
+            .await?
+            .into_inner();
+        let mut chunks = vec![];
+        while let Some(chunk) = stream.message().await? {
+            chunks.push(chunk);
+        }
+        Ok(chunks)
+    }
+
+    fn data(chunks: &[FileChunk]) -> Vec<u8> {
+        chunks.iter().flat_map(|chunk| chunk.data.clone()).collect()
+    }
+
+    #[test]
+    fn should_stream_files_by_id_and_location() -> Result<()> {
+        let content = (0..150_000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut builder = BlockBuilder::new();
+        builder.add(1, "/a.bin", &content[..]).add(2, "/empty", "");
+        let first = builder.build()?;
+        let mut builder = BlockBuilder::new();
+        builder.add(1, "/a.bin", "overridden").add(3, "/c.txt", "c");
+        let second = builder.build()?;
+        let server = GrpcServer::new(vec![
+            (PathBuf::from("first.block"), first),
+            (PathBuf::from("second.block"), second),
+        ]);
+        let runtime = tokio::runtime::Runtime::new()?;
+        let channel = connect(&runtime, server)?;
+        let get_by_id = |id| {
+            let request = GetByIdRequest { id, id_high: 0 };
+            runtime.block_on(get(channel.clone(), "/blocky.Blocky/GetById", request))
+        };
+
+        let chunks = get_by_id(1).unwrap();
+        assert_eq!(chunks.len(), 3);
+        let file = chunks[0].file.as_ref().unwrap();
+        assert_eq!(file.location, b"/a.bin");
+        assert_eq!(file.size, 150_000);
+        assert_eq!(file.hash, md5::compute(&content).to_vec());
+        assert_eq!(file.block, "first.block");
+        assert!(chunks[1..].iter().all(|chunk| chunk.file.is_none()));
+        assert_eq!(data(&chunks), content);
+
+        let chunks = get_by_id(2).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].file.as_ref().unwrap().size, 0);
+
+        assert_eq!(get_by_id(4).unwrap_err().code(), tonic::Code::NotFound);
+
+        let request = GetByLocationRequest {
+            location: b"/c.txt".to_vec(),
+        };
+        let chunks = runtime.block_on(get(
+            channel.clone(),
+            "/blocky.Blocky/GetByLocation",
+            request,
+        ));
+        let chunks = chunks.unwrap();
+        assert_eq!(chunks[0].file.as_ref().unwrap().block, "second.block");
+        assert_eq!(data(&chunks), b"c");
+
+        let request = GetByLocationRequest {
+            location: b"/missing".to_vec(),
+        };
+        let result = runtime.block_on(get(channel, "/blocky.Blocky/GetByLocation", request));
+        assert_eq!(result.unwrap_err().code(), tonic::Code::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn should_stat_blocks() -> Result<()> {
+        let mut builder = BlockBuilder::new();
+        builder.add(1, "/a.txt", "a").add(2, "/b.txt", "b");
+        let first = builder.build()?;
+        let mut builder = BlockBuilder::new();
+        builder.add(3, "/c.txt", "c");
+        let server = GrpcServer::new(vec![
+            (PathBuf::from("first.block"), first),
+            (PathBuf::from("second.block"), builder.build()?),
+        ]);
+        let runtime = tokio::runtime::Runtime::new()?;
+        let channel = connect(&runtime, server)?;
+        let stat = |path: &str| {
+            let mut client = tonic::client::Grpc::new(channel.clone());
+            let request = tonic::Request::new(StatBlockRequest {
+                path: path.to_string(),
+            });
+            let path = http::uri::PathAndQuery::from_static("/blocky.Blocky/StatBlock");
+            runtime.block_on(async move {
+                client.ready().await.unwrap();
+                let codec = ProstCodec::<_, StatBlockResponse>::default();
+                client.unary(request, path, codec).await
+            })
+        };
+
+        let blocks = stat("").unwrap().into_inner().blocks;
+        let paths = blocks.iter().map(|b| b.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["first.block", "second.block"]);
+        assert_eq!(blocks[0].files, 2);
+        assert_eq!(blocks[1].files, 1);
+
+        let blocks = stat("second.block").unwrap().into_inner().blocks;
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(
+            stat("third.block").unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_require_bearer_token() -> Result<()> {
+        let mut builder = BlockBuilder::new();
+        builder.add(1, "/a.txt", "a");
+        let mut server = GrpcServer::new(vec![(PathBuf::from("a.block"), builder.build()?)]);
+        server.token("secret");
+        let runtime = tokio::runtime::Runtime::new()?;
+        let channel = connect(&runtime, server)?;
+        let stat = |authorization: Option<&'static str>| {
+            let mut client = tonic::client::Grpc::new(channel.clone());
+            let mut request = tonic::Request::new(StatBlockRequest::default());
+            if let Some(authorization) = authorization {
+                let value = authorization.parse().unwrap();
+                request.metadata_mut().insert("authorization", value);
+            }
+            let path = http::uri::PathAndQuery::from_static("/blocky.Blocky/StatBlock");
+            runtime.block_on(async move {
+                client.ready().await.unwrap();
+                let codec = ProstCodec::<_, StatBlockResponse>::default();
+                client.unary(request, path, codec).await
+            })
+        };
+
+        let unauthenticated = tonic::Code::Unauthenticated;
+        assert_eq!(stat(None).unwrap_err().code(), unauthenticated);
+        assert_eq!(
+            stat(Some("Bearer other")).unwrap_err().code(),
+            unauthenticated
+        );
+        assert_eq!(
+            stat(Some("Bearer secret"))
+                .unwrap()
+                .into_inner()
+                .blocks
+                .len(),
+            1
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+//! Индекс блока, сохраняемый в отдельный файл (`.idx`).
+//!
+//! Индекс содержит отсортированные таблицы `id → (offset, size)` и `location_hash → id`, что
+//! позволяет серверам находить файлы без открытия самого блока и декодирования его заголовка.
+//!
+//! ## Формат
+//! ```text
+//! magic (4 байта, "BIDX") | version (2 байта) | size (4 байта)
+//! size × [id (8 байт) | offset (4 байта) | size (4 байта)]   – сортировка по id
+//! size × [location_hash (16 байт) | id (8 байт)]             – сортировка по location_hash
+//! ```
+use crate::block::{Block, BlockHeader, SelfSerialize};
+use crate::errors::*;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const INDEX_MAGIC: &[u8; 4] = b"BIDX";
+const INDEX_VERSION: u16 = 1;
+
+/// Положение файла в блоке
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IndexEntry {
+    pub id: u64,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct BlockIndex {
+    by_id: Vec<IndexEntry>,
+    by_location: Vec<(md5::Digest, u64)>,
+}
+
+impl BlockIndex {
+    /// Строит индекс по заголовку блока
+    pub fn from_header(header: &BlockHeader) -> Self {
+        let mut by_id = header
+            .file_info()
+            .iter()
+            .map(|info| IndexEntry {
+                id: info.id,
+                offset: info.offset,
+                size: info.size,
+            })
+            .collect::<Vec<_>>();
+        by_id.sort_by_key(|entry| entry.id);
+
+        let mut by_location = header
+            .file_info()
+            .iter()
+            .map(|info| (info.location_hash, info.id))
+            .collect::<Vec<_>>();
+        by_location.sort_by_key(|(hash, _)| hash.0);
+
+        Self { by_id, by_location }
+    }
+
+    /// Читает индекс из файла
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::decode(&mut reader).map_err(|e| match e {
+            Error::Io(e) => Error::IndexCorrupted(e.to_string()),
+            e => e,
+        })
+    }
+
+    /// Записывает индекс в файл
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.encode(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Возвращает положение файла с идентификатором `id`
+    pub fn get(&self, id: u64) -> Option<&IndexEntry> {
+        self.by_id
+            .binary_search_by_key(&id, |entry| entry.id)
+            .ok()
+            .map(|idx| &self.by_id[idx])
+    }
+
+    /// Возвращает идентификатор файла по хешу его location
+    pub fn id_by_location_hash(&self, location_hash: &md5::Digest) -> Option<u64> {
+        self.by_location
+            .binary_search_by_key(&location_hash.0, |(hash, _)| hash.0)
+            .ok()
+            .map(|idx| self.by_location[idx].1)
+    }
+
+    /// Содержит ли блок файл с идентификатором `id`
+    pub fn contains_id(&self, id: u64) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// Содержит ли блок файл с хешем location `location_hash`. Location должен быть
+    /// нормализован по правилам блока (см. [`Block::normalization`]).
+    ///
+    /// [`Block::normalization`]: ../block/struct.Block.html#method.normalization
+    pub fn contains_location_hash(&self, location_hash: &md5::Digest) -> bool {
+        self.id_by_location_hash(location_hash).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+}
+
+impl SelfSerialize for BlockIndex {
+    fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()> {
+        target.write_all(INDEX_MAGIC)?;
+        target.write_u16::<LE>(INDEX_VERSION)?;
+        target.write_u32::<LE>(self.by_id.len() as u32)?;
+        for entry in self.by_id.iter() {
+            target.write_u64::<LE>(entry.id)?;
+            target.write_u32::<LE>(entry.offset)?;
+            target.write_u32::<LE>(entry.size)?;
+        }
+        for (hash, id) in self.by_location.iter() {
+            target.write_all(&hash.0)?;
+            target.write_u64::<LE>(*id)?;
+        }
+        Ok(())
+    }
+
+    fn decode(source: &mut impl ReadBytesExt) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        source.read_exact(&mut magic)?;
+        let version = source.read_u16::<LE>()?;
+        if &magic != INDEX_MAGIC || version != INDEX_VERSION {
+            return Err(Error::IndexCorrupted("unknown magic or version".into()));
+        }
+
+        let len = source.read_u32::<LE>()?;
+        let mut by_id = vec![];
+        for _ in 0..len {
+            by_id.push(IndexEntry {
+                id: source.read_u64::<LE>()?,
+                offset: source.read_u32::<LE>()?,
+                size: source.read_u32::<LE>()?,
+            });
+        }
+        let mut by_location = vec![];
+        for _ in 0..len {
+            let mut hash = [0u8; 16];
+            source.read_exact(&mut hash)?;
+            by_location.push((md5::Digest(hash), source.read_u64::<LE>()?));
+        }
+        Ok(Self { by_id, by_location })
+    }
+}
+
+impl Block {
+    /// Записывает индекс блока в отдельный файл (см. [`BlockIndex`])
+    ///
+    /// [`BlockIndex`]: ../index/struct.BlockIndex.html
+    pub fn write_index(&self, path: impl AsRef<Path>) -> Result<()> {
+        BlockIndex::from_header(self.header()).write(path)
+    }
+}
+
+/// Возвращает путь к индексу по умолчанию для блока: `<block>.idx`
+pub fn index_path_for(block_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = OsString::from(block_path.as_ref().as_os_str());
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::AddFileRequest;
+    use std::fs;
+
+    #[test]
+    fn should_be_able_to_write_and_read_index() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-index-test")?;
+        let first = tmp.path().join("first.txt");
+        let second = tmp.path().join("second.txt");
+        fs::write(&first, "Hello")?;
+        fs::write(&second, "World!")?;
+        let block_path = tmp.path().join("test.block");
+        let block = Block::from_files(
+            &block_path,
+            &[
+                AddFileRequest {
+                    id: 20,
+                    path: &first,
+                    location: Path::new("/first.txt"),
+                    content_hash: None,
+                    size: None,
+                },
+                AddFileRequest {
+                    id: 10,
+                    path: &second,
+                    location: Path::new("/second.txt"),
+                    content_hash: None,
+                    size: None,
+                },
+            ],
+        )?;
+
+        let index_path = index_path_for(&block_path);
+        block.write_index(&index_path)?;
+        let index = BlockIndex::open(&index_path)?;
+
+        assert_eq!(index, BlockIndex::from_header(block.header()));
+        assert_eq!(index.len(), 2);
+        let entry = index.get(10).unwrap();
+        assert_eq!(entry.size, 6);
+        assert_eq!(entry.offset, block.iter().nth(1).unwrap().offset);
+        assert_eq!(index.get(15), None);
+        assert!(index.contains_id(20));
+        assert!(!index.contains_id(15));
+        assert!(index.contains_location_hash(&md5::compute("/second.txt")));
+        assert!(!index.contains_location_hash(&md5::compute("/third.txt")));
+        assert_eq!(
+            index.id_by_location_hash(&md5::compute("/first.txt")),
+            Some(20)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_non_index_files() {
+        let mut bytes = &b"BLCK\x01\x00\x00\x00\x00\x00"[..];
+        assert!(BlockIndex::decode(&mut bytes).is_err());
+    }
+}
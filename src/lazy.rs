@@ -0,0 +1,422 @@
+//! Ленивое декодирование заголовка блока.
+//!
+//! [`Block`] при открытии декодирует метаинформацию всех файлов блока, что для блоков с
+//! миллионами файлов заметно замедляет открытие и требует памяти пропорционально количеству
+//! файлов. [`LazyBlock`] при открытии читает только поля заголовка фиксированного размера, а
+//! записи метаинформации читает из хранилища блока и декодирует при обращении к ним.
+//!
+//! Для поиска по идентификатору и location при первом таком поиске строится индекс –
+//! упорядоченная по ключу перестановка записей (4 байта на файл), по которой выполняется
+//! двоичный поиск. Если записи в заголовке уже упорядочены по идентификатору, индекс по
+//! идентификатору не строится.
+//!
+//! В отличии от [`Block::open`] заголовок при открытии не проверяется целиком: файл, выходящий
+//! за пределы блока, обнаруживается только при его чтении, а поврежденный основной заголовок не
+//! заменяется резервной копией. Копии заголовков файлов (см.
+//! [`BlockOptions::header_locations`]) и метаданные блока не читаются. Блоки со сжатым блоком
+//! метаинформации (см. [`BlockOptions::compress_header`]) не поддерживаются.
+//!
+//! [`Block`]: ../block/struct.Block.html
+//! [`Block::open`]: ../block/struct.Block.html#method.open
+//! [`LazyBlock`]: struct.LazyBlock.html
+//! [`BlockOptions::header_locations`]: ../block/struct.BlockOptions.html#method.header_locations
+//! [`BlockOptions::compress_header`]: ../block/struct.BlockOptions.html#method.compress_header
+use crate::block::{
+    header_corrupted, read_entry, read_signature, trailer_start_at, BlockHeader, ContentDecoder,
+    FileHeader, FileInfo,
+};
+#[cfg(feature = "encryption")]
+use crate::encryption::EncryptionKey;
+use crate::errors::*;
+use crate::location::{self, Normalization};
+use crate::storage::BlockStorage;
+#[cfg(not(target_arch = "wasm32"))]
+use memmap::MmapOptions;
+use std::borrow::Cow;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::File;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Блок, метаинформация файлов которого декодируется по запросу
+pub struct LazyBlock {
+    data: Box<dyn BlockStorage>,
+    /// Размер блока без подписи
+    data_len: u64,
+    /// Заголовок без записей метаинформации
+    header: BlockHeader,
+    len: usize,
+    /// Смещение первой записи метаинформации
+    records_start: u64,
+    record_len: usize,
+    verify_on_read: bool,
+    /// Номера записей, упорядоченные по идентификатору, или `None`, если записи уже
+    /// упорядочены
+    id_index: OnceLock<Option<Vec<u32>>>,
+    /// Номера записей, упорядоченные по хешу location
+    location_index: OnceLock<Vec<u32>>,
+    #[cfg(feature = "encryption")]
+    decryption_key: Option<EncryptionKey>,
+}
+
+impl LazyBlock {
+    /// Отображает блок `path` в память и читает поля заголовка фиксированного размера
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+        Self::from_storage(mmap).map_err(|e| e.with_path(path))
+    }
+
+    /// Открывает блок, содержимое которого находится в `storage` (см. [`Block::from_storage`])
+    ///
+    /// [`Block::from_storage`]: ../block/struct.Block.html#method.from_storage
+    pub fn from_storage(storage: impl BlockStorage + 'static) -> Result<Self> {
+        let data: Box<dyn BlockStorage> = Box::new(storage);
+        let (data_len, _) = read_signature(&*data)?;
+        let (mut header, mut len) = decode_fixed_at(&*data, 0, data_len)?;
+        let mut header_start = 0;
+        if header.is_streamed() {
+            // Метаинформация блока, записанного потоком, находится в конце блока
+            header_start = trailer_start_at(&*data, data_len).ok_or_else(|| {
+                Error::corrupted("meta section of a streamed block is missing or corrupted")
+            })?;
+            let (trailer, trailer_len) = decode_fixed_at(&*data, header_start, data_len)?;
+            header = trailer;
+            len = trailer_len;
+        }
+        if header.has_compressed_header() {
+            return Err(Error::UnsupportedFeature(
+                "lazy decoding of a compressed header".into(),
+            ));
+        }
+
+        let record_len = FileInfo::encoded_len(header.flags());
+        let records_start = header_start + header.encoded_len();
+        let records_end = u64::from(len)
+            .checked_mul(record_len)
+            .and_then(|records_len| records_start.checked_add(records_len))
+            .filter(|&end| end <= data_len);
+        if records_end.is_none() {
+            return Err(Error::corrupted(format!(
+                "Header of {} files exceeds block size of {} bytes",
+                len, data_len
+            )));
+        }
+        Ok(Self {
+            data,
+            data_len,
+            header,
+            len: len as usize,
+            records_start,
+            record_len: record_len as usize,
+            verify_on_read: false,
+            id_index: OnceLock::new(),
+            location_index: OnceLock::new(),
+            #[cfg(feature = "encryption")]
+            decryption_key: None,
+        })
+    }
+
+    pub fn version(&self) -> u16 {
+        self.header.version()
+    }
+
+    /// Флаги заголовка блока (см. [`BlockHeader::flags`])
+    ///
+    /// [`BlockHeader::flags`]: ../block/struct.BlockHeader.html#method.flags
+    pub fn flags(&self) -> u32 {
+        self.header.flags()
+    }
+
+    /// Правила нормализации location, с которыми создан блок
+    pub fn normalization(&self) -> Normalization {
+        Normalization::from_flags(self.header.flags())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Проверять ли контрольную сумму содержимого при каждом чтении (см.
+    /// [`Block::verify_on_read`])
+    ///
+    /// [`Block::verify_on_read`]: ../block/struct.Block.html#method.verify_on_read
+    pub fn verify_on_read(&mut self, verify_on_read: bool) -> &mut Self {
+        self.verify_on_read = verify_on_read;
+        self
+    }
+
+    /// Задает ключ для чтения зашифрованного блока (см. [`Block::decryption_key`])
+    ///
+    /// [`Block::decryption_key`]: ../block/struct.Block.html#method.decryption_key
+    #[cfg(feature = "encryption")]
+    pub fn decryption_key(&mut self, key: EncryptionKey) -> &mut Self {
+        self.decryption_key = Some(key);
+        self
+    }
+
+    /// Декодирует запись метаинформации с порядковым номером `idx`
+    pub fn file_info(&self, idx: usize) -> Option<FileInfo> {
+        if idx >= self.len {
+            return None;
+        }
+        let start = self.records_start + (idx * self.record_len) as u64;
+        let record = self.data.read_at(start, self.record_len).ok()?;
+        FileInfo::decode_with_flags(&mut &record[..], self.header.flags()).ok()
+    }
+
+    /// Метаинформация файлов блока в порядке записей заголовка. Записи декодируются по мере
+    /// перебора.
+    pub fn iter(&self) -> impl Iterator<Item = FileInfo> + '_ {
+        (0..self.len).filter_map(move |idx| self.file_info(idx))
+    }
+
+    /// Ищет метаинформацию файла с идентификатором `id` двоичным поиском (см. описание модуля)
+    pub fn find_by_id(&self, id: u64) -> Option<FileInfo> {
+        self.find_by_wide_id(u128::from(id))
+    }
+
+    /// Аналог [`find_by_id`] для 128-битных идентификаторов
+    ///
+    /// [`find_by_id`]: #method.find_by_id
+    pub fn find_by_wide_id(&self, id: u128) -> Option<FileInfo> {
+        let wide_id = |idx| self.file_info(idx).map(|info| info.wide_id());
+        let idx = match self.id_index() {
+            Some(index) => {
+                let pos = lower_bound(index.len(), |pos| wide_id(index[pos] as usize), Some(id));
+                *index.get(pos)? as usize
+            }
+            None => lower_bound(self.len, wide_id, Some(id)),
+        };
+        self.file_info(idx).filter(|info| info.wide_id() == id)
+    }
+
+    /// Номера записей, упорядоченные по идентификатору, или `None`, если записи уже
+    /// упорядочены. Строится при первом обращении.
+    fn id_index(&self) -> Option<&[u32]> {
+        let index = self.id_index.get_or_init(|| {
+            let wide_id = |idx: usize| self.file_info(idx).map(|info| info.wide_id());
+            if (1..self.len).all(|idx| wide_id(idx - 1) < wide_id(idx)) {
+                return None;
+            }
+            let mut index = (0..self.len as u32).collect::<Vec<_>>();
+            index.sort_unstable_by_key(|&idx| wide_id(idx as usize));
+            Some(index)
+        });
+        index.as_deref()
+    }
+
+    /// Номера записей, упорядоченные по хешу location. Строится при первом обращении.
+    fn location_index(&self) -> &[u32] {
+        self.location_index.get_or_init(|| {
+            let mut index = (0..self.len as u32).collect::<Vec<_>>();
+            index.sort_unstable_by_key(|&idx| self.location_hash(idx as usize));
+            index
+        })
+    }
+
+    fn location_hash(&self, idx: usize) -> Option<[u8; 16]> {
+        self.file_info(idx).map(|info| info.location_hash.0)
+    }
+
+    /// Возвращает заголовок и содержимое файла с порядковым номером `idx` (см.
+    /// [`Block::file_at`])
+    ///
+    /// [`Block::file_at`]: ../block/struct.Block.html#method.file_at
+    pub fn file_at(&self, idx: usize) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        let len = self.len;
+        let info = self
+            .file_info(idx)
+            .ok_or(Error::IndexOutOfRange { idx, len })?;
+        self.read_file(&info)
+    }
+
+    /// Возвращает заголовок и содержимое файла с идентификатором `id` (см.
+    /// [`Block::file_by_id`])
+    ///
+    /// [`Block::file_by_id`]: ../block/struct.Block.html#method.file_by_id
+    pub fn file_by_id(&self, id: u64) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        self.file_by_wide_id(u128::from(id))
+    }
+
+    /// Аналог [`file_by_id`] для 128-битных идентификаторов
+    ///
+    /// [`file_by_id`]: #method.file_by_id
+    pub fn file_by_wide_id(&self, id: u128) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        let info = self.find_by_wide_id(id).ok_or(Error::FileNotFound { id })?;
+        self.read_file(&info)
+    }
+
+    /// Возвращает заголовок и содержимое файла с location `location`, сверяя location целиком
+    /// (см. [`Block::resolve_location`])
+    ///
+    /// [`Block::resolve_location`]: ../block/struct.Block.html#method.resolve_location
+    pub fn file_by_location(
+        &self,
+        location: impl AsRef<[u8]>,
+    ) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        let location = self.normalization().apply(location.as_ref());
+        let location_hash = md5::compute(&location);
+        let index = self.location_index();
+        let start = lower_bound(
+            index.len(),
+            |pos| self.location_hash(index[pos] as usize),
+            Some(location_hash.0),
+        );
+
+        let mut unconfirmed = None;
+        let mut collision = None;
+        let candidates = index[start..]
+            .iter()
+            .map_while(|&idx| self.file_info(idx as usize))
+            .take_while(|info| info.location_hash == location_hash);
+        for info in candidates {
+            let (header, payload) = read_entry(&*self.data, self.data_len, &info, u16::MAX)?;
+            if header.location[..] == location[..] {
+                return self.read_file(&info);
+            }
+            // Заголовок дедуплицированного файла содержит location другого файла
+            if md5::compute(&header.location) != location_hash {
+                unconfirmed = unconfirmed.or(Some((info, header, payload)));
+            } else {
+                collision = collision.or(Some(info.id));
+            }
+        }
+        match (unconfirmed, collision) {
+            (Some((info, header, payload)), _) => {
+                let content = self.decoder().checked_content(&info, &header, payload)?;
+                Ok((header, content))
+            }
+            (None, Some(id)) => Err(Error::LocationHashCollision {
+                location: location::display(&location).into_owned(),
+                id,
+            }),
+            (None, None) => Err(Error::LocationNotFound(
+                location::display(&location).into_owned(),
+            )),
+        }
+    }
+
+    fn read_file(&self, info: &FileInfo) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        let (header, payload) = read_entry(&*self.data, self.data_len, info, u16::MAX)?;
+        let content = self.decoder().checked_content(info, &header, payload)?;
+        Ok((header, content))
+    }
+
+    fn decoder(&self) -> ContentDecoder<'_> {
+        ContentDecoder {
+            header: &self.header,
+            verify_on_read: self.verify_on_read,
+            #[cfg(feature = "encryption")]
+            decryption_key: self.decryption_key.as_ref(),
+        }
+    }
+}
+
+/// Читает поля заголовка фиксированного размера (см. [`BlockHeader::decode_fixed`]),
+/// записанного со смещения `start`
+///
+/// [`BlockHeader::decode_fixed`]: ../block/struct.BlockHeader.html#method.decode_fixed
+fn decode_fixed_at(
+    data: &dyn BlockStorage,
+    start: u64,
+    data_len: u64,
+) -> Result<(BlockHeader, u32)> {
+    // Версия, флаги и количество файлов
+    let len = data_len.saturating_sub(start).min(2 + 4 + 4);
+    let bytes = data.read_at(start, len as usize)?;
+    BlockHeader::decode_fixed(&bytes).map_err(header_corrupted)
+}
+
+/// Двоичный поиск первой позиции из `0..len`, ключ `key_at` которой не меньше `key`. Ключи
+/// должны быть упорядочены по возрастанию.
+fn lower_bound<K: Ord>(len: usize, key_at: impl Fn(usize) -> K, key: K) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if key_at(mid) < key {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::{AddFileRequest, Block, BlockOptions};
+    use crate::builder::BlockBuilder;
+    use std::path::PathBuf;
+
+    #[test]
+    fn should_decode_file_info_on_demand() -> Result<()> {
+        for ids in &[[1, 2, 3], [3, 1, 2]] {
+            let mut builder = BlockBuilder::new();
+            for id in ids {
+                builder.add(*id, format!("/{}.txt", id), format!("content {}", id));
+            }
+            let block = LazyBlock::from_storage(builder.finish()?)?;
+            assert_eq!(block.len(), 3);
+            assert_eq!(block.id_index().is_none(), ids == &[1, 2, 3]);
+            assert_eq!(block.iter().map(|info| info.id).collect::<Vec<_>>(), ids);
+
+            assert_eq!(block.file_by_id(2)?.1, &b"content 2"[..]);
+            assert_eq!(block.file_by_location("/3.txt")?.1, &b"content 3"[..]);
+            assert_eq!(
+                block.file_at(1)?.0.location,
+                format!("/{}.txt", ids[1]).as_bytes()
+            );
+            assert!(matches!(
+                block.file_by_id(4),
+                Err(Error::FileNotFound { id: 4 })
+            ));
+            assert!(matches!(
+                block.file_by_location("/4.txt"),
+                Err(Error::LocationNotFound(_))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_streamed_block_lazily() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-lazy-test")?;
+        let files = (1..=2)
+            .map(|id| {
+                let path = tmp.path().join(format!("{}.txt", id));
+                std::fs::write(&path, format!("content {}", id))?;
+                Ok((path, PathBuf::from(format!("/{}.txt", id))))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let requests = files
+            .iter()
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location,
+                content_hash: None,
+                size: None,
+            });
+        let block_path = tmp.path().join("test.block");
+        let mut options = BlockOptions::new();
+        options
+            .dedup(true)
+            .create_from_iter(&block_path, requests)?;
+
+        let block = LazyBlock::open(&block_path)?;
+        assert_eq!(block.len(), Block::open(&block_path)?.len());
+        assert_eq!(block.file_by_location("/2.txt")?.1, &b"content 2"[..]);
+        Ok(())
+    }
+}
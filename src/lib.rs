@@ -1,28 +1,40 @@
-#[macro_use]
-extern crate error_chain;
-
 pub mod block;
-
-pub mod errors {
-    error_chain! {
-        errors {
-            NoFilesInBlock {
-
-            }
-            BlockCorrupted {
-                description("Illegal block structure")
-            }
-
-            HeaderCorrupted {
-                description("Header corrupted")
-            }
-
-            BlockFileAlreadyExists(path: String) {
-                display("Block file already exists: {}", path)
-            }
-        }
-        foreign_links {
-            Io(::std::io::Error);
-        }
-    }
-}
+#[cfg(not(target_arch = "wasm32"))]
+pub mod block_set;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod builder;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cache;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod catalog;
+pub mod chunks;
+#[cfg(feature = "zstd")]
+mod compression;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub mod errors;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod index;
+pub mod lazy;
+pub mod location;
+pub mod manifest;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod metrics;
+pub mod multi_block;
+pub mod overlay;
+pub mod parity;
+pub mod reader;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod repair;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod scrub;
+#[cfg(feature = "serde")]
+mod serialization;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
+#[cfg(feature = "signing")]
+pub mod signature;
+pub mod storage;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
@@ -0,0 +1,487 @@
+//! Работа с location файлов блока.
+//!
+//! Location – путь файла относительно корня блока с разделителем `/` (например, `/img/123.jpg`).
+//! Location хранится в блоке как последовательность байт и не обязан быть корректной UTF-8
+//! строкой: имена файлов в большинстве файловых систем Unix – произвольные байты (см.
+//! [`from_path`] и [`display`]).
+//! Location записывается в блок тем, кто его создает, поэтому при выгрузке файлов на диск ему
+//! нельзя доверять: location вида `/../../etc/cron.d/x` не должен выйти за пределы директории
+//! выгрузки (см. [`extraction_path`]).
+//!
+//! Один и тот же файл может быть адресован разными строками: `/a//b.jpg`, `/a/./b.jpg` и
+//! `/a/b.jpg`. Поиск по location выполняется по MD5-хешу строки, поэтому при создании блока
+//! location можно нормализовать (см. [`Normalization`]). Правила нормализации записываются в
+//! заголовок блока, и [`Block::find_by_location`] применяет к искомому location те же правила.
+//! В частности, правило [`Normalization::WINDOWS`] приводит пути Windows (`C:\img\a.jpg`) к
+//! виду `/img/a.jpg`, так что блоки, созданные на Windows, можно читать на других платформах.
+//!
+//! [`from_path`]: fn.from_path.html
+//! [`display`]: fn.display.html
+//! [`extraction_path`]: fn.extraction_path.html
+//! [`Normalization`]: struct.Normalization.html
+//! [`Normalization::WINDOWS`]: struct.Normalization.html#associatedconstant.WINDOWS
+//! [`Block::find_by_location`]: ../block/struct.Block.html#method.find_by_location
+use crate::block::{
+    FLAG_NORMALIZE_LOWERCASE, FLAG_NORMALIZE_PERCENT, FLAG_NORMALIZE_POSIX, FLAG_NORMALIZE_WINDOWS,
+};
+use crate::errors::*;
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::ops::BitOr;
+use std::path::{Component, Path, PathBuf};
+
+/// Возвращает location, соответствующий пути `path` на локальной ФС.
+///
+/// На Unix location совпадает с байтами пути. На остальных платформах путь должен быть
+/// корректной Unicode строкой, иначе возвращается [`Error::InvalidLocation`].
+///
+/// [`Error::InvalidLocation`]: ../errors/enum.Error.html#variant.InvalidLocation
+pub fn from_path(path: &Path) -> Result<&[u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    path.to_str()
+        .map(str::as_bytes)
+        .ok_or_else(|| Error::InvalidLocation(path.to_path_buf()))
+}
+
+/// Возвращает location в виде строки для вывода пользователю. Байты, не являющиеся корректной
+/// UTF-8 последовательностью, заменяются символом `U+FFFD`.
+pub fn display(location: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(location)
+}
+
+/// Возвращает location в виде имени файла локальной ФС. На платформах, отличных от Unix,
+/// location должен быть корректной UTF-8 строкой.
+pub fn to_os_str(location: &[u8]) -> Option<&OsStr> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(OsStr::from_bytes(location))
+    }
+    #[cfg(not(unix))]
+    std::str::from_utf8(location).ok().map(OsStr::new)
+}
+
+/// Правила нормализации location, применяемые при создании блока и поиске файлов в нем.
+///
+/// Правила комбинируются оператором `|` и применяются в следующем порядке:
+/// * [`WINDOWS`] – разделители `\` заменяются на `/`, а префиксы путей Windows (`C:`,
+///   `\\server\share`, `\\?\C:`, `\\?\UNC\server\share`) удаляются, так что
+///   location становится абсолютным относительно корня диска или ресурса;
+/// * [`PERCENT_DECODE`] – последовательности `%XX` заменяются байтами;
+/// * [`POSIX`] – повторяющиеся `/` и компоненты `.` удаляются, а `..` удаляет предыдущий
+///   компонент;
+/// * [`LOWERCASE`] – location приводится к нижнему регистру (если location не является
+///   корректной UTF-8 строкой – только символы ASCII).
+///
+/// ```rust
+/// use blocky::location::Normalization;
+/// let normalization = Normalization::POSIX | Normalization::LOWERCASE;
+/// assert_eq!(normalization.apply("/Img//./a/../B.jpg"), &b"/img/b.jpg"[..]);
+/// ```
+///
+/// [`WINDOWS`]: #associatedconstant.WINDOWS
+/// [`PERCENT_DECODE`]: #associatedconstant.PERCENT_DECODE
+/// [`POSIX`]: #associatedconstant.POSIX
+/// [`LOWERCASE`]: #associatedconstant.LOWERCASE
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct Normalization {
+    flags: u32,
+}
+
+impl Normalization {
+    /// Location используется как есть
+    pub const NONE: Self = Self { flags: 0 };
+    pub const POSIX: Self = Self {
+        flags: FLAG_NORMALIZE_POSIX,
+    };
+    pub const LOWERCASE: Self = Self {
+        flags: FLAG_NORMALIZE_LOWERCASE,
+    };
+    pub const PERCENT_DECODE: Self = Self {
+        flags: FLAG_NORMALIZE_PERCENT,
+    };
+    pub const WINDOWS: Self = Self {
+        flags: FLAG_NORMALIZE_WINDOWS,
+    };
+
+    /// Правила нормализации, записанные во флагах заголовка блока
+    pub fn from_flags(flags: u32) -> Self {
+        Self {
+            flags: flags
+                & (FLAG_NORMALIZE_POSIX
+                    | FLAG_NORMALIZE_LOWERCASE
+                    | FLAG_NORMALIZE_PERCENT
+                    | FLAG_NORMALIZE_WINDOWS),
+        }
+    }
+
+    /// Флаги заголовка блока, соответствующие правилам
+    pub fn flags(self) -> u32 {
+        self.flags
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.flags & other.flags == other.flags
+    }
+
+    /// Нормализует location
+    pub fn apply(self, location: &(impl AsRef<[u8]> + ?Sized)) -> Cow<'_, [u8]> {
+        let mut location = Cow::Borrowed(location.as_ref());
+        if self.contains(Self::WINDOWS) {
+            location = Cow::Owned(windows_normalize(&location));
+        }
+        if self.contains(Self::PERCENT_DECODE) && location.contains(&b'%') {
+            location = Cow::Owned(percent_decode(&location));
+        }
+        if self.contains(Self::POSIX) {
+            location = Cow::Owned(posix_normalize(&location));
+        }
+        if self.contains(Self::LOWERCASE) {
+            location = Cow::Owned(match std::str::from_utf8(&location) {
+                Ok(location) => location.to_lowercase().into_bytes(),
+                Err(_) => location.to_ascii_lowercase(),
+            });
+        }
+        location
+    }
+
+    /// MD5-хеш location после нормализации – значение, по которому блок ищет файл (см.
+    /// [`FileInfo::location_hash`])
+    ///
+    /// [`FileInfo::location_hash`]: ../block/struct.FileInfo.html#structfield.location_hash
+    pub fn hash(self, location: &(impl AsRef<[u8]> + ?Sized)) -> md5::Digest {
+        md5::compute(self.apply(location))
+    }
+}
+
+impl BitOr for Normalization {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self {
+            flags: self.flags | other.flags,
+        }
+    }
+}
+
+/// Заменяет разделители `\` на `/` и удаляет префикс пути Windows
+fn windows_normalize(location: &[u8]) -> Vec<u8> {
+    let location = location
+        .iter()
+        .map(|&b| if b == b'\\' { b'/' } else { b })
+        .collect::<Vec<_>>();
+    match strip_windows_prefix(&location) {
+        Some(rest) if rest.starts_with(b"/") => rest.to_vec(),
+        Some(rest) => [&b"/"[..], rest].concat(),
+        None => location,
+    }
+}
+
+/// Возвращает часть пути после префикса Windows (диска, UNC или verbatim префикса) или `None`,
+/// если путь не содержит префикса. Разделители в пути уже должны быть заменены на `/`.
+fn strip_windows_prefix(location: &[u8]) -> Option<&[u8]> {
+    let verbatim = location
+        .strip_prefix(b"//?/")
+        .or_else(|| location.strip_prefix(b"//./"));
+    if let Some(verbatim) = verbatim {
+        return match verbatim.strip_prefix(b"UNC/") {
+            Some(unc) => Some(skip_names(unc, 2)),
+            None => strip_drive(verbatim).or_else(|| Some(skip_names(verbatim, 1))),
+        };
+    }
+    match location.strip_prefix(b"//") {
+        Some(unc) => Some(skip_names(unc, 2)),
+        None => strip_drive(location),
+    }
+}
+
+/// Возвращает часть пути после буквы диска (`C:`)
+fn strip_drive(path: &[u8]) -> Option<&[u8]> {
+    match path {
+        [letter, b':', rest @ ..] if letter.is_ascii_alphabetic() => Some(rest),
+        _ => None,
+    }
+}
+
+/// Пропускает `count` первых имен пути. Возвращаемая часть пути начинается с `/` или пуста.
+fn skip_names(path: &[u8], count: usize) -> &[u8] {
+    let mut rest = path;
+    for _ in 0..count {
+        let name_start = rest.strip_prefix(b"/").unwrap_or(rest);
+        rest = match name_start.iter().position(|b| *b == b'/') {
+            Some(end) => &name_start[end..],
+            None => &[],
+        };
+    }
+    rest
+}
+
+/// Заменяет последовательности `%XX` соответствующими байтами
+fn percent_decode(bytes: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes.get(idx + 1..idx + 3) {
+            Some(&[hi, lo])
+                if bytes[idx] == b'%' && hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() =>
+            {
+                let hex = [hi, lo];
+                let hex = std::str::from_utf8(&hex).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                idx += 3;
+            }
+            _ => {
+                decoded.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Удаляет повторяющиеся `/` и компоненты `.`, а также разрешает компоненты `..`. Для
+/// абсолютных location `..` в корне отбрасывается, для относительных – сохраняется.
+fn posix_normalize(location: &[u8]) -> Vec<u8> {
+    let absolute = location.starts_with(b"/");
+    let mut parts: Vec<&[u8]> = vec![];
+    for part in location.split(|b| *b == b'/') {
+        match part {
+            b"" | b"." => {}
+            b".." => match parts.last() {
+                Some(&last) if last != b".." => {
+                    parts.pop();
+                }
+                _ if !absolute => parts.push(b".."),
+                _ => {}
+            },
+            part => parts.push(part),
+        }
+    }
+    let normalized = parts.join(&b'/');
+    if absolute {
+        [&b"/"[..], &normalized].concat()
+    } else {
+        normalized
+    }
+}
+
+/// Возвращает путь, по которому файл с location `location` выгружается в директорию `dir`.
+///
+/// Location интерпретируется относительно `dir`: ведущий `/` и компоненты `.` отбрасываются.
+/// Если location содержит компоненты `..`, префиксы путей Windows (`C:`, `\\server\share`)
+/// или разделители, отличные от `/`, позволяющие выйти за пределы `dir` на текущей платформе,
+/// а также если location не содержит ни одного имени или не может быть именем файла на
+/// текущей платформе (см. [`to_os_str`]), возвращается [`Error::UnsafeLocation`].
+///
+/// [`Error::UnsafeLocation`]: ../errors/enum.Error.html#variant.UnsafeLocation
+/// [`to_os_str`]: fn.to_os_str.html
+pub fn extraction_path(dir: &Path, location: &[u8]) -> Result<PathBuf> {
+    let unsafe_location = || Error::UnsafeLocation(display(location).into_owned());
+    let mut path = dir.to_path_buf();
+    let mut names = 0;
+    let parts = location
+        .split(|b| *b == b'/')
+        .filter(|p| !p.is_empty() && *p != b".");
+    for part in parts {
+        // Компонент должен оставаться одним именем и с точки зрения платформы: на Windows
+        // `..\x` или `C:` внутри компонента также позволяют выйти за пределы `dir`
+        let part = to_os_str(part).ok_or_else(unsafe_location)?;
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => path.push(name),
+            _ => return Err(unsafe_location()),
+        }
+        names += 1;
+    }
+    if names == 0 {
+        return Err(unsafe_location());
+    }
+    Ok(path)
+}
+
+/// Проверяет, соответствует ли location шаблону `pattern`.
+///
+/// Шаблон сопоставляется с location целиком, побайтово:
+///
+/// * `?` – любой байт, кроме `/`;
+/// * `*` – любая последовательность байт, не содержащая `/`;
+/// * `**` – любая последовательность байт, в том числе содержащая `/`. `**/` может также не
+///   соответствовать ни одной директории: `/img/**/a.jpg` соответствует `/img/a.jpg`;
+/// * `[abc]`, `[a-z]`, `[!a-z]` – байт из набора (или не из набора). Набор не соответствует `/`;
+/// * `\` экранирует следующий символ.
+///
+/// Например, шаблон `/img/2023/**` соответствует всем файлам поддерева `/img/2023`.
+pub fn matches_glob(pattern: &[u8], location: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => location.is_empty(),
+        Some((b'*', rest)) if rest.first() == Some(&b'*') => {
+            let rest = &rest[1..];
+            if let Some(after_slash) = rest.strip_prefix(b"/") {
+                if matches_glob(after_slash, location) {
+                    return true;
+                }
+            }
+            (0..=location.len()).any(|i| matches_glob(rest, &location[i..]))
+        }
+        Some((b'*', rest)) => {
+            let segment = location.iter().position(|b| *b == b'/');
+            let segment = segment.unwrap_or(location.len());
+            (0..=segment).any(|i| matches_glob(rest, &location[i..]))
+        }
+        Some((b'?', rest)) => match location.split_first() {
+            Some((b, tail)) if *b != b'/' => matches_glob(rest, tail),
+            _ => false,
+        },
+        Some((b'[', rest)) => match (parse_class(rest), location.split_first()) {
+            (Some((class_matches, rest)), Some((b, tail))) => {
+                *b != b'/' && class_matches(*b) && matches_glob(rest, tail)
+            }
+            // Незакрытая `[` сопоставляется как обычный символ
+            (None, Some((b'[', tail))) => matches_glob(rest, tail),
+            _ => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            location.first() == Some(&rest[0]) && matches_glob(&rest[1..], &location[1..])
+        }
+        Some((c, rest)) => location.first() == Some(c) && matches_glob(rest, &location[1..]),
+    }
+}
+
+/// Разбирает набор символов шаблона (без открывающей `[`). Возвращает предикат и остаток
+/// шаблона после закрывающей `]`
+fn parse_class(pattern: &[u8]) -> Option<(impl Fn(u8) -> bool + '_, &[u8])> {
+    let (negated, body) = match pattern.first() {
+        Some(b'!') | Some(b'^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+    // `]` сразу после `[` – часть набора, а не его конец
+    let end = 1 + body.get(1..)?.iter().position(|b| *b == b']')?;
+    let (set, rest) = (&body[..end], &body[end + 1..]);
+    let matches = move |b: u8| {
+        let mut i = 0;
+        let mut found = false;
+        while i < set.len() {
+            if i + 2 < set.len() && set[i + 1] == b'-' {
+                found |= set[i] <= b && b <= set[i + 2];
+                i += 3;
+            } else {
+                found |= set[i] == b;
+                i += 1;
+            }
+        }
+        found != negated
+    };
+    Some((matches, rest))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn should_confine_extraction_paths_to_directory() -> Result<()> {
+        let dir = Path::new("/tmp/out");
+        assert_eq!(extraction_path(dir, b"/img/1.jpg")?, dir.join("img/1.jpg"));
+        assert_eq!(
+            extraction_path(dir, b"img//./1.jpg")?,
+            dir.join("img/1.jpg")
+        );
+
+        let unsafe_locations = ["", "/", "/./", "../x", "/img/../../etc/passwd", "/a/.."];
+        for location in unsafe_locations.iter() {
+            match extraction_path(dir, location.as_bytes()) {
+                Err(Error::UnsafeLocation(_)) => {}
+                r => panic!("UnsafeLocation expected for {:?}, got: {:?}", location, r),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_match_location_globs() {
+        let cases = [
+            ("/img/2023/**", "/img/2023/01/a.jpg", true),
+            ("/img/2023/**", "/img/2024/a.jpg", false),
+            ("/img/*.jpg", "/img/a.jpg", true),
+            ("/img/*.jpg", "/img/2023/a.jpg", false),
+            ("/img/**/a.jpg", "/img/a.jpg", true),
+            ("/img/**/a.jpg", "/img/x/y/a.jpg", true),
+            ("**.png", "/a/b.png", true),
+            ("/?.jpg", "/a.jpg", true),
+            ("/?.jpg", "/ab.jpg", false),
+            ("/[a-c].jpg", "/b.jpg", true),
+            ("/[!a-c].jpg", "/b.jpg", false),
+            ("/[]].jpg", "/].jpg", true),
+            ("/[a.jpg", "/[a.jpg", true),
+            ("/\\*.jpg", "/*.jpg", true),
+            ("/\\*.jpg", "/a.jpg", false),
+            ("/a.jpg", "/a.jpg", true),
+            ("", "", true),
+        ];
+        for (pattern, location, expected) in cases.iter() {
+            assert_eq!(
+                matches_glob(pattern.as_bytes(), location.as_bytes()),
+                *expected,
+                "{} ~ {}",
+                pattern,
+                location
+            );
+        }
+    }
+
+    #[test]
+    fn should_normalize_locations() {
+        let all = Normalization::PERCENT_DECODE | Normalization::POSIX | Normalization::LOWERCASE;
+        let cases = [
+            (Normalization::NONE, "/a//b.jpg", "/a//b.jpg"),
+            (Normalization::POSIX, "/a//./b.jpg", "/a/b.jpg"),
+            (Normalization::POSIX, "/../a/c/../b.jpg/", "/a/b.jpg"),
+            (Normalization::POSIX, "../a/./b", "../a/b"),
+            (Normalization::LOWERCASE, "/A/B.JPG", "/a/b.jpg"),
+            (
+                Normalization::PERCENT_DECODE,
+                "/a%20b/%D1%84%zz%",
+                "/a b/ф%zz%",
+            ),
+            (all, "/A%2F%2e/B.jpg", "/a/b.jpg"),
+            (Normalization::WINDOWS, "C:\\Img\\a.jpg", "/Img/a.jpg"),
+            (Normalization::WINDOWS, "c:img\\a.jpg", "/img/a.jpg"),
+            (Normalization::WINDOWS, "img\\a.jpg", "img/a.jpg"),
+            (Normalization::WINDOWS, "/img/a.jpg", "/img/a.jpg"),
+            (Normalization::WINDOWS, "\\\\server\\share\\a.jpg", "/a.jpg"),
+            (Normalization::WINDOWS, "\\\\?\\D:\\a.jpg", "/a.jpg"),
+            (
+                Normalization::WINDOWS,
+                "\\\\?\\UNC\\srv\\share\\a\\b",
+                "/a/b",
+            ),
+            (Normalization::WINDOWS, "\\\\server\\share", "/"),
+        ];
+        for (normalization, location, expected) in cases.iter() {
+            let normalized = normalization.apply(*location);
+            assert_eq!(normalized, expected.as_bytes(), "{}", location);
+        }
+        // Location, не являющиеся UTF-8 строками, также нормализуются
+        let normalized = all.apply(b"/A/%FF/../\xFF.JPG");
+        assert_eq!(normalized, &b"/a/\xff.jpg"[..]);
+        assert_eq!(Normalization::from_flags(all.flags() | 0x1), all);
+        assert_eq!(
+            Normalization::POSIX.hash("/a//b.jpg"),
+            md5::compute("/a/b.jpg")
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn should_reject_windows_prefixes_and_separators() {
+        for location in ["/C:/x", "/a/..\\..\\x", "\\\\server\\share\\x"].iter() {
+            assert!(extraction_path(Path::new("C:\\out"), location.as_bytes()).is_err());
+        }
+    }
+}
@@ -4,10 +4,48 @@ extern crate clap;
 extern crate error_chain;
 extern crate blocky;
 
-use ::blocky::block::{AddFileRequest, Block};
-use clap::{App, ArgMatches, SubCommand};
+use ::blocky::block::{
+    self, AddFileRequest, Advice, Block, BlockHeader, BlockLayout, BlockOptions, EntryKind,
+    FileHeader, FileInfo, IdAssignment, FLAG_COMPRESSED_HEADER, FLAG_HEADER_LOCATIONS,
+    FLAG_METADATA, FLAG_STREAMED, FLAG_WIDE_IDS, MAX_SUPPORTED_VERSION,
+};
+use ::blocky::block_set::{BlockSetBuilder, BlockSetWriter};
+use ::blocky::catalog::Catalog;
+use ::blocky::chunks::{chunks_path_for, DEFAULT_CHUNK_SIZE};
+#[cfg(feature = "encryption")]
+use ::blocky::encryption::{EncryptionKey, KEY_ENV_VAR};
+#[cfg(feature = "grpc")]
+use ::blocky::grpc::{self, GrpcServer};
+use ::blocky::index::index_path_for;
+use ::blocky::location::{self, Normalization};
+use ::blocky::manifest;
+use ::blocky::metrics::{self, Registry};
+use ::blocky::parity::{parity_path_for, Parity, DEFAULT_GROUP_SIZE, DEFAULT_SHARD_SIZE};
+use ::blocky::repair;
+use ::blocky::scrub::{self, RateLimiter, ScrubRecord, ScrubState, SCRUB_STATE_FILE_NAME};
+use ::blocky::server::{self, AccessLogFormat, FileServer};
+#[cfg(feature = "signing")]
+use ::blocky::signature;
+use byteorder::{ByteOrder, LittleEndian};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use error_chain::ChainedError;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::io::{self, stdout, BufWriter, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+#[allow(deprecated)]
 mod errors {
     error_chain! {
         foreign_links {
@@ -15,44 +53,576 @@ mod errors {
             Io(::std::io::Error);
             Blocky(::blocky::errors::Error);
         }
+
+        errors {
+            /// Файл или location не найдены в блоке
+            NotFound(details: String) {
+                description("not found")
+                display("{}", details)
+            }
+
+            /// Содержимое файлов блока не совпадает с контрольными суммами
+            VerificationFailed(failed: usize) {
+                description("verification failed")
+                display("Verification failed for {} file(s)", failed)
+            }
+        }
     }
 }
 
 use errors::*;
 
-quick_main!(application);
+/// Коды завершения, по которым скрипты могут отличить причину ошибки, не разбирая ее текст
+const EXIT_FAILURE: i32 = 1;
+const EXIT_CORRUPTED: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_IO: i32 = 4;
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    success
+    1    other errors (including invalid arguments)
+    2    block is corrupted or file content doesn't match its checksum
+    3    file or location not found in a block
+    4    I/O error
+
+CONFIGURATION:
+    Defaults for long options of subcommands are read from ~/.config/blocky/config.toml
+    ($XDG_CONFIG_HOME/blocky/config.toml, or the file in $BLOCKY_CONFIG). Top-level keys apply
+    to every subcommand accepting the option, keys in a [subcommand] section only to that
+    subcommand. Flags take true or false:
+
+        jobs = 8
+        [inspect]
+        human-readable = true
+
+    BLOCKY_<OPTION> environment variables (e.g. BLOCKY_JOBS=8, BLOCKY_MAX_BANDWIDTH=50M)
+    override the file, options on the command line override both.";
+
+fn main() {
+    process::exit(match application() {
+        Ok(()) => 0,
+        Err(e) => {
+            eprint!("{}", e.display_chain());
+            exit_code(&e)
+        }
+    });
+}
+
+/// Код завершения по первой ошибке в цепочке причин, для которой он определен
+fn exit_code(e: &Error) -> i32 {
+    let mut cause = own_exit_code(e);
+    let mut source = std::error::Error::source(e);
+    while let (None, Some(e)) = (cause, source) {
+        cause = if let Some(e) = e.downcast_ref::<Error>() {
+            own_exit_code(e)
+        } else if let Some(e) = e.downcast_ref::<blocky::errors::Error>() {
+            blocky_exit_code(e)
+        } else if e.is::<io::Error>() {
+            Some(EXIT_IO)
+        } else {
+            None
+        };
+        source = e.source();
+    }
+    cause.unwrap_or(EXIT_FAILURE)
+}
+
+fn own_exit_code(e: &Error) -> Option<i32> {
+    match e.kind() {
+        ErrorKind::NotFound(_) => Some(EXIT_NOT_FOUND),
+        ErrorKind::VerificationFailed(_) => Some(EXIT_CORRUPTED),
+        ErrorKind::Io(_) => Some(EXIT_IO),
+        ErrorKind::Blocky(e) => blocky_exit_code(e),
+        _ => None,
+    }
+}
+
+fn blocky_exit_code(e: &blocky::errors::Error) -> Option<i32> {
+    use blocky::errors::Error::*;
+    match e {
+        Io(_) => Some(EXIT_IO),
+        BlockCorrupted { .. }
+        | IndexCorrupted(_)
+        | CatalogCorrupted(_)
+        | ChunkHashesCorrupted(_)
+        | ParityCorrupted(_)
+        | ChecksumMismatch { .. }
+        | EntryOutOfBounds { .. }
+        | SignatureInvalid => Some(EXIT_CORRUPTED),
+        FileNotFound { .. } | LocationNotFound(_) | ContentNotFound(_) | IndexOutOfRange { .. } => {
+            Some(EXIT_NOT_FOUND)
+        }
+        _ => None,
+    }
+}
 
 fn application() -> Result<()> {
     let app = App::new("block")
         .version("1.0")
         .author("Denis Bazhenov <dotsid@gmail.com>")
         .about("Block inspection utility")
+        .after_help(EXIT_CODES_HELP)
         .subcommand(
             SubCommand::with_name("inspect")
                 .about("Inspect block contents")
                 .arg_from_usage(
                     "[verbose] -v, --verbose 'Report detailed information about each file'",
                 )
-                .arg_from_usage("<INPUT>... 'Block file names to inspect'"),
+                .arg(
+                    Arg::from_usage("[summary] --summary 'Print one row per block with totals'")
+                        .conflicts_with_all(&[
+                            "verbose", "id", "location", "min-size", "max-size", "sort", "reverse",
+                        ]),
+                )
+                .arg(
+                    Arg::from_usage("[id] --id=[ID]... 'Show only files with given IDs'")
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::from_usage(
+                        "[location] --location=[LOCATION]... 'Show only files with given locations'",
+                    )
+                    .number_of_values(1),
+                )
+                .arg_from_usage(
+                    "[min-size] --min-size=[SIZE] 'Show only files of at least SIZE (e.g. 100M)'",
+                )
+                .arg_from_usage("[max-size] --max-size=[SIZE] 'Show only files of at most SIZE'")
+                .arg(
+                    Arg::from_usage("[sort] --sort=[KEY] 'Sort files by the key'")
+                        .possible_values(&["id", "size", "offset", "location"]),
+                )
+                .arg_from_usage("[reverse] --reverse 'Reverse the sort order'")
+                .arg_from_usage("[human] -H, --human-readable 'Print sizes in KiB, MiB, GiB'")
+                .arg(
+                    Arg::from_usage(
+                        "[format] --format=[FORMAT] 'Output format: table (default) or jsonl (one JSON object per file)'",
+                    )
+                    .possible_values(&["table", "jsonl"])
+                    .conflicts_with("summary"),
+                )
+                .arg_from_usage("[quiet] -q, --quiet 'Print nothing, report the result by exit code only'")
+                .arg_from_usage("<INPUT>... 'Block file names to inspect (- to read from stdin)'"),
+        )
+        .subcommand(
+            SubCommand::with_name("header")
+                .about("Print raw header fields of the block without validating it")
+                .arg_from_usage("[hex] --hex 'Print annotated hex dump of the header and meta section'")
+                .arg_from_usage("<BLOCK> 'Block file name'"),
         )
         .subcommand(
             SubCommand::with_name("create")
                 .about("Create new block")
-                .arg_from_usage("<BLOCK> 'Block file name'")
+                .arg_from_usage("[fsync] --fsync 'Flush the block to disk before exiting'")
+                .arg_from_usage("[packed] --packed 'Store files without alignment padding'")
+                .arg_from_usage("[dedup] --dedup 'Store files with identical content only once'")
+                .arg_from_usage(
+                    "[trailer] --trailer 'Store a backup copy of the header at the end'",
+                )
+                .arg_from_usage("[sparse] --sparse 'Do not allocate disk space for padding'")
+                .arg_from_usage("[compress] --compress 'Compress file content with zstd'")
+                .arg_from_usage(
+                    "[compress-header] --compress-header 'Compress file records of the block header with zstd'",
+                )
+                .arg_from_usage(
+                    "[skip-empty] --skip-empty 'Skip zero-length files with a warning instead of storing them'",
+                )
+                .arg_from_usage(
+                    "[manifest] --manifest 'Store a JSON manifest of all files as a file with id 18446744073709551615 and location /.blocky/manifest.json'",
+                )
+                .arg_from_usage(
+                    "[reserve-entries] --reserve-entries=[N] 'Reserve header space for N more files'",
+                )
+                .arg_from_usage(
+                    "[header-locations] --header-locations 'Duplicate file locations in the block header so they can be listed without reading file content'",
+                )
+                .arg_from_usage(
+                    "[wide-ids] --wide-ids 'Store 128-bit file ids in the block header'",
+                )
+                .arg_from_usage(
+                    "[content-addressed] --content-addressed 'Identify files by content hash, skipping files whose content is already stored'",
+                )
+                .arg_from_usage(
+                    "[metadata] --metadata 'Record creation time and blocky version in the block header'",
+                )
+                .arg(
+                    Arg::from_usage("[ids] --ids=[STRATEGY] 'How file ids are assigned'")
+                        .possible_values(&["sequential", "content-hash", "location-hash"])
+                        .default_value("sequential"),
+                )
+                .arg_from_usage(
+                    "[normalize] --normalize=[RULES] 'Normalize locations: comma-separated posix, lowercase, percent-decode, windows'",
+                )
+                .arg_from_usage(
+                    "[max-block-size] --max-block-size=[SIZE] 'Split files into several blocks of at most SIZE (e.g. 4GiB) named by <BLOCK> pattern (e.g. out-%03d.block)'",
+                )
+                .arg(
+                    Arg::from_usage(
+                        "[dry-run] --dry-run 'Print the block layout without writing the block'",
+                    )
+                    .conflicts_with("max-block-size"),
+                )
+                .arg_from_usage("<BLOCK> 'Block file name (- to stream the block to stdout)'")
                 .arg_from_usage("<INPUT>... 'file list'"),
         )
         .subcommand(
             SubCommand::with_name("export")
                 .about("Export file form the block")
+                .arg(
+                    Arg::from_usage("[out] -o, --out=[PATH] 'Write file to PATH instead of stdout'")
+                        .conflicts_with("out-dir"),
+                )
+                .arg_from_usage(
+                    "[out-dir] --out-dir=[DIR] 'Write each file to DIR/<ID> (required for multiple IDs)'",
+                )
+                .arg(
+                    Arg::from_usage(
+                        "[location] -l, --location=[LOCATION]... 'Export file by its location'",
+                    )
+                    .number_of_values(1),
+                )
+                .arg_from_usage(
+                    "[list] --list=[FILE] 'Export files listed in FILE, one ID or location per line (- for stdin)'",
+                )
+                .arg_from_usage("[verify] --verify 'Fail if file content doesn't match its checksum'")
+                .arg(
+                    Arg::from_usage(
+                        "[range] --range=[OFFSET:LEN] 'Export only LEN bytes of file content starting at OFFSET'",
+                    )
+                    .conflicts_with_all(&["out-dir", "verify"]),
+                )
+                .arg_from_usage(
+                    "[key-file] --key-file=[FILE] 'Decryption key file for encrypted blocks (default: $BLOCKY_KEY)'",
+                )
+                .arg_from_usage("<BLOCK> 'Block file name'")
+                .arg_from_usage("[ID]... 'File IDs to be exported'"),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Extract all files from the block into a directory")
+                .arg_from_usage("[jobs] -j, --jobs=[N] 'Number of files written concurrently'")
+                .arg_from_usage(
+                    "[by-location] --by-location 'Write files as <DIR>/<LOCATION> instead of <DIR>/<ID>'",
+                )
+                .arg(
+                    Arg::from_usage(
+                        "[unsafe-paths] --unsafe-paths 'Allow locations pointing outside of <DIR> (absolute or with ..)'",
+                    )
+                    .requires("by-location"),
+                )
+                .arg_from_usage("[verify] --verify 'Fail if file content doesn't match its checksum'")
+                .arg(
+                    Arg::from_usage(
+                        "[include] --include=[GLOB]... 'Extract only files with location matching the glob (e.g. /img/2023/**)'",
+                    )
+                    .number_of_values(1),
+                )
+                .arg(
+                    Arg::from_usage(
+                        "[exclude] --exclude=[GLOB]... 'Skip files with location matching the glob'",
+                    )
+                    .number_of_values(1),
+                )
+                .arg_from_usage(
+                    "[key-file] --key-file=[FILE] 'Decryption key file for encrypted blocks (default: $BLOCKY_KEY)'",
+                )
+                .arg_from_usage("<BLOCK> 'Block file name'")
+                .arg_from_usage("<DIR> 'Output directory'"),
+        )
+        .subcommand(
+            SubCommand::with_name("checksums")
+                .about("Print MD5 checksums of block files in md5sum format")
+                .arg_from_usage(
+                    "[by-id] --by-id 'Name files by <ID> instead of <LOCATION> (as extract without --by-location)'",
+                )
+                .arg_from_usage(
+                    "[key-file] --key-file=[FILE] 'Decryption key file for encrypted blocks (default: $BLOCKY_KEY)'",
+                )
+                .arg_from_usage("<BLOCK> 'Block file name'"),
+        )
+        .subcommand(
+            SubCommand::with_name("grep")
+                .about("Print files of the block containing the pattern and offsets of its occurrences")
+                .arg_from_usage("[ignore-case] -i, --ignore-case 'Ignore ASCII case when matching'")
+                .arg(
+                    Arg::from_usage(
+                        "[include] --include=[GLOB]... 'Search only files with location matching the glob'",
+                    )
+                    .number_of_values(1),
+                )
+                .arg(
+                    Arg::from_usage(
+                        "[exclude] --exclude=[GLOB]... 'Skip files with location matching the glob'",
+                    )
+                    .number_of_values(1),
+                )
+                .arg_from_usage(
+                    "[key-file] --key-file=[FILE] 'Decryption key file for encrypted blocks (default: $BLOCKY_KEY)'",
+                )
+                .arg_from_usage("<BLOCK> 'Block file name'")
+                .arg_from_usage("<PATTERN> 'Byte string to search for'"),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Write sidecar index file for the block")
+                .arg_from_usage("<BLOCK> 'Block file name'")
+                .arg_from_usage("[OUTPUT] 'Index file name (default: <BLOCK>.idx)'"),
+        )
+        .subcommand(
+            SubCommand::with_name("chunks")
+                .about("Write sidecar file with checksums of fixed-size chunks of block files")
+                .arg_from_usage("[chunk-size] --chunk-size=[SIZE] 'Chunk size (default: 4MiB)'")
+                .arg_from_usage("<BLOCK> 'Block file name'")
+                .arg_from_usage("[OUTPUT] 'Chunk checksums file name (default: <BLOCK>.chunks)'"),
+        )
+        .subcommand(
+            SubCommand::with_name("parity")
+                .about("Write sidecar parity file allowing to heal damaged regions of the block")
+                .arg_from_usage("[shard-size] --shard-size=[SIZE] 'Shard size (default: 64KiB)'")
+                .arg_from_usage(
+                    "[group-size] --group-size=[N] 'Shards per parity shard, overhead is ~1/N (default: 32)'",
+                )
                 .arg_from_usage("<BLOCK> 'Block file name'")
-                .arg_from_usage("<ID> 'File ID to be exported'"),
+                .arg_from_usage("[OUTPUT] 'Parity file name (default: <BLOCK>.parity)'"),
+        )
+        .subcommand(
+            SubCommand::with_name("heal")
+                .about("Find damaged regions of the block and restore them from the parity file")
+                .arg_from_usage(
+                    "[parity] --parity=[FILE] 'Parity file name (default: <BLOCK>.parity)'",
+                )
+                .arg_from_usage("[dry-run] -n, --dry-run 'Only report damaged regions'")
+                .arg_from_usage("<BLOCK> 'Block file name'"),
+        )
+        .subcommand(
+            SubCommand::with_name("locate")
+                .about("Find blocks holding the location among all blocks of a directory")
+                .arg_from_usage("[no-save] --no-save 'Do not update the catalog file in <DIR>'")
+                .arg_from_usage("<DIR> 'Directory with *.block files'")
+                .arg_from_usage("<LOCATION> 'File location'"),
+        )
+        .subcommand(
+            SubCommand::with_name("hash")
+                .about("Print location hashes as stored in block headers")
+                .arg(
+                    Arg::from_usage(
+                        "[normalize] --normalize=[RULES] 'Normalize locations before hashing: comma-separated posix, lowercase, percent-decode, windows'",
+                    )
+                    .conflicts_with("block"),
+                )
+                .arg_from_usage(
+                    "[block] --block=[BLOCK] 'Normalize locations by the rules of the block'",
+                )
+                .arg_from_usage("<LOCATION>... 'File locations'"),
+        )
+        .subcommand(
+            SubCommand::with_name("scrub")
+                .about("Continuously verify all blocks of a directory at a bounded read rate")
+                .arg(
+                    Arg::from_usage(
+                        "[interval] --interval=[DURATION] 'Verify each block once per DURATION (e.g. 30m, 24h, 7d)'",
+                    )
+                    .default_value("24h"),
+                )
+                .arg(
+                    Arg::from_usage(
+                        "[max-bandwidth] --max-bandwidth=[RATE] 'Read at most RATE bytes per second (e.g. 50MB/s, default: unlimited)'",
+                    )
+                    .alias("rate"),
+                )
+                .arg_from_usage(
+                    "[state] --state=[FILE] 'File with last verification time of blocks (default: <DIR>/blocky.scrub)'",
+                )
+                .arg_from_usage("[background] --background 'Read blocks with idle I/O priority (Linux only)'")
+                .arg_from_usage("[drop-cache] --drop-cache 'Evict verified blocks from the page cache'")
+                .arg_from_usage(
+                    "[direct-io] --direct-io 'Read blocks bypassing the page cache (O_DIRECT)'",
+                )
+                .arg_from_usage("[once] --once 'Verify due blocks once and exit instead of running forever'")
+                .arg_from_usage(
+                    "[metrics-addr] --metrics-addr=[ADDR] 'Serve Prometheus metrics on http://ADDR/metrics (e.g. 127.0.0.1:9100)'",
+                )
+                .arg_from_usage("<DIR> 'Directory with *.block files'"),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Seal files appearing in a directory into blocks, removing the originals")
+                .arg(
+                    Arg::from_usage("[batch-size] --batch-size=[N] 'Seal a block once it has N files'")
+                        .default_value("10000"),
+                )
+                .arg(
+                    Arg::from_usage(
+                        "[max-age] --max-age=[DURATION] 'Seal a block DURATION after its first file was added (e.g. 30m, 1h)'",
+                    )
+                    .default_value("1h"),
+                )
+                .arg(
+                    Arg::from_usage("[max-block-size] --max-block-size=[SIZE] 'Seal a block before it exceeds SIZE'")
+                        .default_value("4GiB"),
+                )
+                .arg(
+                    Arg::from_usage(
+                        "[pattern] --pattern=[PATTERN] 'Block file names inside <BLOCK_DIR> (e.g. ingest-%06d.block)'",
+                    )
+                    .default_value("%06d.block"),
+                )
+                .arg(
+                    Arg::from_usage("[interval] --interval=[DURATION] 'Scan <INCOMING_DIR> every DURATION'")
+                        .default_value("10s"),
+                )
+                .arg_from_usage(
+                    "[move-to] --move-to=[DIR] 'Move sealed files to DIR instead of deleting them'",
+                )
+                .arg_from_usage("[once] --once 'Seal files present in <INCOMING_DIR> and exit'")
+                .arg_from_usage("<INCOMING_DIR> 'Directory new files appear in'")
+                .arg_from_usage("<BLOCK_DIR> 'Directory to write blocks to'"),
+        )
+        .subcommand(serve_command())
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Rebuild block with corrupted header by scanning its contents")
+                .arg_from_usage("[packed] --packed 'Block was created without alignment padding'")
+                .arg_from_usage(
+                    "[key-file] --key-file=[FILE] 'Key file for encrypted blocks (default: $BLOCKY_KEY)'",
+                )
+                .arg_from_usage("<BLOCK> 'Corrupted block file name'")
+                .arg_from_usage("<OUT> 'Repaired block file name'"),
+        )
+        .subcommand(
+            SubCommand::with_name("delta")
+                .about("Create block with files absent from or changed versus the base block")
+                .arg_from_usage("<BASE> 'Base block file name'")
+                .arg_from_usage("<INPUT>... 'file list'")
+                .arg_from_usage("<OUT> 'Delta block file name'"),
+        )
+        .subcommand(
+            SubCommand::with_name("reblock")
+                .about("Create block from selected files of another block")
+                .arg(
+                    Arg::from_usage("[ids] --ids=[IDS] 'Comma-separated IDs of files to copy'")
+                        .use_delimiter(true)
+                        .required_unless("locations-file"),
+                )
+                .arg_from_usage(
+                    "[locations-file] --locations-file=[FILE] 'File with locations of files to copy, one per line'",
+                )
+                .arg_from_usage(
+                    "[key-file] --key-file=[FILE] 'Key file for encrypted blocks (default: $BLOCKY_KEY)'",
+                )
+                .arg_from_usage("<IN> 'Source block file name'")
+                .arg_from_usage("<OUT> 'New block file name'"),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Report block size statistics")
+                .arg_from_usage("[human] -H, --human-readable 'Print sizes in KiB, MiB, GiB'")
+                .arg_from_usage("<INPUT>... 'Block file names'"),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Verify content checksums of block files")
+                .arg_from_usage("[jobs] -j, --jobs=[N] 'Number of verification threads'")
+                .arg_from_usage(
+                    "[direct-io] --direct-io 'Read blocks bypassing the page cache (O_DIRECT)'",
+                )
+                .arg_from_usage(
+                    "[public-key] --public-key=[FILE] 'Also verify block signatures with the public key'",
+                )
+                .arg_from_usage("[background] --background 'Read blocks with idle I/O priority (Linux only)'")
+                .arg_from_usage("[drop-cache] --drop-cache 'Evict verified blocks from the page cache'")
+                .arg_from_usage("[quiet] -q, --quiet 'Print nothing, report the result by exit code only'")
+                .arg_from_usage("<INPUT>... 'Block file names to verify'"),
+        );
+    #[cfg(feature = "signing")]
+    let app = app
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Generate Ed25519 key pair for signing blocks")
+                .arg_from_usage(
+                    "<KEY> 'Secret key file name (public key is written to <KEY>.pub)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("Sign blocks with Ed25519 secret key")
+                .arg_from_usage("<key-file> --key-file=<FILE> 'Secret key file'")
+                .arg_from_usage("<INPUT>... 'Block file names to sign'"),
+        );
+    #[cfg(feature = "grpc")]
+    let app = app.subcommand(
+        SubCommand::with_name("grpc-serve")
+            .about("Serve files of blocks over gRPC by id or location")
+            .arg(
+                Arg::from_usage("[listen] --listen=[ADDR] 'Address to listen on'")
+                    .default_value("127.0.0.1:50051"),
+            )
+            .arg_from_usage("[verify] --verify 'Fail requests for files whose content doesn't match its checksum'")
+            .arg_from_usage(
+                "[key-file] --key-file=[FILE] 'Decryption key file for encrypted blocks (default: $BLOCKY_KEY)'",
+            )
+            .arg(
+                Arg::from_usage("[token] --token=[TOKEN] 'Reject requests without this bearer token'")
+                    .conflicts_with("token-file"),
+            )
+            .arg_from_usage("[token-file] --token-file=[FILE] 'Read the bearer token from FILE'")
+            .arg_from_usage("<INPUT>... 'Block file names, earlier blocks override later ones'"),
+    );
+    #[cfg(feature = "encryption")]
+    let app = app
+        .subcommand(
+            SubCommand::with_name("encrypt")
+                .about("Rewrite plaintext block into encrypted one")
+                .arg_from_usage(
+                    "[key-file] --key-file=[FILE] '32-byte key file (default: hex key in $BLOCKY_KEY)'",
+                )
+                .arg_from_usage("<IN> 'Plaintext block file name'")
+                .arg_from_usage("<OUT> 'Encrypted block file name'"),
+        )
+        .subcommand(
+            SubCommand::with_name("decrypt")
+                .about("Rewrite encrypted block into plaintext one")
+                .arg_from_usage(
+                    "[key-file] --key-file=[FILE] '32-byte key file (default: hex key in $BLOCKY_KEY)'",
+                )
+                .arg_from_usage("<IN> 'Encrypted block file name'")
+                .arg_from_usage("<OUT> 'Plaintext block file name'"),
         );
 
-    let matches = app.clone().get_matches();
+    let args = with_defaults(&app, env::args_os().collect())?;
+    let matches = app.clone().get_matches_from(args);
     match matches.subcommand() {
         ("inspect", Some(opts)) => inspect(opts),
+        ("header", Some(opts)) => header(opts),
         ("create", Some(opts)) => create(opts),
         ("export", Some(opts)) => export(opts),
+        ("extract", Some(opts)) => extract(opts),
+        ("checksums", Some(opts)) => checksums(opts),
+        ("grep", Some(opts)) => grep(opts),
+        ("verify", Some(opts)) => verify(opts),
+        ("stats", Some(opts)) => stats(opts),
+        ("delta", Some(opts)) => delta(opts),
+        ("reblock", Some(opts)) => reblock(opts),
+        ("index", Some(opts)) => index(opts),
+        ("chunks", Some(opts)) => chunks(opts),
+        ("parity", Some(opts)) => parity(opts),
+        ("heal", Some(opts)) => heal(opts),
+        ("locate", Some(opts)) => locate(opts),
+        ("hash", Some(opts)) => hash(opts),
+        ("scrub", Some(opts)) => scrub(opts),
+        ("watch", Some(opts)) => watch(opts),
+        ("serve", Some(opts)) => serve(opts),
+        #[cfg(feature = "grpc")]
+        ("grpc-serve", Some(opts)) => grpc_serve(opts),
+        ("repair", Some(opts)) => repair(opts),
+        #[cfg(feature = "signing")]
+        ("keygen", Some(opts)) => keygen(opts),
+        #[cfg(feature = "signing")]
+        ("sign", Some(opts)) => sign(opts),
+        #[cfg(feature = "encryption")]
+        ("encrypt", Some(opts)) => encrypt(opts),
+        #[cfg(feature = "encryption")]
+        ("decrypt", Some(opts)) => decrypt(opts),
         _ => {
             app.write_help(&mut io::stdout()).unwrap();
             Ok(())
@@ -60,97 +630,2439 @@ fn application() -> Result<()> {
     }
 }
 
+/// Переменная окружения с путем к конфигурационному файлу
+const CONFIG_ENV_VAR: &str = "BLOCKY_CONFIG";
+
+/// Префикс переменных окружения со значениями опций по умолчанию
+const OPTION_ENV_PREFIX: &str = "BLOCKY_";
+
+/// Значения опций по умолчанию из конфигурационного файла: общие для всех подкоманд и из
+/// секций `[subcommand]`
+#[derive(Default)]
+struct Config {
+    global: Vec<(String, String)>,
+    sections: HashMap<String, Vec<(String, String)>>,
+}
+
+/// Разбирает подмножество TOML: пары `key = value` (строки в кавычках, числа, `true`/`false`),
+/// секции `[name]` и комментарии `#`. В ключах `_` равнозначно `-`.
+fn parse_config(text: &str) -> Result<Config> {
+    let mut config = Config::default();
+    let mut section = None;
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || format!("Invalid config line {}: {}", idx + 1, line);
+        if let Some(name) = line.strip_prefix('[') {
+            let name = strip_comment(name).strip_suffix(']').ok_or_else(invalid)?;
+            section = Some(name.trim().to_string());
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+        let key = key.trim().replace('_', "-");
+        let value = parse_config_value(value.trim()).ok_or_else(invalid)?;
+        if key.is_empty() {
+            bail!(invalid());
+        }
+        match &section {
+            Some(name) => config
+                .sections
+                .entry(name.clone())
+                .or_default()
+                .push((key, value)),
+            None => config.global.push((key, value)),
+        }
+    }
+    Ok(config)
+}
+
+fn strip_comment(value: &str) -> &str {
+    value.split('#').next().unwrap().trim()
+}
+
+fn parse_config_value(value: &str) -> Option<String> {
+    let quoted = match value.strip_prefix('"') {
+        Some(quoted) => quoted,
+        None => {
+            let value = strip_comment(value);
+            return match value.is_empty() || value.starts_with('[') || value.contains('"') {
+                true => None,
+                false => Some(value.to_string()),
+            };
+        }
+    };
+    let mut result = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let rest = chars.as_str().trim();
+                return match rest.is_empty() || rest.starts_with('#') {
+                    true => Some(result),
+                    false => None,
+                };
+            }
+            '\\' => result.push(chars.next()?),
+            c => result.push(c),
+        }
+    }
+    None
+}
+
+/// Путь к конфигурационному файлу: из `BLOCKY_CONFIG`, иначе `blocky/config.toml` в
+/// `$XDG_CONFIG_HOME` или в `~/.config`
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(CONFIG_ENV_VAR) {
+        return Some(PathBuf::from(path));
+    }
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => Path::new(&env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("blocky").join("config.toml"))
+}
+
+/// Дополняет аргументы командной строки значениями опций подкоманды по умолчанию из
+/// переменных окружения `BLOCKY_<OPTION>` и конфигурационного файла (см. [`config_path`]).
+///
+/// Приоритет: командная строка, переменные окружения, секция подкоманды, общие ключи файла.
+/// Ключи – длинные имена опций. Ключи, которых нет среди опций подкоманды, пропускаются, поэтому
+/// общие ключи вроде `jobs` применяются только к подкомандам, принимающим такую опцию.
+fn with_defaults(app: &App, args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let matches = match app.clone().get_matches_from_safe(&args) {
+        Ok(matches) => matches,
+        // Ошибку в аргументах сообщит основной разбор
+        Err(_) => return Ok(args),
+    };
+    let (name, opts) = match matches.subcommand() {
+        (name, Some(opts)) => (name, opts),
+        _ => return Ok(args),
+    };
+
+    let mut defaults = vec![];
+    for (var, value) in env::vars_os() {
+        let var = var.to_string_lossy();
+        if let Some(key) = var.strip_prefix(OPTION_ENV_PREFIX) {
+            if var != CONFIG_ENV_VAR {
+                let key = key.to_lowercase().replace('_', "-");
+                let value = value.to_string_lossy().into_owned();
+                defaults.push((key, value, format!("{} variable", var)));
+            }
+        }
+    }
+    if let Some(path) = config_path().filter(|path| path.is_file()) {
+        let text = fs::read_to_string(&path)
+            .chain_err(|| format!("Unable to read config {}", path.display()))?;
+        let mut config = parse_config(&text)
+            .chain_err(|| format!("Unable to parse config {}", path.display()))?;
+        let section = config.sections.remove(name).unwrap_or_default();
+        for (key, value) in section.into_iter().chain(config.global) {
+            defaults.push((key, value, path.display().to_string()));
+        }
+    }
+
+    let position = args.iter().skip(1).position(|arg| arg == name).unwrap() + 2;
+    let mut args = args;
+    let mut applied = HashSet::new();
+    for (key, value, source) in defaults {
+        if applied.contains(&key) || opts.occurrences_of(&key) > 0 {
+            continue;
+        }
+        let arg = match value.as_str() {
+            "true" => format!("--{}", key),
+            "false" => {
+                applied.insert(key);
+                continue;
+            }
+            value => format!("--{}={}", key, value),
+        };
+        let mut candidate = args.clone();
+        candidate.insert(position, arg.into());
+        match app.clone().get_matches_from_safe(&candidate) {
+            Ok(_) => args = candidate,
+            // Опции нет у подкоманды, либо она уже задана или несовместима с заданными явно
+            Err(e)
+                if matches!(
+                    e.kind,
+                    clap::ErrorKind::UnknownArgument
+                        | clap::ErrorKind::UnexpectedMultipleUsage
+                        | clap::ErrorKind::ArgumentConflict
+                ) =>
+            {
+                continue
+            }
+            Err(e) => bail!(
+                "Invalid default for --{} from {}: {}",
+                key,
+                source,
+                e.message.lines().next().unwrap_or_default()
+            ),
+        }
+        applied.insert(key);
+    }
+    Ok(args)
+}
+
 /// Создает блок на основании файлов на локальной ФС
 ///
-/// В данный момент файлы (их идентификаторы) нумеруются в блоке последовательно.
+/// По умолчанию файлы (их идентификаторы) нумеруются в блоке последовательно, а с
+/// `--ids content-hash` и `--ids location-hash` идентификаторы вычисляются по содержимому или
+/// location файла (см. `IdAssignment`). Если вместо имени блока указан `-`, то блок
+/// записывается потоком в stdout (см. `BlockOptions::stream`).
+///
+/// Директории из списка файлов записываются в блок как записи директорий (см. `EntryKind`):
+/// без содержимого, но с правами доступа. Их файлы нужно перечислить отдельно.
+///
+/// Пустые файлы по умолчанию сохраняются в блоке как обычные. С `--skip-empty` они
+/// пропускаются с предупреждением в stderr, а идентификаторы остальных файлов не меняются.
 fn create(opts: &ArgMatches) -> Result<()> {
     let files = opts.values_of("INPUT").unwrap();
     let block_path = opts.value_of("BLOCK").unwrap();
 
-    let files = files
-        .enumerate()
-        .map(|(id, file)| AddFileRequest {
-            id: (id + 1) as u64,
+    let normalization = normalization(opts.value_of("normalize").unwrap_or("none"))?;
+
+    let mut files = files
+        .map(|file| AddFileRequest {
+            id: 0,
             path: file.as_ref(),
             // TODO разделить путь и URL
             location: file.as_ref(),
+            content_hash: None,
+            size: None,
         })
         .collect::<Vec<_>>();
-    Block::from_files(block_path, &files)
+    let ids = match opts.value_of("ids") {
+        Some("content-hash") => IdAssignment::ContentHash,
+        Some("location-hash") => IdAssignment::LocationHash,
+        _ => IdAssignment::Sequential,
+    };
+    ids.assign(&mut files, normalization)
+        .chain_err(|| "Unable to assign file ids")?;
+    if opts.is_present("skip-empty") {
+        files.retain(|file| match fs::metadata(file.path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() == 0 => {
+                eprintln!("Skipping empty file {}", file.path.display());
+                false
+            }
+            _ => true,
+        });
+    }
+    let mut options = BlockOptions::new();
+    options
+        .sync(opts.is_present("fsync"))
+        .packed(opts.is_present("packed"))
+        .dedup(opts.is_present("dedup"))
+        .header_trailer(opts.is_present("trailer"))
+        .sparse(opts.is_present("sparse"))
+        .compress(opts.is_present("compress"))
+        .compress_header(opts.is_present("compress-header"))
+        .manifest(opts.is_present("manifest"))
+        .header_locations(opts.is_present("header-locations"))
+        .wide_ids(opts.is_present("wide-ids"))
+        .content_addressed(opts.is_present("content-addressed"))
+        .metadata(opts.is_present("metadata"))
+        .location_normalization(normalization);
+    if opts.is_present("reserve-entries") {
+        options.reserve_entries(value_t!(opts.value_of("reserve-entries"), u32)?);
+    }
+    if opts.is_present("dry-run") {
+        let layout = options.plan(&files).chain_err(|| "Unable to plan block")?;
+        return write_layout(&mut BufWriter::new(stdout().lock()), &layout);
+    }
+    if let Some(max_block_size) = opts.value_of("max-block-size") {
+        if block_path == "-" {
+            bail!("--max-block-size can't be used when streaming the block to stdout");
+        }
+        let set = BlockSetBuilder::new(&options, parse_size(max_block_size)?)
+            .create(block_path, &files)
+            .chain_err(|| "Unable to create blocks")?;
+        for (idx, path) in set.blocks().iter().enumerate() {
+            let count = set.ids().filter(|(_, block)| *block == idx).count();
+            println!("{}: {} files", path.display(), count);
+        }
+        return Ok(());
+    }
+    if block_path == "-" {
+        let stdout = stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        return options
+            .stream(&mut out, &files)
+            .map(|_| ())
+            .chain_err(|| "Unable to stream block");
+    }
+    options
+        .create(block_path, &files)
         .map(|_| ())
         .chain_err(|| "Unable to create block")
 }
 
-/// Выводит информацию о содержимом блока
-fn inspect(opts: &ArgMatches) -> Result<()> {
-    let block_paths = opts.values_of("INPUT").unwrap();
-    let verbose = opts.is_present("verbose");
-    let stdout = stdout();
-    let mut out = BufWriter::new(stdout.lock());
-    for block_path in block_paths {
-        out.write_fmt(format_args!("{}\n", block_path))?;
-        let block =
-            Block::open(block_path).chain_err(|| format!("Fail to open block: {}", block_path))?;
+/// Выводит расположение файлов в будущем блоке (`create --dry-run`)
+fn write_layout(out: &mut impl Write, layout: &BlockLayout) -> Result<()> {
+    out.write_fmt(format_args!(
+        "{id:>9} {offset:>10} {size:>10} {location}\n",
+        id = "ID",
+        offset = "OFFSET",
+        size = "SIZE",
+        location = "LOCATION",
+    ))?;
+    for entry in layout.entries.iter() {
+        out.write_fmt(format_args!(
+            "{id:>9} {offset:>10} {size:>10} {location}\n",
+            id = entry.id,
+            offset = entry.offset,
+            size = entry.size,
+            location = location::display(&entry.location),
+        ))?;
+    }
+    let percent = layout.padding as f64 * 100.0 / layout.block_size as f64;
+    out.write_fmt(format_args!(
+        "{:>12}: {} bytes (+{} bytes reserved)\n",
+        "header", layout.header_size, layout.reserved_size
+    ))?;
+    out.write_fmt(format_args!(
+        "{:>12}: {} bytes ({:.1}%)\n",
+        "padding", layout.padding, percent
+    ))?;
+    out.write_fmt(format_args!(
+        "{:>12}: {} bytes\n",
+        "trailer", layout.trailer_size
+    ))?;
+    out.write_fmt(format_args!(
+        "{:>12}: {} bytes\n",
+        "block size", layout.block_size
+    ))?;
+    if !layout.exact {
+        out.write_fmt(format_args!(
+            "Sizes are upper bounds: compression and deduplication are applied while writing\n"
+        ))?;
+    }
+    out.flush()?;
+    Ok(())
+}
 
-        if verbose {
-            out.write_fmt(format_args!(
-                "{id:>9} {size:>9} {offset:>9} {location_hash:>32} {content_hash:>32} {location:}\n",
-                id = "ID",
-                size = "SIZE",
-                offset = "OFFSET",
-                location_hash = "LOCATION HASH",
-                content_hash = "CONTENT HASH",
-                location = "LOCATION",
-            ))?;
+/// Разбирает размер в байтах с необязательным суффиксом: `K`, `M`, `G`, `T` (степени 1024,
+/// допускаются также `KiB`, `KB` и т.д.)
+fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits);
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => bail!(format!("Invalid size: {}", size)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or_else(|| format!("Invalid size: {}", size).into())
+}
+
+/// Разбирает скорость чтения в байтах в секунду: размер (см. `parse_size`) с необязательным
+/// суффиксом `/s`
+fn parse_rate(rate: &str) -> Result<u64> {
+    let rate = rate.trim();
+    parse_size(rate.strip_suffix("/s").unwrap_or(rate))
+}
+
+/// Разбирает диапазон содержимого файла `OFFSET:LEN` (оба значения в байтах)
+fn parse_range(range: &str) -> Result<(u64, u64)> {
+    let parsed = range
+        .split_once(':')
+        .and_then(|(offset, len)| Some((offset.trim().parse().ok()?, len.trim().parse().ok()?)));
+    parsed.ok_or_else(|| format!("Invalid range: {} (expected OFFSET:LEN)", range).into())
+}
+
+/// Разбирает длительность в секундах с необязательным суффиксом: `s`, `m`, `h`, `d`
+fn parse_duration(duration: &str) -> Result<u64> {
+    let duration = duration.trim();
+    let digits = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(digits);
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!(format!("Invalid duration: {}", duration)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid duration: {}", duration).into())
+}
+
+/// Форматирует время в секундах от UNIX epoch как дату и время UTC: `2024-03-01 12:00:00 UTC`
+fn format_time(secs: u64) -> String {
+    // Перевод количества дней в дату григорианского календаря (алгоритм civil_from_days)
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Форматирует размер в байтах. С `human` размер выводится в наибольших единицах (степени 1024),
+/// в которых он не меньше единицы, с одним знаком после запятой: `9.5 KiB`.
+fn format_size(size: u64, human: bool) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if !human || size < 1024 {
+        return if human {
+            format!("{} B", size)
         } else {
-            out.write_fmt(format_args!(
-                "{id:>9} {size:>9} {offset:>9} {location_hash:>32}\n",
-                id = "ID",
-                size = "SIZE",
-                offset = "OFFSET",
-                location_hash = "LOCATION HASH"
-            ))?;
-        }
+            size.to_string()
+        };
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
 
-        for (idx, file) in block.iter().enumerate() {
-            if verbose {
-                let (header, _) = block
-                    .file_at(idx)
-                    .ok_or("Unable to read file from the block")?;
-                out.write_fmt(format_args!(
-                    "{id:>9} {size:>9} {offset:>9} {location_hash:32} {content_hash:32} {location:<}\n",
-                    id = file.id,
-                    size = file.size,
-                    offset = file.offset,
-                    location_hash = format!("{:x}", file.location_hash),
-                    content_hash = format!("{:x}", header.hash),
-                    location = header.location,
-                ))?;
-            } else {
-                out.write_fmt(format_args!(
-                    "{id:>9} {size:>9} {offset:>9} {location_hash:32}\n",
-                    id = file.id,
-                    size = file.size,
-                    offset = file.offset,
-                    location_hash = format!("{:x}", file.location_hash)
-                ))?;
+/// Разбирает правила нормализации location, перечисленные через запятую
+fn normalization(rules: &str) -> Result<Normalization> {
+    let mut normalization = Normalization::NONE;
+    for rule in rules.split(',').map(str::trim) {
+        normalization = normalization
+            | match rule {
+                "none" => Normalization::NONE,
+                "posix" => Normalization::POSIX,
+                "lowercase" => Normalization::LOWERCASE,
+                "percent-decode" => Normalization::PERCENT_DECODE,
+                "windows" => Normalization::WINDOWS,
+                rule => bail!(format!("Unknown location normalization rule: {}", rule)),
+            };
+    }
+    Ok(normalization)
+}
+
+/// Создает блок из файлов другого блока, выбранных по идентификаторам (`--ids`) и/или по
+/// location из файла (`--locations-file`, по одному location в строке, пустые строки
+/// пропускаются)
+fn reblock(opts: &ArgMatches) -> Result<()> {
+    let in_path = opts.value_of("IN").unwrap();
+    let out_path = opts.value_of("OUT").unwrap();
+    #[allow(unused_mut)]
+    let mut block =
+        Block::open(in_path).chain_err(|| format!("Fail to open block: {}", in_path))?;
+    #[cfg(feature = "encryption")]
+    if block.header().is_encrypted() {
+        block.decryption_key(encryption_key(opts)?);
+    }
+
+    let mut ids = match opts.values_of("ids") {
+        Some(_) => values_t!(opts.values_of("ids"), u64)?,
+        None => vec![],
+    };
+    if let Some(locations_file) = opts.value_of("locations-file") {
+        let locations = fs::read(locations_file)
+            .chain_err(|| format!("Unable to read locations file: {}", locations_file))?;
+        for location in locations.split(|&b| b == b'\n') {
+            let location = location.strip_suffix(b"\r").unwrap_or(location);
+            if location.is_empty() {
+                continue;
+            }
+            match block.find_by_location(location) {
+                Some(info) => ids.push(info.id),
+                None => bail!(ErrorKind::NotFound(format!(
+                    "File with location {} not found in a block",
+                    location::display(location)
+                ))),
             }
         }
     }
 
+    let subset = block
+        .subset_to(&ids, out_path)
+        .chain_err(|| format!("Unable to create block: {}", out_path))?;
+    println!(
+        "{} of {} files copied to {}",
+        subset.len(),
+        block.len(),
+        out_path
+    );
     Ok(())
 }
 
-fn export(opts: &ArgMatches) -> Result<()> {
-    let block_file = opts.value_of("BLOCK").unwrap();
-    let id = value_t!(opts.value_of("ID"), u64)?;
+/// Создает разностный блок относительно базового блока
+///
+/// Файлы, уже присутствующие в базовом блоке, сохраняют свои идентификаторы, новые файлы
+/// нумеруются после максимального идентификатора базового блока.
+fn delta(opts: &ArgMatches) -> Result<()> {
+    let base_path = opts.value_of("BASE").unwrap();
+    let out_path = opts.value_of("OUT").unwrap();
+    let base = Block::open(base_path).chain_err(|| format!("Fail to open block: {}", base_path))?;
 
-    let block = Block::open(block_file)?;
-    let (_, content) = block
-        .file_by_id(id)
-        .ok_or(format!("File with id {} not found in a block", id))?;
-    let out = stdout();
-    let mut out = BufWriter::new(out.lock());
-    out.write_all(&content)?;
-    Ok(())
+    let mut next_id = base.iter().map(|f| f.id).max().unwrap_or(0) + 1;
+    let files = opts
+        .values_of("INPUT")
+        .unwrap()
+        .map(|file| {
+            let id = match base.find_by_location(file) {
+                Some(info) => info.id,
+                None => {
+                    next_id += 1;
+                    next_id - 1
+                }
+            };
+            AddFileRequest {
+                id,
+                path: file.as_ref(),
+                location: file.as_ref(),
+                content_hash: None,
+                size: None,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let delta =
+        Block::delta_from(&base, out_path, &files).chain_err(|| "Unable to create delta block")?;
+    let stdout = stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    out.write_fmt(format_args!(
+        "{} of {} files added to delta block\n",
+        delta.len(),
+        files.len()
+    ))?;
+    Ok(())
+}
+
+/// Выводит информацию о содержимом блока
+///
+/// Вместо имени блока можно указать `-`, тогда блок читается из stdin. При этом из потока читается
+/// только метаинформация, а содержимое файлов пропускается.
+///
+/// С `--format jsonl` вместо таблиц выводится по одному JSON-объекту на файл (см.
+/// `write_jsonl_entries`), так что вывод можно обрабатывать построчно, не дожидаясь конца.
+fn inspect(opts: &ArgMatches) -> Result<()> {
+    if opts.is_present("summary") {
+        return inspect_summary(opts);
+    }
+    let block_paths = opts.values_of("INPUT").unwrap();
+    let verbose = opts.is_present("verbose");
+    let human = opts.is_present("human");
+    let filter = EntryFilter::from_opts(opts)?;
+    let sort = opts.value_of("sort");
+    let reverse = opts.is_present("reverse");
+    let jsonl = opts.value_of("format") == Some("jsonl");
+    // Для сортировки по location нужны заголовки файлов, даже если они не выводятся
+    let read_headers = verbose || sort == Some("location");
+    let mut out = output(opts);
+    for block_path in block_paths {
+        if !jsonl {
+            out.write_fmt(format_args!("{}\n", block_path))?;
+        }
+        if block_path == "-" {
+            let stdin = io::stdin();
+            let (header, file_headers) =
+                BlockHeader::read_from_stream(&mut stdin.lock(), read_headers)
+                    .chain_err(|| "Fail to read block from stdin")?;
+            let mut entries = filter.apply(header.flags(), header.file_info(), &file_headers);
+            sort_entries(&mut entries, sort, reverse);
+            if jsonl {
+                write_jsonl_entries(&mut out, block_path, &entries, verbose)?;
+                continue;
+            }
+            write_metadata(&mut out, &header)?;
+            let by_content = header.is_content_addressed() && read_headers;
+            write_entries(&mut out, &entries, verbose, human, by_content)?;
+            continue;
+        }
+
+        let block =
+            Block::open(block_path).chain_err(|| format!("Fail to open block: {}", block_path))?;
+        if block.needs_repair() {
+            let warning = "WARNING: primary header is corrupted, using backup copy";
+            match jsonl {
+                true => eprintln!("{}: {}", block_path, warning),
+                false => out.write_fmt(format_args!("{}\n", warning))?,
+            }
+        }
+        if !jsonl {
+            write_metadata(&mut out, block.header())?;
+        }
+        // Файлы блока, адресуемого по содержимому, выводятся по контрольной сумме
+        let by_content = block.header().is_content_addressed();
+        let read_headers = read_headers || by_content;
+        // Копии заголовков файлов в заголовке блока избавляют от чтения страниц с содержимым
+        let file_headers = match block.header().file_headers() {
+            Some(file_headers) if read_headers => file_headers.to_vec(),
+            _ if read_headers => block
+                .entries()
+                .map(|entry| entry.header().cloned())
+                .collect::<blocky::errors::Result<Vec<_>>>()?,
+            _ => vec![],
+        };
+        let header = block.header();
+        let mut entries = filter.apply(header.flags(), header.file_info(), &file_headers);
+        sort_entries(&mut entries, sort, reverse);
+        match jsonl {
+            true => write_jsonl_entries(&mut out, block_path, &entries, verbose)?,
+            false => write_entries(&mut out, &entries, verbose, human, by_content)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Выводит время создания блока и версию blocky, если они записаны в блок (см.
+/// `BlockOptions::metadata`)
+fn write_metadata(out: &mut impl Write, header: &BlockHeader) -> Result<()> {
+    if let Some(metadata) = header.metadata() {
+        writeln!(
+            out,
+            "created {} by {}",
+            format_time(metadata.created),
+            metadata.created_by
+        )?;
+    }
+    Ok(())
+}
+
+/// Условия отбора файлов, выводимых `inspect`: идентификаторы, location и границы размера.
+/// Файл выводится, если удовлетворяет всем заданным условиям.
+struct EntryFilter {
+    ids: HashSet<u128>,
+    locations: Vec<Vec<u8>>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+impl EntryFilter {
+    fn from_opts(opts: &ArgMatches) -> Result<Self> {
+        let ids = match opts.values_of("id") {
+            Some(_) => values_t!(opts.values_of("id"), u128)?.into_iter().collect(),
+            None => HashSet::new(),
+        };
+        let locations = opts
+            .values_of_os("location")
+            .into_iter()
+            .flatten()
+            .map(|location| Ok(location::from_path(Path::new(location))?.to_vec()))
+            .collect::<Result<Vec<_>>>()?;
+        let size = |name| opts.value_of(name).map(parse_size).transpose();
+        Ok(Self {
+            ids,
+            locations,
+            min_size: size("min-size")?,
+            max_size: size("max-size")?,
+        })
+    }
+
+    /// Отбирает файлы блока с флагами `flags`. Location сравниваются по хешу после
+    /// нормализации, с которой создан блок. `file_headers` либо пуст, либо содержит заголовки
+    /// файлов в том же порядке, что и `file_info`.
+    fn apply<'a>(
+        &self,
+        flags: u32,
+        file_info: &'a [FileInfo],
+        file_headers: &'a [FileHeader],
+    ) -> Vec<(&'a FileInfo, Option<&'a FileHeader>)> {
+        let normalization = Normalization::from_flags(flags);
+        let location_hashes = self
+            .locations
+            .iter()
+            .map(|location| normalization.hash(location))
+            .collect::<Vec<_>>();
+        file_info
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| self.ids.is_empty() || self.ids.contains(&info.wide_id()))
+            .filter(|(_, info)| {
+                location_hashes.is_empty() || location_hashes.contains(&info.location_hash)
+            })
+            .filter(|(_, info)| self.min_size.is_none_or(|min| u64::from(info.size) >= min))
+            .filter(|(_, info)| self.max_size.is_none_or(|max| u64::from(info.size) <= max))
+            .map(|(idx, info)| (info, file_headers.get(idx)))
+            .collect()
+    }
+}
+
+/// Поток для вывода таблиц: stdout, а с `--quiet` – никуда
+fn output(opts: &ArgMatches) -> Box<dyn Write> {
+    if opts.is_present("quiet") {
+        Box::new(io::sink())
+    } else {
+        Box::new(BufWriter::new(stdout().lock()))
+    }
+}
+
+/// Выводит поля заголовка блока: версию формата, флаги и количество файлов, а с `--hex` –
+/// дамп заголовка и блока метаинформации, в котором каждое поле записано отдельной строкой с
+/// расшифровкой.
+///
+/// Заголовок разбирается непосредственно из файла без проверок, которые выполняет
+/// `Block::open`, поэтому команда позволяет изучить и поврежденные блоки, и блоки с
+/// неподдерживаемыми версиями или флагами. Разбор останавливается на конце файла.
+fn header(opts: &ArgMatches) -> Result<()> {
+    let block_path = opts.value_of("BLOCK").unwrap();
+    let file =
+        fs::File::open(block_path).chain_err(|| format!("Fail to open block: {}", block_path))?;
+    let block_len = file.metadata()?.len();
+    let mut raw = RawHeader {
+        reader: io::BufReader::new(file),
+        offset: 0,
+        fields: vec![],
+        truncated: false,
+    };
+
+    let mut out = BufWriter::new(stdout().lock());
+    let version = match raw.field(2, |b| format!("version = {}", LittleEndian::read_u16(b)))? {
+        Some(b) => LittleEndian::read_u16(&b),
+        None => bail!("Block is too short: {} bytes", block_len),
+    };
+    writeln!(out, "version:     {}", version)?;
+    let flags = if version > MAX_SUPPORTED_VERSION {
+        None
+    } else if version >= 2 {
+        raw.field(4, |b| format!("flags = 0x{:x}", LittleEndian::read_u32(b)))?
+            .map(|b| LittleEndian::read_u32(&b))
+    } else {
+        Some(0)
+    };
+    let entries = match flags {
+        Some(_) => raw
+            .field(4, |b| format!("entries = {}", LittleEndian::read_u32(b)))?
+            .map(|b| LittleEndian::read_u32(&b)),
+        _ => None,
+    };
+    if let Some(flags) = flags {
+        let names = (0..32)
+            .map(|bit| 1 << bit)
+            .filter(|flag| flags & flag != 0)
+            .map(block::feature_name)
+            .collect::<Vec<_>>();
+        let names = if names.is_empty() {
+            String::from("none")
+        } else {
+            names.join(", ")
+        };
+        writeln!(out, "flags:       0x{:08x} ({})", flags, names)?;
+    }
+    if version > MAX_SUPPORTED_VERSION {
+        writeln!(out, "unsupported version, remaining fields are unknown")?;
+    }
+    if let Some(entries) = entries {
+        let flags = flags.unwrap_or(0);
+        let wide_ids = flags & FLAG_WIDE_IDS != 0;
+        let entry_len = if wide_ids { 40 } else { 32 };
+        let mut header_len = raw.offset + u64::from(entries) * entry_len;
+        writeln!(out, "entries:     {}", entries)?;
+        // Размер копий заголовков файлов становится известен только после их разбора
+        let header_locations = flags & FLAG_HEADER_LOCATIONS != 0;
+        let metadata = flags & FLAG_METADATA != 0;
+        let compressed = flags & FLAG_COMPRESSED_HEADER != 0 && entries > 0;
+        if opts.is_present("hex") || header_locations || metadata || compressed {
+            // Сжатые записи не распаковываются: поврежденный блок может не распаковаться
+            let compressed_len = if compressed {
+                raw.field(4, |b| {
+                    format!("compressed entries length = {}", LittleEndian::read_u32(b))
+                })?
+                .map(|b| u64::from(LittleEndian::read_u32(&b)))
+            } else {
+                None
+            };
+            match compressed_len {
+                Some(len) if raw.offset + len <= block_len => {
+                    raw.field(len as usize, |_| String::from("compressed entries"))?;
+                }
+                Some(_) => raw.truncated = true,
+                None => {}
+            }
+            let records = if compressed { 0 } else { entries };
+            for idx in 0..records {
+                let id = if wide_ids {
+                    raw.field(16, |b| {
+                        format!("[{}] id = {}", idx, LittleEndian::read_u128(b))
+                    })?
+                } else {
+                    raw.field(8, |b| {
+                        format!("[{}] id = {}", idx, LittleEndian::read_u64(b))
+                    })?
+                };
+                let size = raw.field(4, |b| {
+                    format!("[{}] size = {}", idx, LittleEndian::read_u32(b))
+                })?;
+                let offset = raw.field(4, |b| {
+                    format!("[{}] offset = {}", idx, LittleEndian::read_u32(b))
+                })?;
+                let hash = raw.field(16, |_| format!("[{}] location hash", idx))?;
+                if id.is_none() || size.is_none() || offset.is_none() || hash.is_none() {
+                    break;
+                }
+            }
+            let copies = if header_locations && !raw.truncated {
+                entries
+            } else {
+                0
+            };
+            for idx in 0..copies {
+                let hash = raw.field(16, |_| format!("[{}] content hash", idx))?;
+                let len = raw.field(2, |b| {
+                    format!("[{}] location length = {}", idx, LittleEndian::read_u16(b))
+                })?;
+                let location = match (hash, len) {
+                    (Some(_), Some(len)) => raw
+                        .field(LittleEndian::read_u16(&len).into(), |b| {
+                            format!("[{}] location = {}", idx, location::display(b))
+                        })?,
+                    _ => None,
+                };
+                if location.is_none() {
+                    break;
+                }
+            }
+            if metadata && !raw.truncated {
+                let created = raw.field(8, |b| {
+                    format!("created = {}", format_time(LittleEndian::read_u64(b)))
+                })?;
+                let len = raw.field(2, |b| {
+                    format!("created by length = {}", LittleEndian::read_u16(b))
+                })?;
+                let created_by = match (&created, len) {
+                    (Some(_), Some(len)) => raw
+                        .field(LittleEndian::read_u16(&len).into(), |b| {
+                            format!("created by = {}", String::from_utf8_lossy(b))
+                        })?,
+                    _ => None,
+                };
+                if let (Some(created), Some(created_by)) = (created, created_by) {
+                    writeln!(
+                        out,
+                        "created:     {} by {}",
+                        format_time(LittleEndian::read_u64(&created)),
+                        String::from_utf8_lossy(&created_by)
+                    )?;
+                }
+            }
+            if !raw.truncated {
+                header_len = raw.offset;
+            }
+        }
+        writeln!(out, "header size: {} bytes", header_len)?;
+        if header_len > block_len {
+            writeln!(out, "header exceeds block size of {} bytes", block_len)?;
+        }
+        if flags & FLAG_STREAMED != 0 {
+            writeln!(
+                out,
+                "entries of a streamed block are stored at the end of the block"
+            )?;
+        }
+    }
+
+    if opts.is_present("hex") {
+        writeln!(out)?;
+        for (offset, bytes, annotation) in raw.fields.iter() {
+            let hex = bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>();
+            writeln!(out, "{:08x}  {:<47}  {}", offset, hex.join(" "), annotation)?;
+        }
+        if raw.truncated {
+            writeln!(out, "{:08x}  end of file", block_len)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Последовательно читает поля заголовка блока, запоминая их положение и расшифровку для дампа
+struct RawHeader<R> {
+    reader: R,
+    offset: u64,
+    fields: Vec<(u64, Vec<u8>, String)>,
+    /// Закончился ли файл раньше заголовка
+    truncated: bool,
+}
+
+impl<R: io::Read> RawHeader<R> {
+    /// Читает поле длиной `len` байт. Возвращает `None`, если файл закончился раньше.
+    fn field(
+        &mut self,
+        len: usize,
+        describe: impl FnOnce(&[u8]) -> String,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut bytes = vec![0; len];
+        match self.reader.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                self.truncated = true;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+        self.fields
+            .push((self.offset, bytes.clone(), describe(&bytes)));
+        self.offset += len as u64;
+        Ok(Some(bytes))
+    }
+}
+
+/// Выводит по одной строке на каждый блок (версия формата, количество файлов, размер
+/// содержимого, логический и физический размер файла блока, доля выравнивания) и итоговую
+/// строку по всем блокам
+fn inspect_summary(opts: &ArgMatches) -> Result<()> {
+    let block_paths = opts.values_of("INPUT").unwrap().collect::<Vec<_>>();
+    let human = opts.is_present("human");
+    if block_paths.contains(&"-") {
+        bail!("--summary can't be used when reading a block from stdin");
+    }
+    let width = block_paths
+        .iter()
+        .map(|path| path.len())
+        .chain(iter::once("TOTAL".len()))
+        .max()
+        .unwrap_or(0);
+    let mut out = output(opts);
+    out.write_fmt(format_args!(
+        "{block:width$} {version:>7} {files:>9} {content:>12} {logical:>12} {physical:>12} {padding:>7}\n",
+        block = "BLOCK",
+        version = "VERSION",
+        files = "FILES",
+        content = "CONTENT",
+        logical = "LOGICAL",
+        physical = "PHYSICAL",
+        padding = "PADDING",
+        width = width,
+    ))?;
+
+    let mut total = BlockSummary::default();
+    for block_path in block_paths {
+        let block =
+            Block::open(block_path).chain_err(|| format!("Fail to open block: {}", block_path))?;
+        let metadata = fs::metadata(block_path)?;
+        let summary = BlockSummary {
+            files: block.len() as u64,
+            content: block.iter().map(|f| u64::from(f.size)).sum(),
+            logical: metadata.len(),
+            physical: physical_size(&metadata),
+            padding: block
+                .padding()
+                .chain_err(|| format!("Fail to read block: {}", block_path))?,
+        };
+        summary.write_row(
+            &mut out,
+            block_path,
+            &block.header().version().to_string(),
+            width,
+            human,
+        )?;
+        total.add(&summary);
+    }
+    total.write_row(&mut out, "TOTAL", "", width, human)?;
+    Ok(())
+}
+
+/// Строка сводной таблицы `inspect --summary`
+#[derive(Default)]
+struct BlockSummary {
+    files: u64,
+    content: u64,
+    logical: u64,
+    physical: u64,
+    padding: u64,
+}
+
+impl BlockSummary {
+    fn add(&mut self, other: &BlockSummary) {
+        self.files += other.files;
+        self.content += other.content;
+        self.logical += other.logical;
+        self.physical += other.physical;
+        self.padding += other.padding;
+    }
+
+    fn write_row(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+        version: &str,
+        width: usize,
+        human: bool,
+    ) -> Result<()> {
+        let padding = if self.logical > 0 {
+            self.padding as f64 * 100.0 / self.logical as f64
+        } else {
+            0.0
+        };
+        out.write_fmt(format_args!(
+            "{name:width$} {version:>7} {files:>9} {content:>12} {logical:>12} {physical:>12} {padding:>6.1}%\n",
+            name = name,
+            version = version,
+            files = self.files,
+            content = format_size(self.content, human),
+            logical = format_size(self.logical, human),
+            physical = format_size(self.physical, human),
+            padding = padding,
+            width = width,
+        ))?;
+        Ok(())
+    }
+}
+
+/// Сортирует файлы по ключу `--sort`. Без ключа сохраняется порядок файлов в заголовке блока.
+/// Сортировка устойчива, поэтому файлы с одинаковым ключом остаются в порядке заголовка, в том
+/// числе при `--reverse`.
+fn sort_entries(
+    entries: &mut [(&FileInfo, Option<&FileHeader>)],
+    key: Option<&str>,
+    reverse: bool,
+) {
+    let compare = |(a, a_header): &(&FileInfo, Option<&FileHeader>),
+                   (b, b_header): &(&FileInfo, Option<&FileHeader>)| match key {
+        Some("id") => a.wide_id().cmp(&b.wide_id()),
+        Some("size") => a.size.cmp(&b.size),
+        Some("offset") => a.offset.cmp(&b.offset),
+        Some("location") => a_header
+            .map(|h| &h.location)
+            .cmp(&b_header.map(|h| &h.location)),
+        _ => cmp::Ordering::Equal,
+    };
+    match (key, reverse) {
+        (None, true) => entries.reverse(),
+        (_, true) => entries.sort_by(|a, b| compare(b, a)),
+        (_, false) => entries.sort_by(compare),
+    }
+}
+
+/// Выводит таблицу файлов блока. В подробном режиме для каждого файла передается его заголовок.
+fn write_entries(
+    out: &mut impl Write,
+    entries: &[(&FileInfo, Option<&FileHeader>)],
+    verbose: bool,
+    human: bool,
+    by_content: bool,
+) -> Result<()> {
+    if by_content {
+        return write_content_addressed_entries(out, entries, verbose, human);
+    }
+    if verbose {
+        out.write_fmt(format_args!(
+            "{id:>9} {size:>9} {offset:>9} {location_hash:>32} {content_hash:>32} {location:}\n",
+            id = "ID",
+            size = "SIZE",
+            offset = "OFFSET",
+            location_hash = "LOCATION HASH",
+            content_hash = "CONTENT HASH",
+            location = "LOCATION",
+        ))?;
+    } else {
+        out.write_fmt(format_args!(
+            "{id:>9} {size:>9} {offset:>9} {location_hash:>32}\n",
+            id = "ID",
+            size = "SIZE",
+            offset = "OFFSET",
+            location_hash = "LOCATION HASH"
+        ))?;
+    }
+
+    for (file, header) in entries {
+        match header {
+            Some(header) if verbose => out.write_fmt(format_args!(
+                "{id:>9} {size:>9} {offset:>9} {location_hash:32} {content_hash:32} {location:<}\n",
+                id = file.wide_id(),
+                size = format_size(u64::from(file.size), human),
+                offset = file.offset,
+                location_hash = format!("{:x}", file.location_hash),
+                content_hash = format!("{:x}", header.hash),
+                location = header.display_location(),
+            ))?,
+            _ => out.write_fmt(format_args!(
+                "{id:>9} {size:>9} {offset:>9} {location_hash:32}\n",
+                id = file.wide_id(),
+                size = format_size(u64::from(file.size), human),
+                offset = file.offset,
+                location_hash = format!("{:x}", file.location_hash)
+            ))?,
+        }
+    }
+    Ok(())
+}
+
+/// Выводит по одной строке JSON на каждый файл блока `block_path`:
+///
+/// ```text
+/// {"block":"1.block","id":1,"size":1024,"offset":4096,"location_hash":"...","md5":"...","location":"/a.jpg"}
+/// ```
+///
+/// `md5` и `location` выводятся только с `--verbose` и только если заголовок файла прочитан.
+/// Location, не являющийся корректной UTF-8 строкой, выводится с заменой некорректных байт на
+/// `U+FFFD`. Размеры всегда выводятся в байтах.
+fn write_jsonl_entries(
+    out: &mut impl Write,
+    block_path: &str,
+    entries: &[(&FileInfo, Option<&FileHeader>)],
+    verbose: bool,
+) -> Result<()> {
+    let mut line = String::new();
+    for (file, header) in entries {
+        line.clear();
+        line.push_str("{\"block\":");
+        manifest::write_string(&mut line, block_path);
+        line.push_str(&format!(
+            ",\"id\":{},\"size\":{},\"offset\":{},\"location_hash\":\"{:x}\"",
+            file.wide_id(),
+            file.size,
+            file.offset,
+            file.location_hash
+        ));
+        if let Some(header) = header.filter(|_| verbose) {
+            line.push_str(&format!(",\"md5\":\"{:x}\",\"location\":", header.hash));
+            manifest::write_string(&mut line, &String::from_utf8_lossy(&header.location));
+        }
+        line.push_str("}\n");
+        out.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Выводит таблицу файлов блока, адресуемого по содержимому: первым столбцом – контрольная
+/// сумма содержимого, идентифицирующая файл. Для каждого файла передается его заголовок.
+fn write_content_addressed_entries(
+    out: &mut impl Write,
+    entries: &[(&FileInfo, Option<&FileHeader>)],
+    verbose: bool,
+    human: bool,
+) -> Result<()> {
+    out.write_fmt(format_args!(
+        "{content_hash:32} {id:>20} {size:>9} {offset:>9}{location}\n",
+        content_hash = "CONTENT HASH",
+        id = "ID",
+        size = "SIZE",
+        offset = "OFFSET",
+        location = if verbose { " LOCATION" } else { "" },
+    ))?;
+    for (file, header) in entries {
+        let content_hash = header.map(|h| format!("{:x}", h.hash)).unwrap_or_default();
+        let location = match header {
+            Some(header) if verbose => format!(" {}", header.display_location()),
+            _ => String::new(),
+        };
+        out.write_fmt(format_args!(
+            "{content_hash:32} {id:>20} {size:>9} {offset:>9}{location}\n",
+            content_hash = content_hash,
+            id = file.wide_id(),
+            size = format_size(u64::from(file.size), human),
+            offset = file.offset,
+            location = location,
+        ))?;
+    }
+    Ok(())
+}
+
+/// Выгружает содержимое файлов из блока в stdout, в файл (`--out`) или в директорию
+/// (`--out-dir`), где каждый файл сохраняется под именем своего идентификатора
+///
+/// `--list` позволяет выгрузить за один запуск произвольное количество файлов, открывая блок
+/// один раз: каждая непустая строка списка – идентификатор, а если она не является числом –
+/// location файла.
+fn export(opts: &ArgMatches) -> Result<()> {
+    let block_file = opts.value_of("BLOCK").unwrap();
+    let mut block = Block::open(block_file)?;
+    block.verify_on_read(opts.is_present("verify"));
+    #[cfg(feature = "encryption")]
+    if block.header().is_encrypted() {
+        block.decryption_key(encryption_key(opts)?);
+    }
+
+    let mut ids = match opts.values_of("ID") {
+        Some(_) => values_t!(opts.values_of("ID"), u64)?,
+        None => vec![],
+    };
+    for location in opts.values_of("location").into_iter().flatten() {
+        ids.push(block.resolve_location(location)?.id);
+    }
+    if let Some(list) = opts.value_of("list") {
+        let text = match list {
+            "-" => io::read_to_string(io::stdin())?,
+            path => {
+                fs::read_to_string(path).chain_err(|| format!("Unable to read list: {}", path))?
+            }
+        };
+        for line in text.lines().map(|line| line.trim_end_matches('\r')) {
+            if line.is_empty() {
+                continue;
+            }
+            match line.parse::<u64>() {
+                Ok(id) => ids.push(id),
+                Err(_) => ids.push(block.resolve_location(line)?.id),
+            }
+        }
+    }
+    let out_dir = opts.value_of("out-dir").map(PathBuf::from);
+    if ids.is_empty() {
+        bail!("At least one ID, --location or --list entry is required");
+    }
+    if ids.len() > 1 && out_dir.is_none() {
+        bail!("--out-dir is required when exporting multiple files");
+    }
+
+    if let Some(out_dir) = out_dir {
+        fs::create_dir_all(&out_dir)?;
+        for id in ids {
+            let (_, content) = block.file_by_id(id)?;
+            let path = out_dir.join(id.to_string());
+            fs::write(&path, content)
+                .chain_err(|| format!("Unable to write file: {}", path.display()))?;
+        }
+        return Ok(());
+    }
+
+    // Диапазон читается без остального содержимого файла (см. Block::read_range)
+    if let Some(range) = opts.value_of("range") {
+        let (offset, len) = parse_range(range)?;
+        let content = block.read_range(ids[0], offset, len)?;
+        match opts.value_of("out") {
+            Some(path) => {
+                fs::write(path, content).chain_err(|| format!("Unable to write file: {}", path))?
+            }
+            None => stdout().lock().write_all(&content)?,
+        }
+        return Ok(());
+    }
+
+    match opts.value_of("out") {
+        Some(path) => {
+            // Содержимое копируется ядром напрямую из файла блока (см. Block::copy_entry_to)
+            let out =
+                fs::File::create(path).chain_err(|| format!("Unable to write file: {}", path))?;
+            if let Err(e) = block.copy_entry_to(ids[0], &out) {
+                drop(out);
+                let _ = fs::remove_file(path);
+                return Err(e).chain_err(|| format!("Unable to write file: {}", path));
+            }
+        }
+        None => {
+            let (_, content) = block.file_by_id(ids[0])?;
+            let out = stdout();
+            let mut out = BufWriter::new(out.lock());
+            out.write_all(&content)?;
+        }
+    }
+    Ok(())
+}
+
+/// Выгружает все файлы блока в директорию `DIR`, где каждый файл сохраняется под именем своего
+/// идентификатора, а с `--by-location` – по своему location.
+///
+/// Location записывается создателем блока, поэтому по умолчанию выгрузка по location не может
+/// выйти за пределы `DIR` (см. `location::extraction_path`). `--unsafe-paths` отключает эту
+/// проверку. Файлы, разделяющие содержимое с другими файлами (см. `BlockOptions::dedup`),
+/// выгружаются по location только один раз, так как их собственный location в блоке не сохранен.
+///
+/// `--include` и `--exclude` отбирают файлы по location (см. `location::matches_glob`): файл
+/// выгружается, если его location соответствует хотя бы одному шаблону `--include` (или они не
+/// заданы) и ни одному шаблону `--exclude`. Собственный location файлов с общим содержимым
+/// неизвестен, поэтому при отборе они пропускаются.
+///
+/// С `--by-location` записи директорий (см. `EntryKind`) воссоздаются вместе с правами
+/// доступа, в том числе пустые директории. Права устанавливаются после выгрузки всех файлов,
+/// чтобы директории без права записи не помешали записать файлы в них. Без `--by-location`
+/// директории пропускаются.
+///
+/// Файлы пишутся `--jobs` потоками, каждый из которых держит в памяти не более одного файла,
+/// поэтому потребление памяти ограничено независимо от количества файлов в блоке. При первой
+/// ошибке выгрузка прекращается.
+fn extract(opts: &ArgMatches) -> Result<()> {
+    let block_path = opts.value_of("BLOCK").unwrap();
+    let out_dir = Path::new(opts.value_of("DIR").unwrap());
+    let jobs = jobs(opts)?.max(1);
+    let mut block =
+        Block::open(block_path).chain_err(|| format!("Fail to open block: {}", block_path))?;
+    block.verify_on_read(opts.is_present("verify"));
+    #[cfg(feature = "encryption")]
+    if block.header().is_encrypted() {
+        block.decryption_key(encryption_key(opts)?);
+    }
+    let by_location = opts.is_present("by-location");
+    let unsafe_paths = opts.is_present("unsafe-paths");
+    let selected = select_by_location(&block, opts)?;
+    fs::create_dir_all(out_dir)?;
+
+    let next_idx = AtomicUsize::new(0);
+    let extracted = AtomicUsize::new(0);
+    let directories = Mutex::new(vec![]);
+    let failed = AtomicBool::new(false);
+    let extract_next = || -> Result<()> {
+        loop {
+            let next = next_idx.fetch_add(1, Ordering::Relaxed);
+            if next >= selected.len() || failed.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let idx = selected[next];
+            let info = &block.header().file_info()[idx];
+            let result = block
+                .file_at(idx)
+                .map_err(Error::from)
+                .and_then(|(header, content)| {
+                    let kind = block.entry_kind(&header, &content)?;
+                    if !by_location && kind != EntryKind::File {
+                        return Ok(());
+                    }
+                    let path = if !by_location {
+                        out_dir.join(info.wide_id().to_string())
+                    } else if md5::compute(&header.location) != info.location_hash {
+                        // Заголовок файла с общим содержимым содержит чужой location
+                        return Ok(());
+                    } else if unsafe_paths {
+                        match header.location_os_str() {
+                            Some(location) => out_dir.join(location),
+                            None => bail!(format!(
+                                "Location can't be used as a file name: {}",
+                                header.display_location()
+                            )),
+                        }
+                    } else {
+                        location::extraction_path(out_dir, &header.location)?
+                    };
+                    if let EntryKind::Directory { mode } = kind {
+                        fs::create_dir_all(&path)?;
+                        directories.lock().unwrap().push((path, mode));
+                        return Ok(());
+                    }
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::write(&path, content)?;
+                    extracted.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                })
+                .chain_err(|| format!("Unable to extract file {}", info.wide_id()));
+            if result.is_err() {
+                failed.store(true, Ordering::Relaxed);
+                return result;
+            }
+        }
+    };
+    let results = thread::scope(|scope| {
+        let workers = (0..jobs.min(selected.len()))
+            .map(|_| scope.spawn(extract_next))
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    results.into_iter().collect::<Result<()>>()?;
+
+    // Вложенные директории раньше родительских: для изменения прав нужен доступ к родителю
+    let mut directories = directories.into_inner().unwrap();
+    directories.sort_by_key(|(path, _)| cmp::Reverse(path.components().count()));
+    #[cfg(unix)]
+    for (path, mode) in directories.iter() {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(*mode))
+            .chain_err(|| format!("Unable to set permissions of {}", path.display()))?;
+    }
+
+    let stdout = stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    if directories.is_empty() {
+        out.write_fmt(format_args!(
+            "{} files extracted to {}\n",
+            extracted.into_inner(),
+            out_dir.display()
+        ))?;
+    } else {
+        out.write_fmt(format_args!(
+            "{} files and {} directories extracted to {}\n",
+            extracted.into_inner(),
+            directories.len(),
+            out_dir.display()
+        ))?;
+    }
+    Ok(())
+}
+
+/// Порядковые номера файлов блока, отобранных шаблонами `--include` и `--exclude`
+fn select_by_location(block: &Block, opts: &ArgMatches) -> Result<Vec<usize>> {
+    let include = opts.values_of("include").map(|v| v.collect::<Vec<_>>());
+    let exclude = opts.values_of("exclude").map(|v| v.collect::<Vec<_>>());
+    if include.is_none() && exclude.is_none() {
+        return Ok((0..block.len()).collect());
+    }
+    let include = include.unwrap_or_default();
+    let exclude = exclude.unwrap_or_default();
+    let matches_any = |patterns: &[&str], location: &[u8]| {
+        patterns
+            .iter()
+            .any(|p| location::matches_glob(p.as_bytes(), location))
+    };
+    let mut selected = vec![];
+    for (idx, entry) in block.entries().enumerate() {
+        let header = entry
+            .header()
+            .chain_err(|| format!("Unable to read file {}", entry.info().id))?;
+        if md5::compute(&header.location) != entry.info().location_hash {
+            continue;
+        }
+        if (include.is_empty() || matches_any(&include, &header.location))
+            && !matches_any(&exclude, &header.location)
+        {
+            selected.push(idx);
+        }
+    }
+    Ok(selected)
+}
+
+/// Выводит контрольные суммы файлов блока в формате `md5sum`.
+///
+/// Каждая строка имеет вид `<MD5>  <LOCATION>`, где location записан без начального `/`, то
+/// есть относительно директории, в которую файлы выгружены `extract --by-location`. С `--by-id`
+/// вместо location выводится идентификатор файла. Поэтому выгруженные файлы можно проверить
+/// стандартной утилитой (`cd DIR && md5sum -c`) без blocky. Имена, содержащие `\` или перевод
+/// строки, экранируются так же, как это делает `md5sum`.
+///
+/// Для незашифрованных блоков выводится записанная в заголовке файла контрольная сумма, поэтому
+/// содержимое не читается. Контрольная сумма зашифрованного блока вычислена по зашифрованному
+/// содержимому, поэтому для таких блоков содержимое расшифровывается и хешируется заново.
+/// Файлы с общим содержимым (см. `BlockOptions::dedup`) без `--by-id` пропускаются, так как их
+/// собственный location в блоке не сохранен. Директории (см. `EntryKind`) также пропускаются.
+fn checksums(opts: &ArgMatches) -> Result<()> {
+    let block_path = opts.value_of("BLOCK").unwrap();
+    #[allow(unused_mut)]
+    let mut block =
+        Block::open(block_path).chain_err(|| format!("Fail to open block: {}", block_path))?;
+    #[cfg(feature = "encryption")]
+    if block.header().is_encrypted() {
+        block.decryption_key(encryption_key(opts)?);
+    }
+    let by_id = opts.is_present("by-id");
+
+    let stdout = stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    for entry in block.entries() {
+        let info = entry.info();
+        let header = entry
+            .header()
+            .chain_err(|| format!("Unable to read file {}", info.id))?;
+        if entry.kind()? != EntryKind::File {
+            continue;
+        }
+        let name = if by_id {
+            info.id.to_string().into_bytes()
+        } else if md5::compute(&header.location) == info.location_hash {
+            let start = header.location.iter().take_while(|b| **b == b'/').count();
+            header.location[start..].to_vec()
+        } else {
+            continue;
+        };
+        let hash = if block.header().is_encrypted() {
+            let content = entry
+                .content()
+                .chain_err(|| format!("Unable to read file {}", info.id))?;
+            md5::compute(content)
+        } else {
+            header.hash
+        };
+        write_checksum_line(&mut out, &hash, &name)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Записывает строку `<HASH>  <NAME>` в формате `md5sum`: имя, содержащее `\` или перевод
+/// строки, экранируется, а строка начинается с `\`
+fn write_checksum_line(out: &mut impl Write, hash: &md5::Digest, name: &[u8]) -> io::Result<()> {
+    let escape = name.iter().any(|b| *b == b'\\' || *b == b'\n');
+    if escape {
+        out.write_all(b"\\")?;
+    }
+    write!(out, "{:x}  ", hash)?;
+    if escape {
+        for b in name {
+            match b {
+                b'\\' => out.write_all(b"\\\\")?,
+                b'\n' => out.write_all(b"\\n")?,
+                b => out.write_all(&[*b])?,
+            }
+        }
+    } else {
+        out.write_all(name)?;
+    }
+    out.write_all(b"\n")
+}
+
+/// Ищет подстроку во всех файлах блока и выводит строки `<BLOCK>:<ID>:<LOCATION>:<OFFSET>` для
+/// каждого вхождения, где `OFFSET` – смещение вхождения от начала содержимого файла в байтах.
+///
+/// Содержимое незашифрованного и несжатого блока читается прямо из отображенного в память
+/// файла, без копирования. С `--include`/`--exclude` просматриваются только файлы с подходящим
+/// location (см. `location::matches_glob`). Если собственный location файла в блоке не сохранен
+/// (см. `BlockOptions::dedup`), вместо него выводится `-`. Директории пропускаются. Если
+/// вхождений нет, команда завершается с кодом `EXIT_NOT_FOUND`.
+fn grep(opts: &ArgMatches) -> Result<()> {
+    let block_path = opts.value_of("BLOCK").unwrap();
+    let pattern = opts.value_of_os("PATTERN").unwrap();
+    let pattern = pattern_bytes(pattern);
+    if pattern.is_empty() {
+        bail!("Pattern should not be empty");
+    }
+    let ignore_case = opts.is_present("ignore-case");
+    #[allow(unused_mut)]
+    let mut block =
+        Block::open(block_path).chain_err(|| format!("Fail to open block: {}", block_path))?;
+    #[cfg(feature = "encryption")]
+    if block.header().is_encrypted() {
+        block.decryption_key(encryption_key(opts)?);
+    }
+    let selected = select_by_location(&block, opts)?;
+
+    let stdout = stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut found = false;
+    for idx in selected {
+        let info = &block.header().file_info()[idx];
+        let (header, content) = block
+            .file_at(idx)
+            .chain_err(|| format!("Unable to read file {}", info.id))?;
+        if block.entry_kind(&header, &content)? != EntryKind::File {
+            continue;
+        }
+        let location = if md5::compute(&header.location) == info.location_hash {
+            location::display(&header.location).to_string()
+        } else {
+            String::from("-")
+        };
+        for offset in find_all(&content, &pattern, ignore_case) {
+            found = true;
+            writeln!(out, "{}:{}:{}:{}", block_path, info.id, location, offset)?;
+        }
+    }
+    out.flush()?;
+    if !found {
+        bail!(ErrorKind::NotFound(String::from("Pattern not found")));
+    }
+    Ok(())
+}
+
+/// Байтовое представление шаблона `grep` (на unix – без преобразования в UTF-8)
+fn pattern_bytes(pattern: &OsStr) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        pattern.as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        pattern.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+/// Смещения всех (в том числе перекрывающихся) вхождений `pattern` в `haystack`
+fn find_all<'a>(
+    haystack: &'a [u8],
+    pattern: &'a [u8],
+    ignore_case: bool,
+) -> impl Iterator<Item = usize> + 'a {
+    haystack
+        .windows(pattern.len())
+        .enumerate()
+        .filter(move |(_, window)| {
+            if ignore_case {
+                window.eq_ignore_ascii_case(pattern)
+            } else {
+                *window == pattern
+            }
+        })
+        .map(|(offset, _)| offset)
+}
+
+/// Количество потоков из параметра `--jobs`, по умолчанию – количество доступных ядер
+fn jobs(opts: &ArgMatches) -> Result<usize> {
+    match opts.value_of("jobs") {
+        Some(_) => Ok(value_t!(opts.value_of("jobs"), usize)?),
+        None => Ok(thread::available_parallelism().map_or(1, |n| n.get())),
+    }
+}
+
+/// Записывает индекс блока в отдельный файл
+fn index(opts: &ArgMatches) -> Result<()> {
+    let block_path = opts.value_of("BLOCK").unwrap();
+    let index_path = opts
+        .value_of("OUTPUT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| index_path_for(block_path));
+
+    let block = Block::open(block_path)?;
+    block
+        .write_index(&index_path)
+        .chain_err(|| format!("Unable to write index: {}", index_path.display()))
+}
+
+/// Записывает контрольные суммы фрагментов файлов блока в отдельный файл (см. `ChunkHashes`)
+fn chunks(opts: &ArgMatches) -> Result<()> {
+    let block_path = opts.value_of("BLOCK").unwrap();
+    let chunks_path = opts
+        .value_of("OUTPUT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| chunks_path_for(block_path));
+    let chunk_size = match opts.value_of("chunk-size") {
+        Some(size) => u32::try_from(parse_size(size)?)
+            .ok()
+            .filter(|size| *size > 0)
+            .ok_or_else(|| format!("Invalid chunk size: {}", size))?,
+        None => DEFAULT_CHUNK_SIZE,
+    };
+
+    let block = Block::options()
+        .advice(Advice::Sequential)
+        .open(block_path)
+        .chain_err(|| format!("Fail to open block: {}", block_path))?;
+    block
+        .write_chunk_hashes(&chunks_path, chunk_size)
+        .chain_err(|| format!("Unable to write chunk checksums: {}", chunks_path.display()))
+}
+
+/// Записывает данные четности блока в отдельный файл (см. `Parity`)
+fn parity(opts: &ArgMatches) -> Result<()> {
+    let block_path = opts.value_of("BLOCK").unwrap();
+    let parity_path = opts
+        .value_of("OUTPUT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| parity_path_for(block_path));
+    let shard_size = match opts.value_of("shard-size") {
+        Some(size) => u32::try_from(parse_size(size)?)
+            .ok()
+            .filter(|size| *size > 0)
+            .ok_or_else(|| format!("Invalid shard size: {}", size))?,
+        None => DEFAULT_SHARD_SIZE,
+    };
+    let group_size = match opts.value_of("group-size") {
+        Some(_) => value_t!(opts.value_of("group-size"), u32)?,
+        None => DEFAULT_GROUP_SIZE,
+    };
+    if group_size == 0 {
+        bail!("Group size should be positive");
+    }
+
+    let mut block = io::BufReader::new(fs::File::open(block_path)?);
+    let parity = Parity::compute(&mut block, shard_size, group_size)?;
+    parity
+        .write(&parity_path)
+        .chain_err(|| format!("Unable to write parity: {}", parity_path.display()))
+}
+
+/// Восстанавливает поврежденные участки блока по данным четности (см. `Parity::heal`).
+///
+/// Выводит каждый поврежденный участок и завершается с ошибкой, если хотя бы один из них
+/// восстановить невозможно. С `--dry-run` блок не изменяется.
+fn heal(opts: &ArgMatches) -> Result<()> {
+    let block_path = opts.value_of("BLOCK").unwrap();
+    let parity_path = opts
+        .value_of("parity")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| parity_path_for(block_path));
+    let dry_run = opts.is_present("dry-run");
+    let parity = Parity::open(&parity_path)
+        .chain_err(|| format!("Unable to read parity: {}", parity_path.display()))?;
+
+    let mut block = fs::OpenOptions::new()
+        .read(true)
+        .write(!dry_run)
+        .open(block_path)?;
+    let report = if dry_run {
+        parity.check(&mut block)
+    } else {
+        parity.heal(&mut block).and_then(|report| {
+            block.sync_all()?;
+            Ok(report)
+        })
+    }
+    .chain_err(|| format!("Unable to heal block: {}", block_path))?;
+
+    let status = if dry_run { "damaged" } else { "restored" };
+    for (offset, len) in report.repaired.iter() {
+        println!("{}: {}+{} {}", block_path, offset, len, status);
+    }
+    for (offset, len) in report.unrecoverable.iter() {
+        println!("{}: {}+{} can't be restored", block_path, offset, len);
+    }
+    if !report.unrecoverable.is_empty() {
+        return Err(blocky::errors::Error::BlockCorrupted {
+            path: Some(PathBuf::from(block_path)),
+            details: format!(
+                "{} damaged region(s) can't be restored",
+                report.unrecoverable.len()
+            ),
+        }
+        .into());
+    }
+    if !report.is_damaged() {
+        println!("{}: no damaged regions found", block_path);
+    }
+    Ok(())
+}
+
+/// Выводит блоки директории, содержащие файл с заданным location, и идентификаторы файла в них.
+///
+/// Поиск выполняется по каталогу директории (см. `Catalog`), который обновляется и сохраняется,
+/// если блоки директории изменились.
+fn locate(opts: &ArgMatches) -> Result<()> {
+    let dir = opts.value_of("DIR").unwrap();
+    let location = Path::new(opts.value_of_os("LOCATION").unwrap());
+    let location = location::from_path(location)?;
+
+    let (catalog, stats) =
+        Catalog::open(dir).chain_err(|| format!("Unable to read catalog: {}", dir))?;
+    for (path, e) in stats.failed.iter() {
+        eprintln!("Skipping unreadable block {}: {}", path.display(), e);
+    }
+    if stats.is_changed() && !opts.is_present("no-save") {
+        if let Err(e) = catalog.save() {
+            eprintln!("Unable to save catalog: {}", e);
+        }
+    }
+
+    let entries = catalog.lookup_all(location);
+    if entries.is_empty() {
+        bail!(ErrorKind::NotFound(format!(
+            "Location not found: {}",
+            location::display(location)
+        )));
+    }
+    for entry in entries {
+        println!("{}\t{}", entry.block_path.display(), entry.id);
+    }
+    Ok(())
+}
+
+/// Выводит MD5-хеши location в том виде, в котором они записаны в заголовок блока (см.
+/// `Normalization::hash`): по строке `<HASH>  <LOCATION>` на каждый location. Правила
+/// нормализации задаются `--normalize` или берутся из блока `--block`.
+fn hash(opts: &ArgMatches) -> Result<()> {
+    let normalization = match opts.value_of("block") {
+        Some(block_path) => Block::open(block_path)
+            .chain_err(|| format!("Fail to open block: {}", block_path))?
+            .normalization(),
+        None => normalization(opts.value_of("normalize").unwrap_or("none"))?,
+    };
+    let mut out = BufWriter::new(stdout().lock());
+    for location in opts.values_of_os("LOCATION").unwrap() {
+        let location = location::from_path(Path::new(location))?;
+        writeln!(
+            out,
+            "{:x}  {}",
+            normalization.hash(location),
+            location::display(location)
+        )?;
+    }
+    Ok(())
+}
+
+/// Восстанавливает блок с поврежденным заголовком. Если задан ключ (`--key-file` или
+/// `$BLOCKY_KEY`), то зашифрованные им файлы расшифровываются и шифруются в новом блоке заново
+fn repair(opts: &ArgMatches) -> Result<()> {
+    let block_path = opts.value_of("BLOCK").unwrap();
+    let out_path = opts.value_of("OUT").unwrap();
+
+    let mut options = BlockOptions::new();
+    options.packed(opts.is_present("packed"));
+    #[cfg(feature = "encryption")]
+    if opts.is_present("key-file") || env::var_os(KEY_ENV_VAR).is_some() {
+        options.encryption_key(Some(encryption_key(opts)?));
+    }
+    let entries = repair::repair(block_path, out_path, &options)
+        .chain_err(|| format!("Unable to repair block: {}", block_path))?;
+
+    let stdout = stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let lost_ids = entries.iter().filter(|e| e.id.is_none()).count();
+    out.write_fmt(format_args!(
+        "{} files recovered, {} of them with new ids\n",
+        entries.len(),
+        lost_ids
+    ))?;
+    Ok(())
+}
+
+/// Выводит размер содержимого блока, а также логический и физический (занятый на диске) размер
+/// файла блока
+fn stats(opts: &ArgMatches) -> Result<()> {
+    let block_paths = opts.values_of("INPUT").unwrap();
+    let human = opts.is_present("human");
+    let stdout = stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    for block_path in block_paths {
+        let block =
+            Block::open(block_path).chain_err(|| format!("Fail to open block: {}", block_path))?;
+        let metadata = fs::metadata(block_path)?;
+        let content_size = block.iter().map(|f| u64::from(f.size)).sum::<u64>();
+        out.write_fmt(format_args!("{}\n", block_path))?;
+        out.write_fmt(format_args!("{:>16}: {}\n", "files", block.len()))?;
+        out.write_fmt(format_args!(
+            "{:>16}: {}\n",
+            "content size",
+            format_size(content_size, human)
+        ))?;
+        out.write_fmt(format_args!(
+            "{:>16}: {}\n",
+            "logical size",
+            format_size(metadata.len(), human)
+        ))?;
+        out.write_fmt(format_args!(
+            "{:>16}: {}\n",
+            "physical size",
+            format_size(physical_size(&metadata), human)
+        ))?;
+        if let Some(metadata) = block.header().metadata() {
+            out.write_fmt(format_args!(
+                "{:>16}: {}\n",
+                "created",
+                format_time(metadata.created)
+            ))?;
+            out.write_fmt(format_args!(
+                "{:>16}: {}\n",
+                "created by", metadata.created_by
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Количество байт, фактически занятых файлом на диске
+#[cfg(unix)]
+fn physical_size(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn physical_size(metadata: &fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+/// Периодически проверяет контрольные суммы всех блоков директории.
+///
+/// Каждый блок проверяется не чаще одного раза за `--interval`, начиная с блоков, которые дольше
+/// всего не проверялись. Время проверки сохраняется в файл состояния (см. `ScrubState`) после
+/// каждого блока, поэтому перезапуск не приводит к повторной проверке. Поврежденные файлы и итог
+/// проверки каждого блока выводятся отдельными строками. Без `--once` команда не завершается:
+/// между циклами она ждет, пока наступит срок проверки очередного блока, и заново просматривает
+/// директорию, чтобы найти новые блоки. С `--once` команда проверяет блоки, срок проверки
+/// которых наступил, и завершается с ошибкой, если хотя бы один из них поврежден. С
+/// `--metrics-addr` счетчики проверенных блоков и найденных повреждений отдаются в формате
+/// Prometheus (см. `metrics::serve`). Скорость чтения всех блоков ограничивается общим
+/// `--max-bandwidth` (`--rate` – прежнее имя параметра), а `--background`, `--drop-cache` и
+/// `--direct-io` действуют так же, как в `verify`.
+fn scrub(opts: &ArgMatches) -> Result<()> {
+    /// Как часто директория просматривается заново, если ни один блок проверять не нужно
+    const RESCAN_PERIOD: u64 = 10 * 60;
+
+    let dir = Path::new(opts.value_of("DIR").unwrap());
+    let interval = parse_duration(opts.value_of("interval").unwrap())?;
+    let rate = opts.value_of("max-bandwidth").map(parse_rate).transpose()?;
+    let once = opts.is_present("once");
+    let drop_cache = opts.is_present("drop-cache");
+    let direct_io = opts.is_present("direct-io");
+    if opts.is_present("background") {
+        scrub::set_idle_io_priority().chain_err(|| "Unable to set idle I/O priority")?;
+    }
+    let state_path = opts
+        .value_of("state")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| dir.join(SCRUB_STATE_FILE_NAME));
+    let mut state = ScrubState::load(&state_path)
+        .chain_err(|| format!("Unable to read scrub state: {}", state_path.display()))?;
+
+    let registry = Arc::new(Registry::new());
+    let blocks_count = registry.gauge("blocky_scrub_blocks", "Number of blocks in the directory");
+    let blocks_verified = registry.counter(
+        "blocky_scrub_blocks_verified_total",
+        "Number of verified blocks",
+    );
+    let bytes_verified = registry.counter(
+        "blocky_scrub_bytes_verified_total",
+        "Number of bytes read while verifying blocks",
+    );
+    let files_failed = registry.counter(
+        "blocky_scrub_verification_failures_total",
+        "Number of files with content not matching its checksum",
+    );
+    let blocks_failed = registry.counter(
+        "blocky_scrub_block_errors_total",
+        "Number of blocks that could not be opened",
+    );
+    if let Some(addr) = opts.value_of("metrics-addr") {
+        metrics::serve(Arc::clone(&registry), addr)
+            .chain_err(|| format!("Unable to serve metrics on {}", addr))?;
+    }
+
+    let mut limiter = RateLimiter::new(rate);
+    let mut failed = 0;
+    loop {
+        let blocks = scrub::find_blocks(dir)
+            .chain_err(|| format!("Unable to list blocks: {}", dir.display()))?;
+        blocks_count.set(blocks.len() as u64);
+        state.retain(&blocks);
+        for path in state.due(&blocks, unix_time(), interval) {
+            let block_path = dir.join(&path);
+            let consumed = limiter.consumed();
+            let result = scrub::scrub_block(&block_path, &mut limiter, drop_cache, direct_io);
+            bytes_verified.add(limiter.consumed() - consumed);
+            let ok = match result {
+                Ok(results) => {
+                    let failures = results.iter().filter(|r| r.result.is_err()).count();
+                    blocks_verified.inc();
+                    files_failed.add(failures as u64);
+                    for entry in results.iter() {
+                        if let Err(e) = &entry.result {
+                            println!("{}: {:>9} {}", block_path.display(), entry.id, e);
+                        }
+                    }
+                    println!(
+                        "{}: {} files checked, {} failed",
+                        block_path.display(),
+                        results.len(),
+                        failures
+                    );
+                    failed += failures;
+                    failures == 0
+                }
+                Err(e) => {
+                    println!("{}: unable to open block: {}", block_path.display(), e);
+                    blocks_failed.inc();
+                    failed += 1;
+                    false
+                }
+            };
+            state.record(
+                path,
+                ScrubRecord {
+                    verified: unix_time(),
+                    ok,
+                },
+            );
+            state
+                .save()
+                .chain_err(|| format!("Unable to save scrub state: {}", state_path.display()))?;
+        }
+        if once {
+            break;
+        }
+        let now = unix_time();
+        let next = state
+            .next_due(&blocks, interval)
+            .unwrap_or(u64::MAX)
+            .clamp(now + 1, now + RESCAN_PERIOD);
+        thread::sleep(Duration::from_secs(next - now));
+    }
+
+    if failed > 0 {
+        bail!(ErrorKind::VerificationFailed(failed));
+    }
+    Ok(())
+}
+
+/// Собирает файлы, появляющиеся в директории, в блоки (см. `BlockSetWriter`).
+///
+/// Директория просматривается каждые `--interval` (включая поддиректории). Файл добавляется в
+/// блок, когда его размер и время изменения не изменились с предыдущего просмотра, то есть
+/// запись файла, по всей видимости, завершена. Location файла – его путь относительно
+/// директории, начинающийся с `/`. Блок закрывается, когда в нем `--batch-size` файлов, когда
+/// его размер достигает `--max-block-size` или через `--max-age` после добавления первого
+/// файла. Только после закрытия блока исходные файлы удаляются (или переносятся в `--move-to`
+/// с сохранением относительных путей), поэтому при перезапуске файлы незакрытого блока
+/// собираются заново. Файл удаляется, только если его устройство, inode, размер и время
+/// изменения совпадают с теми, что были при добавлении; иначе файл был заменен или
+/// перезаписан, остается на месте и собирается заново. С `--once` файлы, присутствующие в
+/// директории, собираются без ожидания, последний блок закрывается, и команда завершается.
+///
+/// Идентификаторы файлов продолжают наибольший идентификатор среди блоков `BLOCK_DIR` (см.
+/// `Catalog::max_id`), поэтому для `--pattern` следует использовать расширение `.block`.
+///
+/// Директории блоков и `--move-to` не должны находиться внутри просматриваемой директории.
+fn watch(opts: &ArgMatches) -> Result<()> {
+    let incoming = Path::new(opts.value_of("INCOMING_DIR").unwrap());
+    let block_dir = Path::new(opts.value_of("BLOCK_DIR").unwrap());
+    let batch_size = value_t!(opts.value_of("batch-size"), usize)?;
+    let max_age = parse_duration(opts.value_of("max-age").unwrap())?;
+    let max_block_size = parse_size(opts.value_of("max-block-size").unwrap())?;
+    let interval = parse_duration(opts.value_of("interval").unwrap())?;
+    let move_to = opts.value_of("move-to").map(Path::new);
+    let once = opts.is_present("once");
+
+    fs::create_dir_all(block_dir)
+        .chain_err(|| format!("Unable to create directory: {}", block_dir.display()))?;
+    let pattern = block_dir.join(opts.value_of("pattern").unwrap());
+    let pattern = pattern
+        .to_str()
+        .ok_or_else(|| format!("Invalid block path pattern: {}", pattern.display()))?;
+
+    let sealed = RefCell::new(vec![]);
+    let mut writer = BlockSetWriter::new(BlockOptions::new(), pattern, max_block_size, |path| {
+        sealed.borrow_mut().push(path.to_path_buf())
+    })?;
+    writer
+        .max_files(batch_size)
+        .max_age(Duration::from_secs(max_age));
+
+    // Исходные файлы, добавленные в еще не закрытые блоки, и их идентичность (см.
+    // `file_identity`) по идентификаторам
+    let mut pending = HashMap::new();
+    let mut added = HashSet::new();
+    let mut previous = HashMap::new();
+    // Идентификаторы продолжают идентификаторы уже записанных в директорию блоков, чтобы
+    // после перезапуска не повторять их
+    let (catalog, _) = Catalog::open(block_dir)
+        .chain_err(|| format!("Unable to read catalog: {}", block_dir.display()))?;
+    let mut next_id = catalog.max_id().map_or(1, |id| id + 1);
+    loop {
+        let mut files = vec![];
+        list_files(incoming, Path::new(""), &mut files)
+            .chain_err(|| format!("Unable to list files: {}", incoming.display()))?;
+        files.sort();
+        let mut current = HashMap::new();
+        for (relative, state) in files {
+            if added.contains(&relative) {
+                continue;
+            }
+            if !once && previous.get(&relative) != Some(&state) {
+                current.insert(relative, state);
+                continue;
+            }
+            let path = incoming.join(&relative);
+            let result = fs::File::open(&path).map_err(Error::from).and_then(|file| {
+                let identity = file_identity(&file.metadata()?)?;
+                let location = Path::new("/").join(&relative);
+                writer.add(next_id, location::from_path(&location)?, file)?;
+                Ok(identity)
+            });
+            match result {
+                Ok(identity) => {
+                    pending.insert(next_id, (relative.clone(), identity));
+                    added.insert(relative);
+                    next_id += 1;
+                }
+                Err(e) => eprintln!("Unable to add {}: {}", path.display(), e),
+            }
+        }
+        writer.seal_expired()?;
+        if once {
+            writer.seal()?;
+        }
+
+        for block_path in sealed.borrow_mut().drain(..) {
+            let block = Block::open(&block_path)?;
+            for info in block.iter() {
+                let (relative, identity) = match pending.remove(&info.id) {
+                    Some(pending) => pending,
+                    None => continue,
+                };
+                added.remove(&relative);
+                let path = incoming.join(&relative);
+                // Файл мог быть заменен или перезаписан после добавления в блок: такой файл
+                // остается на месте и собирается заново
+                let current = fs::symlink_metadata(&path).and_then(|m| file_identity(&m));
+                if current.as_ref().ok() != Some(&identity) {
+                    eprintln!("{} changed after it was added, keeping it", path.display());
+                    continue;
+                }
+                let result = match move_to {
+                    Some(move_to) => {
+                        let target = move_to.join(&relative);
+                        target
+                            .parent()
+                            .map_or(Ok(()), fs::create_dir_all)
+                            .and_then(|_| fs::rename(&path, &target))
+                    }
+                    None => fs::remove_file(&path),
+                };
+                if let Err(e) = result {
+                    eprintln!("Unable to remove {}: {}", path.display(), e);
+                }
+            }
+            println!("{}: {} files", block_path.display(), block.len());
+        }
+
+        if once {
+            return Ok(());
+        }
+        previous = current;
+        thread::sleep(Duration::from_secs(interval.max(1)));
+    }
+}
+
+/// Рекурсивно находит файлы директории `root.join(relative)` и добавляет в `files` их пути
+/// относительно `root` вместе с размером и временем изменения
+fn list_files(
+    root: &Path,
+    relative: &Path,
+    files: &mut Vec<(PathBuf, (u64, SystemTime))>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(root.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_files(root, &path, files)?;
+        } else if metadata.is_file() {
+            files.push((path, (metadata.len(), metadata.modified()?)));
+        }
+    }
+    Ok(())
+}
+
+/// Устройство, inode, размер и время изменения файла. Совпадение этих значений означает, что
+/// файл не был заменен или перезаписан.
+type FileIdentity = (u64, u64, u64, SystemTime);
+
+#[cfg(unix)]
+fn file_identity(metadata: &fs::Metadata) -> io::Result<FileIdentity> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((
+        metadata.dev(),
+        metadata.ino(),
+        metadata.len(),
+        metadata.modified()?,
+    ))
+}
+
+#[cfg(not(unix))]
+fn file_identity(metadata: &fs::Metadata) -> io::Result<FileIdentity> {
+    Ok((0, 0, metadata.len(), metadata.modified()?))
+}
+
+/// Параметры подкоманды `serve`
+fn serve_command<'a, 'b>() -> App<'a, 'b> {
+    let command = SubCommand::with_name("serve")
+        .about("Serve files of blocks over HTTP by location")
+        .arg(
+            Arg::from_usage("[listen] --listen=[ADDR] 'Address to listen on'")
+                .default_value("127.0.0.1:8080"),
+        )
+        .arg_from_usage("[verify] --verify 'Fail requests for files whose content doesn't match its checksum'")
+        .arg_from_usage(
+            "[access-log] --access-log=[FILE] 'Append a line per request to FILE (- for stdout)'",
+        )
+        .arg(
+            Arg::from_usage("[access-log-format] --access-log-format=[FORMAT] 'Access log format'")
+                .possible_values(&["common", "json"])
+                .default_value("common"),
+        )
+        .arg_from_usage(
+            "[key-file] --key-file=[FILE] 'Decryption key file for encrypted blocks (default: $BLOCKY_KEY)'",
+        )
+        .arg(
+            Arg::from_usage("[token] --token=[TOKEN] 'Reject requests without this bearer token'")
+                .conflicts_with("token-file"),
+        )
+        .arg_from_usage("[token-file] --token-file=[FILE] 'Read the bearer token from FILE'")
+        .arg_from_usage("<INPUT>... 'Block file names, earlier blocks override later ones'");
+    #[cfg(feature = "tls")]
+    let command = command
+        .arg(
+            Arg::from_usage("[cert] --cert=[FILE] 'PEM certificate chain, serve over HTTPS'")
+                .requires("key"),
+        )
+        .arg(Arg::from_usage("[key] --key=[FILE] 'PEM private key for --cert'").requires("cert"));
+    command
+}
+
+/// Отдает файлы блоков по HTTP (см. `server::FileServer`): `GET /<location>` возвращает
+/// содержимое файла с этим location из первого блока `INPUT`, в котором он есть. Поддерживаются
+/// запросы диапазонов (`Range`) и условные запросы с контрольной суммой файла в качестве ETag.
+///
+/// С `--access-log` каждый запрос записывается в журнал в Common Log Format или, с
+/// `--access-log-format json`, в виде JSON-объекта (см. `server::AccessLogFormat`). С feature
+/// `tls` и параметрами `--cert`/`--key` сервер принимает только HTTPS-соединения. С `--token`
+/// или `--token-file` запросы без этого токена в заголовке `Authorization` отклоняются.
+fn serve(opts: &ArgMatches) -> Result<()> {
+    let addr = opts.value_of("listen").unwrap();
+    let mut server = FileServer::new(open_served_blocks(opts)?);
+    if let Some(token) = bearer_token(opts)? {
+        server.token(token);
+    }
+    if let Some(path) = opts.value_of("access-log") {
+        let format = match opts.value_of("access-log-format") {
+            Some("json") => AccessLogFormat::Json,
+            _ => AccessLogFormat::Common,
+        };
+        match path {
+            "-" => server.access_log(format, io::stdout()),
+            path => {
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .chain_err(|| format!("Unable to open access log: {}", path))?;
+                server.access_log(format, file)
+            }
+        };
+    }
+    #[allow(unused_mut)]
+    let mut scheme = "http";
+    #[cfg(feature = "tls")]
+    if let (Some(cert), Some(key)) = (opts.value_of("cert"), opts.value_of("key")) {
+        server.tls(server::tls_config(Path::new(cert), Path::new(key))?);
+        scheme = "https";
+    }
+    let server = Arc::new(server);
+    let handle =
+        server::serve(server, addr).chain_err(|| format!("Unable to listen on {}", addr))?;
+    eprintln!("Serving on {}://{}", scheme, addr);
+    handle
+        .join()
+        .map_err(|_| Error::from("HTTP server thread panicked"))
+}
+
+/// Отдает файлы блоков по gRPC (см. `grpc::GrpcServer`): потоком по идентификатору или
+/// location из первого блока `INPUT`, в котором файл есть, а также метаинформацию блоков. С
+/// `--token` или `--token-file` вызовы без этого токена отклоняются.
+#[cfg(feature = "grpc")]
+fn grpc_serve(opts: &ArgMatches) -> Result<()> {
+    let addr = opts.value_of("listen").unwrap();
+    let mut server = GrpcServer::new(open_served_blocks(opts)?);
+    if let Some(token) = bearer_token(opts)? {
+        server.token(token);
+    }
+    let server = Arc::new(server);
+    let handle = grpc::serve(server, addr).chain_err(|| format!("Unable to listen on {}", addr))?;
+    eprintln!("Serving gRPC on {}", addr);
+    handle
+        .join()
+        .map_err(|_| Error::from("gRPC server thread panicked"))?
+        .chain_err(|| "gRPC server failed")
+}
+
+/// Открывает блоки `INPUT` для `serve` и `grpc-serve`
+fn open_served_blocks(opts: &ArgMatches) -> Result<Vec<(PathBuf, Block)>> {
+    let mut blocks = vec![];
+    for path in opts.values_of("INPUT").unwrap() {
+        let mut block =
+            Block::open(path).chain_err(|| format!("Unable to open block: {}", path))?;
+        block.verify_on_read(opts.is_present("verify"));
+        #[cfg(feature = "encryption")]
+        if block.header().is_encrypted() {
+            block.decryption_key(encryption_key(opts)?);
+        }
+        blocks.push((PathBuf::from(path), block));
+    }
+    Ok(blocks)
+}
+
+/// Токен доступа к серверу из `--token` или файла `--token-file` (без завершающих пробелов и
+/// перевода строки)
+fn bearer_token(opts: &ArgMatches) -> Result<Option<String>> {
+    let token = match (opts.value_of("token"), opts.value_of("token-file")) {
+        (Some(token), _) => token.to_string(),
+        (None, Some(path)) => fs::read_to_string(path)
+            .chain_err(|| format!("Unable to read token file: {}", path))?
+            .trim_end()
+            .to_string(),
+        (None, None) => return Ok(None),
+    };
+    if token.is_empty() || token.contains(char::is_whitespace) {
+        bail!("Bearer token must be non-empty and contain no whitespace");
+    }
+    Ok(Some(token))
+}
+
+/// Текущее время в секундах от UNIX epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Проверяет контрольные суммы всех файлов в блоках
+///
+/// Выводит информацию о каждом поврежденном файле и завершается с ошибкой, если такие файлы
+/// найдены хотя бы в одном блоке. С `--background` блоки читаются с приоритетом ввода-вывода
+/// idle, а с `--drop-cache` проверенные блоки вытесняются из page cache, чтобы проверка не
+/// мешала другим читателям тех же дисков.
+fn verify(opts: &ArgMatches) -> Result<()> {
+    let block_paths = opts.values_of("INPUT").unwrap();
+    let jobs = jobs(opts)?;
+    let mut out = output(opts);
+    if opts.is_present("background") {
+        scrub::set_idle_io_priority().chain_err(|| "Unable to set idle I/O priority")?;
+    }
+    let mut failed = 0;
+    for block_path in block_paths {
+        // Блок читается один раз от начала до конца, поэтому прочитанные страницы не должны
+        // вытеснять из page cache данные других процессов
+        let block = Block::options()
+            .advice(Advice::Sequential)
+            .direct_io(opts.is_present("direct-io"))
+            .open(block_path)
+            .chain_err(|| format!("Fail to open block: {}", block_path))?;
+        if let Some(key_file) = opts.value_of("public-key") {
+            verify_signature(&block, key_file)
+                .chain_err(|| format!("Signature verification failed: {}", block_path))?;
+        }
+        let results = block.verify_all_parallel(jobs);
+        if opts.is_present("drop-cache") {
+            block.drop_page_cache()?;
+        } else {
+            block.release_pages()?;
+        }
+        let failures = results
+            .iter()
+            .filter(|r| r.result.is_err())
+            .collect::<Vec<_>>();
+        for entry in failures.iter() {
+            if let Err(e) = &entry.result {
+                out.write_fmt(format_args!("{}: {:>9} {}\n", block_path, entry.id, e))?;
+            }
+        }
+        out.write_fmt(format_args!(
+            "{}: {} files checked, {} failed\n",
+            block_path,
+            results.len(),
+            failures.len()
+        ))?;
+        failed += failures.len();
+    }
+    out.flush()?;
+
+    if failed > 0 {
+        bail!(ErrorKind::VerificationFailed(failed));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "signing")]
+fn verify_signature(block: &Block, key_file: &str) -> Result<()> {
+    let key = signature::read_verifying_key(key_file)?;
+    Ok(block.verify_signature(&key)?)
+}
+
+#[cfg(not(feature = "signing"))]
+fn verify_signature(_block: &Block, _key_file: &str) -> Result<()> {
+    bail!("blocky is built without signature support")
+}
+
+/// Создает пару ключей Ed25519: секретный ключ записывается в `KEY`, публичный – в `KEY.pub`
+#[cfg(feature = "signing")]
+fn keygen(opts: &ArgMatches) -> Result<()> {
+    let key_path = opts.value_of("KEY").unwrap();
+    let public_key_path = format!("{}.pub", key_path);
+    let key = signature::generate_key();
+    write_new_file(key_path, &key.to_bytes(), true)?;
+    write_new_file(&public_key_path, key.verifying_key().as_bytes(), false)?;
+    Ok(())
+}
+
+/// Записывает файл, только если он еще не существует, чтобы не затереть существующий ключ.
+/// Если `private`, то файл доступен только владельцу.
+#[cfg(feature = "signing")]
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn write_new_file(path: &str, content: &[u8], private: bool) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .chain_err(|| format!("Unable to create file: {}", path))?;
+    file.write_all(content)?;
+    Ok(())
+}
+
+#[cfg(feature = "signing")]
+fn sign(opts: &ArgMatches) -> Result<()> {
+    let key = signature::read_signing_key(opts.value_of("key-file").unwrap())?;
+    for block_path in opts.values_of("INPUT").unwrap() {
+        signature::sign(block_path, &key)
+            .chain_err(|| format!("Unable to sign block: {}", block_path))?;
+    }
+    Ok(())
+}
+
+/// Перезаписывает незашифрованный блок `IN` в зашифрованный блок `OUT`
+#[cfg(feature = "encryption")]
+fn encrypt(opts: &ArgMatches) -> Result<()> {
+    let (in_path, out_path) = (opts.value_of("IN").unwrap(), opts.value_of("OUT").unwrap());
+    let source = Block::open(in_path).chain_err(|| format!("Fail to open block: {}", in_path))?;
+    if source.header().is_encrypted() {
+        bail!(format!("Block is already encrypted: {}", in_path));
+    }
+    rewrite_options(&source)
+        .encryption_key(Some(encryption_key(opts)?))
+        .rewrite(&source, out_path)
+        .chain_err(|| format!("Unable to encrypt block: {}", in_path))?;
+    Ok(())
+}
+
+/// Перезаписывает зашифрованный блок `IN` в незашифрованный блок `OUT`
+#[cfg(feature = "encryption")]
+fn decrypt(opts: &ArgMatches) -> Result<()> {
+    let (in_path, out_path) = (opts.value_of("IN").unwrap(), opts.value_of("OUT").unwrap());
+    let mut source =
+        Block::open(in_path).chain_err(|| format!("Fail to open block: {}", in_path))?;
+    if !source.header().is_encrypted() {
+        bail!(format!("Block is not encrypted: {}", in_path));
+    }
+    source.decryption_key(encryption_key(opts)?);
+    rewrite_options(&source)
+        .rewrite(&source, out_path)
+        .chain_err(|| format!("Unable to decrypt block: {}", in_path))?;
+    Ok(())
+}
+
+/// Параметры перезаписываемого блока: раскладка, сжатие и манифест сохраняются, а файлы с
+/// общим содержимым по-прежнему хранятся однократно
+#[cfg(feature = "encryption")]
+fn rewrite_options(source: &Block) -> BlockOptions {
+    let mut options = BlockOptions::new();
+    options
+        .packed(source.header().is_packed())
+        .compress(source.header().is_compressed())
+        .compress_header(source.header().has_compressed_header())
+        .manifest(source.has_manifest())
+        .header_locations(source.header().has_header_locations())
+        .wide_ids(source.header().has_wide_ids())
+        .metadata(source.header().metadata().is_some())
+        .dedup(true);
+    options
+}
+
+/// Читает ключ шифрования из файла `--key-file` или, если он не указан, из переменной окружения
+/// `BLOCKY_KEY` (64 шестнадцатеричных символа)
+#[cfg(feature = "encryption")]
+fn encryption_key(opts: &ArgMatches) -> Result<EncryptionKey> {
+    if let Some(key_file) = opts.value_of("key-file") {
+        return Ok(EncryptionKey::read_from(key_file)?);
+    }
+    match env::var(KEY_ENV_VAR) {
+        Ok(hex) => Ok(EncryptionKey::from_hex(&hex)
+            .chain_err(|| format!("Invalid key in {} variable", KEY_ENV_VAR))?),
+        Err(_) => bail!(format!(
+            "Block is encrypted: key is required (--key-file or {} variable)",
+            KEY_ENV_VAR
+        )),
+    }
 }
@@ -0,0 +1,147 @@
+//! Манифест блока – JSON-описание его файлов, записываемое в блок отдельным файлом.
+//!
+//! Манифест позволяет перечислить содержимое блока системам, которые не умеют разбирать
+//! бинарный заголовок: достаточно найти в блоке один файл с идентификатором [`MANIFEST_ID`] и
+//! location [`MANIFEST_LOCATION`] (см. [`BlockOptions::manifest`]). Для остальных читателей это
+//! обычный файл блока, поэтому формат блока не меняется.
+//!
+//! ## Формат
+//! ```text
+//! {"version":1,"files":[
+//! {"id":1,"location":"/img/1.jpg","size":1024,"md5":"0cc175b9c0f1b6a831c399e269772661"},
+//! {"id":2,"location":null,"size":512,"md5":"92eb5ffee6ae2fec3ad71c777531578f"}
+//! ]}
+//! ```
+//! Каждый файл записан отдельной строкой в порядке добавления в блок. `size` и `md5` описывают
+//! исходное содержимое файла (до сжатия и шифрования), так что по манифесту можно проверить
+//! выгруженные файлы. Location, не являющийся корректной UTF-8 строкой, записывается с заменой
+//! некорректных байт на `U+FFFD`. Если location файла неизвестен (например, при копировании
+//! файлов с общим содержимым, см. [`BlockOptions::dedup`]), вместо него записывается `null`.
+//!
+//! [`MANIFEST_ID`]: constant.MANIFEST_ID.html
+//! [`MANIFEST_LOCATION`]: constant.MANIFEST_LOCATION.html
+//! [`BlockOptions::manifest`]: ../block/struct.BlockOptions.html#method.manifest
+//! [`BlockOptions::dedup`]: ../block/struct.BlockOptions.html#method.dedup
+use std::fmt::Write;
+
+/// Идентификатор файла манифеста
+pub const MANIFEST_ID: u64 = u64::MAX;
+
+/// Location файла манифеста
+pub const MANIFEST_LOCATION: &[u8] = b"/.blocky/manifest.json";
+
+const HEAD: &str = "{\"version\":1,\"files\":[\n";
+const TAIL: &str = "]}\n";
+
+/// Описание файла в манифесте
+#[derive(Debug, Clone)]
+pub(crate) struct ManifestEntry {
+    /// Идентификатор файла, в блоках со 128-битными идентификаторами – полный (см.
+    /// [`FileInfo::wide_id`])
+    ///
+    /// [`FileInfo::wide_id`]: ../block/struct.FileInfo.html#method.wide_id
+    pub(crate) id: u128,
+    pub(crate) location: Option<Vec<u8>>,
+    pub(crate) size: u64,
+    pub(crate) hash: md5::Digest,
+}
+
+/// Является ли файл с идентификатором `id` и хешем location `location_hash` манифестом
+pub(crate) fn is_manifest(id: u64, location_hash: &md5::Digest) -> bool {
+    id == MANIFEST_ID && *location_hash == md5::compute(MANIFEST_LOCATION)
+}
+
+/// Кодирует манифест файлов `entries`
+pub(crate) fn encode(entries: &[ManifestEntry]) -> Vec<u8> {
+    let mut json = String::from(HEAD);
+    for (idx, entry) in entries.iter().enumerate() {
+        json.push_str("{\"id\":");
+        write!(json, "{}", entry.id).unwrap();
+        json.push_str(",\"location\":");
+        match &entry.location {
+            Some(location) => write_string(&mut json, &String::from_utf8_lossy(location)),
+            None => json.push_str("null"),
+        }
+        write!(
+            json,
+            ",\"size\":{},\"md5\":\"{:x}\"}}",
+            entry.size, entry.hash
+        )
+        .unwrap();
+        if idx + 1 < entries.len() {
+            json.push(',');
+        }
+        json.push('\n');
+    }
+    json.push_str(TAIL);
+    json.into_bytes()
+}
+
+/// Верхняя оценка размера манифеста без записей о файлах
+pub(crate) fn max_envelope_len() -> u64 {
+    (HEAD.len() + TAIL.len()) as u64
+}
+
+/// Верхняя оценка размера записи о файле с location длиной `location_len` байт в манифесте
+pub(crate) fn max_entry_len(location_len: usize) -> u64 {
+    // Идентификатор – не более 39 цифр, размер – не более 20 цифр, location – не менее 4 байт
+    // (`null`), а каждый его байт – не более 6 байт (`\u001f`)
+    const FIXED: &str = "{\"id\":,\"location\":,\"size\":,\"md5\":\"\"},\n";
+    (FIXED.len() + 39 + 20 + 32 + 4 + 6 * location_len) as u64
+}
+
+/// Записывает `value` в виде JSON строки
+pub fn write_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn should_encode_manifest_as_json() {
+        let entries = vec![
+            ManifestEntry {
+                id: 1,
+                location: Some(b"/a \"b\"\\c\x01.jpg".to_vec()),
+                size: 3,
+                hash: md5::compute(b"abc"),
+            },
+            ManifestEntry {
+                id: 2,
+                location: None,
+                size: 0,
+                hash: md5::compute(b""),
+            },
+        ];
+        let json = String::from_utf8(encode(&entries)).unwrap();
+        assert_eq!(
+            json,
+            "{\"version\":1,\"files\":[\n\
+             {\"id\":1,\"location\":\"/a \\\"b\\\"\\\\c\\u0001.jpg\",\"size\":3,\"md5\":\"900150983cd24fb0d6963f7d28e17f72\"},\n\
+             {\"id\":2,\"location\":null,\"size\":0,\"md5\":\"d41d8cd98f00b204e9800998ecf8427e\"}\n\
+             ]}\n"
+        );
+
+        let max_len = max_envelope_len()
+            + entries
+                .iter()
+                .map(|e| max_entry_len(e.location.as_ref().map_or(0, Vec::len)))
+                .sum::<u64>();
+        assert!(json.len() as u64 <= max_len);
+    }
+}
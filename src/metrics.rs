@@ -0,0 +1,207 @@
+//! Метрики в формате Prometheus.
+//!
+//! Долгоживущие команды (например, `blocky scrub`) регистрируют счетчики в [`Registry`] и
+//! обновляют их по ходу работы, а [`serve`] отдает текущие значения по HTTP на `GET /metrics`
+//! в текстовом формате Prometheus, так что процесс можно мониторить без отдельного экспортера.
+//!
+//! [`Registry`]: struct.Registry.html
+//! [`serve`]: fn.serve.html
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Тип метрики
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Kind {
+    /// Монотонно возрастающий счетчик
+    Counter,
+
+    /// Значение, которое может как расти, так и уменьшаться
+    Gauge,
+}
+
+/// Значение метрики. Обновляется атомарно, поэтому может разделяться между потоками.
+#[derive(Debug, Default)]
+pub struct Metric(AtomicU64);
+
+impl Metric {
+    /// Увеличивает значение на `value`
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Увеличивает значение на единицу
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Устанавливает значение (только для метрик, созданных [`Registry::gauge`])
+    ///
+    /// [`Registry::gauge`]: struct.Registry.html#method.gauge
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Зарегистрированная метрика
+#[derive(Debug)]
+struct Entry {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    metric: Arc<Metric>,
+}
+
+/// Набор метрик процесса
+#[derive(Debug, Default)]
+pub struct Registry {
+    metrics: Mutex<Vec<Entry>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Регистрирует счетчик с именем `name` (по соглашениям Prometheus – с суффиксом `_total`)
+    /// и описанием `help`
+    pub fn counter(&self, name: &'static str, help: &'static str) -> Arc<Metric> {
+        self.register(name, help, Kind::Counter)
+    }
+
+    /// Регистрирует метрику, значение которой может уменьшаться
+    pub fn gauge(&self, name: &'static str, help: &'static str) -> Arc<Metric> {
+        self.register(name, help, Kind::Gauge)
+    }
+
+    fn register(&self, name: &'static str, help: &'static str, kind: Kind) -> Arc<Metric> {
+        let metric = Arc::new(Metric::default());
+        self.metrics.lock().unwrap().push(Entry {
+            name,
+            help,
+            kind,
+            metric: Arc::clone(&metric),
+        });
+        metric
+    }
+
+    /// Текущие значения метрик в текстовом формате Prometheus
+    pub fn encode(&self) -> String {
+        let mut text = String::new();
+        for Entry {
+            name,
+            help,
+            kind,
+            metric,
+        } in self.metrics.lock().unwrap().iter()
+        {
+            let kind = match kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            writeln!(text, "# HELP {} {}", name, help).unwrap();
+            writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(text, "{} {}", name, metric.get()).unwrap();
+        }
+        text
+    }
+}
+
+/// Запускает в отдельном потоке HTTP сервер, отдающий метрики `registry` на `GET /metrics`.
+///
+/// Запросы обрабатываются последовательно, соединение закрывается после каждого ответа.
+/// Сервер работает до завершения процесса.
+pub fn serve(registry: Arc<Registry>, addr: impl ToSocketAddrs) -> io::Result<JoinHandle<()>> {
+    Ok(serve_on(registry, TcpListener::bind(addr)?))
+}
+
+fn serve_on(registry: Arc<Registry>, listener: TcpListener) -> JoinHandle<()> {
+    thread::spawn(move || {
+        // Ошибка одного соединения не должна останавливать сервер
+        for stream in listener.incoming().flatten() {
+            let _ = respond(&registry, stream);
+        }
+    })
+}
+
+fn respond(registry: &Registry, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Заголовки запроса не используются, но должны быть прочитаны до ответа
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", registry.encode()),
+        (Some("GET"), _) => ("404 Not Found", String::from("Not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            String::from("Method not allowed\n"),
+        ),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn should_serve_metrics() -> io::Result<()> {
+        let registry = Arc::new(Registry::new());
+        let verified = registry.counter("blocky_test_verified_total", "Verified files");
+        let blocks = registry.gauge("blocky_test_blocks", "Known blocks");
+        verified.add(3);
+        verified.inc();
+        blocks.set(7);
+        assert_eq!(
+            registry.encode(),
+            "# HELP blocky_test_verified_total Verified files\n\
+             # TYPE blocky_test_verified_total counter\n\
+             blocky_test_verified_total 4\n\
+             # HELP blocky_test_blocks Known blocks\n\
+             # TYPE blocky_test_blocks gauge\n\
+             blocky_test_blocks 7\n"
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        serve_on(Arc::clone(&registry), listener);
+        let get = |path: &str| -> io::Result<String> {
+            let mut stream = TcpStream::connect(addr)?;
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+
+        let response = get("/metrics")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("blocky_test_blocks 7\n"));
+        assert!(get("/")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+//! Несколько блоков как один логический блок.
+//!
+//! [`MultiBlock`] объединяет упорядоченный список блоков (например, шарды одного хранилища) и
+//! предоставляет тот же API, что и [`Block`]: [`len`], [`iter`], [`file_by_id`] и
+//! [`file_by_location`], так что сервисам не нужно самостоятельно перебирать блоки.
+//!
+//! Блоки упорядочены по приоритету: если файл с одним и тем же идентификатором или location
+//! есть в нескольких блоках, используется файл из блока, стоящего в списке раньше.
+//!
+//! [`MultiBlock`]: struct.MultiBlock.html
+//! [`Block`]: ../block/struct.Block.html
+//! [`len`]: struct.MultiBlock.html#method.len
+//! [`iter`]: struct.MultiBlock.html#method.iter
+//! [`file_by_id`]: struct.MultiBlock.html#method.file_by_id
+//! [`file_by_location`]: struct.MultiBlock.html#method.file_by_location
+use crate::block::{Block, FileHeader, FileInfo};
+use crate::errors::*;
+use crate::location;
+use std::borrow::Cow;
+use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+
+/// Упорядоченный по приоритету список блоков, доступный как один блок
+pub struct MultiBlock {
+    blocks: Vec<Block>,
+    block_by_id: HashMap<u128, usize>,
+}
+
+impl MultiBlock {
+    /// Объединяет блоки `blocks`. Блоки, стоящие в списке раньше, перекрывают последующие.
+    pub fn new(blocks: Vec<Block>) -> Self {
+        let mut block_by_id = HashMap::new();
+        for (idx, block) in blocks.iter().enumerate() {
+            for info in block.iter() {
+                block_by_id.entry(info.wide_id()).or_insert(idx);
+            }
+        }
+        Self {
+            blocks,
+            block_by_id,
+        }
+    }
+
+    /// Открывает блоки `paths` (см. [`Block::open`]) и объединяет их в порядке следования.
+    ///
+    /// [`Block::open`]: ../block/struct.Block.html#method.open
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open(paths: impl IntoIterator<Item = impl AsRef<Path>>) -> Result<Self> {
+        let blocks = paths
+            .into_iter()
+            .map(Block::open)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(blocks))
+    }
+
+    /// Блоки в порядке приоритета
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// Количество различных идентификаторов файлов во всех блоках
+    pub fn len(&self) -> usize {
+        self.block_by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.block_by_id.is_empty()
+    }
+
+    /// Метаинформация файлов всех блоков в порядке следования блоков. Файлы, идентификаторы
+    /// которых уже встречались в предыдущих блоках, пропускаются.
+    pub fn iter(&self) -> impl Iterator<Item = &FileInfo> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .flat_map(move |(idx, block)| {
+                block
+                    .iter()
+                    .filter(move |info| self.block_by_id.get(&info.wide_id()) == Some(&idx))
+            })
+    }
+
+    /// Блок, из которого возвращается файл с идентификатором `id`
+    pub fn block_of(&self, id: u64) -> Option<&Block> {
+        self.block_of_wide_id(u128::from(id))
+    }
+
+    /// Блок, из которого возвращается файл со 128-битным идентификатором `id`
+    pub fn block_of_wide_id(&self, id: u128) -> Option<&Block> {
+        self.block_by_id.get(&id).map(|&idx| &self.blocks[idx])
+    }
+
+    /// Содержит ли какой-либо из блоков файл с идентификатором `id`
+    pub fn contains_id(&self, id: u64) -> bool {
+        self.block_by_id.contains_key(&u128::from(id))
+    }
+
+    /// Возвращает заголовок и содержимое файла с идентификатором `id` из первого блока,
+    /// содержащего этот файл (см. [`Block::file_by_id`]).
+    ///
+    /// [`Block::file_by_id`]: ../block/struct.Block.html#method.file_by_id
+    pub fn file_by_id(&self, id: u64) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        self.file_by_wide_id(u128::from(id))
+    }
+
+    /// Аналог [`file_by_id`] для 128-битных идентификаторов (см. [`Block::file_by_wide_id`]).
+    ///
+    /// [`file_by_id`]: #method.file_by_id
+    /// [`Block::file_by_wide_id`]: ../block/struct.Block.html#method.file_by_wide_id
+    pub fn file_by_wide_id(&self, id: u128) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        self.block_of_wide_id(id)
+            .ok_or(Error::FileNotFound { id })?
+            .file_by_wide_id(id)
+    }
+
+    /// Возвращает заголовок и содержимое файла с location `location` из первого блока, в
+    /// котором он найден (см. [`Block::file_by_location`]). Location нормализуется по правилам
+    /// каждого блока отдельно.
+    ///
+    /// Коллизия хешей в одном из блоков не прерывает поиск: [`Error::LocationHashCollision`]
+    /// возвращается, только если файл не найден ни в одном блоке.
+    ///
+    /// [`Block::file_by_location`]: ../block/struct.Block.html#method.file_by_location
+    /// [`Error::LocationHashCollision`]: ../errors/enum.Error.html#variant.LocationHashCollision
+    pub fn file_by_location(
+        &self,
+        location: impl AsRef<[u8]>,
+    ) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        self.first_by_location(location.as_ref(), |_, block, location| {
+            block.file_by_location(location)
+        })
+    }
+
+    /// Находит файл с location `location` так же, как [`file_by_location`], и возвращает
+    /// номер блока, в котором он найден (см. [`blocks`]), и метаинформацию файла.
+    ///
+    /// [`file_by_location`]: #method.file_by_location
+    /// [`blocks`]: #method.blocks
+    pub fn resolve_location(&self, location: impl AsRef<[u8]>) -> Result<(usize, &FileInfo)> {
+        self.first_by_location(location.as_ref(), |idx, block, location| {
+            block.resolve_location(location).map(|info| (idx, info))
+        })
+    }
+
+    fn first_by_location<'a, T>(
+        &'a self,
+        location: &[u8],
+        find: impl Fn(usize, &'a Block, &[u8]) -> Result<T>,
+    ) -> Result<T> {
+        let mut collision = None;
+        for (idx, block) in self.blocks.iter().enumerate() {
+            match find(idx, block, location) {
+                Err(Error::LocationNotFound(_)) => {}
+                Err(e @ Error::LocationHashCollision { .. }) => collision = collision.or(Some(e)),
+                result => return result,
+            }
+        }
+        Err(collision
+            .unwrap_or_else(|| Error::LocationNotFound(location::display(location).into_owned())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::builder::BlockBuilder;
+
+    fn create_block(files: &[(u64, &str, &str)]) -> Result<Block> {
+        let mut builder = BlockBuilder::new();
+        for (id, location, content) in files {
+            builder.add(*id, location, *content);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn should_read_files_across_blocks_by_precedence() -> Result<()> {
+        let first = create_block(&[(1, "/a.txt", "new a"), (2, "/b.txt", "b")])?;
+        let second = create_block(&[(1, "/old-a.txt", "old a"), (3, "/a.txt", "c")])?;
+        let multi = MultiBlock::new(vec![first, second]);
+
+        assert_eq!(multi.len(), 3);
+        let ids = multi.iter().map(|info| info.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        assert_eq!(&multi.file_by_id(1)?.1[..], b"new a");
+        assert_eq!(&multi.file_by_id(3)?.1[..], b"c");
+        assert!(matches!(
+            multi.file_by_id(4),
+            Err(Error::FileNotFound { id: 4 })
+        ));
+        assert!(multi.contains_id(3));
+
+        assert_eq!(&multi.file_by_location("/a.txt")?.1[..], b"new a");
+        assert_eq!(&multi.file_by_location("/old-a.txt")?.1[..], b"old a");
+        let (idx, info) = multi.resolve_location("/old-a.txt")?;
+        assert_eq!((idx, info.id), (1, 1));
+        assert_eq!(multi.resolve_location("/a.txt")?.0, 0);
+        assert!(matches!(
+            multi.file_by_location("/d.txt"),
+            Err(Error::LocationNotFound(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! Блоки-обновления поверх базового блока.
+//!
+//! [`OverlayBlock`] позволяет выпустить небольшой блок-обновление («патч») поверх большого
+//! базового блока, не пересобирая базовый, по аналогии со слоями образов контейнеров. Файл
+//! патча перекрывает файл базового блока с тем же location, остальные файлы базового блока
+//! остаются доступны.
+//!
+//! Location сравниваются по MD5-хешу из заголовков блоков, поэтому оба блока должны быть
+//! созданы с одинаковыми правилами нормализации (см. [`BlockOptions::location_normalization`]).
+//!
+//! [`OverlayBlock`]: struct.OverlayBlock.html
+//! [`BlockOptions::location_normalization`]: ../block/struct.BlockOptions.html#method.location_normalization
+use crate::block::{Block, FileHeader, FileInfo};
+use crate::errors::*;
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+/// Базовый блок и перекрывающий его по location блок-обновление
+pub struct OverlayBlock {
+    base: Block,
+    patch: Block,
+    patched_locations: HashSet<md5::Digest>,
+}
+
+impl OverlayBlock {
+    /// Накладывает блок `patch` на блок `base`.
+    ///
+    /// Если правила нормализации location блоков различаются, возвращает
+    /// [`Error::UnsupportedFeature`], так как location таких блоков нельзя сопоставить по хешу.
+    ///
+    /// [`Error::UnsupportedFeature`]: ../errors/enum.Error.html#variant.UnsupportedFeature
+    pub fn new(base: Block, patch: Block) -> Result<Self> {
+        if base.normalization() != patch.normalization() {
+            return Err(Error::UnsupportedFeature(
+                "overlay of blocks with different location normalization".to_string(),
+            ));
+        }
+        let patched_locations = patch.iter().map(|info| info.location_hash).collect();
+        Ok(Self {
+            base,
+            patch,
+            patched_locations,
+        })
+    }
+
+    pub fn base(&self) -> &Block {
+        &self.base
+    }
+
+    pub fn patch(&self) -> &Block {
+        &self.patch
+    }
+
+    /// Перекрыт ли файл базового блока файлом патча
+    pub fn is_shadowed(&self, info: &FileInfo) -> bool {
+        self.patched_locations.contains(&info.location_hash)
+    }
+
+    /// Количество видимых файлов: все файлы патча и неперекрытые файлы базового блока
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patch.is_empty() && self.base.is_empty()
+    }
+
+    /// Метаинформация видимых файлов: сначала файлы патча, затем неперекрытые файлы базового
+    /// блока
+    pub fn iter(&self) -> impl Iterator<Item = &FileInfo> {
+        self.patch
+            .iter()
+            .chain(self.base.iter().filter(move |info| !self.is_shadowed(info)))
+    }
+
+    /// Возвращает заголовок и содержимое файла с идентификатором `id`: из патча, а если в
+    /// патче его нет – из базового блока, если этот файл не перекрыт патчем.
+    pub fn file_by_id(&self, id: u64) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        if self.patch.contains_id(id) {
+            return self.patch.file_by_id(id);
+        }
+        let visible = self
+            .base
+            .iter()
+            .any(|info| info.id == id && !self.is_shadowed(info));
+        if !visible {
+            return Err(Error::FileNotFound { id: id.into() });
+        }
+        self.base.file_by_id(id)
+    }
+
+    /// Возвращает заголовок и содержимое файла с location `location`: из патча, а если в
+    /// патче его нет – из базового блока (см. [`Block::file_by_location`]).
+    ///
+    /// [`Block::file_by_location`]: ../block/struct.Block.html#method.file_by_location
+    pub fn file_by_location(
+        &self,
+        location: impl AsRef<[u8]>,
+    ) -> Result<(FileHeader, Cow<'_, [u8]>)> {
+        let location = location.as_ref();
+        match self.patch.file_by_location(location) {
+            Err(Error::LocationNotFound(_)) => self.base.file_by_location(location),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::BlockOptions;
+    use crate::builder::BlockBuilder;
+    use crate::location::Normalization;
+
+    fn create_block(files: &[(u64, &str, &str)], normalization: Normalization) -> Result<Block> {
+        let mut options = BlockOptions::new();
+        options.location_normalization(normalization);
+        let mut builder = BlockBuilder::with_options(&options);
+        for (id, location, content) in files {
+            builder.add(*id, location, *content);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn patch_should_shadow_base_by_location() -> Result<()> {
+        let base = create_block(
+            &[(1, "/a.txt", "old a"), (2, "/b.txt", "b")],
+            Normalization::NONE,
+        )?;
+        let patch = create_block(
+            &[(3, "/a.txt", "new a"), (4, "/c.txt", "c")],
+            Normalization::NONE,
+        )?;
+        let overlay = OverlayBlock::new(base, patch)?;
+
+        assert_eq!(overlay.len(), 3);
+        let ids = overlay.iter().map(|info| info.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![3, 4, 2]);
+
+        assert_eq!(&overlay.file_by_location("/a.txt")?.1[..], b"new a");
+        assert_eq!(&overlay.file_by_location("/b.txt")?.1[..], b"b");
+        assert!(matches!(
+            overlay.file_by_location("/d.txt"),
+            Err(Error::LocationNotFound(_))
+        ));
+
+        assert_eq!(&overlay.file_by_id(2)?.1[..], b"b");
+        assert_eq!(&overlay.file_by_id(3)?.1[..], b"new a");
+        assert!(matches!(
+            overlay.file_by_id(1),
+            Err(Error::FileNotFound { id: 1 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_blocks_with_different_normalization() -> Result<()> {
+        let base = create_block(&[(1, "/a.txt", "a")], Normalization::NONE)?;
+        let patch = create_block(&[(2, "/A.txt", "a")], Normalization::LOWERCASE)?;
+        assert!(matches!(
+            OverlayBlock::new(base, patch),
+            Err(Error::UnsupportedFeature(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+//! Данные четности для восстановления поврежденных участков блока, сохраняемые в отдельный файл
+//! (`.parity`).
+//!
+//! Блок целиком (включая заголовок) делится на фрагменты по `shard_size` байт, а фрагменты – на
+//! группы по `group_size`. Для каждого фрагмента сохраняется MD5, что позволяет найти
+//! поврежденные фрагменты, а для каждой группы – фрагмент четности (XOR всех фрагментов группы,
+//! последний фрагмент блока дополняется нулями). Поврежденный фрагмент восстанавливается по
+//! четности и остальным фрагментам группы (см. [`Parity::heal`]), если он единственный
+//! поврежденный в своей группе. Объем данных четности – примерно `1 / group_size` от размера
+//! блока (~3% для параметров по умолчанию).
+//!
+//! Данные четности строятся по записанному блоку и должны соответствовать ему: после изменения
+//! блока их нужно построить заново.
+//!
+//! ## Формат
+//! ```text
+//! magic (4 байта, "BPAR") | version (2 байта) | shard_size (4 байта) | group_size (4 байта)
+//! | block_size (8 байт)
+//! ⌈block_size / shard_size⌉ × md5 (16 байт)                      – фрагменты блока
+//! ⌈⌈block_size / shard_size⌉ / group_size⌉ × shard_size байт     – фрагменты четности
+//! ```
+//!
+//! [`Parity::heal`]: struct.Parity.html#method.heal
+use crate::block::SelfSerialize;
+use crate::errors::*;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const PARITY_MAGIC: &[u8; 4] = b"BPAR";
+const PARITY_VERSION: u16 = 1;
+
+/// Размер фрагмента по умолчанию
+pub const DEFAULT_SHARD_SIZE: u32 = 64 * 1024;
+
+/// Количество фрагментов в группе по умолчанию
+pub const DEFAULT_GROUP_SIZE: u32 = 32;
+
+/// Участок блока: смещение и длина в байтах
+pub type Region = (u64, u64);
+
+/// Результат проверки или восстановления блока (см. [`Parity::check`], [`Parity::heal`])
+///
+/// [`Parity::check`]: struct.Parity.html#method.check
+/// [`Parity::heal`]: struct.Parity.html#method.heal
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct HealReport {
+    /// Поврежденные участки, которые восстановлены (или могут быть восстановлены)
+    pub repaired: Vec<Region>,
+
+    /// Поврежденные участки, которые восстановить невозможно: в группе поврежден более чем один
+    /// фрагмент, или повреждены сами данные четности
+    pub unrecoverable: Vec<Region>,
+}
+
+impl HealReport {
+    /// Поврежден ли блок
+    pub fn is_damaged(&self) -> bool {
+        !self.repaired.is_empty() || !self.unrecoverable.is_empty()
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Parity {
+    shard_size: u32,
+    group_size: u32,
+    block_size: u64,
+    hashes: Vec<md5::Digest>,
+    parity: Vec<Vec<u8>>,
+}
+
+impl Parity {
+    /// Строит данные четности блока, читая его из `source`
+    pub fn compute(source: &mut impl Read, shard_size: u32, group_size: u32) -> Result<Self> {
+        if shard_size == 0 || group_size == 0 {
+            return Err(Error::FormatLimitExceeded(
+                "shard and group sizes should be positive".into(),
+            ));
+        }
+        let mut result = Self {
+            shard_size,
+            group_size,
+            block_size: 0,
+            hashes: vec![],
+            parity: vec![],
+        };
+        let mut shard = vec![0u8; shard_size as usize];
+        loop {
+            let len = read_shard(source, &mut shard)?;
+            if len == 0 {
+                break;
+            }
+            result.block_size += len as u64;
+            result.hashes.push(md5::compute(&shard[..len]));
+            if (result.hashes.len() - 1).is_multiple_of(group_size as usize) {
+                result.parity.push(vec![0u8; shard_size as usize]);
+            }
+            xor(result.parity.last_mut().unwrap(), &shard[..len]);
+        }
+        Ok(result)
+    }
+
+    /// Читает данные четности из файла
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::decode(&mut reader).map_err(|e| match e {
+            Error::Io(e) => Error::ParityCorrupted(e.to_string()),
+            e => e,
+        })
+    }
+
+    /// Записывает данные четности в файл
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.encode(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Размер блока, для которого построены данные четности
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Находит поврежденные участки блока, не изменяя его
+    pub fn check(&self, block: &mut (impl Read + Seek)) -> Result<HealReport> {
+        self.scan(block, |_, _, _| Ok(()))
+    }
+
+    /// Находит поврежденные участки блока и восстанавливает те из них, для которых это
+    /// возможно. Размер блока должен совпадать с размером, для которого построены данные
+    /// четности, иначе возвращается [`Error::BlockCorrupted`].
+    ///
+    /// [`Error::BlockCorrupted`]: ../errors/enum.Error.html#variant.BlockCorrupted
+    pub fn heal(&self, block: &mut (impl Read + Write + Seek)) -> Result<HealReport> {
+        self.scan(block, |block, offset, shard| {
+            block.seek(SeekFrom::Start(offset))?;
+            block.write_all(shard)
+        })
+    }
+
+    fn scan<T: Read + Seek>(
+        &self,
+        block: &mut T,
+        mut repair: impl FnMut(&mut T, u64, &[u8]) -> io::Result<()>,
+    ) -> Result<HealReport> {
+        let size = block.seek(SeekFrom::End(0))?;
+        if size != self.block_size {
+            return Err(Error::corrupted(format!(
+                "block size is {} bytes, parity is built for {} bytes",
+                size, self.block_size
+            )));
+        }
+        block.seek(SeekFrom::Start(0))?;
+
+        let shard_size = self.shard_size as usize;
+        let group_size = self.group_size as usize;
+        let mut report = HealReport::default();
+        let mut buffer = vec![0u8; shard_size];
+        for (group_idx, parity) in self.parity.iter().enumerate() {
+            let first_shard = group_idx * group_size;
+            let shards = group_size.min(self.hashes.len() - first_shard);
+            let mut damaged = vec![];
+            let mut recovered = parity.clone();
+            for i in 0..shards {
+                let len = read_shard(block, &mut buffer)?;
+                let shard = &buffer[..len];
+                if md5::compute(shard) == self.hashes[first_shard + i] {
+                    xor(&mut recovered, shard);
+                } else {
+                    damaged.push(i);
+                }
+            }
+            let group_end = block.stream_position()?;
+
+            let region = |i: usize| {
+                let offset = ((first_shard + i) * shard_size) as u64;
+                (offset, (self.block_size - offset).min(shard_size as u64))
+            };
+            match damaged[..] {
+                [] => {}
+                [i] => {
+                    let (offset, len) = region(i);
+                    let shard = &recovered[..len as usize];
+                    if md5::compute(shard) == self.hashes[first_shard + i] {
+                        repair(block, offset, shard)?;
+                        block.seek(SeekFrom::Start(group_end))?;
+                        report.repaired.push((offset, len));
+                    } else {
+                        report.unrecoverable.push((offset, len));
+                    }
+                }
+                _ => report.unrecoverable.extend(damaged.into_iter().map(region)),
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl SelfSerialize for Parity {
+    fn encode(&self, target: &mut impl WriteBytesExt) -> Result<()> {
+        target.write_all(PARITY_MAGIC)?;
+        target.write_u16::<LE>(PARITY_VERSION)?;
+        target.write_u32::<LE>(self.shard_size)?;
+        target.write_u32::<LE>(self.group_size)?;
+        target.write_u64::<LE>(self.block_size)?;
+        for hash in self.hashes.iter() {
+            target.write_all(&hash.0)?;
+        }
+        for parity in self.parity.iter() {
+            target.write_all(parity)?;
+        }
+        Ok(())
+    }
+
+    fn decode(source: &mut impl ReadBytesExt) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        source.read_exact(&mut magic)?;
+        let version = source.read_u16::<LE>()?;
+        if &magic != PARITY_MAGIC || version != PARITY_VERSION {
+            return Err(Error::ParityCorrupted("unknown magic or version".into()));
+        }
+        let shard_size = source.read_u32::<LE>()?;
+        let group_size = source.read_u32::<LE>()?;
+        if shard_size == 0 || group_size == 0 {
+            return Err(Error::ParityCorrupted("zero shard or group size".into()));
+        }
+        let block_size = source.read_u64::<LE>()?;
+
+        // Память не выделяется заранее по прочитанным размерам: при их повреждении чтение
+        // завершится ошибкой на конце файла
+        let shards = block_size.div_ceil(u64::from(shard_size));
+        let mut hashes = vec![];
+        for _ in 0..shards {
+            let mut hash = [0u8; 16];
+            source.read_exact(&mut hash)?;
+            hashes.push(md5::Digest(hash));
+        }
+        let mut parity = vec![];
+        for _ in 0..shards.div_ceil(u64::from(group_size)) {
+            let mut shard = vec![0u8; shard_size as usize];
+            source.read_exact(&mut shard)?;
+            parity.push(shard);
+        }
+        Ok(Self {
+            shard_size,
+            group_size,
+            block_size,
+            hashes,
+            parity,
+        })
+    }
+}
+
+/// Читает фрагмент целиком (короче он может быть только в конце источника) и возвращает его
+/// длину. Остаток `shard` заполняется нулями.
+fn read_shard(source: &mut impl Read, shard: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < shard.len() {
+        match source.read(&mut shard[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    shard[len..].fill(0);
+    Ok(len)
+}
+
+fn xor(target: &mut [u8], source: &[u8]) {
+    for (t, s) in target.iter_mut().zip(source) {
+        *t ^= s;
+    }
+}
+
+/// Возвращает путь к данным четности по умолчанию для блока: `<block>.parity`
+pub fn parity_path_for(block_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = OsString::from(block_path.as_ref().as_os_str());
+    path.push(".parity");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn should_heal_single_damaged_shard_per_group() -> Result<()> {
+        // 10 фрагментов по 4 байта (последний – 2 байта), группы по 3 фрагмента
+        let original = (0..38u8).collect::<Vec<_>>();
+        let parity = Parity::compute(&mut &original[..], 4, 3)?;
+        let mut encoded = vec![];
+        parity.encode(&mut encoded)?;
+        assert_eq!(Parity::decode(&mut &encoded[..])?, parity);
+        assert_eq!(parity.block_size(), 38);
+
+        let mut block = Cursor::new(original.clone());
+        assert!(!parity.check(&mut block)?.is_damaged());
+
+        let mut damaged = original.clone();
+        damaged[1] ^= 0xFF; // группа 0
+        damaged[37] ^= 0xFF; // последний, неполный фрагмент
+        damaged[13] ^= 0xFF; // группа 1, два фрагмента
+        damaged[20] ^= 0xFF;
+        let mut block = Cursor::new(damaged);
+        let expected = HealReport {
+            repaired: vec![(0, 4), (36, 2)],
+            unrecoverable: vec![(12, 4), (20, 4)],
+        };
+        assert_eq!(parity.check(&mut block)?, expected);
+        assert_ne!(block.get_ref()[1], original[1]);
+
+        assert_eq!(parity.heal(&mut block)?, expected);
+        let healed = block.into_inner();
+        assert_eq!(healed[..12], original[..12]);
+        assert_eq!(healed[24..], original[24..]);
+        assert_ne!(healed[13], original[13]);
+
+        let mut truncated = Cursor::new(original[..30].to_vec());
+        assert!(matches!(
+            parity.check(&mut truncated),
+            Err(Error::BlockCorrupted { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_non_parity_files() {
+        let mut bytes = &b"BIDX\x01\x00\x00\x00\x00\x00"[..];
+        assert!(Parity::decode(&mut bytes).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+//! Чтение блока из произвольного источника `Read + Seek`.
+//!
+//! [`Block`] требует, чтобы содержимое блока целиком было доступно в памяти (см.
+//! [`BlockStorage`]). [`BlockReader`] читает блок из любого источника, поддерживающего
+//! позиционирование: например, из файла внутри другого архива или из обертки над сетевым
+//! потоком. При открытии читается только заголовок, а содержимое каждого файла читается по
+//! запросу и возвращается в виде `Vec<u8>`.
+//!
+//! [`Block`]: ../block/struct.Block.html
+//! [`BlockStorage`]: ../storage/trait.BlockStorage.html
+//! [`BlockReader`]: struct.BlockReader.html
+use crate::block::{
+    header_corrupted, split_signature, BlockHeader, ContentDecoder, DecodeLimits, FileHeader,
+    FileInfo, SIGNATURE_SECTION_SIZE, TRAILER_FIXED_SIZE,
+};
+#[cfg(feature = "encryption")]
+use crate::encryption::EncryptionKey;
+use crate::errors::*;
+use crate::location;
+use byteorder::{ReadBytesExt, LE};
+use std::borrow::Cow;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+
+/// Блок, файлы которого читаются из источника `R` по запросу
+pub struct BlockReader<R> {
+    source: R,
+    header: BlockHeader,
+    limits: DecodeLimits,
+    verify_on_read: bool,
+    #[cfg(feature = "encryption")]
+    decryption_key: Option<EncryptionKey>,
+}
+
+impl<R: Read + Seek> BlockReader<R> {
+    /// Открывает блок, читая его заголовок из `source`.
+    ///
+    /// Заголовок блока, записанного потоком (см. [`BlockOptions::stream`]), читается из конца
+    /// блока. Если основной заголовок поврежден, используется его резервная копия (см.
+    /// [`BlockOptions::header_trailer`]).
+    ///
+    /// [`BlockOptions::stream`]: ../block/struct.BlockOptions.html#method.stream
+    /// [`BlockOptions::header_trailer`]: ../block/struct.BlockOptions.html#method.header_trailer
+    pub fn open(source: R) -> Result<Self> {
+        Self::open_limited(source, DecodeLimits::unlimited())
+    }
+
+    /// Аналогичен [`open`], но ограничивает размер заголовка и длину location так же, как
+    /// [`Block::open_untrusted`].
+    ///
+    /// [`open`]: #method.open
+    /// [`Block::open_untrusted`]: ../block/struct.Block.html#method.open_untrusted
+    pub fn open_untrusted(source: R) -> Result<Self> {
+        Self::open_limited(source, DecodeLimits::untrusted())
+    }
+
+    fn open_limited(mut source: R, limits: DecodeLimits) -> Result<Self> {
+        let data_len = data_len(&mut source)?;
+        source.seek(SeekFrom::Start(0))?;
+        let primary = BlockHeader::decode_limited(
+            &mut BufReader::new((&mut source).take(data_len)),
+            data_len,
+            &limits,
+        )
+        .and_then(|header| header.validate(data_len).map(|_| header));
+        let header = match primary {
+            Ok(primary) if !primary.is_streamed() => primary,
+            primary => match read_trailer(&mut source, data_len, &limits)? {
+                Some(trailer) => trailer,
+                None if primary.is_ok() => {
+                    return Err(Error::corrupted(
+                        "meta section of a streamed block is missing or corrupted",
+                    ))
+                }
+                None => return Err(header_corrupted(primary.unwrap_err())),
+            },
+        };
+        Ok(Self {
+            source,
+            header,
+            limits,
+            verify_on_read: false,
+            #[cfg(feature = "encryption")]
+            decryption_key: None,
+        })
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.file_info().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.header.file_info().is_empty()
+    }
+
+    /// Метаинформация файлов блока в порядке записей заголовка
+    pub fn iter(&self) -> impl Iterator<Item = &FileInfo> {
+        self.header.file_info().iter()
+    }
+
+    /// Проверять ли контрольную сумму содержимого при каждом чтении (см.
+    /// [`Block::verify_on_read`])
+    ///
+    /// [`Block::verify_on_read`]: ../block/struct.Block.html#method.verify_on_read
+    pub fn verify_on_read(&mut self, verify: bool) -> &mut Self {
+        self.verify_on_read = verify;
+        self
+    }
+
+    /// Задает ключ для чтения зашифрованного блока (см. [`Block::decryption_key`])
+    ///
+    /// [`Block::decryption_key`]: ../block/struct.Block.html#method.decryption_key
+    #[cfg(feature = "encryption")]
+    pub fn decryption_key(&mut self, key: EncryptionKey) -> &mut Self {
+        self.decryption_key = Some(key);
+        self
+    }
+
+    /// Возвращает источник блока
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Читает заголовок и содержимое файла с порядковым номером `idx` (см. [`Block::file_at`])
+    ///
+    /// [`Block::file_at`]: ../block/struct.Block.html#method.file_at
+    pub fn file_at(&mut self, idx: usize) -> Result<(FileHeader, Vec<u8>)> {
+        let len = self.len();
+        let info = *self
+            .header
+            .file_info()
+            .get(idx)
+            .ok_or(Error::IndexOutOfRange { idx, len })?;
+        self.read_file(&info)
+    }
+
+    /// Читает заголовок и содержимое файла с идентификатором `id` (см. [`Block::file_by_id`])
+    ///
+    /// [`Block::file_by_id`]: ../block/struct.Block.html#method.file_by_id
+    pub fn file_by_id(&mut self, id: u64) -> Result<(FileHeader, Vec<u8>)> {
+        self.file_by_wide_id(u128::from(id))
+    }
+
+    /// Аналог [`file_by_id`] для 128-битных идентификаторов (см. [`Block::file_by_wide_id`])
+    ///
+    /// [`file_by_id`]: #method.file_by_id
+    /// [`Block::file_by_wide_id`]: ../block/struct.Block.html#method.file_by_wide_id
+    pub fn file_by_wide_id(&mut self, id: u128) -> Result<(FileHeader, Vec<u8>)> {
+        let info = *self
+            .iter()
+            .find(|info| info.wide_id() == id)
+            .ok_or(Error::FileNotFound { id })?;
+        self.read_file(&info)
+    }
+
+    /// Читает заголовок и содержимое файла с location `location`, сверяя location целиком
+    /// (см. [`Block::resolve_location`])
+    ///
+    /// [`Block::resolve_location`]: ../block/struct.Block.html#method.resolve_location
+    pub fn file_by_location(
+        &mut self,
+        location: impl AsRef<[u8]>,
+    ) -> Result<(FileHeader, Vec<u8>)> {
+        let normalization = location::Normalization::from_flags(self.header.flags());
+        let location = normalization.apply(location.as_ref()).into_owned();
+        let location_hash = md5::compute(&location);
+        let candidates = self
+            .iter()
+            .filter(|info| info.location_hash == location_hash)
+            .copied()
+            .collect::<Vec<_>>();
+
+        let mut unconfirmed = None;
+        let mut collision = None;
+        for info in candidates {
+            let (header, content) = self.read_file(&info)?;
+            if header.location == location {
+                return Ok((header, content));
+            }
+            // Заголовок дедуплицированного файла содержит location другого файла
+            if md5::compute(&header.location) != location_hash {
+                unconfirmed = unconfirmed.or(Some((header, content)));
+            } else {
+                collision = collision.or(Some(info.id));
+            }
+        }
+        match (unconfirmed, collision) {
+            (Some(file), _) => Ok(file),
+            (None, Some(id)) => Err(Error::LocationHashCollision {
+                location: location::display(&location).into_owned(),
+                id,
+            }),
+            (None, None) => Err(Error::LocationNotFound(
+                location::display(&location).into_owned(),
+            )),
+        }
+    }
+
+    fn read_file(&mut self, info: &FileInfo) -> Result<(FileHeader, Vec<u8>)> {
+        let out_of_bounds = || Error::EntryOutOfBounds {
+            id: info.wide_id(),
+            offset: info.offset,
+            size: info.size,
+        };
+        self.source.seek(SeekFrom::Start(u64::from(info.offset)))?;
+        let mut reader = BufReader::new(&mut self.source);
+        let header = FileHeader::decode_limited(&mut reader, self.limits.max_location_len)
+            .map_err(|e| match e {
+                Error::Io(_) => out_of_bounds(),
+                e => e,
+            })?;
+        let mut payload = vec![0; info.size as usize];
+        reader
+            .read_exact(&mut payload)
+            .map_err(|_| out_of_bounds())?;
+
+        let decoder = ContentDecoder {
+            header: &self.header,
+            verify_on_read: self.verify_on_read,
+            #[cfg(feature = "encryption")]
+            decryption_key: self.decryption_key.as_ref(),
+        };
+        let content = decoder
+            .checked_content(info, &header, Cow::Owned(payload))?
+            .into_owned();
+        Ok((header, content))
+    }
+}
+
+/// Размер блока без подписи (см. модуль `signature`)
+fn data_len(source: &mut (impl Read + Seek)) -> Result<u64> {
+    let len = source.seek(SeekFrom::End(0))?;
+    let section_len = SIGNATURE_SECTION_SIZE as u64;
+    if len < section_len {
+        return Ok(len);
+    }
+    let mut section = vec![0; SIGNATURE_SECTION_SIZE];
+    source.seek(SeekFrom::Start(len - section_len))?;
+    source.read_exact(&mut section)?;
+    match split_signature(&section) {
+        (_, Some(_)) => Ok(len - section_len),
+        (_, None) => Ok(len),
+    }
+}
+
+/// Читает резервную копию заголовка из конца блока размером `data_len` байт
+fn read_trailer(
+    source: &mut (impl Read + Seek),
+    data_len: u64,
+    limits: &DecodeLimits,
+) -> Result<Option<BlockHeader>> {
+    let fixed_len = TRAILER_FIXED_SIZE as u64;
+    let fixed_start = match data_len.checked_sub(fixed_len) {
+        Some(start) => start,
+        None => return Ok(None),
+    };
+    source.seek(SeekFrom::Start(fixed_start))?;
+    let len = u64::from(source.read_u32::<LE>()?);
+    if len > limits.max_header_len {
+        return Ok(None);
+    }
+    let start = match fixed_start.checked_sub(len) {
+        Some(start) => start,
+        None => return Ok(None),
+    };
+    let mut trailer = vec![0; (len + fixed_len) as usize];
+    source.seek(SeekFrom::Start(start))?;
+    source.read_exact(&mut trailer)?;
+    Ok(BlockHeader::decode_trailer(&trailer, limits)
+        .filter(|header| header.validate(data_len).is_ok()))
+}
+
+impl BlockReader<Cursor<Vec<u8>>> {
+    /// Открывает блок в памяти. В отличии от [`Block::from_bytes`] не декодирует содержимое
+    /// файлов заранее и возвращает его копии.
+    ///
+    /// [`Block::from_bytes`]: ../block/struct.Block.html#method.from_bytes
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Self::open(Cursor::new(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::{AddFileRequest, BlockOptions};
+    use std::fs::{self, File};
+    use std::path::Path;
+
+    fn write_files(dir: &Path) -> Result<Vec<(u64, std::path::PathBuf, &'static str)>> {
+        let files = vec![
+            (1, dir.join("a.txt"), "/a.txt"),
+            (2, dir.join("b.txt"), "/b.txt"),
+        ];
+        fs::write(&files[0].1, "first")?;
+        fs::write(&files[1].1, "second")?;
+        Ok(files)
+    }
+
+    #[test]
+    fn should_read_files_from_seekable_source() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-reader-test")?;
+        let files = write_files(tmp.path())?;
+        let requests = files
+            .iter()
+            .map(|(id, path, location)| AddFileRequest {
+                id: *id,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+
+        let block_path = tmp.path().join("test.block");
+        BlockOptions::new().create(&block_path, &requests)?;
+        let mut reader = BlockReader::open(File::open(&block_path)?)?;
+        reader.verify_on_read(true);
+        assert_eq!(reader.len(), 2);
+        assert_eq!(reader.file_by_id(2)?.1, b"second");
+        assert_eq!(reader.file_by_location("/a.txt")?.1, b"first");
+        assert_eq!(reader.file_at(1)?.0.location, b"/b.txt");
+        assert!(matches!(
+            reader.file_by_id(3),
+            Err(Error::FileNotFound { id: 3 })
+        ));
+        assert!(matches!(
+            reader.file_by_location("/c.txt"),
+            Err(Error::LocationNotFound(_))
+        ));
+
+        // Заголовок блока, записанного потоком, находится в конце
+        let mut streamed = vec![];
+        BlockOptions::new().stream(&mut streamed, &requests)?;
+        let mut reader = BlockReader::from_bytes(streamed)?;
+        assert_eq!(reader.file_by_location("/b.txt")?.1, b"second");
+        Ok(())
+    }
+
+    #[test]
+    fn should_detect_corrupted_content() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-reader-test")?;
+        let files = write_files(tmp.path())?;
+        let requests = files
+            .iter()
+            .map(|(id, path, location)| AddFileRequest {
+                id: *id,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+        let block_path = tmp.path().join("test.block");
+        let block = BlockOptions::new().create(&block_path, &requests)?;
+        let offset = block.header().file_info()[0].offset as usize;
+
+        let mut bytes = fs::read(&block_path)?;
+        let content_start = offset + 18 + "/a.txt".len();
+        bytes[content_start] ^= 0xff;
+        let mut reader = BlockReader::from_bytes(bytes)?;
+        assert!(reader.file_by_id(1).is_ok());
+        reader.verify_on_read(true);
+        assert!(matches!(
+            reader.file_by_id(1),
+            Err(Error::ChecksumMismatch { id: 1 })
+        ));
+
+        let mut truncated = fs::read(&block_path)?;
+        truncated.truncate(content_start);
+        assert!(BlockReader::from_bytes(truncated).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,758 @@
+//! Восстановление блоков с поврежденным заголовком.
+//!
+//! Перед содержимым каждого файла в блоке записан [`FileHeader`] с location и контрольной
+//! суммой файла. Поэтому даже при полностью уничтоженном блоке метаинформации файлы можно найти,
+//! просканировав блок по границам выравнивания: на каждой границе пробуем декодировать
+//! [`FileHeader`], а размер файла подбираем так, чтобы контрольная сумма содержимого совпала с
+//! записанной в заголовке. Благодаря проверке контрольной суммы случайные данные, похожие на
+//! заголовок, не принимаются за файл.
+//!
+//! Идентификаторы файлов хранятся только в блоке метаинформации. Если он читается хотя бы
+//! частично, то идентификаторы берутся оттуда, иначе файлам назначаются новые последовательные
+//! идентификаторы. Уцелевшие записи метаинформации также ограничивают поиск: размер файла по
+//! известному смещению сначала проверяется по записи, а содержимое файла не может продолжаться
+//! за начало следующего известного файла. Заголовки, для которых записи нет, принимаются только
+//! с правдоподобным location (не длиннее 4096 байт и без управляющих символов), иначе случайные
+//! данные, похожие на заголовок, приходилось бы хешировать до конца блока. Файлы, дедуплицированные при создании блока (см. [`BlockOptions::dedup`]),
+//! восстанавливаются в единственном экземпляре.
+//!
+//! Контрольная сумма в [`FileHeader`] сжатого блока (см. [`BlockOptions::compress`]) вычислена
+//! по исходному содержимому, поэтому в каждой точке, где может заканчиваться файл, проверяется
+//! также, не является ли содержимое сжатым. Сжатое содержимое распаковывается и записывается в
+//! новый блок в соответствии с его параметрами.
+//!
+//! Контрольная сумма зашифрованного содержимого (см. [`BlockOptions::encryption_key`]) вычислена
+//! по записанным данным, поэтому такие файлы находятся сканированием без ключа. Для записи в
+//! новый блок они расшифровываются ключом, заданным в параметрах восстановления, и шифруются им
+//! же заново. Если заголовок блока уничтожен, то признак шифрования неизвестен: файлы, которые
+//! удалось расшифровать ключом, считаются зашифрованными, а без ключа содержимое переносится как
+//! есть.
+//!
+//! [`FileHeader`]: ../block/struct.FileHeader.html
+//! [`BlockOptions::dedup`]: ../block/struct.BlockOptions.html#method.dedup
+//! [`BlockOptions::compress`]: ../block/struct.BlockOptions.html#method.compress
+//! [`BlockOptions::encryption_key`]: ../block/struct.BlockOptions.html#method.encryption_key
+#[cfg(feature = "zstd")]
+use crate::block::decompress;
+use crate::block::{
+    feature_name, round_up_to, trailer_start, Block, BlockHeader, BlockOptions, BlockWriter,
+    FileHeader, FileInfo, SelfSerialize, FLAG_COMPRESSED, FLAG_COMPRESSED_HEADER, FLAG_ENCRYPTED,
+    FLAG_WIDE_IDS, MAX_SUPPORTED_VERSION,
+};
+#[cfg(feature = "zstd")]
+use crate::compression::{CompressedContent, CHUNK_SIZE};
+#[cfg(feature = "encryption")]
+use crate::encryption;
+use crate::errors::*;
+use crate::manifest;
+use byteorder::{ReadBytesExt, LE};
+use memmap::MmapOptions;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Максимальная длина location файла, для которого не уцелела запись метаинформации
+const MAX_LOCATION_LEN: u16 = 4096;
+
+/// Файл, найденный при сканировании блока
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RecoveredEntry {
+    /// Полный (128-битный, см. [`FileInfo::wide_id`]) идентификатор файла. `None`, если его не
+    /// удалось восстановить из блока метаинформации
+    ///
+    /// [`FileInfo::wide_id`]: ../block/struct.FileInfo.html#method.wide_id
+    pub id: Option<u128>,
+
+    /// Смещение заголовка файла относительно начала блока
+    pub offset: u32,
+
+    /// Смещение содержимого файла относительно начала блока
+    pub content_offset: u32,
+
+    /// Размер содержимого файла в блоке (для сжатого содержимого – размер после сжатия)
+    pub size: u32,
+
+    /// Содержимое записано в блоке сжатым (см. [`BlockOptions::compress`])
+    ///
+    /// [`BlockOptions::compress`]: ../block/struct.BlockOptions.html#method.compress
+    pub compressed: bool,
+
+    pub header: FileHeader,
+}
+
+/// Находит все файлы блока, сканируя его содержимое с шагом `alignment` байт
+pub fn scan(data: &[u8], alignment: u32) -> Vec<RecoveredEntry> {
+    let data = &data[..trailer_start(data).unwrap_or(data.len())];
+    let known = KnownFiles::new(data, salvage_file_info(data));
+
+    let alignment = alignment as usize;
+    // Первый файл не может начинаться раньше конца заголовка (версия и количество файлов). Если
+    // заголовок содержит копии заголовков файлов, сканирование начинается после них, иначе копии
+    // пустых файлов были бы приняты за файлы
+    let header_end = BlockHeader::decode(&mut Cursor::new(data))
+        .ok()
+        .filter(|header| header.has_header_locations())
+        .map_or(2 + 4, |header| header.encoded_len() as usize);
+    let mut offset = round_up(header_end, alignment);
+    let mut entries = vec![];
+    while offset < data.len() {
+        match recover_entry_at(data, offset, alignment, &known) {
+            Some(mut entry) => {
+                let location_hash = md5::compute(&entry.header.location);
+                entry.id = known
+                    .get(entry.offset, location_hash)
+                    .map(FileInfo::wide_id);
+                offset = round_up((entry.content_offset + entry.size) as usize, alignment);
+                entries.push(entry);
+            }
+            None => offset += alignment,
+        }
+    }
+    entries
+}
+
+/// Восстанавливает блок `source`, записывая найденные в нем файлы в новый блок `target`.
+///
+/// Блок сканируется с выравниванием, заданным `options` (см. [`BlockOptions::packed`]).
+/// Возвращает найденные файлы в порядке их следования в блоке.
+///
+/// Для восстановления зашифрованного блока в `options` должен быть задан ключ, которым он
+/// зашифрован (см. [`BlockOptions::encryption_key`]), иначе возвращается
+/// [`Error::KeyRequired`].
+///
+/// [`BlockOptions::packed`]: ../block/struct.BlockOptions.html#method.packed
+/// [`BlockOptions::encryption_key`]: ../block/struct.BlockOptions.html#method.encryption_key
+/// [`Error::KeyRequired`]: ../errors/enum.Error.html#variant.KeyRequired
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(source = %source.as_ref().display(), target = %target.as_ref().display())
+    )
+)]
+pub fn repair(
+    source: impl AsRef<Path>,
+    target: impl AsRef<Path>,
+    options: &BlockOptions,
+) -> Result<Vec<RecoveredEntry>> {
+    let file = File::open(source)?;
+    let data = unsafe { MmapOptions::new().map(&file)? };
+    // Флаги неизвестны, если заголовок блока уничтожен
+    let flags = read_flags(&mut Cursor::new(&data[..]));
+    let has_flag = |flag| flags.is_some_and(|flags| flags & flag != 0);
+    // Без поддержки zstd сжатое содержимое не распознается сканированием
+    if !cfg!(feature = "zstd") && has_flag(FLAG_COMPRESSED) {
+        return Err(Error::UnsupportedFeature(feature_name(FLAG_COMPRESSED)));
+    }
+    if has_flag(FLAG_ENCRYPTED) && !options.is_encrypted() {
+        return Err(if cfg!(feature = "encryption") {
+            Error::KeyRequired
+        } else {
+            Error::UnsupportedFeature(feature_name(FLAG_ENCRYPTED))
+        });
+    }
+    let entries = scan(&data, options.alignment());
+    #[cfg(feature = "tracing")]
+    tracing::info!(files = entries.len(), "block scanned");
+    if entries.is_empty() {
+        return Err(Error::NoFilesInBlock);
+    }
+
+    let target = target.as_ref();
+    if target.exists() {
+        return Err(Error::BlockFileAlreadyExists(target.to_path_buf()));
+    }
+    // Манифест описывает исходный блок, часть файлов которого может быть утеряна, поэтому он не
+    // переносится (и записывается заново, если это задано `options`)
+    let files = entries
+        .iter()
+        .filter(|entry| {
+            let location_hash = md5::compute(&entry.header.location);
+            !entry
+                .id
+                .and_then(|id| u64::try_from(id).ok())
+                .is_some_and(|id| manifest::is_manifest(id, &location_hash))
+        })
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return Err(Error::NoFilesInBlock);
+    }
+    let mut next_id = files.iter().filter_map(|e| e.id).max().unwrap_or(0) + 1;
+    // Идентификаторы блока с 128-битными идентификаторами переносятся без усечения
+    let mut options = options.clone();
+    if has_flag(FLAG_WIDE_IDS) || next_id > u128::from(u64::MAX) {
+        options.wide_ids(true);
+    }
+    let options = &options;
+    options.write_atomically(target, |tmp_file| {
+        let locations = files.iter().map(|entry| &entry.header.location[..]);
+        let mut writer = BlockWriter::with_target(options, tmp_file, locations)?;
+        for entry in files.iter() {
+            let id = entry.id.unwrap_or_else(|| {
+                next_id += 1;
+                next_id - 1
+            });
+            let start = entry.content_offset as usize;
+            let payload = &data[start..start + entry.size as usize];
+            let content = stored_content(entry, payload, flags, options)?;
+            let location = &entry.header.location;
+            writer.add_entry(id, location, md5::compute(location), &content[..], None)?;
+        }
+        writer.finish().map(drop)
+    })?;
+    Block::open(target)?;
+    Ok(entries)
+}
+
+/// Файлы, известные по уцелевшим записям метаинформации
+struct KnownFiles {
+    /// Записи по смещению и хешу location
+    records: HashMap<(u32, md5::Digest), FileInfo>,
+    /// Смещения всех записей
+    offsets: HashSet<u32>,
+    /// Отсортированные смещения, по которым действительно записаны заголовки файлов записей.
+    /// Записи с другими смещениями могут быть повреждены, поэтому поиск ими не ограничивается
+    verified_offsets: Vec<usize>,
+}
+
+impl KnownFiles {
+    fn new(data: &[u8], file_info: Vec<FileInfo>) -> Self {
+        let mut verified_offsets = file_info
+            .iter()
+            .filter(|info| {
+                let header = data
+                    .get(info.offset as usize..)
+                    .and_then(|bytes| decode_header(&mut Cursor::new(bytes), u16::MAX));
+                header.is_some_and(|h| md5::compute(&h.location) == info.location_hash)
+            })
+            .map(|info| info.offset as usize)
+            .collect::<Vec<_>>();
+        verified_offsets.sort_unstable();
+        Self {
+            offsets: file_info.iter().map(|info| info.offset).collect(),
+            records: file_info
+                .into_iter()
+                .map(|info| ((info.offset, info.location_hash), info))
+                .collect(),
+            verified_offsets,
+        }
+    }
+
+    fn get(&self, offset: u32, location_hash: md5::Digest) -> Option<&FileInfo> {
+        self.records.get(&(offset, location_hash))
+    }
+
+    /// Смещение ближайшего известного файла, начинающегося после `offset`
+    fn next_after(&self, offset: usize) -> Option<usize> {
+        let offsets = &self.verified_offsets;
+        offsets
+            .get(offsets.partition_point(|&o| o <= offset))
+            .cloned()
+    }
+}
+
+/// Пытается найти файл, заголовок которого начинается со смещения `offset`
+fn recover_entry_at(
+    data: &[u8],
+    offset: usize,
+    alignment: usize,
+    known: &KnownFiles,
+) -> Option<RecoveredEntry> {
+    let mut cursor = Cursor::new(data.get(offset..)?);
+    // Длинный location допустим, только если по этому смещению есть запись метаинформации
+    let max_location_len = if known.offsets.contains(&(offset as u32)) {
+        u16::MAX
+    } else {
+        MAX_LOCATION_LEN
+    };
+    let header = decode_header(&mut cursor, max_location_len)?;
+    let content_start = offset + cursor.position() as usize;
+
+    let info = known.get(offset as u32, md5::compute(&header.location));
+    if info.is_none() && !is_plausible_location(&header.location) {
+        return None;
+    }
+    let known_size = info
+        .and_then(|info| check_content_size(data, content_start, info.size as usize, &header.hash));
+    let (size, compressed) = match known_size {
+        Some(found) => found,
+        None => {
+            // Содержимое не может продолжаться за начало следующего известного файла и не
+            // может быть больше максимального размера файла в блоке
+            let limit = known
+                .next_after(offset)
+                .unwrap_or(data.len())
+                .min(data.len())
+                .min(content_start.saturating_add(u32::MAX as usize));
+            let data = data.get(..limit)?;
+            find_content_size(data, content_start, alignment, &header.hash)?
+        }
+    };
+
+    Some(RecoveredEntry {
+        id: None,
+        offset: offset as u32,
+        content_offset: content_start as u32,
+        size: size as u32,
+        compressed,
+        header,
+    })
+}
+
+/// Декодирует заголовок файла с непустым location не длиннее `max_location_len` байт
+fn decode_header(cursor: &mut Cursor<&[u8]>, max_location_len: u16) -> Option<FileHeader> {
+    FileHeader::decode_limited(cursor, max_location_len)
+        .ok()
+        .filter(|header| !header.location.is_empty())
+}
+
+/// Похож ли `location` на location файла: не содержит управляющих символов (в том числе
+/// нулевых байтов, которыми заполнены отступы между файлами)
+fn is_plausible_location(location: &[u8]) -> bool {
+    location.iter().all(|&b| b >= 0x20)
+}
+
+/// Проверяет, что содержимое размером `size`, начинающееся со смещения `start`, имеет
+/// контрольную сумму `hash` (в том числе после распаковки). Возвращает размер и признак того,
+/// что содержимое сжато.
+fn check_content_size(
+    data: &[u8],
+    start: usize,
+    size: usize,
+    hash: &md5::Digest,
+) -> Option<(usize, bool)> {
+    let payload = data.get(start..start.checked_add(size)?)?;
+    if md5::compute(payload) == *hash {
+        Some((size, false))
+    } else if is_compressed_content(payload, hash) {
+        Some((size, true))
+    } else {
+        None
+    }
+}
+
+/// Возвращает исходное содержимое файла по записанным в блоке данным `payload`.
+///
+/// `flags` – флаги заголовка исходного блока, если он читается. Содержимое расшифровывается
+/// ключом из `options`; если флаги неизвестны, то файл считается зашифрованным, только если его
+/// удалось расшифровать.
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+fn stored_content<'a>(
+    entry: &RecoveredEntry,
+    payload: &'a [u8],
+    flags: Option<u32>,
+    options: &BlockOptions,
+) -> Result<Cow<'a, [u8]>> {
+    #[cfg(feature = "encryption")]
+    if let Some(key) = options.key() {
+        match encryption::decrypt(key, &entry.header.location, payload) {
+            Ok(decrypted) => {
+                // Содержимое сжимается до шифрования, поэтому сжатие определяется по
+                // расшифрованным данным
+                let compressed = match flags {
+                    Some(flags) => flags & FLAG_COMPRESSED != 0,
+                    #[cfg(feature = "zstd")]
+                    None => looks_compressed(&decrypted),
+                    // Без поддержки zstd сжатое содержимое не распознается
+                    #[cfg(not(feature = "zstd"))]
+                    None => false,
+                };
+                #[cfg(feature = "zstd")]
+                if compressed {
+                    return decompress(&decrypted).map(Cow::Owned);
+                }
+                debug_assert!(!compressed);
+                return Ok(Cow::Owned(decrypted));
+            }
+            Err(e) if flags.is_some_and(|flags| flags & FLAG_ENCRYPTED != 0) => return Err(e),
+            Err(_) => {}
+        }
+    }
+    #[cfg(feature = "zstd")]
+    if entry.compressed {
+        return decompress(payload).map(Cow::Owned);
+    }
+    debug_assert!(!entry.compressed);
+    Ok(Cow::Borrowed(payload))
+}
+
+/// Проверяет, похоже ли `payload` на сжатое содержимое (см. [`BlockOptions::compress`]): оно
+/// завершается размером фрагмента, а таблица фрагментов согласована с размером содержимого
+///
+/// [`BlockOptions::compress`]: ../block/struct.BlockOptions.html#method.compress
+#[cfg(feature = "zstd")]
+fn looks_compressed(payload: &[u8]) -> bool {
+    payload.ends_with(&CHUNK_SIZE.to_le_bytes()) && CompressedContent::decode(payload).is_ok()
+}
+
+/// Проверяет, является ли `payload` сжатым содержимым с контрольной суммой `hash`
+#[cfg(feature = "zstd")]
+fn is_compressed_content(payload: &[u8], hash: &md5::Digest) -> bool {
+    looks_compressed(payload)
+        && decompress(payload).is_ok_and(|content| md5::compute(content) == *hash)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn is_compressed_content(_payload: &[u8], _hash: &md5::Digest) -> bool {
+    false
+}
+
+/// Подбирает размер содержимого файла, начинающегося со смещения `start`, так чтобы его
+/// контрольная сумма совпала с `hash`. Возвращает размер и признак того, что содержимое сжато.
+///
+/// Файл может заканчиваться только там, где до следующей границы выравнивания идут нулевые байты
+/// (отступ между файлами), поэтому контрольная сумма вычисляется инкрементально и проверяется
+/// только в таких точках.
+fn find_content_size(
+    data: &[u8],
+    start: usize,
+    alignment: usize,
+    hash: &md5::Digest,
+) -> Option<(usize, bool)> {
+    let mut context = md5::Context::new();
+    let mut consumed = start;
+    let mut window_start = start;
+    let mut boundary = round_up(start, alignment);
+    loop {
+        let boundary_end = boundary.min(data.len());
+        let mut end = boundary_end;
+        while end > window_start && data[end - 1] == 0 {
+            end -= 1;
+        }
+
+        context.consume(&data[consumed..end]);
+        consumed = end;
+        while consumed <= boundary_end {
+            if context.clone().compute() == *hash {
+                return Some((consumed - start, false));
+            }
+            if is_compressed_content(&data[start..consumed], hash) {
+                return Some((consumed - start, true));
+            }
+            if consumed == boundary_end {
+                break;
+            }
+            context.consume(&data[consumed..=consumed]);
+            consumed += 1;
+        }
+
+        if boundary_end == data.len() {
+            return None;
+        }
+        window_start = boundary_end + 1;
+        consumed = boundary_end;
+        boundary += alignment;
+    }
+}
+
+/// Читает версию и флаги заголовка блока, оставляя `cursor` после них. Возвращает `None`, если
+/// версия не поддерживается (например, заголовок уничтожен)
+fn read_flags(cursor: &mut Cursor<&[u8]>) -> Option<u32> {
+    let version = cursor.read_u16::<LE>().ok()?;
+    if version == 0 || version > MAX_SUPPORTED_VERSION {
+        return None;
+    }
+    if version >= 2 {
+        cursor.read_u32::<LE>().ok()
+    } else {
+        Some(0)
+    }
+}
+
+/// Читает из блока метаинформации все записи, которые удается декодировать
+fn salvage_file_info(data: &[u8]) -> Vec<FileInfo> {
+    let mut cursor = Cursor::new(data);
+    let flags = match read_flags(&mut cursor) {
+        Some(flags) => flags,
+        None => return vec![],
+    };
+    if flags & FLAG_COMPRESSED_HEADER != 0 {
+        // Поврежденные сжатые записи не распаковываются, файлы находятся сканированием
+        return vec![];
+    }
+    let declared_len = cursor.read_u32::<LE>().unwrap_or(0) as usize;
+    let max_len = data.len() / FileInfo::encoded_len(flags) as usize;
+
+    let mut file_info = vec![];
+    for _ in 0..declared_len.min(max_len) {
+        match FileInfo::decode_with_flags(&mut cursor, flags) {
+            Ok(info) => file_info.push(info),
+            Err(_) => break,
+        }
+    }
+    file_info
+}
+
+fn round_up(value: usize, alignment: usize) -> usize {
+    round_up_to(value as u32, alignment as u32) as usize
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::AddFileRequest;
+    use std::fs;
+
+    fn create_block(
+        dir: &Path,
+        files: &[(u64, &str, &[u8])],
+        options: &BlockOptions,
+    ) -> Result<Vec<u8>> {
+        let paths = files
+            .iter()
+            .map(|(id, _, content)| {
+                let path = dir.join(format!("{}.bin", id));
+                fs::write(&path, content).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let requests = files
+            .iter()
+            .zip(paths.iter())
+            .map(|((id, location, _), path)| AddFileRequest {
+                id: *id,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+
+        let block_path = dir.join("source.block");
+        options.create(&block_path, &requests)?;
+        Ok(fs::read(&block_path)?)
+    }
+
+    const FILES: [(u64, &str, &[u8]); 4] = [
+        (10, "/a.txt", b"first"),
+        (20, "/empty", b""),
+        (30, "/zeros.bin", b"ends with zeros\0\0\0"),
+        (40, "/d.txt", b"last"),
+    ];
+
+    #[test]
+    fn should_recover_ids_from_partially_corrupted_header() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-repair-test")?;
+        let mut bytes = create_block(tmp.path(), &FILES, &BlockOptions::new())?;
+        // Портим количество файлов в заголовке
+        bytes[2..6].copy_from_slice(&[0xFF; 4]);
+        assert!(Block::from_bytes(bytes.clone()).is_err());
+
+        let entries = scan(&bytes, 1024);
+        let found = entries
+            .iter()
+            .map(|e| (e.id.unwrap(), &e.header.location[..], e.size))
+            .collect::<Vec<_>>();
+        let expected = FILES
+            .iter()
+            .map(|(id, location, content)| {
+                (u128::from(*id), location.as_bytes(), content.len() as u32)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(found, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn should_rebuild_block_with_destroyed_header() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-repair-test")?;
+        let mut bytes = create_block(tmp.path(), &FILES, &BlockOptions::new())?;
+        for byte in bytes[..1024].iter_mut() {
+            *byte = 0xAB;
+        }
+        let source = tmp.path().join("corrupted.block");
+        fs::write(&source, &bytes)?;
+
+        let target = tmp.path().join("repaired.block");
+        let entries = repair(&source, &target, &BlockOptions::new())?;
+        assert_eq!(entries.len(), FILES.len());
+
+        let block = Block::open(&target)?;
+        for (idx, (_, location, content)) in FILES.iter().enumerate() {
+            // Идентификаторы утеряны вместе с заголовком и назначаются заново
+            let (header, bytes) = block.file_by_id(idx as u64 + 1).unwrap();
+            assert_eq!(header.location, location.as_bytes());
+            assert_eq!(&bytes, content);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_accept_implausible_locations_only_from_salvaged_records() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-repair-test")?;
+        let files: [(u64, &str, &[u8]); 2] =
+            [(1, "/new\nline.txt", b"first"), (2, "/b.txt", b"second")];
+        let mut bytes = create_block(tmp.path(), &files, &BlockOptions::new())?;
+
+        let entries = scan(&bytes, 1024);
+        let ids = entries.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids, [Some(1), Some(2)]);
+
+        // Без записей метаинформации location с управляющим символом не принимается
+        bytes[..2].copy_from_slice(&[0xFF; 2]);
+        let entries = scan(&bytes, 1024);
+        let locations = entries
+            .iter()
+            .map(|e| &e.header.location[..])
+            .collect::<Vec<_>>();
+        assert_eq!(locations, [&b"/b.txt"[..]]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_random_data_quickly() {
+        // Данные без нулевых байтов, на каждом смещении которых декодируется заголовок файла
+        let mut state = 1u32;
+        let data = (0..64 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8 | 1
+            })
+            .collect::<Vec<_>>();
+        assert!(scan(&data, 1).is_empty());
+    }
+
+    #[test]
+    fn should_keep_wide_ids_when_rebuilding_block() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-repair-test")?;
+        let (a, b) = (tmp.path().join("a"), tmp.path().join("b"));
+        fs::write(&a, "first")?;
+        fs::write(&b, "second")?;
+        // Идентификаторы различаются только старшими битами
+        let (first_id, second_id) = (1u128 << 64 | 7, 2u128 << 64 | 7);
+        let request = |path, location| AddFileRequest {
+            id: 0,
+            path,
+            location: Path::new(location),
+            content_hash: None,
+            size: None,
+        };
+        let files = [
+            (first_id, request(&a, "/a")),
+            (second_id, request(&b, "/b")),
+        ];
+        let source = tmp.path().join("source.block");
+        let mut options = BlockOptions::new();
+        options.wide_ids(true);
+        options.create_wide(&source, &files)?;
+
+        // Портим количество файлов в заголовке (после версии и флагов)
+        let mut bytes = fs::read(&source)?;
+        bytes[6..10].copy_from_slice(&[0xFF; 4]);
+        assert!(Block::from_bytes(bytes.clone()).is_err());
+        fs::write(&source, &bytes)?;
+
+        let target = tmp.path().join("repaired.block");
+        let entries = repair(&source, &target, &BlockOptions::new())?;
+        let ids = entries.iter().filter_map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids, [first_id, second_id]);
+        let block = Block::open(&target)?;
+        assert!(block.header().has_wide_ids());
+        assert_eq!(block.file_by_wide_id(first_id)?.1, &b"first"[..]);
+        assert_eq!(block.file_by_wide_id(second_id)?.1, &b"second"[..]);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn should_rebuild_compressed_block() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-repair-test")?;
+        let large = (0..3 * CHUNK_SIZE)
+            .map(|i| (i % 7) as u8)
+            .collect::<Vec<_>>();
+        let files = [
+            (10, "/a.txt", &b"first"[..]),
+            (20, "/empty", &b""[..]),
+            (30, "/large.bin", &large[..]),
+        ];
+        let mut options = BlockOptions::new();
+        options.compress(true);
+        let mut bytes = create_block(tmp.path(), &files, &options)?;
+        for byte in bytes[..1024].iter_mut() {
+            *byte = 0xAB;
+        }
+        let source = tmp.path().join("corrupted.block");
+        fs::write(&source, &bytes)?;
+
+        let target = tmp.path().join("repaired.block");
+        let entries = repair(&source, &target, &BlockOptions::new())?;
+        assert_eq!(entries.len(), files.len());
+        assert!(entries[2].compressed);
+        assert!((entries[2].size as usize) < large.len());
+
+        let block = Block::open(&target)?;
+        assert!(!block.header().is_compressed());
+        for (idx, (_, location, content)) in files.iter().enumerate() {
+            let (header, bytes) = block.file_by_id(idx as u64 + 1).unwrap();
+            assert_eq!(header.location, location.as_bytes());
+            assert_eq!(&bytes[..], *content);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn should_reencrypt_entries_of_encrypted_block() -> Result<()> {
+        use crate::encryption::EncryptionKey;
+
+        let tmp = tempdir::TempDir::new("rust-repair-test")?;
+        let key = EncryptionKey::generate();
+        let mut options = BlockOptions::new();
+        options.encryption_key(Some(key.clone()));
+        let mut bytes = create_block(tmp.path(), &FILES, &options)?;
+        // Портим количество файлов в заголовке (после версии и флагов)
+        bytes[6..10].copy_from_slice(&[0xFF; 4]);
+        let source = tmp.path().join("corrupted.block");
+        fs::write(&source, &bytes)?;
+
+        let target = tmp.path().join("repaired.block");
+        let result = repair(&source, &target, &BlockOptions::new());
+        assert!(matches!(result, Err(Error::KeyRequired)), "{:?}", result);
+        assert!(!target.exists());
+
+        repair(&source, &target, &options)?;
+        let mut block = Block::open(&target)?;
+        assert!(block.header().is_encrypted());
+        block.decryption_key(key);
+        for (id, location, content) in FILES.iter() {
+            let (header, bytes) = block.file_by_id(*id)?;
+            assert_eq!(header.location, location.as_bytes());
+            assert_eq!(&bytes[..], *content);
+        }
+        Ok(())
+    }
+
+    #[cfg(all(feature = "encryption", feature = "zstd"))]
+    #[test]
+    fn should_decrypt_entries_of_block_with_destroyed_header() -> Result<()> {
+        use crate::encryption::EncryptionKey;
+
+        let tmp = tempdir::TempDir::new("rust-repair-test")?;
+        let key = EncryptionKey::generate();
+        let mut options = BlockOptions::new();
+        options.encryption_key(Some(key.clone())).compress(true);
+        let mut bytes = create_block(tmp.path(), &FILES, &options)?;
+        for byte in bytes[..1024].iter_mut() {
+            *byte = 0xAB;
+        }
+        let source = tmp.path().join("corrupted.block");
+        fs::write(&source, &bytes)?;
+
+        let target = tmp.path().join("repaired.block");
+        let mut target_options = BlockOptions::new();
+        target_options.encryption_key(Some(key.clone()));
+        repair(&source, &target, &target_options)?;
+        let mut block = Block::open(&target)?;
+        block.decryption_key(key);
+        for (idx, (_, location, content)) in FILES.iter().enumerate() {
+            let (header, bytes) = block.file_by_id(idx as u64 + 1)?;
+            assert_eq!(header.location, location.as_bytes());
+            assert_eq!(&bytes[..], *content);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,402 @@
+//! Фоновая проверка блоков директории (scrubbing).
+//!
+//! Блоки директории периодически проверяются целиком (см. [`scrub_block`]), чтобы повреждение
+//! носителя обнаруживалось до того, как файл понадобится. Чтобы проверка не мешала основной
+//! нагрузке, скорость чтения ограничивается [`RateLimiter`]. Время последней проверки каждого
+//! блока сохраняется в файл состояния ([`ScrubState`]), поэтому после перезапуска проверка
+//! продолжается с блоков, которые дольше всего не проверялись.
+//!
+//! ## Формат файла состояния
+//! ```text
+//! <время проверки, сек. от UNIX epoch> <ok|failed> <путь к блоку относительно директории>
+//! ```
+//! Каждый блок записан отдельной строкой. Строки, которые не удается разобрать, игнорируются, а
+//! блоки с путями, не являющимися корректной UTF-8 строкой, в файл не записываются (такие блоки
+//! проверяются в каждом цикле).
+//!
+//! [`scrub_block`]: fn.scrub_block.html
+//! [`RateLimiter`]: struct.RateLimiter.html
+//! [`ScrubState`]: struct.ScrubState.html
+use crate::block::{Advice, Block, EntryVerification};
+use crate::catalog;
+use crate::errors::*;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind::NotFound, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Имя файла состояния в корне директории с блоками
+pub const SCRUB_STATE_FILE_NAME: &str = "blocky.scrub";
+
+/// Размер части содержимого файла, читаемой за раз при проверке. Скорость чтения учитывается
+/// после каждой части, поэтому большие файлы не читаются в обход [`RateLimiter`].
+///
+/// [`RateLimiter`]: struct.RateLimiter.html
+const SCRUB_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Результат последней проверки блока
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ScrubRecord {
+    /// Время окончания проверки в секундах от UNIX epoch
+    pub verified: u64,
+
+    /// Все ли файлы блока прошли проверку
+    pub ok: bool,
+}
+
+/// Время последней проверки блоков директории
+#[derive(Debug)]
+pub struct ScrubState {
+    path: PathBuf,
+
+    /// Путь к блоку относительно корня директории → результат последней проверки
+    blocks: BTreeMap<PathBuf, ScrubRecord>,
+}
+
+impl ScrubState {
+    /// Читает файл состояния `path`. Если файла нет, состояние пусто.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut blocks = BTreeMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    if let Some((block, record)) = parse_line(&line?) {
+                        blocks.insert(block, record);
+                    }
+                }
+            }
+            Err(e) if e.kind() == NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            blocks,
+        })
+    }
+
+    /// Сохраняет состояние в файл, из которого оно было прочитано
+    pub fn save(&self) -> Result<()> {
+        let file_name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_path = self.path.with_file_name(format!(".{}.tmp", file_name));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for (block, record) in self.blocks.iter() {
+            if let Some(block) = block.to_str().filter(|b| !b.contains('\n')) {
+                let status = if record.ok { "ok" } else { "failed" };
+                writeln!(writer, "{} {} {}", record.verified, status, block)?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Результат последней проверки блока `block` (путь относительно корня директории)
+    pub fn get(&self, block: impl AsRef<Path>) -> Option<ScrubRecord> {
+        self.blocks.get(block.as_ref()).copied()
+    }
+
+    /// Записывает результат проверки блока `block`
+    pub fn record(&mut self, block: impl Into<PathBuf>, record: ScrubRecord) {
+        self.blocks.insert(block.into(), record);
+    }
+
+    /// Удаляет из состояния блоки, отсутствующие среди `blocks`
+    pub fn retain(&mut self, blocks: &[PathBuf]) {
+        self.blocks.retain(|block, _| blocks.contains(block));
+    }
+
+    /// Блоки из `blocks`, которые на момент `now` не проверялись дольше `interval` секунд, в
+    /// порядке давности последней проверки (первыми – ни разу не проверенные)
+    pub fn due(&self, blocks: &[PathBuf], now: u64, interval: u64) -> Vec<PathBuf> {
+        let mut due = blocks
+            .iter()
+            .map(|block| (self.get(block).map(|r| r.verified), block))
+            .filter(|(verified, _)| verified.is_none_or(|v| v.saturating_add(interval) <= now))
+            .collect::<Vec<_>>();
+        due.sort();
+        due.into_iter().map(|(_, block)| block.clone()).collect()
+    }
+
+    /// Время, когда наступит срок проверки первого из блоков `blocks`
+    pub fn next_due(&self, blocks: &[PathBuf], interval: u64) -> Option<u64> {
+        blocks
+            .iter()
+            .map(|block| {
+                self.get(block)
+                    .map_or(0, |r| r.verified.saturating_add(interval))
+            })
+            .min()
+    }
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, ScrubRecord)> {
+    let mut parts = line.splitn(3, ' ');
+    let verified = parts.next()?.parse().ok()?;
+    let ok = match parts.next()? {
+        "ok" => true,
+        "failed" => false,
+        _ => return None,
+    };
+    let block = parts.next().filter(|b| !b.is_empty())?;
+    Some((PathBuf::from(block), ScrubRecord { verified, ok }))
+}
+
+/// Находит блоки директории `dir` (включая поддиректории) и возвращает их пути относительно
+/// `dir` в отсортированном порядке
+pub fn find_blocks(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    catalog::find_blocks(dir.as_ref(), Path::new(""), &mut paths)?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Ограничивает скорость чтения по алгоритму token bucket: токены (байты) накапливаются со
+/// скоростью `rate`, но не больше, чем на одну секунду чтения, и поток приостанавливается, если
+/// прочитано больше, чем накоплено. Благодаря ограничению накопленного простой (например,
+/// ожидание между циклами проверки) не позволяет затем читать быстрее заданной скорости.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Байт в секунду, `None` – без ограничения
+    rate: Option<u64>,
+    /// Доступные байты, отрицательное значение – долг, который нужно переждать
+    tokens: f64,
+    updated: Instant,
+    consumed: u64,
+}
+
+impl RateLimiter {
+    /// Ограничение в `rate` байт в секунду, `None` – без ограничения
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|r| *r > 0);
+        Self {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            updated: Instant::now(),
+            consumed: 0,
+        }
+    }
+
+    /// Учитывает `bytes` прочитанных байт и приостанавливает поток, пока они не будут покрыты
+    /// накопленными токенами
+    pub fn consume(&mut self, bytes: u64) {
+        let delay = self.take(bytes, Instant::now());
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Количество байт, учтенных с момента создания
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Списывает `bytes` байт в момент `now` и возвращает, сколько нужно подождать, чтобы
+    /// скорость не превышала заданную
+    fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.consumed = self.consumed.saturating_add(bytes);
+        let rate = match self.rate {
+            Some(rate) => rate as f64,
+            None => return Duration::ZERO,
+        };
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.updated = self.updated.max(now);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate) - bytes as f64;
+        if self.tokens < 0. {
+            Duration::from_secs_f64(-self.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Переводит процесс в класс ввода-вывода idle (аналог `ionice -c3`): его запросы к диску
+/// обслуживаются, только когда диск не занят другими процессами. Поддерживается только в
+/// Linux, на остальных платформах возвращается ошибка.
+pub fn set_idle_io_priority() -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "I/O priority is supported only on Linux",
+    )
+    .into())
+}
+
+/// Проверяет контрольные суммы всех файлов блока `path`, читая его со скоростью не выше
+/// заданной `limiter`. С `drop_cache` прочитанные страницы блока вытесняются из page cache
+/// (см. [`Block::drop_page_cache`]), а с `direct_io` блок читается в обход page cache
+/// небольшими частями (см. [`BlockOpenOptions::direct_io`]).
+///
+/// Результаты возвращаются в порядке следования файлов в блоке. Ошибка возвращается, только
+/// если блок не удалось открыть.
+///
+/// [`Block::drop_page_cache`]: ../block/struct.Block.html#method.drop_page_cache
+/// [`BlockOpenOptions::direct_io`]: ../block/struct.BlockOpenOptions.html#method.direct_io
+pub fn scrub_block(
+    path: impl AsRef<Path>,
+    limiter: &mut RateLimiter,
+    drop_cache: bool,
+    direct_io: bool,
+) -> Result<Vec<EntryVerification>> {
+    // Блок читается один раз от начала до конца, поэтому прочитанные страницы не должны
+    // вытеснять из page cache данные других процессов
+    let block = Block::options()
+        .advice(Advice::Sequential)
+        .direct_io(direct_io)
+        .open(path.as_ref())?;
+    let results = block
+        .iter()
+        .enumerate()
+        .map(|(idx, info)| {
+            let result =
+                block.verify_at_with(idx, SCRUB_CHUNK_SIZE, |bytes| limiter.consume(bytes));
+            EntryVerification {
+                id: info.wide_id(),
+                result,
+            }
+        })
+        .collect();
+    if drop_cache {
+        block.drop_page_cache()?;
+    } else {
+        block.release_pages()?;
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::{AddFileRequest, BlockOptions};
+
+    #[test]
+    fn should_schedule_least_recently_verified_blocks() -> Result<()> {
+        let dir = tempdir::TempDir::new("rust-scrub-test")?;
+        let state_path = dir.path().join(SCRUB_STATE_FILE_NAME);
+        let blocks = ["a.block", "b.block", "c.block", "new.block"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+
+        let mut state = ScrubState::load(&state_path)?;
+        state.record(
+            "a.block",
+            ScrubRecord {
+                verified: 300,
+                ok: true,
+            },
+        );
+        state.record(
+            "b.block",
+            ScrubRecord {
+                verified: 100,
+                ok: false,
+            },
+        );
+        state.record(
+            "c.block",
+            ScrubRecord {
+                verified: 900,
+                ok: true,
+            },
+        );
+        state.record(
+            "gone.block",
+            ScrubRecord {
+                verified: 10,
+                ok: true,
+            },
+        );
+        state.retain(&blocks);
+        state.save()?;
+
+        let state = ScrubState::load(&state_path)?;
+        assert_eq!(state.get("gone.block"), None);
+        assert_eq!(
+            state.get("b.block"),
+            Some(ScrubRecord {
+                verified: 100,
+                ok: false
+            })
+        );
+        assert_eq!(
+            state.due(&blocks, 1000, 500),
+            vec![
+                PathBuf::from("new.block"),
+                "b.block".into(),
+                "a.block".into()
+            ]
+        );
+        assert_eq!(state.next_due(&blocks, 500), Some(0));
+        assert_eq!(state.next_due(&blocks[2..3], 500), Some(1400));
+        Ok(())
+    }
+
+    #[test]
+    fn should_limit_read_rate() {
+        let mut limiter = RateLimiter::new(Some(1000));
+        let started = limiter.updated;
+        assert_eq!(limiter.take(2500, started), Duration::from_millis(1500));
+        assert_eq!(
+            limiter.take(500, started + Duration::from_secs(2)),
+            Duration::ZERO
+        );
+
+        // Простой не накапливает больше секунды чтения
+        let later = started + Duration::from_secs(100);
+        assert_eq!(limiter.take(3000, later), Duration::from_secs(2));
+        assert_eq!(limiter.consumed(), 6000);
+
+        let mut limiter = RateLimiter::new(None);
+        assert_eq!(limiter.take(u64::MAX, Instant::now()), Duration::ZERO);
+    }
+
+    #[test]
+    fn should_scrub_block() -> Result<()> {
+        let dir = tempdir::TempDir::new("rust-scrub-test")?;
+        let file = dir.path().join("file");
+        fs::write(&file, b"content")?;
+        let block_path = dir.path().join("a.block");
+        let files = [AddFileRequest {
+            id: 1,
+            path: &file,
+            location: Path::new("/file"),
+            content_hash: None,
+            size: None,
+        }];
+        BlockOptions::new().create(&block_path, &files)?;
+
+        for direct_io in [false, true] {
+            let mut limiter = RateLimiter::new(Some(1 << 20));
+            let results = scrub_block(&block_path, &mut limiter, true, direct_io)?;
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].id, 1);
+            assert!(results[0].result.is_ok());
+        }
+
+        assert_eq!(find_blocks(dir.path())?, vec![PathBuf::from("a.block")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! Поддержка serde для метаинформации блоков (feature `serde`).
+//!
+//! `Serialize` и `Deserialize` реализованы для [`FileInfo`], [`FileHeader`], [`BlockHeader`],
+//! [`BlockMetadata`], [`BlockLayout`], [`PlannedEntry`], [`BlockDiff`] и [`CatalogEntry`], так
+//! что метаинформацию можно выгрузить в JSON или сохранить в любом формате, поддерживаемом
+//! serde.
+//!
+//! В форматах, предназначенных для чтения человеком (например, JSON), контрольные суммы
+//! записываются шестнадцатеричной строкой, а location – строкой, если это корректная UTF-8
+//! строка, и массивом байт в остальных случаях. В бинарных форматах и то и другое записывается
+//! последовательностью байт.
+//!
+//! Десериализованный [`BlockHeader`] проверяется так же, как прочитанный из блока: заголовок с
+//! неподдерживаемой версией или флагами не принимается.
+//!
+//! [`FileInfo`]: ../block/struct.FileInfo.html
+//! [`FileHeader`]: ../block/struct.FileHeader.html
+//! [`BlockHeader`]: ../block/struct.BlockHeader.html
+//! [`BlockMetadata`]: ../block/struct.BlockMetadata.html
+//! [`BlockLayout`]: ../block/struct.BlockLayout.html
+//! [`PlannedEntry`]: ../block/struct.PlannedEntry.html
+//! [`BlockDiff`]: ../block/struct.BlockDiff.html
+//! [`CatalogEntry`]: ../catalog/struct.CatalogEntry.html
+use crate::block::{
+    BlockDiff, BlockHeader, BlockLayout, BlockMetadata, FileHeader, FileInfo, PlannedEntry,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::catalog::CatalogEntry;
+use serde::de::{self, Deserialize, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
+
+/// Поле, сериализуемое как есть
+struct Plain<T>(T);
+
+impl<T: Serialize> Serialize for Plain<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Plain<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Plain)
+    }
+}
+
+/// Контрольная сумма MD5
+struct Hex<D>(D);
+
+impl<D: Borrow<md5::Digest>> Serialize for Hex<D> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let digest = self.0.borrow();
+        if serializer.is_human_readable() {
+            serializer.collect_str(&format_args!("{:x}", digest))
+        } else {
+            serializer.serialize_bytes(&digest.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hex<md5::Digest> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DigestVisitor;
+
+        impl<'de> Visitor<'de> for DigestVisitor {
+            type Value = md5::Digest;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("MD5 digest (32 hex digits or 16 bytes)")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                let invalid = || E::invalid_value(de::Unexpected::Str(value), &self);
+                if value.len() != 32 || !value.is_ascii() {
+                    return Err(invalid());
+                }
+                let mut digest = [0u8; 16];
+                for (idx, byte) in digest.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(&value[idx * 2..idx * 2 + 2], 16)
+                        .map_err(|_| invalid())?;
+                }
+                Ok(md5::Digest(digest))
+            }
+
+            fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+                let digest = <[u8; 16]>::try_from(value)
+                    .map_err(|_| E::invalid_length(value.len(), &self))?;
+                Ok(md5::Digest(digest))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                let bytes = collect_bytes(seq)?;
+                self.visit_bytes(&bytes)
+            }
+        }
+
+        let digest = if deserializer.is_human_readable() {
+            deserializer.deserialize_any(DigestVisitor)?
+        } else {
+            deserializer.deserialize_bytes(DigestVisitor)?
+        };
+        Ok(Hex(digest))
+    }
+}
+
+/// Location файла: произвольная последовательность байт
+struct Location<L>(L);
+
+impl<L: AsRef<[u8]>> Serialize for Location<L> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let location = self.0.as_ref();
+        match std::str::from_utf8(location) {
+            Ok(location) if serializer.is_human_readable() => serializer.serialize_str(location),
+            _ => serializer.serialize_bytes(location),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Location<Vec<u8>> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LocationVisitor;
+
+        impl<'de> Visitor<'de> for LocationVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("location (string or bytes)")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(value.as_bytes().to_vec())
+            }
+
+            fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+                Ok(value.to_vec())
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(value)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                collect_bytes(seq)
+            }
+        }
+
+        let location = if deserializer.is_human_readable() {
+            deserializer.deserialize_any(LocationVisitor)?
+        } else {
+            deserializer.deserialize_byte_buf(LocationVisitor)?
+        };
+        Ok(Location(location))
+    }
+}
+
+fn collect_bytes<'de, A: SeqAccess<'de>>(mut seq: A) -> Result<Vec<u8>, A::Error> {
+    let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+    while let Some(byte) = seq.next_element()? {
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+/// Реализует `Serialize` и `Deserialize` для структуры с перечисленными полями. Для каждого
+/// поля указывается его тип и представление: `Plain`, `Hex` или `Location`. Если задана
+/// функция `check`, она проверяет десериализованное значение.
+macro_rules! serde_struct {
+    ($name:ident { $($field:ident: $ty:ty => $repr:ident),* $(,)? }) => {
+        serde_struct!($name { $($field: $ty => $repr),* }, check = |_| Ok(()));
+    };
+    ($name:ident { $($field:ident: $ty:ty => $repr:ident),* $(,)? }, check = $check:expr) => {
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let fields = [$(stringify!($field)),*].len();
+                let mut state = serializer.serialize_struct(stringify!($name), fields)?;
+                $(state.serialize_field(stringify!($field), &$repr(&self.$field))?;)*
+                state.end()
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                const FIELDS: &[&str] = &[$(stringify!($field)),*];
+
+                struct StructVisitor;
+
+                impl<'de> Visitor<'de> for StructVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                        f.write_str(concat!("struct ", stringify!($name)))
+                    }
+
+                    fn visit_map<A: MapAccess<'de>>(
+                        self,
+                        mut map: A,
+                    ) -> Result<Self::Value, A::Error> {
+                        $(let mut $field: Option<$ty> = None;)*
+                        while let Some(key) = map.next_key::<String>()? {
+                            match key.as_str() {
+                                $(stringify!($field) => {
+                                    $field = Some(map.next_value::<$repr<$ty>>()?.0);
+                                })*
+                                _ => {
+                                    map.next_value::<IgnoredAny>()?;
+                                }
+                            }
+                        }
+                        let value = $name {
+                            $($field: $field
+                                .ok_or_else(|| de::Error::missing_field(stringify!($field)))?,)*
+                        };
+                        let check: fn(&$name) -> crate::errors::Result<()> = $check;
+                        check(&value).map_err(de::Error::custom)?;
+                        Ok(value)
+                    }
+
+                    fn visit_seq<A: SeqAccess<'de>>(
+                        self,
+                        mut seq: A,
+                    ) -> Result<Self::Value, A::Error> {
+                        let mut len = 0;
+                        $(
+                            let $field = seq
+                                .next_element::<$repr<$ty>>()?
+                                .ok_or_else(|| de::Error::invalid_length(len, &self))?
+                                .0;
+                            len += 1;
+                        )*
+                        let _ = len;
+                        let value = $name { $($field),* };
+                        let check: fn(&$name) -> crate::errors::Result<()> = $check;
+                        check(&value).map_err(de::Error::custom)?;
+                        Ok(value)
+                    }
+                }
+
+                deserializer.deserialize_struct(stringify!($name), FIELDS, StructVisitor)
+            }
+        }
+    };
+}
+
+serde_struct!(FileInfo {
+    id: u64 => Plain,
+    id_high: u64 => Plain,
+    size: u32 => Plain,
+    offset: u32 => Plain,
+    location_hash: md5::Digest => Hex,
+});
+
+serde_struct!(FileHeader {
+    hash: md5::Digest => Hex,
+    location: Vec<u8> => Location,
+});
+
+serde_struct!(
+    BlockHeader {
+        version: u16 => Plain,
+        flags: u32 => Plain,
+        file_info: Vec<FileInfo> => Plain,
+        file_headers: Vec<FileHeader> => Plain,
+        metadata: Option<BlockMetadata> => Plain,
+    },
+    check = BlockHeader::check_format
+);
+
+serde_struct!(BlockMetadata {
+    created: u64 => Plain,
+    created_by: String => Plain,
+});
+
+serde_struct!(BlockLayout {
+    header_size: u64 => Plain,
+    reserved_size: u64 => Plain,
+    entries: Vec<PlannedEntry> => Plain,
+    padding: u64 => Plain,
+    trailer_size: u64 => Plain,
+    block_size: u64 => Plain,
+    exact: bool => Plain,
+});
+
+serde_struct!(PlannedEntry {
+    id: u128 => Plain,
+    offset: u64 => Plain,
+    size: u64 => Plain,
+    location: Vec<u8> => Location,
+});
+
+serde_struct!(BlockDiff {
+    added: Vec<u128> => Plain,
+    removed: Vec<u128> => Plain,
+    changed_content: Vec<u128> => Plain,
+    changed_location: Vec<u128> => Plain,
+});
+
+#[cfg(not(target_arch = "wasm32"))]
+serde_struct!(CatalogEntry {
+    block_path: PathBuf => Plain,
+    id: u64 => Plain,
+});
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde::de::value::{Error, MapDeserializer};
+
+    fn from_pairs<'de, T: Deserialize<'de>>(pairs: &[(&'de str, &'de str)]) -> Result<T, Error> {
+        T::deserialize(MapDeserializer::new(pairs.iter().cloned()))
+    }
+
+    #[test]
+    fn should_deserialize_file_header() {
+        let header = from_pairs::<FileHeader>(&[
+            ("hash", "9a0364b9e99bb480dd25e1f0284c8555"),
+            ("location", "/file"),
+            ("unknown", "ignored"),
+        ])
+        .unwrap();
+        assert_eq!(header.hash, md5::compute("content"));
+        assert_eq!(header.location, b"/file");
+
+        let invalid = [("hash", "not a digest"), ("location", "/file")];
+        assert!(from_pairs::<FileHeader>(&invalid).is_err());
+        assert!(from_pairs::<FileHeader>(&[("location", "/file")]).is_err());
+    }
+}
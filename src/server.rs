@@ -0,0 +1,951 @@
+//! HTTP сервер содержимого блоков.
+//!
+//! [`FileServer`] отдает файлы набора блоков по `GET /<location>` (например,
+//! `GET /img/123.jpg`; location без начального `/` запрашивается так же). Блоки упорядочены
+//! по приоритету так же, как в [`MultiBlock`]. Сервер поддерживает запросы диапазонов
+//! (`Range`, ответ `206 Partial Content`) и условные запросы (`If-None-Match`, `If-Range`): ETag
+//! файла – его контрольная сумма из заголовка файла, поэтому сервер можно ставить за CDN или
+//! кеширующий прокси.
+//!
+//! Каждое соединение обслуживается в отдельном потоке и закрывается после ответа. Запросы
+//! можно записывать в журнал (см. [`FileServer::access_log`]). С feature `tls` сервер
+//! принимает соединения по TLS (см. [`FileServer::tls`]). Доступ к файлам можно ограничить
+//! токеном (см. [`FileServer::token`]).
+//!
+//! [`FileServer`]: struct.FileServer.html
+//! [`MultiBlock`]: ../multi_block/struct.MultiBlock.html
+//! [`FileServer::access_log`]: struct.FileServer.html#method.access_log
+//! [`FileServer::tls`]: struct.FileServer.html#method.tls
+//! [`FileServer::token`]: struct.FileServer.html#method.token
+use crate::block::Block;
+use crate::errors::*;
+use crate::location;
+use crate::manifest::write_string;
+use crate::multi_block::MultiBlock;
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Максимальная длина строки запроса и каждого из заголовков
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+/// Максимальное количество заголовков запроса
+const MAX_HEADERS: usize = 100;
+
+/// Отдает файлы блоков по HTTP (см. [`serve`])
+///
+/// [`serve`]: fn.serve.html
+pub struct FileServer {
+    blocks: MultiBlock,
+    /// Пути блоков в порядке приоритета
+    paths: Vec<PathBuf>,
+    access_log: Option<AccessLog>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    token: Option<String>,
+}
+
+/// Формат журнала запросов (см. [`FileServer::access_log`])
+///
+/// [`FileServer::access_log`]: struct.FileServer.html#method.access_log
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AccessLogFormat {
+    /// Common Log Format, дополненный путем блока, идентификатором файла и временем ответа в
+    /// секундах (`-`, если файл не найден):
+    ///
+    /// ```text
+    /// 127.0.0.1 - - [10/Oct/2024:13:55:36 +0000] "GET /a.jpg HTTP/1.1" 200 2326 "0001.block" 17 0.000125
+    /// ```
+    Common,
+
+    /// Один JSON-объект на строку с полями `time`, `remote`, `method`, `target`, `location`,
+    /// `block`, `id`, `status`, `bytes` и `latency_ms`. Поля, значение которых неизвестно
+    /// (например, `block` для ненайденного файла), равны `null`.
+    Json,
+}
+
+struct AccessLog {
+    format: AccessLogFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+/// Файл, который вернул сервер
+#[derive(Clone)]
+struct ServedFile {
+    /// Номер блока в порядке приоритета
+    block: usize,
+    id: u128,
+    location: Vec<u8>,
+}
+
+impl FileServer {
+    /// Создает сервер для блоков `blocks` с путями, по которым они были открыты. Блоки, стоящие
+    /// в списке раньше, перекрывают последующие (см. [`MultiBlock`]).
+    ///
+    /// [`MultiBlock`]: ../multi_block/struct.MultiBlock.html
+    pub fn new(blocks: Vec<(PathBuf, Block)>) -> Self {
+        let (paths, blocks) = blocks.into_iter().unzip();
+        Self {
+            blocks: MultiBlock::new(blocks),
+            paths,
+            access_log: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            token: None,
+        }
+    }
+
+    /// Отвечает `401 Unauthorized` на запросы без заголовка `Authorization: Bearer <token>`
+    pub fn token(&mut self, token: impl Into<String>) -> &mut Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Принимает соединения только по TLS с параметрами `config` (см. [`tls_config`])
+    ///
+    /// [`tls_config`]: fn.tls_config.html
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, config: Arc<rustls::ServerConfig>) -> &mut Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Записывает в `out` по строке на каждый запрос в формате `format`: адрес клиента, запрос,
+    /// найденный блок и идентификатор файла, статус ответа, количество отправленных байт
+    /// содержимого и время ответа.
+    pub fn access_log(
+        &mut self,
+        format: AccessLogFormat,
+        out: impl Write + Send + 'static,
+    ) -> &mut Self {
+        self.access_log = Some(AccessLog {
+            format,
+            out: Mutex::new(Box::new(out)),
+        });
+        self
+    }
+
+    /// Пути блоков в порядке приоритета
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    fn handle(&self, request: &Request) -> Response<'_> {
+        if let Some(token) = &self.token {
+            if !bearer_token_matches(request.header("Authorization"), token) {
+                let mut response = Response::text(401, "Unauthorized\n");
+                response.header("WWW-Authenticate", "Bearer");
+                return response;
+            }
+        }
+        let head = match request.method.as_str() {
+            "GET" => false,
+            "HEAD" => true,
+            _ => {
+                let mut response = Response::text(405, "Method not allowed\n");
+                response.header("Allow", "GET, HEAD");
+                return response;
+            }
+        };
+        let location = match decode_path(&request.target) {
+            Some(location) => location,
+            None => return Response::text(400, "Bad request\n"),
+        };
+        let (idx, id, location) = match self.resolve(&location) {
+            Ok(resolved) => resolved,
+            Err(Error::LocationNotFound(_)) | Err(Error::LocationHashCollision { .. }) => {
+                return Response::text(404, "Not found\n")
+            }
+            Err(_) => return Response::text(500, "Internal server error\n"),
+        };
+        let (header, content) = match self.blocks.blocks()[idx].file_by_location(location) {
+            Ok(file) => file,
+            Err(_) => return Response::text(500, "Internal server error\n"),
+        };
+
+        let etag = format!("\"{:x}\"", header.hash);
+        let size = content.len() as u64;
+        let mut response = match request.header("If-None-Match") {
+            Some(tags) if etag_matches(tags, &etag) => Response::empty(304),
+            _ => {
+                // Диапазон отдается, только если файл не изменился с ответа, в котором клиент
+                // получил ETag
+                let range = match request.header("If-Range") {
+                    Some(tag) if tag.trim() != etag => None,
+                    _ => request.header("Range"),
+                };
+                match byte_range(range, size) {
+                    ByteRange::Full => Response::new(200, content),
+                    ByteRange::Partial { offset, len } => {
+                        let range = offset as usize..(offset + len) as usize;
+                        let content = match content {
+                            Cow::Borrowed(content) => Cow::Borrowed(&content[range]),
+                            Cow::Owned(content) => Cow::Owned(content[range].to_vec()),
+                        };
+                        let mut response = Response::new(206, content);
+                        let last = offset + len - 1;
+                        let content_range = format!("bytes {}-{}/{}", offset, last, size);
+                        response.header("Content-Range", content_range);
+                        response
+                    }
+                    ByteRange::Unsatisfiable => {
+                        let mut response = Response::text(416, "Range not satisfiable\n");
+                        response.header("Content-Range", format!("bytes */{}", size));
+                        response
+                    }
+                }
+            }
+        };
+        response.header("ETag", etag);
+        response.header("Accept-Ranges", "bytes");
+        response.head = head;
+        response.file = Some(ServedFile {
+            block: idx,
+            id,
+            location: location.to_vec(),
+        });
+        response
+    }
+
+    /// Находит блок с файлом, запрошенным путем `path`, идентификатор и location этого файла.
+    /// Если файла с location `path` нет, ищется location без начального `/`, как у файлов,
+    /// добавленных в блок по относительным путям.
+    fn resolve<'a>(&self, path: &'a [u8]) -> Result<(usize, u128, &'a [u8])> {
+        let (location, (idx, info)) = match self.blocks.resolve_location(path) {
+            Err(Error::LocationNotFound(_)) => {
+                let relative = &path[1..];
+                (relative, self.blocks.resolve_location(relative)?)
+            }
+            result => (path, result?),
+        };
+        Ok((idx, info.wide_id(), location))
+    }
+
+    fn log(&self, entry: &LogEntry) {
+        let log = match self.access_log.as_ref() {
+            Some(log) => log,
+            None => return,
+        };
+        let block = |file: &ServedFile| self.paths[file.block].to_string_lossy().into_owned();
+        let mut line = String::new();
+        match log.format {
+            AccessLogFormat::Common => {
+                let request = match entry.request {
+                    Some(r) => format!("{} {} {}", r.method, r.target, r.version),
+                    None => String::from("-"),
+                };
+                let (block, id) = match entry.file {
+                    Some(file) => (format!("{:?}", block(file)), file.id.to_string()),
+                    None => (String::from("-"), String::from("-")),
+                };
+                writeln!(
+                    line,
+                    "{} - - [{}] {:?} {} {} {} {} {:.6}",
+                    entry
+                        .remote
+                        .map_or_else(|| String::from("-"), |ip| ip.to_string()),
+                    format_clf_time(entry.time),
+                    request,
+                    entry.status,
+                    entry.bytes,
+                    block,
+                    id,
+                    entry.latency.as_secs_f64()
+                )
+                .unwrap();
+            }
+            AccessLogFormat::Json => {
+                let string = |line: &mut String, value: Option<&str>| match value {
+                    Some(value) => write_string(line, value),
+                    None => line.push_str("null"),
+                };
+                line.push_str("{\"time\":");
+                write_string(&mut line, &format_rfc3339(entry.time));
+                line.push_str(",\"remote\":");
+                string(&mut line, entry.remote.map(|ip| ip.to_string()).as_deref());
+                line.push_str(",\"method\":");
+                string(&mut line, entry.request.map(|r| r.method.as_str()));
+                line.push_str(",\"target\":");
+                string(&mut line, entry.request.map(|r| r.target.as_str()));
+                line.push_str(",\"location\":");
+                let location = entry.file.map(|file| location::display(&file.location));
+                string(&mut line, location.as_deref());
+                line.push_str(",\"block\":");
+                string(&mut line, entry.file.map(block).as_deref());
+                line.push_str(",\"id\":");
+                match entry.file {
+                    Some(file) => write!(line, "{}", file.id).unwrap(),
+                    None => line.push_str("null"),
+                }
+                writeln!(
+                    line,
+                    ",\"status\":{},\"bytes\":{},\"latency_ms\":{:.3}}}",
+                    entry.status,
+                    entry.bytes,
+                    entry.latency.as_secs_f64() * 1000.0
+                )
+                .unwrap();
+            }
+        }
+        // Ошибка записи журнала не должна влиять на ответы
+        let _ = log.out.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+/// Запись журнала запросов
+struct LogEntry<'a> {
+    time: SystemTime,
+    remote: Option<IpAddr>,
+    /// `None`, если запрос не удалось разобрать
+    request: Option<&'a Request>,
+    file: Option<&'a ServedFile>,
+    status: u16,
+    /// Количество отправленных байт содержимого (без заголовков ответа)
+    bytes: u64,
+    latency: Duration,
+}
+
+/// Запускает HTTP сервер `server` на адресе `addr` в отдельном потоке. Сервер работает до
+/// завершения процесса.
+pub fn serve(server: Arc<FileServer>, addr: impl ToSocketAddrs) -> io::Result<JoinHandle<()>> {
+    Ok(serve_on(server, TcpListener::bind(addr)?))
+}
+
+/// Читает сертификат (вместе с цепочкой промежуточных сертификатов) и закрытый ключ сервера
+/// из PEM-файлов `cert_path` и `key_path` и возвращает параметры TLS для [`FileServer::tls`].
+///
+/// [`FileServer::tls`]: struct.FileServer.html#method.tls
+#[cfg(feature = "tls")]
+pub fn tls_config(cert_path: &Path, key_path: &Path) -> Result<Arc<rustls::ServerConfig>> {
+    let invalid = |e: &dyn std::fmt::Display| Error::InvalidTlsConfig(e.to_string());
+    let certs = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(cert_path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        let message = format!("no certificates in {}", cert_path.display());
+        return Err(Error::InvalidTlsConfig(message));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(std::fs::File::open(key_path)?))?
+        .ok_or_else(|| {
+            Error::InvalidTlsConfig(format!("no private key in {}", key_path.display()))
+        })?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(&e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(&e))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn serve_on(server: Arc<FileServer>, listener: TcpListener) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let server = Arc::clone(&server);
+            // Ошибка одного соединения не должна останавливать сервер
+            thread::spawn(move || {
+                let _ = accept(&server, stream);
+            });
+        }
+    })
+}
+
+fn accept(server: &FileServer, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let remote = stream.peer_addr().ok().map(|addr| addr.ip());
+    #[cfg(feature = "tls")]
+    if let Some(config) = server.tls.as_ref() {
+        let connection =
+            rustls::ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+        let mut stream = rustls::StreamOwned::new(connection, stream);
+        respond(server, &mut stream, remote)?;
+        stream.conn.send_close_notify();
+        return stream.flush();
+    }
+    respond(server, &mut stream, remote)
+}
+
+fn respond(
+    server: &FileServer,
+    stream: &mut (impl Read + Write),
+    remote: Option<IpAddr>,
+) -> io::Result<()> {
+    let request = match read_request(&mut BufReader::new(&mut *stream))? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let (time, start) = (SystemTime::now(), Instant::now());
+    let response = match request.as_ref() {
+        Ok(request) => server.handle(request),
+        Err(response) => response.clone(),
+    };
+    let sent = response.write_to(stream).and_then(|sent| {
+        stream.flush()?;
+        Ok(sent)
+    });
+    server.log(&LogEntry {
+        time,
+        remote,
+        request: request.as_ref().ok(),
+        file: response.file.as_ref(),
+        status: response.status,
+        bytes: *sent.as_ref().unwrap_or(&0),
+        latency: start.elapsed(),
+    });
+    sent.map(|_| ())
+}
+
+/// Запрос клиента
+struct Request {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Значение заголовка `name` (без учета регистра)
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Читает запрос. Возвращает `None`, если клиент закрыл соединение, не отправив запрос, и
+/// ответ с ошибкой, если запрос не удалось разобрать.
+fn read_request(
+    reader: &mut impl BufRead,
+) -> io::Result<Option<std::result::Result<Request, Response<'static>>>> {
+    let request_line = match read_line(reader)? {
+        Some(line) if !line.is_empty() => line,
+        Some(_) | None => return Ok(None),
+    };
+    let mut headers = vec![];
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Ok(Some(Err(Response::text(431, "Too many headers\n"))));
+        }
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name.to_string(), value.trim().to_string())),
+            None => return Ok(Some(Err(Response::text(400, "Bad request\n")))),
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            Ok(Some(Ok(Request {
+                method: method.to_string(),
+                target: target.to_string(),
+                version: version.to_string(),
+                headers,
+            })))
+        }
+        _ => Ok(Some(Err(Response::text(400, "Bad request\n")))),
+    }
+}
+
+/// Читает строку запроса без завершающего `\r\n`. Возвращает `None` в конце потока, а слишком
+/// длинные строки считает ошибкой.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = vec![];
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request line is too long or incomplete",
+        ));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Location, запрошенный путем `target`: путь без параметров запроса с декодированными
+/// `%XX`-последовательностями. Возвращает `None`, если путь некорректен.
+fn decode_path(target: &str) -> Option<Vec<u8>> {
+    let path = target.split('?').next().unwrap_or_default();
+    if !path.starts_with('/') {
+        return None;
+    }
+    let mut location = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            location.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            location.push(byte);
+        }
+    }
+    Some(location)
+}
+
+/// Совпадает ли ETag `etag` с одним из значений заголовка `If-None-Match` (слабое сравнение)
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    tags.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Содержит ли значение заголовка `Authorization` токен `token` (схема `Bearer`). Токены
+/// сравниваются за время, не зависящее от совпадающего префикса.
+pub(crate) fn bearer_token_matches(authorization: Option<&str>, token: &str) -> bool {
+    let credentials = match authorization.map(str::trim).and_then(|a| a.split_once(' ')) {
+        Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("Bearer") => credentials,
+        _ => return false,
+    };
+    let (credentials, token) = (credentials.trim().as_bytes(), token.as_bytes());
+    credentials.len() == token.len()
+        && credentials
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Часть содержимого файла, запрошенная заголовком `Range`
+#[derive(Debug, Eq, PartialEq)]
+enum ByteRange {
+    /// Файл целиком: диапазон не запрошен, или запрос не поддерживается
+    Full,
+
+    Partial {
+        offset: u64,
+        len: u64,
+    },
+
+    /// Диапазон не пересекается с содержимым файла размером `size`
+    Unsatisfiable,
+}
+
+/// Разбирает значение заголовка `Range` для файла размером `size`. Поддерживается один
+/// диапазон в байтах: `bytes=A-B`, `bytes=A-` или `bytes=-N` (последние `N` байт). Запросы
+/// других видов, в том числе нескольких диапазонов, игнорируются, как допускает RFC 9110.
+fn byte_range(range: Option<&str>, size: u64) -> ByteRange {
+    let spec = match range.and_then(|range| range.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+    let parse = |value: &str| value.parse::<u64>().ok();
+    let (offset, end) = match (first, last) {
+        ("", suffix) => match parse(suffix) {
+            Some(0) => return ByteRange::Unsatisfiable,
+            Some(len) => (size.saturating_sub(len), size),
+            None => return ByteRange::Full,
+        },
+        (first, "") => match parse(first) {
+            Some(first) => (first, size),
+            None => return ByteRange::Full,
+        },
+        (first, last) => match (parse(first), parse(last)) {
+            (Some(first), Some(last)) if first <= last => (first, size.min(last + 1)),
+            _ => return ByteRange::Full,
+        },
+    };
+    if offset >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial {
+        offset,
+        len: end - offset,
+    }
+}
+
+/// Ответ сервера
+#[derive(Clone)]
+struct Response<'a> {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Cow<'a, [u8]>,
+    /// Ответ на `HEAD`: заголовки отправляются, а тело – нет
+    head: bool,
+    /// Файл, содержимое (или заголовки) которого возвращается
+    file: Option<ServedFile>,
+}
+
+impl<'a> Response<'a> {
+    fn new(status: u16, body: Cow<'a, [u8]>) -> Self {
+        let mut response = Self {
+            status,
+            headers: vec![],
+            body,
+            head: false,
+            file: None,
+        };
+        response.header("Content-Type", "application/octet-stream");
+        response
+    }
+
+    fn text(status: u16, text: &'static str) -> Self {
+        let mut response = Self::new(status, Cow::Borrowed(text.as_bytes()));
+        response.headers.clear();
+        response.header("Content-Type", "text/plain; charset=utf-8");
+        response
+    }
+
+    fn empty(status: u16) -> Self {
+        let mut response = Self::new(status, Cow::Borrowed(&[]));
+        response.headers.clear();
+        response
+    }
+
+    fn header(&mut self, name: &'static str, value: impl Into<String>) {
+        self.headers.push((name, value.into()));
+    }
+
+    /// Отправляет ответ и возвращает количество отправленных байт тела
+    fn write_to(&self, out: &mut impl Write) -> io::Result<u64> {
+        write!(out, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        for (name, value) in &self.headers {
+            write!(out, "{}: {}\r\n", name, value)?;
+        }
+        // У ответа 304 нет тела, а Content-Length ответа на HEAD – длина тела ответа на GET
+        if self.status != 304 {
+            write!(out, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        write!(out, "Connection: close\r\n\r\n")?;
+        if self.head {
+            return Ok(0);
+        }
+        out.write_all(&self.body)?;
+        Ok(self.body.len() as u64)
+    }
+}
+
+/// Дата григорианского календаря (год, месяц, день) и секунды от начала дня UTC для времени
+/// `time` (алгоритм civil_from_days)
+fn civil_time(time: SystemTime) -> (i64, i64, i64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day, secs % 86400)
+}
+
+/// Время в формате Common Log Format: `10/Oct/2024:13:55:36 +0000`
+fn format_clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, secs) = civil_time(time);
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Время в формате RFC 3339: `2024-10-10T13:55:36Z`
+fn format_rfc3339(time: SystemTime) -> String {
+    let (year, month, day, secs) = civil_time(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::builder::BlockBuilder;
+    use std::net::SocketAddr;
+
+    fn request(addr: SocketAddr, request: &str) -> io::Result<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn should_serve_files_by_location() -> Result<()> {
+        let mut builder = BlockBuilder::new();
+        builder.add(1, "/a b.txt", "Hello, world");
+        let first = builder.build()?;
+        let mut builder = BlockBuilder::new();
+        builder.add(1, "/a b.txt", "overridden").add(2, "c.txt", "");
+        let second = builder.build()?;
+        let etag = format!("\"{:x}\"", md5::compute("Hello, world"));
+        let server = FileServer::new(vec![
+            (PathBuf::from("first.block"), first),
+            (PathBuf::from("second.block"), second),
+        ]);
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        serve_on(Arc::new(server), listener);
+        let get = |path: &str, headers: &str| {
+            request(addr, &format!("GET {} HTTP/1.1\r\n{}\r\n", path, headers))
+        };
+
+        let response = get("/a%20b.txt?v=1", "")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("ETag: {}\r\n", etag)));
+        assert!(response.ends_with("\r\n\r\nHello, world"));
+
+        let response = get("/a%20b.txt", "Range: bytes=7-\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("Content-Range: bytes 7-11/12\r\n"));
+        assert!(response.ends_with("\r\n\r\nworld"));
+
+        let response = get("/a%20b.txt", "Range: bytes=12-\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(response.contains("Content-Range: bytes */12\r\n"));
+
+        // Диапазон к измененному файлу не применяется
+        let response = get("/a%20b.txt", "Range: bytes=0-4\r\nIf-Range: \"other\"\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let response = get("/a%20b.txt", &format!("If-None-Match: \"x\", {}\r\n", etag))?;
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        let response = request(addr, "HEAD /c.txt HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 0\r\n"));
+
+        assert!(get("/missing", "")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get("missing", "")?.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let response = request(addr, "DELETE /c.txt HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        Ok(())
+    }
+
+    /// Журнал, содержимое которого доступно тесту
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedLog {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    #[test]
+    fn should_write_access_log() -> Result<()> {
+        let mut builder = BlockBuilder::new();
+        builder.add(7, "/a.txt", "Hello, world");
+        let mut server = FileServer::new(vec![(PathBuf::from("a.block"), builder.build()?)]);
+        let log = SharedLog::default();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        server.access_log(AccessLogFormat::Common, log.clone());
+        serve_on(Arc::new(server), listener);
+
+        request(addr, "GET /a.txt HTTP/1.1\r\nRange: bytes=0-4\r\n\r\n")?;
+        request(addr, "GET /b.txt HTTP/1.1\r\n\r\n")?;
+        request(addr, "HEAD /a.txt HTTP/1.1\r\n\r\n")?;
+        let log = log.take();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{}", log);
+        assert!(lines[0].starts_with("127.0.0.1 - - ["));
+        assert!(lines[0].contains("] \"GET /a.txt HTTP/1.1\" 206 5 \"a.block\" 7 "));
+        assert!(lines[1].contains("] \"GET /b.txt HTTP/1.1\" 404 10 - - "));
+        assert!(lines[2].contains("] \"HEAD /a.txt HTTP/1.1\" 200 0 \"a.block\" 7 "));
+
+        let mut builder = BlockBuilder::new();
+        builder.add(7, "/a.txt", "Hello, world");
+        let mut server = FileServer::new(vec![(PathBuf::from("a.block"), builder.build()?)]);
+        let log = SharedLog::default();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        server.access_log(AccessLogFormat::Json, log.clone());
+        serve_on(Arc::new(server), listener);
+
+        request(addr, "GET /a.txt HTTP/1.1\r\n\r\n")?;
+        request(addr, "GET /b.txt HTTP/1.1\r\n\r\n")?;
+        let log = log.take();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2, "{}", log);
+        assert!(lines[0].contains(
+            "\"remote\":\"127.0.0.1\",\"method\":\"GET\",\"target\":\"/a.txt\",\
+             \"location\":\"/a.txt\",\"block\":\"a.block\",\"id\":7,\"status\":200,\"bytes\":12,"
+        ));
+        assert!(lines[1].contains("\"location\":null,\"block\":null,\"id\":null,\"status\":404,"));
+        Ok(())
+    }
+
+    #[test]
+    fn should_require_bearer_token() -> Result<()> {
+        let mut builder = BlockBuilder::new();
+        builder.add(1, "/a.txt", "Hello, world");
+        let mut server = FileServer::new(vec![(PathBuf::from("a.block"), builder.build()?)]);
+        server.token("secret");
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        serve_on(Arc::new(server), listener);
+
+        let response = request(addr, "GET /a.txt HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(response.contains("WWW-Authenticate: Bearer\r\n"));
+        let response = request(
+            addr,
+            "GET /a.txt HTTP/1.1\r\nAuthorization: Bearer other\r\n\r\n",
+        )?;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        let response = request(
+            addr,
+            "GET /a.txt HTTP/1.1\r\nAuthorization: bearer secret\r\n\r\n",
+        )?;
+        assert!(response.ends_with("\r\n\r\nHello, world"));
+        Ok(())
+    }
+
+    #[test]
+    fn should_match_bearer_tokens() {
+        assert!(bearer_token_matches(Some("Bearer secret"), "secret"));
+        assert!(bearer_token_matches(Some(" BEARER  secret "), "secret"));
+        assert!(!bearer_token_matches(Some("Bearer secre"), "secret"));
+        assert!(!bearer_token_matches(Some("Bearer secret2"), "secret"));
+        assert!(!bearer_token_matches(Some("Basic secret"), "secret"));
+        assert!(!bearer_token_matches(Some("secret"), "secret"));
+        assert!(!bearer_token_matches(None, "secret"));
+    }
+
+    #[test]
+    fn should_format_log_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_728_568_536);
+        assert_eq!(format_clf_time(time), "10/Oct/2024:13:55:36 +0000");
+        assert_eq!(format_rfc3339(time), "2024-10-10T13:55:36Z");
+    }
+
+    #[test]
+    fn should_parse_byte_ranges() {
+        let partial = |offset, len| ByteRange::Partial { offset, len };
+        assert_eq!(byte_range(None, 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=2-4"), 10), partial(2, 3));
+        assert_eq!(byte_range(Some("bytes=2-"), 10), partial(2, 8));
+        assert_eq!(byte_range(Some("bytes=5-100"), 10), partial(5, 5));
+        assert_eq!(byte_range(Some("bytes=-3"), 10), partial(7, 3));
+        assert_eq!(byte_range(Some("bytes=-30"), 10), partial(0, 10));
+        assert_eq!(byte_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=-0"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=4-2"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=0-1,3-4"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("items=0-1"), 10), ByteRange::Full);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn should_serve_files_over_tls() -> Result<()> {
+        use rustls::pki_types::ServerName;
+        use std::convert::TryFrom;
+
+        let mut builder = BlockBuilder::new();
+        builder.add(1, "/a.txt", "Hello, world");
+        let mut server = FileServer::new(vec![(PathBuf::from("a.block"), builder.build()?)]);
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/tls");
+        let config = tls_config(&fixtures.join("server.pem"), &fixtures.join("server.key"))?;
+        server.tls(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        serve_on(Arc::new(server), listener);
+
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &include_bytes!("../fixtures/tls/ca.pem")[..]) {
+            roots.add(cert?).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = rustls::ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut stream = rustls::StreamOwned::new(connection, TcpStream::connect(addr)?);
+        stream.write_all(b"GET /a.txt HTTP/1.1\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nHello, world"));
+
+        // Без TLS сервер не отвечает
+        assert!(!request(addr, "GET /a.txt HTTP/1.1\r\n\r\n")
+            .unwrap_or_default()
+            .starts_with("HTTP/1.1"));
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn should_reject_invalid_tls_config() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/tls");
+        let result = tls_config(&fixtures.join("ca.pem"), &fixtures.join("ca.pem"));
+        assert!(matches!(result, Err(Error::InvalidTlsConfig(_))));
+    }
+}
@@ -0,0 +1,239 @@
+//! Подпись блоков Ed25519.
+//!
+//! Подпись дописывается в конец уже созданного блока (см. [`sign`]), так что блоки можно
+//! подписывать в системе сборки, не пересоздавая их. Подписывается сообщение
+//!
+//! ```text
+//! | "blocky-signature-v1" | SHA-256 заголовка (32 байта) | корень дерева Меркла (32 байта) |
+//! ```
+//!
+//! Заголовок хешируется вместе с блоком метаинформации, а листьями дерева Меркла служат SHA-256
+//! location и содержимого файлов в порядке записей заголовка (см. [`content_root`]). Поэтому
+//! подпись удостоверяет и метаинформацию, и содержимое всех файлов блока. Контрольные суммы MD5 из
+//! заголовков файлов для этого не подходят: для MD5 известны способы построения коллизий.
+//!
+//! Подпись хранится после всех остальных структур блока вместе со своей MD5 и сигнатурой `BSIG`
+//! и при чтении блока пропускается, поэтому подписанный блок читается и версиями библиотеки без
+//! поддержки подписей.
+//!
+//! Ключи хранятся в файлах в виде 32 байт: секретный ключ – seed Ed25519, публичный – сжатая
+//! точка кривой (см. [`generate_key`]).
+//!
+//! [`sign`]: fn.sign.html
+//! [`content_root`]: fn.content_root.html
+//! [`generate_key`]: fn.generate_key.html
+use crate::block::{encode_signature, Block};
+use crate::errors::*;
+use ed25519_dalek::{Signature, Signer, Verifier};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Префикс подписываемого сообщения, отличающий его от сообщений других форматов
+const MESSAGE_PREFIX: &[u8] = b"blocky-signature-v1";
+
+/// Создает новый секретный ключ
+pub fn generate_key() -> SigningKey {
+    SigningKey::generate(&mut rand_core::OsRng)
+}
+
+/// Читает секретный ключ из файла
+pub fn read_signing_key(path: impl AsRef<Path>) -> Result<SigningKey> {
+    Ok(SigningKey::from_bytes(&read_key_bytes(path.as_ref())?))
+}
+
+/// Читает публичный ключ из файла
+pub fn read_verifying_key(path: impl AsRef<Path>) -> Result<VerifyingKey> {
+    let bytes = read_key_bytes(path.as_ref())?;
+    VerifyingKey::from_bytes(&bytes)
+        .map_err(|e| Error::InvalidKey(format!("{}: {}", path.as_ref().display(), e)))
+}
+
+fn read_key_bytes(path: &Path) -> Result<[u8; 32]> {
+    let bytes = fs::read(path)?;
+    <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| {
+        Error::InvalidKey(format!(
+            "{}: expected 32 bytes, got {}",
+            path.display(),
+            bytes.len()
+        ))
+    })
+}
+
+/// Подписывает блок `block_path` ключом `key`, дописывая подпись в конец блока. Существующая
+/// подпись заменяется.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn sign(block_path: impl AsRef<Path>, key: &SigningKey) -> Result<()> {
+    let block_path = block_path.as_ref();
+    let block = Block::open(block_path)?;
+    let signature = key.sign(&signed_message(&block)?);
+    let unsigned_len = block.unsigned_len() as u64;
+    // Блок отображен в память, поэтому его нельзя обрезать, пока он открыт
+    drop(block);
+
+    let mut file = OpenOptions::new().write(true).open(block_path)?;
+    file.set_len(unsigned_len)?;
+    file.seek(SeekFrom::Start(unsigned_len))?;
+    file.write_all(&encode_signature(&signature.to_bytes()))?;
+    file.sync_all()?;
+    Ok(())
+}
+
+impl Block {
+    /// Проверяет, что блок подписан ключом, соответствующим публичному ключу `key`, и что ни
+    /// заголовок, ни содержимое файлов не изменились после подписания.
+    ///
+    /// Для проверки читается содержимое всех файлов блока. Возвращает
+    /// [`Error::SignatureMissing`], если блок не подписан, и [`Error::SignatureInvalid`], если
+    /// подпись не совпадает.
+    ///
+    /// [`Error::SignatureMissing`]: ../errors/enum.Error.html#variant.SignatureMissing
+    /// [`Error::SignatureInvalid`]: ../errors/enum.Error.html#variant.SignatureInvalid
+    pub fn verify_signature(&self, key: &VerifyingKey) -> Result<()> {
+        let signature = self.signature().ok_or(Error::SignatureMissing)?;
+        let signature = Signature::from_bytes(signature);
+        key.verify(&signed_message(self)?, &signature)
+            .map_err(|_| Error::SignatureInvalid)
+    }
+}
+
+/// Формирует подписываемое сообщение для блока
+fn signed_message(block: &Block) -> Result<Vec<u8>> {
+    let mut header = vec![];
+    block.header().encode_uncompressed(&mut header)?;
+
+    let mut message = MESSAGE_PREFIX.to_vec();
+    message.extend_from_slice(&Sha256::digest(&header));
+    message.extend_from_slice(&content_root(block)?);
+    Ok(message)
+}
+
+/// Вычисляет корень дерева Меркла над SHA-256 файлов блока. Лист дерева – хеш длины location
+/// (2 байта), location и содержимого файла.
+///
+/// Листья и внутренние узлы хешируются с разными префиксами (`0x00` и `0x01`), чтобы внутренний
+/// узел нельзя было выдать за лист. Узел без пары переносится на следующий уровень без
+/// изменений. Корень блока без файлов – SHA-256 пустой строки.
+pub fn content_root(block: &Block) -> Result<[u8; 32]> {
+    let mut level = (0..block.len())
+        .map(|idx| {
+            let (header, content) = block.file_at(idx)?;
+            let location = &header.location[..];
+            let location_len = (location.len() as u16).to_le_bytes();
+            Ok(hash_node(0x00, &[&location_len, location, &content]))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if level.is_empty() {
+        return Ok(Sha256::digest(b"").into());
+    }
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => hash_node(0x01, &[left, right]),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    Ok(level[0])
+}
+
+fn hash_node(prefix: u8, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([prefix]);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::{AddFileRequest, BlockOptions, FILE_HEADER_FIXED_SIZE};
+
+    fn create_block(dir: &Path, header_trailer: bool) -> Result<std::path::PathBuf> {
+        let paths = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                fs::write(&path, format!("content of {}", name)).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let requests = paths
+            .iter()
+            .enumerate()
+            .map(|(idx, path)| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location: path,
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+
+        let block_path = dir.join(format!("{}.block", header_trailer));
+        BlockOptions::new()
+            .header_trailer(header_trailer)
+            .create(&block_path, &requests)?;
+        Ok(block_path)
+    }
+
+    #[test]
+    fn should_sign_and_verify_block() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-signature-test")?;
+        let key = generate_key();
+        for header_trailer in [false, true].iter() {
+            let block_path = create_block(tmp.path(), *header_trailer)?;
+            match Block::open(&block_path)?.verify_signature(&key.verifying_key()) {
+                Err(Error::SignatureMissing) => {}
+                r => panic!("SignatureMissing expected, got: {:?}", r),
+            }
+
+            sign(&block_path, &key)?;
+            // Повторная подпись заменяет существующую
+            sign(&block_path, &key)?;
+            let block = Block::open(&block_path)?;
+            assert!(block.is_signed());
+            assert!(!block.needs_repair());
+            assert_eq!(block.file_by_id(3)?.1, &b"content of c"[..]);
+            block.verify_signature(&key.verifying_key())?;
+
+            match block.verify_signature(&generate_key().verifying_key()) {
+                Err(Error::SignatureInvalid) => {}
+                r => panic!("SignatureInvalid expected, got: {:?}", r),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_detect_tampered_content() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-signature-test")?;
+        let key = generate_key();
+        let block_path = create_block(tmp.path(), false)?;
+        sign(&block_path, &key)?;
+
+        let block = Block::open(&block_path)?;
+        let offset = block.iter().nth(1).unwrap().offset as usize;
+        let content_offset =
+            offset + FILE_HEADER_FIXED_SIZE as usize + block.file_at(1)?.0.location.len();
+        drop(block);
+        let mut bytes = fs::read(&block_path)?;
+        bytes[content_offset] = b'C';
+        fs::write(&block_path, &bytes)?;
+
+        match Block::open(&block_path)?.verify_signature(&key.verifying_key()) {
+            Err(Error::SignatureInvalid) => {}
+            r => panic!("SignatureInvalid expected, got: {:?}", r),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! Абстракция над источником байт блока.
+//!
+//! Позволяет декодировать заголовок блока не только из отображенного в память файла, но и из
+//! буфера в памяти или из источника, поддерживающего чтение диапазонов (например, `fetch` с
+//! заголовком `Range` в браузере). Благодаря этому разбор метаинформации блока не зависит от
+//! `memmap` и `File` и доступен на wasm32.
+//!
+//! Содержимое открытого блока хранится в [`BlockStorage`], так что [`Block`] не зависит от
+//! того, отображен ли файл блока в память, прочитан в буфер или читается с диска по мере
+//! обращения к файлам (`pread`).
+//!
+//! [`BlockStorage`]: trait.BlockStorage.html
+//! [`Block`]: ../block/struct.Block.html
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read};
+
+/// Источник, из которого можно прочитать произвольный диапазон байт блока
+pub trait RangeRead {
+    /// Размер источника в байтах
+    fn size(&self) -> io::Result<u64>;
+
+    /// Заполняет `buf` байтами начиная со смещения `offset`.
+    ///
+    /// Если источник заканчивается раньше, чем буфер будет заполнен, возвращается ошибка
+    /// [`ErrorKind::UnexpectedEof`].
+    ///
+    /// [`ErrorKind::UnexpectedEof`]: https://doc.rust-lang.org/std/io/enum.ErrorKind.html
+    fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+impl RangeRead for [u8] {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.copy_from_slice(slice_range(self, offset, buf.len())?);
+        Ok(())
+    }
+}
+
+/// Возвращает `len` байт `bytes` начиная со смещения `offset`
+fn slice_range(bytes: &[u8], offset: u64, len: usize) -> io::Result<&[u8]> {
+    let start = usize::try_from(offset).ok();
+    let end = start.and_then(|start| start.checked_add(len));
+    match (start, end) {
+        (Some(start), Some(end)) if end <= bytes.len() => Ok(&bytes[start..end]),
+        _ => Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "Range is out of source bounds",
+        )),
+    }
+}
+
+impl RangeRead for Vec<u8> {
+    fn size(&self) -> io::Result<u64> {
+        self.as_slice().size()
+    }
+
+    fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.as_slice().read_range(offset, buf)
+    }
+}
+
+impl<R: RangeRead + ?Sized> RangeRead for &R {
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_range(offset, buf)
+    }
+}
+
+#[cfg(unix)]
+impl RangeRead for std::fs::File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.read_exact_at(buf, offset)
+    }
+}
+
+/// Хранилище содержимого блока с произвольным доступом, поверх которого работает [`Block`].
+///
+/// [`Block`] читает заголовок блока и файлы диапазонами ([`read_at`]), поэтому хранилищу
+/// достаточно уметь читать произвольный диапазон байт ([`RangeRead`]). Хранилища, содержимое
+/// которых находится в памяти, дополнительно предоставляют его целиком ([`bytes`]), и тогда
+/// диапазоны, а значит и содержимое файлов, возвращаются без копирования.
+///
+/// Реализации есть для буфера в памяти (`Vec<u8>`), отображенного в память файла
+/// (`memmap::Mmap`) и файла, диапазоны которого читаются с диска по мере обращения (`File`,
+/// только Unix, см. [`BlockOpenOptions::pread`]).
+///
+/// [`Block`]: ../block/struct.Block.html
+/// [`read_at`]: #method.read_at
+/// [`bytes`]: #method.bytes
+/// [`RangeRead`]: trait.RangeRead.html
+/// [`BlockOpenOptions::pread`]: ../block/struct.BlockOpenOptions.html#method.pread
+pub trait BlockStorage: RangeRead + Send + Sync {
+    /// Содержимое блока целиком, если оно находится в памяти
+    fn bytes(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Возвращает `len` байт блока начиная со смещения `offset`. Если содержимое блока
+    /// находится в памяти ([`bytes`]), то диапазон возвращается без копирования.
+    ///
+    /// Если диапазон выходит за границы блока, возвращается ошибка
+    /// [`ErrorKind::UnexpectedEof`].
+    ///
+    /// [`bytes`]: #method.bytes
+    /// [`ErrorKind::UnexpectedEof`]: https://doc.rust-lang.org/std/io/enum.ErrorKind.html
+    fn read_at(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        if let Some(bytes) = self.bytes() {
+            return slice_range(bytes, offset, len).map(Cow::Borrowed);
+        }
+        let mut buf = vec![0; len];
+        self.read_range(offset, &mut buf)?;
+        Ok(Cow::Owned(buf))
+    }
+}
+
+impl BlockStorage for Vec<u8> {
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+impl BlockStorage for &'static [u8] {
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl RangeRead for memmap::Mmap {
+    fn size(&self) -> io::Result<u64> {
+        self[..].size()
+    }
+
+    fn read_range(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self[..].read_range(offset, buf)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl BlockStorage for memmap::Mmap {
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+/// Файл, диапазоны которого читаются с диска при каждом обращении (`pread`). В отличии от
+/// отображения в память не занимает адресное пространство процесса, а ошибки чтения
+/// возвращаются как ошибки, а не сигналом `SIGBUS`
+#[cfg(unix)]
+impl BlockStorage for std::fs::File {}
+
+/// Адаптер, позволяющий последовательно читать [`RangeRead`] через `std::io::Read`.
+///
+/// Каждый вызов `read` превращается в отдельное чтение диапазона, поэтому для источников с
+/// дорогим доступом (сеть) адаптер стоит оборачивать в `BufReader`. Размер источника
+/// запрашивается один раз при создании адаптера.
+///
+/// [`RangeRead`]: trait.RangeRead.html
+pub struct RangeReader<'a, R: RangeRead + ?Sized> {
+    source: &'a R,
+    position: u64,
+    size: u64,
+}
+
+impl<'a, R: RangeRead + ?Sized> RangeReader<'a, R> {
+    pub fn new(source: &'a R) -> io::Result<Self> {
+        Ok(Self {
+            source,
+            position: 0,
+            size: source.size()?,
+        })
+    }
+}
+
+impl<'a, R: RangeRead + ?Sized> Read for RangeReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.position);
+        let len = remaining.min(buf.len() as u64) as usize;
+        self.source.read_range(self.position, &mut buf[..len])?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn file_storage_should_read_ranges_from_disk() -> io::Result<()> {
+        let tmp = tempdir::TempDir::new("rust-storage-test")?;
+        let path = tmp.path().join("data");
+        std::fs::write(&path, b"0123456789")?;
+        let file = std::fs::File::open(&path)?;
+
+        assert!(BlockStorage::bytes(&file).is_none());
+        assert_eq!(file.read_at(2, 3)?, &b"234"[..]);
+        assert_eq!(file.read_at(10, 0)?, &b""[..]);
+        let error = file.read_at(8, 3).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_storage_should_return_ranges_without_copying() -> io::Result<()> {
+        let data = b"0123456789".to_vec();
+        assert!(matches!(data.read_at(2, 3)?, Cow::Borrowed(b"234")));
+        let error = data.read_at(u64::MAX, 1).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+}
@@ -0,0 +1,768 @@
+//! Экспериментальное чтение файлов блока через io_uring (Linux).
+//!
+//! В отличии от [`Block`] блок не отображается в память и не читается целиком: [`UringBlockReader`]
+//! читает только заголовок блока, а содержимое файлов – позиционными чтениями, которые
+//! отправляются в ядро пачкой одним системным вызовом. Это снижает накладные расходы на
+//! системные вызовы при большом количестве одновременных запросов.
+//!
+//! Заголовки файлов ([`FileHeader`]) запоминаются после первого чтения, поэтому повторные
+//! запросы к тем же файлам требуют одного обращения к ядру, а первые – трех (фиксированная
+//! часть заголовка файла, location, содержимое).
+//!
+//! Поддерживаются только несжатые и незашифрованные блоки. Требуется ядро 5.6 или новее.
+//!
+//! [`Block`]: ../block/struct.Block.html
+//! [`UringBlockReader`]: struct.UringBlockReader.html
+//! [`FileHeader`]: ../block/struct.FileHeader.html
+use crate::block::{BlockHeader, FileHeader, FileInfo, FILE_HEADER_FIXED_SIZE};
+use crate::errors::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Читает файлы блока пачками через io_uring (см. [`BlockOpenOptions::open_uring`])
+///
+/// [`BlockOpenOptions::open_uring`]: ../block/struct.BlockOpenOptions.html#method.open_uring
+pub struct UringBlockReader {
+    file: File,
+    header: BlockHeader,
+    ring: Ring,
+    verify_on_read: bool,
+    max_location_len: u16,
+    /// Номера записей заголовка, упорядоченные по идентификатору
+    id_index: Vec<u32>,
+    /// Прочитанные заголовки файлов и смещения их содержимого по идентификатору файла
+    file_headers: HashMap<u128, (FileHeader, u64)>,
+}
+
+/// Запрос на чтение содержимого файла: идентификатор, смещение и длина (`None` – файл целиком)
+type ContentRead = (u128, u64, Option<u64>);
+
+impl UringBlockReader {
+    pub(crate) fn new(
+        path: &Path,
+        header: BlockHeader,
+        verify_on_read: bool,
+        max_location_len: u16,
+        queue_depth: u32,
+    ) -> Result<Self> {
+        if header.is_compressed() || header.is_encrypted() {
+            return Err(Error::UnsupportedFeature(
+                "io_uring reads of compressed or encrypted blocks".into(),
+            ));
+        }
+        let file_info = header.file_info();
+        let mut id_index = (0..file_info.len() as u32).collect::<Vec<_>>();
+        // Стабильная сортировка сохраняет порядок записей с одинаковыми идентификаторами
+        id_index.sort_by_key(|&idx| file_info[idx as usize].wide_id());
+        Ok(Self {
+            file: File::open(path)?,
+            header,
+            ring: Ring::new(queue_depth)?,
+            verify_on_read,
+            max_location_len,
+            id_index,
+            file_headers: HashMap::new(),
+        })
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    /// Читает файлы с идентификаторами `ids` и возвращает их заголовки и содержимое в том же
+    /// порядке. Аналог [`Block::file_by_id`] для нескольких файлов.
+    ///
+    /// [`Block::file_by_id`]: ../block/struct.Block.html#method.file_by_id
+    pub fn read_files(&mut self, ids: &[u64]) -> Vec<Result<(FileHeader, Vec<u8>)>> {
+        let ids = ids.iter().map(|&id| u128::from(id)).collect::<Vec<_>>();
+        self.read_files_wide(&ids)
+    }
+
+    /// Аналог [`read_files`] для 128-битных идентификаторов (см. [`FileInfo::wide_id`])
+    ///
+    /// [`read_files`]: #method.read_files
+    /// [`FileInfo::wide_id`]: ../block/struct.FileInfo.html#method.wide_id
+    pub fn read_files_wide(&mut self, ids: &[u128]) -> Vec<Result<(FileHeader, Vec<u8>)>> {
+        let reads = ids.iter().map(|&id| (id, 0, None)).collect::<Vec<_>>();
+        self.read_contents(&reads)
+            .into_iter()
+            .zip(ids.iter())
+            .map(|(result, id)| {
+                let content = result?;
+                let (header, _) = &self.file_headers[id];
+                if self.verify_on_read && md5::compute(&content) != header.hash {
+                    return Err(Error::ChecksumMismatch { id: *id });
+                }
+                Ok((header.clone(), content))
+            })
+            .collect()
+    }
+
+    /// Читает диапазоны содержимого файлов, заданные идентификатором файла, смещением и длиной.
+    /// Аналог [`Block::read_range`] для нескольких диапазонов.
+    ///
+    /// [`Block::read_range`]: ../block/struct.Block.html#method.read_range
+    pub fn read_ranges(&mut self, ranges: &[(u64, u64, u64)]) -> Vec<Result<Vec<u8>>> {
+        let ranges = ranges
+            .iter()
+            .map(|&(id, offset, len)| (u128::from(id), offset, len))
+            .collect::<Vec<_>>();
+        self.read_ranges_wide(&ranges)
+    }
+
+    /// Аналог [`read_ranges`] для 128-битных идентификаторов
+    ///
+    /// [`read_ranges`]: #method.read_ranges
+    pub fn read_ranges_wide(&mut self, ranges: &[(u128, u64, u64)]) -> Vec<Result<Vec<u8>>> {
+        let reads = ranges
+            .iter()
+            .map(|&(id, offset, len)| (id, offset, Some(len)))
+            .collect::<Vec<_>>();
+        self.read_contents(&reads)
+    }
+
+    fn read_contents(&mut self, reads: &[ContentRead]) -> Vec<Result<Vec<u8>>> {
+        let ids = reads.iter().map(|(id, _, _)| *id).collect::<Vec<_>>();
+        let results = self
+            .read_file_headers(&ids)
+            .into_iter()
+            .zip(reads.iter())
+            .map(|(result, &(id, offset, len))| {
+                result?;
+                let (_, content_offset) = self.file_headers[&id];
+                let size = u64::from(self.file_info(id)?.size);
+                let len = len.unwrap_or(size);
+                match offset.checked_add(len) {
+                    Some(end) if end <= size => Ok((content_offset + offset, len)),
+                    _ => Err(Error::RangeOutOfBounds {
+                        id,
+                        offset,
+                        len,
+                        size,
+                    }),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let pending = results
+            .iter()
+            .filter_map(|r| r.as_ref().ok())
+            .map(|&(offset, len)| (offset, vec![0; len as usize]))
+            .collect();
+        let mut completed = match self.ring.read_batch(self.file.as_raw_fd(), pending) {
+            Ok(completed) => completed.into_iter(),
+            Err(e) => {
+                let e = Error::from(e);
+                return results.iter().map(|_| Err(duplicate_error(&e))).collect();
+            }
+        };
+        results
+            .into_iter()
+            .zip(ids)
+            .map(|(result, id)| {
+                result?;
+                let (buf, read) = completed.next().expect("read for every pending request");
+                read.map_err(|e| self.read_error(id, e))?;
+                Ok(buf)
+            })
+            .collect()
+    }
+
+    /// Читает заголовки файлов, которые еще не были прочитаны
+    fn read_file_headers(&mut self, ids: &[u128]) -> Vec<Result<()>> {
+        let mut results = ids
+            .iter()
+            .map(|&id| self.file_info(id).map(|info| (info.offset, info.size)))
+            .collect::<Vec<_>>();
+        let mut missing = ids
+            .iter()
+            .zip(results.iter())
+            .filter(|(id, r)| r.is_ok() && !self.file_headers.contains_key(id))
+            .map(|(&id, r)| (id, u64::from(r.as_ref().unwrap().0)))
+            .collect::<Vec<_>>();
+        missing.sort_unstable();
+        missing.dedup();
+
+        // Фиксированная часть заголовка: хеш содержимого и длина location
+        let reads = missing
+            .iter()
+            .map(|(_, offset)| (*offset, vec![0; FILE_HEADER_FIXED_SIZE as usize]))
+            .collect();
+        let fixed = match self.ring.read_batch(self.file.as_raw_fd(), reads) {
+            Ok(fixed) => fixed,
+            Err(e) => return ring_failure(ids, e),
+        };
+
+        let mut locations = vec![];
+        let mut errors = HashMap::new();
+        for ((id, offset), (fixed, result)) in missing.iter().zip(fixed) {
+            if let Err(e) = result {
+                errors.insert(*id, self.read_error(*id, e));
+                continue;
+            }
+            let location_len = u16::from_le_bytes([fixed[16], fixed[17]]);
+            if location_len > self.max_location_len {
+                let message = format!(
+                    "location of {} bytes exceeds {} bytes",
+                    location_len, self.max_location_len
+                );
+                errors.insert(*id, Error::DecodeLimitExceeded(message));
+                continue;
+            }
+            let mut hash = [0; 16];
+            hash.copy_from_slice(&fixed[..16]);
+            let location_offset = offset + u64::from(FILE_HEADER_FIXED_SIZE);
+            locations.push((*id, hash, location_offset, location_len));
+        }
+
+        let reads = locations
+            .iter()
+            .map(|&(_, _, offset, len)| (offset, vec![0; len as usize]))
+            .collect();
+        let read_locations = match self.ring.read_batch(self.file.as_raw_fd(), reads) {
+            Ok(read_locations) => read_locations,
+            Err(e) => return ring_failure(ids, e),
+        };
+        for ((id, hash, offset, _), (location, result)) in locations.into_iter().zip(read_locations)
+        {
+            match result {
+                Ok(()) => {
+                    let content_offset = offset + location.len() as u64;
+                    let header = FileHeader {
+                        hash: md5::Digest(hash),
+                        location,
+                    };
+                    self.file_headers.insert(id, (header, content_offset));
+                }
+                Err(e) => {
+                    errors.insert(id, self.read_error(id, e));
+                }
+            }
+        }
+
+        for (result, id) in results.iter_mut().zip(ids.iter()) {
+            if let Some(e) = errors.get(id) {
+                *result = Err(duplicate_error(e));
+            }
+        }
+        results.into_iter().map(|r| r.map(|_| ())).collect()
+    }
+
+    fn file_info(&self, id: u128) -> Result<&FileInfo> {
+        // Если идентификаторы повторяются, находится первая из таких записей
+        let file_info = self.header.file_info();
+        let pos = self
+            .id_index
+            .partition_point(|&idx| file_info[idx as usize].wide_id() < id);
+        self.id_index
+            .get(pos)
+            .map(|&idx| &file_info[idx as usize])
+            .filter(|info| info.wide_id() == id)
+            .ok_or(Error::FileNotFound { id })
+    }
+
+    /// Чтение за пределами файла блока означает, что файл выходит за границы блока
+    fn read_error(&self, id: u128, e: io::Error) -> Error {
+        match (e.kind(), self.file_info(id)) {
+            (io::ErrorKind::UnexpectedEof, Ok(info)) => Error::EntryOutOfBounds {
+                id,
+                offset: info.offset,
+                size: info.size,
+            },
+            _ => e.into(),
+        }
+    }
+}
+
+/// Ошибка каждого из запросов `ids`, если кольцо io_uring больше нельзя использовать (см.
+/// `Ring::read_batch`)
+fn ring_failure(ids: &[u128], e: io::Error) -> Vec<Result<()>> {
+    let e = Error::from(e);
+    ids.iter().map(|_| Err(duplicate_error(&e))).collect()
+}
+
+/// Копирует ошибку чтения заголовка файла для каждого запроса с тем же идентификатором
+fn duplicate_error(e: &Error) -> Error {
+    match e {
+        Error::DecodeLimitExceeded(message) => Error::DecodeLimitExceeded(message.clone()),
+        Error::EntryOutOfBounds { id, offset, size } => Error::EntryOutOfBounds {
+            id: *id,
+            offset: *offset,
+            size: *size,
+        },
+        Error::Io(e) => Error::Io(io::Error::new(e.kind(), e.to_string())),
+        e => Error::Io(io::Error::other(e.to_string())),
+    }
+}
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_OP_READ: u8 = 22;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+
+/// Максимальный размер одного чтения: ядро читает не больше `MAX_RW_COUNT` байт за раз, так
+/// что чтения больших файлов продолжаются с прочитанного места
+const MAX_READ_LEN: usize = 0x7fff_f000;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UringParams {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// Область памяти, разделяемая с ядром
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+
+    fn atomic(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*self.at::<AtomicU32>(offset) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// Минимальная реализация кольца io_uring, выполняющая только позиционные чтения
+struct Ring {
+    fd: File,
+    params: UringParams,
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    /// Не удалось дождаться завершения принятых ядром запросов (см. [`read_batch`])
+    ///
+    /// [`read_batch`]: #method.read_batch
+    failed: bool,
+}
+
+// Кольцо используется только через `&mut self`, а разделяемые с ядром области принадлежат ему
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = UringParams::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries.max(1),
+                &mut params as *mut UringParams,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { File::from_raw_fd(fd as RawFd) };
+        let raw_fd = fd.as_raw_fd();
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        Ok(Self {
+            sq: Mapping::new(raw_fd, sq_len, IORING_OFF_SQ_RING)?,
+            cq: Mapping::new(raw_fd, cq_len, IORING_OFF_CQ_RING)?,
+            sqes: Mapping::new(raw_fd, sqes_len, IORING_OFF_SQES)?,
+            fd,
+            params,
+            failed: false,
+        })
+    }
+
+    /// Читает в каждый буфер `buf.len()` байт файла `fd` начиная со смещения `offset` и
+    /// возвращает буферы вместе с результатами чтений. Чтения отправляются в ядро пачками
+    /// размером с очередь кольца.
+    ///
+    /// Если дождаться завершения принятых ядром запросов не удалось, ядро еще может записать
+    /// данные в буферы, поэтому буферы пачки не освобождаются, а возвращается ошибка. После этого
+    /// кольцо больше не используется и все следующие вызовы также возвращают ошибку.
+    fn read_batch(
+        &mut self,
+        fd: RawFd,
+        reads: Vec<(u64, Vec<u8>)>,
+    ) -> io::Result<Vec<(Vec<u8>, io::Result<()>)>> {
+        if self.failed {
+            return Err(io::Error::other(
+                "io_uring is unusable after failed wait for completions",
+            ));
+        }
+        let mut completed = Vec::with_capacity(reads.len());
+        let entries = self.params.sq_entries as usize;
+        let mut reads = reads;
+        while !reads.is_empty() {
+            let rest = reads.split_off(entries.min(reads.len()));
+            match self.submit_and_wait(fd, &mut reads) {
+                Ok(results) => completed.extend(reads.into_iter().map(|(_, buf)| buf).zip(results)),
+                Err(e) => {
+                    std::mem::forget(reads);
+                    self.failed = true;
+                    return Err(e);
+                }
+            }
+            reads = rest;
+        }
+        Ok(completed)
+    }
+
+    /// Выполняет чтения `reads`, количество которых не превышает размер очереди кольца.
+    ///
+    /// Запросы, не принятые ядром, убираются из очереди, а завершения всех принятых запросов
+    /// дожидаются даже при ошибке отправки. Ошибка возвращается, только если дождаться
+    /// завершений не удалось, и тогда буферы `reads` нельзя освобождать.
+    fn submit_and_wait(
+        &mut self,
+        fd: RawFd,
+        reads: &mut [(u64, Vec<u8>)],
+    ) -> io::Result<Vec<io::Result<()>>> {
+        let mut results = reads.iter().map(|_| Ok(())).collect::<Vec<_>>();
+        let mut done = vec![0; reads.len()];
+        let mut queue = (0..reads.len())
+            .filter(|&idx| !reads[idx].1.is_empty())
+            .collect::<Vec<_>>();
+        while !queue.is_empty() {
+            let batch = std::mem::take(&mut queue);
+            let (accepted, error) = self.submit(fd, reads, &done, &batch);
+            if let Some(error) = error {
+                for &idx in &batch[accepted..] {
+                    results[idx] = Err(io::Error::new(error.kind(), error.to_string()));
+                }
+            }
+            self.wait(accepted, reads, &mut done, &mut results, &mut queue)?;
+        }
+        Ok(results)
+    }
+
+    /// Ставит в очередь чтения `batch` (индексы в `reads`), продолжая каждое с `done[idx]`
+    /// байт, и отправляет их в ядро. Возвращает количество запросов, принятых ядром, и ошибку,
+    /// из-за которой остальные не были отправлены. Такие запросы убираются из очереди, чтобы
+    /// следующая отправка не передала ядру указатели на освобожденные буферы.
+    fn submit(
+        &mut self,
+        fd: RawFd,
+        reads: &mut [(u64, Vec<u8>)],
+        done: &[usize],
+        batch: &[usize],
+    ) -> (usize, Option<io::Error>) {
+        let sq_off = &self.params.sq_off;
+        let mask = unsafe { *self.sq.at::<u32>(sq_off.ring_mask) };
+        let start = self.sq.atomic(sq_off.tail).load(Ordering::Relaxed);
+        let mut tail = start;
+        for &idx in batch {
+            let (offset, buf) = &mut reads[idx];
+            let rest = &mut buf[done[idx]..];
+            let slot = tail & mask;
+            let sqe = Sqe {
+                opcode: IORING_OP_READ,
+                fd,
+                off: *offset + done[idx] as u64,
+                addr: rest.as_mut_ptr() as u64,
+                len: rest.len().min(MAX_READ_LEN) as u32,
+                user_data: idx as u64,
+                ..Default::default()
+            };
+            unsafe {
+                ptr::write(self.sqes.at::<Sqe>(0).add(slot as usize), sqe);
+                ptr::write(self.sq.at::<u32>(sq_off.array).add(slot as usize), slot);
+            }
+            tail = tail.wrapping_add(1);
+        }
+        self.sq.atomic(sq_off.tail).store(tail, Ordering::Release);
+
+        let mut to_submit = batch.len() as u32;
+        while to_submit > 0 {
+            match self.enter(to_submit, 0, 0) {
+                Ok(submitted) => to_submit -= submitted,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    // Ядро забирает запросы из очереди по порядку и только внутри
+                    // io_uring_enter, поэтому непринятые запросы – все после головы очереди
+                    let head = self.sq.atomic(sq_off.head).load(Ordering::Acquire);
+                    self.sq.atomic(sq_off.tail).store(head, Ordering::Release);
+                    return (head.wrapping_sub(start) as usize, Some(e));
+                }
+            }
+        }
+        (batch.len(), None)
+    }
+
+    /// Дожидается завершения `in_flight` принятых ядром запросов. Незавершенные короткими
+    /// чтениями запросы добавляются в `queue`, чтобы продолжить их с прочитанного места.
+    fn wait(
+        &mut self,
+        in_flight: usize,
+        reads: &[(u64, Vec<u8>)],
+        done: &mut [usize],
+        results: &mut [io::Result<()>],
+        queue: &mut Vec<usize>,
+    ) -> io::Result<()> {
+        let mut remaining = in_flight;
+        loop {
+            remaining -= self.reap(reads, done, results, queue);
+            if remaining == 0 {
+                return Ok(());
+            }
+            if let Err(e) = self.enter(0, remaining as u32, IORING_ENTER_GETEVENTS) {
+                let retry = e.kind() == io::ErrorKind::Interrupted
+                    || e.raw_os_error() == Some(libc::EAGAIN)
+                    || e.raw_os_error() == Some(libc::EBUSY);
+                if !retry {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn enter(&self, to_submit: u32, min_complete: u32, flags: libc::c_uint) -> io::Result<u32> {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd.as_raw_fd(),
+                to_submit,
+                min_complete,
+                flags,
+                ptr::null::<libc::c_void>(),
+                0usize,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result as u32)
+    }
+
+    /// Забирает завершенные запросы из очереди завершения
+    fn reap(
+        &mut self,
+        reads: &[(u64, Vec<u8>)],
+        done: &mut [usize],
+        results: &mut [io::Result<()>],
+        queue: &mut Vec<usize>,
+    ) -> usize {
+        let cq_off = &self.params.cq_off;
+        let mask = unsafe { *self.cq.at::<u32>(cq_off.ring_mask) };
+        let mut head = self.cq.atomic(cq_off.head).load(Ordering::Relaxed);
+        let tail = self.cq.atomic(cq_off.tail).load(Ordering::Acquire);
+        let mut reaped = 0;
+        while head != tail {
+            let cqe =
+                unsafe { ptr::read(self.cq.at::<Cqe>(cq_off.cqes).add((head & mask) as usize)) };
+            let idx = cqe.user_data as usize;
+            match cqe.res {
+                res if res < 0 => results[idx] = Err(io::Error::from_raw_os_error(-res)),
+                // Чтение обычного файла возвращает 0 только в конце файла
+                0 => results[idx] = Err(io::ErrorKind::UnexpectedEof.into()),
+                res => {
+                    done[idx] += res as usize;
+                    if done[idx] < reads[idx].1.len() {
+                        queue.push(idx);
+                    }
+                }
+            }
+            head = head.wrapping_add(1);
+            reaped += 1;
+        }
+        self.cq.atomic(cq_off.head).store(head, Ordering::Release);
+        reaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::{AddFileRequest, Block, BlockOptions};
+    use std::fs;
+
+    #[test]
+    fn should_read_files_in_batches() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-uring-test")?;
+        let paths = (1..=3)
+            .map(|id| {
+                let path = tmp.path().join(format!("{}.bin", id));
+                fs::write(&path, vec![id as u8; id * 1000]).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let locations = (1..=3).map(|id| format!("/{}", id)).collect::<Vec<_>>();
+        let files = paths
+            .iter()
+            .zip(locations.iter())
+            .enumerate()
+            .map(|(idx, (path, location))| AddFileRequest {
+                id: idx as u64 + 1,
+                path,
+                location: Path::new(location),
+                content_hash: None,
+                size: None,
+            })
+            .collect::<Vec<_>>();
+        let block_path = tmp.path().join("test.block");
+        let block = BlockOptions::new().create(&block_path, &files)?;
+
+        let mut reader = match Block::options()
+            .verify_on_read(true)
+            .open_uring(&block_path, 2)
+        {
+            Ok(reader) => reader,
+            // io_uring может быть отключен в окружении, где выполняются тесты
+            Err(Error::Io(e)) if e.raw_os_error() == Some(libc::ENOSYS) => return Ok(()),
+            Err(Error::Io(e)) if e.raw_os_error() == Some(libc::EPERM) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for _ in 0..2 {
+            let results = reader.read_files(&[3, 1, 42, 2]);
+            assert_eq!(results.len(), 4);
+            for (result, id) in results.iter().zip([3, 1, 42, 2].iter()) {
+                match (result, block.file_by_id(*id)) {
+                    (Ok((header, content)), Ok((expected_header, expected))) => {
+                        assert_eq!(header, &expected_header);
+                        assert_eq!(content, &expected[..]);
+                    }
+                    (Err(Error::FileNotFound { id: 42 }), Err(_)) => {}
+                    (r, _) => panic!("Unexpected result for {}: {:?}", id, r),
+                }
+            }
+        }
+
+        let ranges = reader.read_ranges(&[(2, 10, 5), (3, 2999, 1), (1, 999, 2)]);
+        assert_eq!(ranges[0].as_ref().unwrap(), &vec![2; 5]);
+        assert_eq!(ranges[1].as_ref().unwrap(), &vec![3; 1]);
+        match &ranges[2] {
+            Err(Error::RangeOutOfBounds {
+                id: 1, size: 1000, ..
+            }) => {}
+            r => panic!("RangeOutOfBounds expected, got: {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ring_should_report_reads_past_end_of_file() -> Result<()> {
+        let tmp = tempdir::TempDir::new("rust-uring-test")?;
+        let path = tmp.path().join("data");
+        fs::write(&path, (0..100u8).collect::<Vec<_>>())?;
+        let file = File::open(&path)?;
+        let mut ring = match Ring::new(2) {
+            Ok(ring) => ring,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EPERM)) => {
+                return Ok(())
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let reads = vec![(90, vec![0; 10]), (0, vec![]), (95, vec![0; 20])];
+        let results = ring.read_batch(file.as_raw_fd(), reads)?;
+        let (exact, empty, past_end) = (&results[0], &results[1], &results[2]);
+        assert!(exact.1.is_ok());
+        assert!(empty.1.is_ok());
+        match &past_end.1 {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            r => panic!("UnexpectedEof expected, got: {:?}", r),
+        }
+        assert_eq!(exact.0, [90, 91, 92, 93, 94, 95, 96, 97, 98, 99]);
+        assert_eq!(&past_end.0[..5], &[95, 96, 97, 98, 99]);
+
+        // После неудачного ожидания завершений кольцо не используется
+        ring.failed = true;
+        assert!(ring
+            .read_batch(file.as_raw_fd(), vec![(0, vec![0; 10])])
+            .is_err());
+        Ok(())
+    }
+}